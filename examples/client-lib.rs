@@ -1,4 +1,5 @@
 use portguard::client;
+use portguard::client::ClientEvent;
 
 #[no_mangle]
 extern "C" fn portguard_run_client(port: u16) {
@@ -7,6 +8,51 @@ extern "C" fn portguard_run_client(port: u16) {
         .enable_all()
         .build()
         .unwrap()
-        .block_on(async { client::Client::run_client(port, None).await })
+        .block_on(async {
+            let opts = client::ClientOptions {
+                port: Some(port),
+                ..Default::default()
+            };
+            client::Client::run_client(opts).await
+        })
         .unwrap();
-}
\ No newline at end of file
+}
+
+/// run client and report events to callback
+/// event codes: 1 connected, 2 reconnecting, 3 rejected,
+/// 4 server unreachable, 5 target unreachable, 6 transferred (with bytes sent and received),
+/// 7 ready
+#[no_mangle]
+extern "C" fn portguard_run_client_with_callback(
+    port: u16,
+    callback: extern "C" fn(event: i32, sent: u64, received: u64),
+) {
+    env_logger::init();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(event) = rx.recv().await {
+                    match event {
+                        ClientEvent::Connected => callback(1, 0, 0),
+                        ClientEvent::Reconnecting => callback(2, 0, 0),
+                        ClientEvent::Rejected(_) => callback(3, 0, 0),
+                        ClientEvent::ServerUnreachable(_) => callback(4, 0, 0),
+                        ClientEvent::TargetUnreachable(_) => callback(5, 0, 0),
+                        ClientEvent::Transferred { sent, received } => callback(6, sent, received),
+                        ClientEvent::Ready(_) => callback(7, 0, 0),
+                    }
+                }
+            });
+            let opts = client::ClientOptions {
+                port: Some(port),
+                events: Some(tx),
+                ..Default::default()
+            };
+            client::Client::run_client(opts).await
+        })
+        .unwrap();
+}
@@ -0,0 +1,16 @@
+#!/bin/sh
+# build size-optimized, statically linked client template for `gen-cli --profile small`
+# and install it next to `portguard`, built for host or for $PORTGUARD_TARGET if set
+set -e
+TARGET=${TARGET:-x86_64-unknown-linux-musl}
+cargo build --profile small --no-default-features --bin pgcli --target "$TARGET"
+if [ -n "$PORTGUARD_TARGET" ]; then
+    cargo build --release --bin portguard --target "$PORTGUARD_TARGET"
+    OUT="target/$PORTGUARD_TARGET/release"
+else
+    cargo build --release --bin portguard
+    OUT="target/release"
+fi
+mkdir -p "$OUT/templates/small"
+cp "target/$TARGET/small/pgcli" "$OUT/templates/small/"
+echo "template installed to $OUT/templates/small/pgcli"
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// network of allowed source addresses, e.g. "192.168.1.0/24" or "10.0.0.2"
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct AllowedNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// networks of a local network, allowed in bridge mode by default
+const PRIVATE_NETS: [&str; 8] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "fe80::/10",
+    "::1",
+];
+
+impl AllowedNet {
+    /// private, link-local and loopback networks
+    pub(crate) fn private_nets() -> Vec<AllowedNet> {
+        PRIVATE_NETS
+            .iter()
+            .map(|net| net.parse().expect("valid network"))
+            .collect()
+    }
+    /// network is inside a private, link-local or loopback network
+    pub(crate) fn is_private(&self) -> bool {
+        Self::private_nets()
+            .iter()
+            .any(|net| net.prefix <= self.prefix && net.contains(self.addr))
+    }
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AllowedNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{e}: {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix length: {s}"))?,
+            None => max,
+        };
+        Ok(AllowedNet { addr, prefix })
+    }
+}
+
+impl fmt::Display for AllowedNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// access control of client's local listener
+#[derive(Debug, Default)]
+pub(crate) struct LocalAcl {
+    /// only accept connections from loopback addresses
+    loopback_only: bool,
+    /// allowed source addresses, all are allowed if empty
+    nets: Vec<AllowedNet>,
+    /// allowed peer uids of unix socket, all are allowed if empty
+    uids: Vec<u32>,
+}
+
+impl LocalAcl {
+    pub(crate) fn new(loopback_only: bool, nets: Vec<AllowedNet>, uids: Vec<u32>) -> Self {
+        LocalAcl {
+            loopback_only,
+            nets,
+            uids,
+        }
+    }
+    /// check source address of a tcp connection
+    pub(crate) fn allows_addr(&self, ip: IpAddr) -> bool {
+        if self.loopback_only && !ip.to_canonical().is_loopback() {
+            return false;
+        }
+        self.nets.is_empty() || self.nets.iter().any(|net| net.contains(ip))
+    }
+    /// allowed source networks, all are allowed if empty
+    pub(crate) fn nets(&self) -> &[AllowedNet] {
+        &self.nets
+    }
+    /// check peer uid of a unix socket connection
+    pub(crate) fn allows_uid(&self, uid: u32) -> bool {
+        self.uids.is_empty() || self.uids.contains(&uid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<AllowedNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn networks_are_parsed() {
+        let cases = [
+            ("10.0.0.0/8", Some("10.0.0.0/8")),
+            ("10.0.0.2", Some("10.0.0.2/32")),
+            ("fe80::/10", Some("fe80::/10")),
+            ("::1", Some("::1/128")),
+            ("10.0.0.0/33", None),
+            ("::/129", None),
+            ("10.0.0.0/x", None),
+            ("10.0.0/8", None),
+            ("", None),
+        ];
+        for (s, expected) in cases {
+            let net = s.parse::<AllowedNet>().ok().map(|net| net.to_string());
+            assert_eq!(net.as_deref(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn addresses_are_matched_against_networks() {
+        let cases = [
+            ("192.168.1.0/24", "192.168.1.77", true),
+            ("192.168.1.0/24", "192.168.2.1", false),
+            ("10.0.0.2", "10.0.0.2", true),
+            ("10.0.0.2", "10.0.0.3", false),
+            ("0.0.0.0/0", "8.8.8.8", true),
+            ("0.0.0.0/0", "::1", false),
+            ("192.168.1.0/24", "::ffff:192.168.1.5", true),
+            ("fd00::/8", "fd12::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("::/0", "10.0.0.1", false),
+        ];
+        for (net, ip, expected) in cases {
+            let allowed = net
+                .parse::<AllowedNet>()
+                .unwrap()
+                .contains(ip.parse().unwrap());
+            assert_eq!(allowed, expected, "{ip} in {net}");
+        }
+    }
+
+    #[test]
+    fn private_networks_are_detected() {
+        let cases = [
+            ("192.168.1.0/24", true),
+            ("10.1.2.3", true),
+            ("127.0.0.1", true),
+            ("fe80::1", true),
+            ("::1", true),
+            ("10.0.0.0/7", false),
+            ("8.8.8.8", false),
+            ("0.0.0.0/0", false),
+            ("2001:db8::/32", false),
+        ];
+        for (net, expected) in cases {
+            assert_eq!(
+                net.parse::<AllowedNet>().unwrap().is_private(),
+                expected,
+                "{net}"
+            );
+        }
+    }
+
+    #[test]
+    fn listener_checks_allowlist_and_loopback() {
+        let open = LocalAcl::default();
+        let loopback = LocalAcl::new(true, vec![], vec![]);
+        let allowlist = LocalAcl::new(false, nets(&["192.168.1.0/24", "::1"]), vec![]);
+        let both = LocalAcl::new(true, nets(&["127.0.0.2"]), vec![]);
+        let cases = [
+            ("8.8.8.8", [true, false, false, false]),
+            ("127.0.0.1", [true, true, false, false]),
+            ("127.0.0.2", [true, true, false, true]),
+            ("::1", [true, true, true, false]),
+            ("::ffff:127.0.0.2", [true, true, false, true]),
+            ("192.168.1.5", [true, false, true, false]),
+            ("::ffff:192.168.1.5", [true, false, true, false]),
+        ];
+        for (ip, expected) in cases {
+            let ip = ip.parse().unwrap();
+            let allowed = [&open, &loopback, &allowlist, &both].map(|acl| acl.allows_addr(ip));
+            assert_eq!(allowed, expected, "{ip}");
+        }
+    }
+
+    #[test]
+    fn unix_peers_are_checked_by_uid() {
+        assert!(LocalAcl::default().allows_uid(1000));
+        let acl = LocalAcl::new(false, vec![], vec![0, 1000]);
+        assert!(acl.allows_uid(1000));
+        assert!(!acl.allows_uid(1001));
+    }
+}
@@ -0,0 +1,191 @@
+/// admin api on health check listener, each token of `[[admin_tokens]]` has a role:
+/// read-only tokens view status, operators also act on running services,
+/// admins also revoke clients
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+
+use crate::server::Server;
+
+/// role of an admin token, each one is allowed what roles before it are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Role {
+    /// view status, clients and online services
+    ReadOnly,
+    /// disconnect services and change log level
+    Operator,
+    /// revoke clients
+    Admin,
+}
+
+/// bearer token of admin api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AdminToken {
+    /// who holds the token, logged with actions taken by it
+    pub(crate) name: String,
+    pub(crate) token: String,
+    pub(crate) role: Role,
+}
+
+/// token matching `token`, tokens are compared by digest,
+/// so time taken does not tell how much of a token matches
+fn find<'a>(tokens: &'a [AdminToken], token: Option<&str>) -> Option<&'a AdminToken> {
+    let digest = |t: &str| Blake2s256::digest(t.as_bytes());
+    let token = digest(token?);
+    tokens
+        .iter()
+        .find(|t| !t.token.is_empty() && digest(&t.token) == token)
+}
+
+/// role needed by a request, `None` if there is no such endpoint
+fn required_role(method: &str, path: &str) -> Option<Role> {
+    match (method, path) {
+        ("GET", "/admin/status" | "/admin/clients") => Some(Role::ReadOnly),
+        ("POST", "/admin/log-level") => Some(Role::Operator),
+        ("POST", _) if path.starts_with("/admin/disconnect/") => Some(Role::Operator),
+        ("POST", _) if path.starts_with("/admin/revoke/") => Some(Role::Admin),
+        _ => None,
+    }
+}
+
+/// decode `%XX` escapes of a path segment, `None` if it is not valid utf-8
+fn decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// handle request of admin api, returning status and body of response
+/// `GET /admin/status` returns health report and online services (read-only)
+/// `GET /admin/clients` returns clients, their fingerprint and tenant (read-only)
+/// `POST /admin/log-level` cycles log level: info -> debug -> trace (operator)
+/// `POST /admin/disconnect/<service>` disconnects rclient of service "7" or "team-a/7" (operator)
+/// `POST /admin/revoke/<client>` revokes a client by name or fingerprint, saved to config (admin)
+pub(crate) async fn handle(
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    server: &Server,
+    report: String,
+) -> (&'static str, String) {
+    let role = match required_role(method, path) {
+        Some(role) => role,
+        None => return ("404 Not Found", String::from("not found\n")),
+    };
+    let admin = match find(server.admin_tokens(), token) {
+        Some(admin) => admin,
+        None => return ("401 Unauthorized", String::from("unauthorized\n")),
+    };
+    if admin.role < role {
+        log::warn!(
+            "Admin token {} is not allowed to {method} {path}",
+            admin.name
+        );
+        return ("403 Forbidden", String::from("forbidden\n"));
+    }
+    let arg = |prefix: &str| path.strip_prefix(prefix).and_then(decode);
+    match (method, path) {
+        ("GET", "/admin/status") => ("200 OK", report + &server.admin_services()),
+        ("GET", "/admin/clients") => ("200 OK", server.admin_clients()),
+        ("POST", "/admin/log-level") => {
+            log::warn!("Admin token {} changes log level", admin.name);
+            crate::logger::cycle_level();
+            ("200 OK", format!("log level: {}\n", log::max_level()))
+        }
+        ("POST", _) if path.starts_with("/admin/disconnect/") => {
+            match arg("/admin/disconnect/").and_then(|s| server.disconnect_service(&s)) {
+                Some(service) => {
+                    log::warn!("Admin token {} disconnected service {service}", admin.name);
+                    ("200 OK", format!("service {service} disconnected\n"))
+                }
+                None => ("404 Not Found", String::from("service is not online\n")),
+            }
+        }
+        _ => {
+            let client = arg("/admin/revoke/").unwrap_or_default();
+            match server.revoke_at_runtime(&client, &admin.name).await {
+                Ok(name) => ("200 OK", format!("client {name} revoked\n")),
+                Err(e) => ("409 Conflict", format!("{e}\n")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(name: &str, role: Role) -> AdminToken {
+        AdminToken {
+            name: String::from(name),
+            token: format!("secret-of-{name}"),
+            role,
+        }
+    }
+
+    #[test]
+    fn tokens_are_found_by_value() {
+        let tokens = [token("oncall", Role::ReadOnly), token("root", Role::Admin)];
+        let found = find(&tokens, Some("secret-of-root")).map(|t| t.role);
+        assert_eq!(found, Some(Role::Admin));
+        assert!(find(&tokens, Some("secret-of")).is_none());
+        assert!(find(&tokens, None).is_none());
+        let empty = [AdminToken {
+            token: String::new(),
+            ..token("empty", Role::Admin)
+        }];
+        assert!(find(&empty, Some("")).is_none());
+    }
+
+    #[test]
+    fn endpoints_need_roles() {
+        let table = [
+            ("GET", "/admin/status", Some(Role::ReadOnly)),
+            ("GET", "/admin/clients", Some(Role::ReadOnly)),
+            ("POST", "/admin/log-level", Some(Role::Operator)),
+            ("POST", "/admin/disconnect/team-a/7", Some(Role::Operator)),
+            ("POST", "/admin/revoke/alice", Some(Role::Admin)),
+            ("GET", "/admin/revoke/alice", None),
+            ("POST", "/admin/status", None),
+            ("GET", "/admin/", None),
+        ];
+        for (method, path, role) in table {
+            assert_eq!(required_role(method, path), role, "{method} {path}");
+        }
+    }
+
+    #[test]
+    fn roles_include_those_before_them() {
+        assert!(Role::ReadOnly < Role::Operator && Role::Operator < Role::Admin);
+        let token: AdminToken =
+            toml::from_str("name = 'oncall'\ntoken = 't'\nrole = 'read-only'").unwrap();
+        assert_eq!(token.role, Role::ReadOnly);
+        assert!(toml::from_str::<AdminToken>("name = 'a'\ntoken = 't'\nrole = 'root'").is_err());
+    }
+
+    #[test]
+    fn path_segments_are_decoded() {
+        assert_eq!(decode("alice").as_deref(), Some("alice"));
+        assert_eq!(decode("team%2Da%2F7").as_deref(), Some("team-a/7"));
+        assert_eq!(decode("bob%20smith").as_deref(), Some("bob smith"));
+        assert_eq!(decode("100%").as_deref(), Some("100%"));
+        assert_eq!(decode("%ff"), None);
+    }
+}
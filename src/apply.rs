@@ -0,0 +1,149 @@
+/// desired state of clients, reconciled with clients in config by `apply` subcommand
+///
+/// clients are matched by name: missing ones are generated, changed ones are replaced
+/// by new binaries with new keys, and ones not in desired state are revoked
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::remote::Remote;
+
+/// content of desired state file
+/// [[clients]]
+/// name = "alice"
+/// target = "127.0.0.1:22"  # and/or service, as `--target` and `--service` of `gen-cli`,
+/// service = 3              # remote of server or tenant if both are not set
+/// via = 7                  # with target, reach it through reverse proxy service exposing socks5
+/// tenant = "team-a"
+/// output = "out/alice"     # location of generated binary, `<output dir>/<name>` by default
+#[derive(Debug, Deserialize)]
+pub(crate) struct DesiredState {
+    #[serde(default)]
+    pub(crate) clients: Vec<DesiredClient>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DesiredClient {
+    pub(crate) name: String,
+    target: Option<String>,
+    service: Option<usize>,
+    via: Option<usize>,
+    pub(crate) tenant: Option<String>,
+    output: Option<PathBuf>,
+}
+
+impl DesiredClient {
+    pub(crate) fn remote(&self) -> Result<Option<Remote>> {
+        match (&self.target, self.service, self.via) {
+            (None, None, None) => Ok(None),
+            (Some(target), None, Some(id)) => Remote::try_chain(target, id).map(Some),
+            (_, Some(_), Some(_)) | (None, _, Some(_)) => Err(Error::InvalidRemote(format!(
+                "via of client {} needs a target and no service",
+                self.name
+            ))),
+            (target, service, None) => Remote::try_parse(target.as_deref(), service).map(Some),
+        }
+    }
+    pub(crate) fn output(&self, dir: &Path) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| dir.join(&self.name))
+    }
+}
+
+impl DesiredState {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let state: DesiredState = toml::de::from_str(&std::fs::read_to_string(path)?)?;
+        let mut names: Vec<&str> = state.clients.iter().map(|c| c.name.as_str()).collect();
+        names.sort_unstable();
+        if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+            Err(Error::Config(format!(
+                "client {} is desired more than once",
+                w[0]
+            )))?
+        }
+        for client in &state.clients {
+            client.remote()?;
+        }
+        Ok(state)
+    }
+}
+
+/// a client in config, as compared with desired state
+pub(crate) struct CurrentClient<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) remote: Option<&'a Remote>,
+    pub(crate) tenant: Option<&'a str>,
+}
+
+/// changes to reach desired state
+#[derive(Debug, Default)]
+pub(crate) struct Plan {
+    pub(crate) create: Vec<DesiredClient>,
+    /// revoked and created again
+    pub(crate) replace: Vec<DesiredClient>,
+    /// names of clients to revoke
+    pub(crate) revoke: Vec<String>,
+}
+
+impl Plan {
+    pub(crate) fn new(desired: &DesiredState, current: &[CurrentClient]) -> Result<Self> {
+        let mut plan = Plan::default();
+        for client in &desired.clients {
+            let remote = client.remote()?;
+            let matched: Vec<&CurrentClient> =
+                current.iter().filter(|c| c.name == client.name).collect();
+            let same = |c: &&CurrentClient| {
+                c.remote == remote.as_ref() && c.tenant == client.tenant.as_deref()
+            };
+            match matched.len() {
+                0 => plan.create.push(client.clone()),
+                1 if matched.iter().all(same) => {}
+                _ => plan.replace.push(client.clone()),
+            }
+        }
+        let mut revoke: Vec<String> = current
+            .iter()
+            .filter(|c| !desired.clients.iter().any(|d| d.name == c.name))
+            .map(|c| c.name.to_string())
+            .collect();
+        revoke.sort();
+        revoke.dedup();
+        plan.revoke = revoke;
+        Ok(plan)
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.create.is_empty() && self.replace.is_empty() && self.revoke.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |c: &DesiredClient| {
+            let remote = match c.remote() {
+                Ok(Some(remote)) => remote.to_string(),
+                _ => String::from("default remote"),
+            };
+            match &c.tenant {
+                Some(tenant) => format!("{} ({}, tenant {})", c.name, remote, tenant),
+                None => format!("{} ({})", c.name, remote),
+            }
+        };
+        for client in &self.create {
+            writeln!(f, "+ create  {}", describe(client))?;
+        }
+        for client in &self.replace {
+            writeln!(f, "~ replace {}", describe(client))?;
+        }
+        for name in &self.revoke {
+            writeln!(f, "- revoke  {name}")?;
+        }
+        writeln!(
+            f,
+            "Plan: {} to create, {} to replace, {} to revoke",
+            self.create.len(),
+            self.replace.len(),
+            self.revoke.len()
+        )
+    }
+}
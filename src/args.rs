@@ -0,0 +1,495 @@
+/// command line of `pgcli`, parsed by hand so client templates are built without clap,
+/// options are the same as of `portguard client`, see `ClientArgs`
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::client::{ClientArgs, ReconnectPolicy};
+use crate::service::{InstallArgs, UninstallArgs};
+use crate::sockopt::SocketOpts;
+
+/// usage shown by `--help`
+pub const USAGE: &str = "\
+Portguard client
+
+USAGE:
+    pgcli [OPTIONS]
+    pgcli install-service [--name <NAME>] [--user] [--dry-run] [-- <CLIENT ARGS>...]
+    pgcli uninstall-service [--name <NAME>] [--user] [--dry-run] [--remove-binary]
+
+OPTIONS:
+    -p, --port <PORT>                   local port to listen, 8022 by default
+        --profile <PROFILE>             use a profile embedded in client
+    -l, --listen <LISTEN>               local address to listen [default: 127.0.0.1]
+    -s, --server <SERVER>               use another server address in this run
+        --path <PATH>                   local address used as a path to server, repeatable
+        --log-level <LOG_LEVEL>         log level [env: RUST_LOG] [default: info]
+    -v, --verbose                       print logs instead of status display
+        --show-conf                     show builtin config and exit
+        --json                          show builtin config in json
+        --control <CONTROL>             loopback address of control endpoint
+        --status                        show active connections of client with --control
+        --stop                          stop client running with --control
+        --reconnect-max-elapsed <SECS>  stop reconnecting after this many seconds
+        --reconnect-initial-interval <MS>
+        --reconnect-max-interval <SECS>
+        --reconnect-jitter <PERCENT>
+        --loopback-only                 only accept connections from loopback addresses
+        --allow <NET>                   source network allowed to connect, repeatable
+        --bridge                        share service with local network
+        --allow-public                  accept public --allow networks in bridge mode
+        --unix-socket <PATH>            listen on a unix socket instead of a tcp port
+        --no-history                    do not keep local history of connections
+        --history-file <PATH>           location of history file
+        --allow-uid <UID>               uid allowed to connect to unix socket, repeatable
+        --allow-target <RULE>           target reachable by visitors of socks5, repeatable
+        --mdns <TYPE>                   advertise local listener via mdns
+        --mdns-name <NAME>              instance name advertised via mdns [default: portguard]
+        --http-proxy                    serve an http proxy instead of socks5
+        --keepalive <SECS>              seconds of idle before tcp keepalive probes
+        --nodelay                       disable nagle's algorithm
+        --send-buffer <BYTES>           bytes of socket send buffer
+        --recv-buffer <BYTES>           bytes of socket receive buffer
+        --tos <TOS>                     tos byte of packets
+        --so-mark <MARK>                firewall mark of connections (linux only)
+        --bind-device <DEVICE>          network device connections are bound to (linux only)
+        --fair                          send small writes before bulk transfers
+        --passphrase-attempts <N>       times key passphrase is asked [default: 3]
+        --passphrase-delay <SECS>       seconds to wait after a wrong passphrase [default: 1]
+        --pinentry <PROGRAM>            pinentry program asking key passphrase
+        --key-file <PATH>               file with private key of client [env: PORTGUARD_KEY_FILE]
+        --supervise                     restart client after a panic or an unrecoverable error
+        --crash-dir <DIR>               directory of crash reports of --supervise
+        --reload                        reload builtin config when client binary is replaced
+    -h, --help                          print help information
+    -V, --version                       print version information
+
+A port and server address given positionally in any order, e.g. `pgcli 8022 1.2.3.4:8022`,
+are taken as --port and --server, as older versions took them.
+";
+
+/// options needing another one, as `requires` of clap
+const REQUIRES: &[(&str, &str)] = &[
+    ("json", "show-conf"),
+    ("status", "control"),
+    ("stop", "control"),
+    ("allow-public", "bridge"),
+    ("allow-uid", "unix-socket"),
+    ("mdns-name", "mdns"),
+    ("crash-dir", "supervise"),
+];
+
+/// options not used together, as `conflicts_with` of clap
+const CONFLICTS: &[(&str, &str)] = &[
+    ("stop", "status"),
+    ("bridge", "loopback-only"),
+    ("bridge", "unix-socket"),
+    ("unix-socket", "port"),
+    ("unix-socket", "listen"),
+    ("history-file", "no-history"),
+    ("mdns", "unix-socket"),
+];
+
+/// what `pgcli` is asked to do
+#[derive(Debug)]
+pub enum Command {
+    /// run client
+    Run(Box<ClientArgs>),
+    InstallService(InstallArgs),
+    UninstallService(UninstallArgs),
+    /// print usage and exit
+    Help,
+    /// print version and exit
+    Version,
+}
+
+/// argument of command line
+enum Arg {
+    /// option by its long name, short ones are expanded
+    Opt(String),
+    Positional(String),
+    /// `--`, the rest are not options
+    Rest,
+}
+
+struct Parser {
+    args: std::vec::IntoIter<String>,
+    /// value of current option given as `--name=value` or `-pVALUE`
+    value: Option<String>,
+}
+
+impl Parser {
+    fn next_arg(&mut self) -> Result<Option<Arg>, String> {
+        if let Some(value) = self.value.take() {
+            return Err(format!("unexpected value {value:?} of a flag"));
+        }
+        let arg = match self.args.next() {
+            Some(arg) => arg,
+            None => return Ok(None),
+        };
+        if arg == "--" {
+            return Ok(Some(Arg::Rest));
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            let name = match long.split_once('=') {
+                Some((name, value)) => {
+                    self.value = Some(value.to_string());
+                    name
+                }
+                None => long,
+            };
+            return Ok(Some(Arg::Opt(name.to_string())));
+        }
+        let mut short = match arg.strip_prefix('-') {
+            Some(short) if !short.is_empty() => short.chars(),
+            _ => return Ok(Some(Arg::Positional(arg))),
+        };
+        let name = match short.next() {
+            Some('p') => "port",
+            Some('l') => "listen",
+            Some('s') => "server",
+            Some('v') => "verbose",
+            Some('h') => "help",
+            Some('V') => "version",
+            _ => return Err(format!("unknown option {arg}")),
+        };
+        let value = short.as_str();
+        if !value.is_empty() {
+            self.value = Some(value.trim_start_matches('=').to_string());
+        }
+        Ok(Some(Arg::Opt(name.to_string())))
+    }
+    /// value of option `name`, inline or in next argument
+    fn value<T>(&mut self, name: &str) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self
+            .value
+            .take()
+            .or_else(|| self.args.next())
+            .ok_or_else(|| format!("option --{name} needs a value"))?;
+        value
+            .parse()
+            .map_err(|e| format!("invalid value {value:?} of --{name}, {e}"))
+    }
+}
+
+/// parse arguments of `pgcli`, without the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args: Vec<String> = args.into_iter().collect();
+    let subcommand = match args.first().map(String::as_str) {
+        Some(cmd @ ("install-service" | "uninstall-service")) => Some(cmd.to_string()),
+        _ => None,
+    };
+    if subcommand.is_some() {
+        args.remove(0);
+    }
+    let mut parser = Parser {
+        args: args.into_iter(),
+        value: None,
+    };
+    match subcommand.as_deref() {
+        Some("install-service") => parse_install(&mut parser),
+        Some(_) => parse_uninstall(&mut parser),
+        None => parse_client(&mut parser),
+    }
+}
+
+/// client arguments left unset by command line
+fn defaults() -> ClientArgs {
+    ClientArgs {
+        port: None,
+        profile: None,
+        listen: IpAddr::from([127, 0, 0, 1]),
+        server: None,
+        paths: Vec::new(),
+        log_level: std::env::var("RUST_LOG").unwrap_or_else(|_| String::from("info")),
+        verbose: false,
+        show_conf: false,
+        json: false,
+        control: None,
+        status: false,
+        stop: false,
+        reconnect: ReconnectPolicy::default(),
+        loopback_only: false,
+        allow: Vec::new(),
+        bridge: false,
+        allow_public: false,
+        unix_socket: None,
+        no_history: false,
+        history_file: None,
+        allow_uids: Vec::new(),
+        allow_targets: Vec::new(),
+        mdns: None,
+        mdns_name: String::from("portguard"),
+        http_proxy: false,
+        socket: SocketOpts::default(),
+        fair: false,
+        passphrase_attempts: 3,
+        passphrase_delay: 1,
+        pinentry: None,
+        key_file: std::env::var_os("PORTGUARD_KEY_FILE").map(Into::into),
+        supervise: false,
+        crash_dir: None,
+        reload: false,
+    }
+}
+
+fn parse_client(p: &mut Parser) -> Result<Command, String> {
+    let mut args = defaults();
+    let mut given = HashSet::new();
+    let mut positional = Vec::new();
+    while let Some(arg) = p.next_arg()? {
+        let name = match arg {
+            Arg::Opt(name) => name,
+            Arg::Positional(arg) => {
+                positional.push(arg);
+                continue;
+            }
+            Arg::Rest => return Err(String::from("unexpected argument --")),
+        };
+        let n = name.as_str();
+        match n {
+            "help" => return Ok(Command::Help),
+            "version" => return Ok(Command::Version),
+            "port" => args.port = Some(p.value(n)?),
+            "profile" => args.profile = Some(p.value(n)?),
+            "listen" => args.listen = p.value(n)?,
+            "server" => args.server = Some(p.value(n)?),
+            "path" => args.paths.push(p.value(n)?),
+            "log-level" => args.log_level = p.value(n)?,
+            "verbose" => args.verbose = true,
+            "show-conf" => args.show_conf = true,
+            "json" => args.json = true,
+            "control" => args.control = Some(p.value(n)?),
+            "status" => args.status = true,
+            "stop" => args.stop = true,
+            "reconnect-max-elapsed" => args.reconnect.max_elapsed = Some(p.value(n)?),
+            "reconnect-initial-interval" => args.reconnect.initial_interval = Some(p.value(n)?),
+            "reconnect-max-interval" => args.reconnect.max_interval = Some(p.value(n)?),
+            "reconnect-jitter" => args.reconnect.jitter = Some(p.value(n)?),
+            "loopback-only" => args.loopback_only = true,
+            "allow" => args.allow.push(p.value(n)?),
+            "bridge" => args.bridge = true,
+            "allow-public" => args.allow_public = true,
+            "unix-socket" => args.unix_socket = Some(p.value(n)?),
+            "no-history" => args.no_history = true,
+            "history-file" => args.history_file = Some(p.value(n)?),
+            "allow-uid" => args.allow_uids.push(p.value(n)?),
+            "allow-target" => args.allow_targets.push(p.value(n)?),
+            "mdns" => args.mdns = Some(p.value(n)?),
+            "mdns-name" => args.mdns_name = p.value(n)?,
+            "http-proxy" => args.http_proxy = true,
+            "keepalive" => args.socket.keepalive = Some(p.value(n)?),
+            "nodelay" => args.socket.nodelay = true,
+            "send-buffer" => args.socket.send_buffer = Some(p.value(n)?),
+            "recv-buffer" => args.socket.recv_buffer = Some(p.value(n)?),
+            "tos" => args.socket.tos = Some(p.value(n)?),
+            "so-mark" => args.socket.so_mark = Some(p.value(n)?),
+            "bind-device" => args.socket.bind_device = Some(p.value(n)?),
+            "fair" => args.fair = true,
+            "passphrase-attempts" => args.passphrase_attempts = p.value(n)?,
+            "passphrase-delay" => args.passphrase_delay = p.value(n)?,
+            "pinentry" => args.pinentry = Some(p.value(n)?),
+            "key-file" => args.key_file = Some(p.value(n)?),
+            "supervise" => args.supervise = true,
+            "crash-dir" => args.crash_dir = Some(p.value(n)?),
+            "reload" => args.reload = true,
+            _ => return Err(format!("unknown option --{n}")),
+        }
+        given.insert(name);
+    }
+    for (option, required) in REQUIRES {
+        if given.contains(*option) && !given.contains(*required) {
+            return Err(format!("option --{option} requires --{required}"));
+        }
+    }
+    for (a, b) in CONFLICTS {
+        if given.contains(*a) && given.contains(*b) {
+            return Err(format!("option --{a} cannot be used with --{b}"));
+        }
+    }
+    apply_positional(&mut args, &positional)?;
+    Ok(Command::Run(Box::new(args)))
+}
+
+/// apply port and server address given positionally, e.g. `pgcli 8022 1.2.3.4:8022`,
+/// first valid one of each is used as older versions did, flags take precedence
+fn apply_positional(args: &mut ClientArgs, positional: &[String]) -> Result<(), String> {
+    for arg in positional {
+        if let Ok(port) = arg.parse::<u16>() {
+            args.port.get_or_insert(port);
+        } else if let Ok(server) = arg.parse::<SocketAddr>() {
+            args.server.get_or_insert(server);
+        } else {
+            return Err(format!(
+                "invalid argument {arg}, expected a port or server address"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_install(p: &mut Parser) -> Result<Command, String> {
+    let mut args = InstallArgs {
+        name: None,
+        user: false,
+        dry_run: false,
+        args: Vec::new(),
+    };
+    while let Some(arg) = p.next_arg()? {
+        match arg {
+            Arg::Opt(name) => match name.as_str() {
+                "help" => return Ok(Command::Help),
+                "name" => args.name = Some(p.value(&name)?),
+                "user" => args.user = true,
+                "dry-run" => args.dry_run = true,
+                _ => return Err(format!("unknown option --{name} of install-service")),
+            },
+            Arg::Positional(arg) => return Err(format!("unexpected argument {arg}")),
+            Arg::Rest => args.args.extend(p.args.by_ref()),
+        }
+    }
+    Ok(Command::InstallService(args))
+}
+
+fn parse_uninstall(p: &mut Parser) -> Result<Command, String> {
+    let mut args = UninstallArgs {
+        name: None,
+        user: false,
+        dry_run: false,
+        remove_binary: false,
+    };
+    while let Some(arg) = p.next_arg()? {
+        match arg {
+            Arg::Opt(name) => match name.as_str() {
+                "help" => return Ok(Command::Help),
+                "name" => args.name = Some(p.value(&name)?),
+                "user" => args.user = true,
+                "dry-run" => args.dry_run = true,
+                "remove-binary" => args.remove_binary = true,
+                _ => return Err(format!("unknown option --{name} of uninstall-service")),
+            },
+            Arg::Positional(arg) => return Err(format!("unexpected argument {arg}")),
+            Arg::Rest => return Err(String::from("unexpected argument --")),
+        }
+    }
+    Ok(Command::UninstallService(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn client(args: &[&str]) -> ClientArgs {
+        match parse_args(args) {
+            Ok(Command::Run(args)) => *args,
+            other => panic!("{args:?}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_match_portguard_client() {
+        let args = client(&[]);
+        assert_eq!(args.port, None);
+        assert_eq!(args.listen, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(args.mdns_name, "portguard");
+        assert_eq!((args.passphrase_attempts, args.passphrase_delay), (3, 1));
+        assert!(!args.verbose && !args.show_conf && args.paths.is_empty());
+    }
+
+    #[test]
+    fn options_take_values_in_any_form() {
+        let args = client(&[
+            "-p",
+            "2222",
+            "--listen=0.0.0.0",
+            "-s1.2.3.4:8022",
+            "--path",
+            "10.0.0.2",
+            "--path=10.0.0.3",
+            "-v",
+            "--reconnect-jitter",
+            "20",
+            "--nodelay",
+            "--tos",
+            "184",
+            "--allow",
+            "192.168.1.0/24",
+        ]);
+        assert_eq!(args.port, Some(2222));
+        assert_eq!(args.listen, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(args.server, Some("1.2.3.4:8022".parse().unwrap()));
+        assert_eq!(args.paths.len(), 2);
+        assert!(args.verbose);
+        assert_eq!(args.reconnect.jitter, Some(20));
+        assert!(args.socket.nodelay);
+        assert_eq!(args.socket.tos, Some(184));
+        assert_eq!(args.allow, vec!["192.168.1.0/24".parse().unwrap()]);
+    }
+
+    #[test]
+    fn positional_port_and_server_are_aliases() {
+        let server: SocketAddr = "1.2.3.4:8022".parse().unwrap();
+        for args in [["9000", "1.2.3.4:8022"], ["1.2.3.4:8022", "9000"]] {
+            let args = client(&args);
+            assert_eq!((args.port, args.server), (Some(9000), Some(server)));
+        }
+        let args = client(&["9000"]);
+        assert_eq!((args.port, args.server), (Some(9000), None));
+        // flags take precedence
+        let args = client(&["-p", "7000", "9000", "-s", "5.6.7.8:22", "1.2.3.4:8022"]);
+        assert_eq!(args.port, Some(7000));
+        assert_eq!(args.server, Some("5.6.7.8:22".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_arguments_are_errors() {
+        let invalid: &[&[&str]] = &[
+            &["not-a-port"],
+            &["--port"],
+            &["--port", "70000"],
+            &["--no-such-option"],
+            &["-x"],
+            &["--verbose=yes"],
+            &["--json"],
+            &["--stop", "--status", "--control", "127.0.0.1:9"],
+            &["--unix-socket", "/tmp/pg.sock", "-p", "22"],
+            &["--", "8022"],
+        ];
+        for args in invalid {
+            assert!(parse_args(args).is_err(), "{args:?}");
+        }
+        let err = parse_args(&["--json"]).unwrap_err();
+        assert_eq!(err, "option --json requires --show-conf");
+    }
+
+    #[test]
+    fn subcommands_take_their_options() {
+        match parse_args(&["install-service", "--user", "--name=pg", "--", "-p", "2222"]) {
+            Ok(Command::InstallService(args)) => {
+                assert!(args.user && !args.dry_run);
+                assert_eq!(args.name.as_deref(), Some("pg"));
+                assert_eq!(args.args, ["-p", "2222"]);
+            }
+            other => panic!("{other:?}"),
+        }
+        match parse_args(&["uninstall-service", "--remove-binary", "--dry-run"]) {
+            Ok(Command::UninstallService(args)) => assert!(args.remove_binary && args.dry_run),
+            other => panic!("{other:?}"),
+        }
+        assert!(parse_args(&["uninstall-service", "--", "x"]).is_err());
+        assert!(matches!(
+            parse_args(&["-p", "1", "--help"]),
+            Ok(Command::Help)
+        ));
+        assert!(matches!(parse_args(&["-V"]), Ok(Command::Version)));
+    }
+}
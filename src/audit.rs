@@ -0,0 +1,63 @@
+/// append-only audit log of client generation by `gen-cli`, `mod-cli`, `clone-cli` and `apply`,
+/// so provenance of a client binary found in the wild can be traced by its hash or public key
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use blake2::{Blake2s256, Digest};
+
+use crate::error::Result;
+use crate::gen;
+
+/// name of audit log file
+const AUDIT_FILE: &str = "portguard_audit.log";
+
+/// audit log next to server config, in current directory if there is no config file
+pub fn path_of(config: Option<&Path>) -> PathBuf {
+    match config {
+        Some(path) => path.with_file_name(AUDIT_FILE),
+        None => PathBuf::from(AUDIT_FILE),
+    }
+}
+
+/// user running current process
+fn user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// append a line of generated binary `output`: who, when, its hash, and the config embedded in it
+pub fn record(log: &Path, command: &str, name: Option<&str>, output: &Path) -> Result<()> {
+    let hash: String = Blake2s256::digest(std::fs::read(output)?)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let conf = gen::read_client_conf(output)?;
+    // private key protected by passphrase, or given at run time, cannot derive public key
+    let pubkey = match (conf.has_keypass, conf.has_external_key()) {
+        (true, _) => String::from("protected"),
+        (_, true) => String::from("external"),
+        _ => base64::encode(gen::derive_pubkey(&conf.client_prikey)?),
+    };
+    let output = output
+        .canonicalize()
+        .unwrap_or_else(|_| output.to_path_buf());
+    let line = format!(
+        "{} {} by {}: client {}, output {}, blake2s {}, pubkey {}, server {}, remote {}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        command,
+        user(),
+        name.unwrap_or("-"),
+        output.display(),
+        hash,
+        pubkey,
+        conf.server_addr,
+        conf.remote
+    );
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
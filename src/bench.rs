@@ -0,0 +1,97 @@
+/// benchmark of noise handshake and transport in memory,
+/// to compare crypto backends selected by cargo features
+use std::io;
+use std::time::Instant;
+
+use snowstorm::NoiseStream;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::consts::PATTERN;
+use crate::error::Result;
+
+#[cfg(all(feature = "ring-accelerated", feature = "libsodium-accelerated"))]
+compile_error!(
+    "features `ring-accelerated` and `libsodium-accelerated` cannot be enabled together"
+);
+
+/// crypto backend of noise in this build, the default one is used for what it lacks
+pub const CRYPTO_BACKEND: &str = if cfg!(feature = "ring-accelerated") {
+    "ring"
+} else if cfg!(feature = "libsodium-accelerated") {
+    "libsodium"
+} else {
+    "default"
+};
+
+const CHUNK_LEN: usize = 64 * 1024;
+const PIPE_LEN: usize = 1024 * 1024;
+
+type Pair = (NoiseStream<DuplexStream>, NoiseStream<DuplexStream>);
+
+async fn handshake(
+    server_prikey: &[u8],
+    server_pubkey: &[u8],
+    client_prikey: &[u8],
+) -> Result<Pair> {
+    let (client, server) = duplex(PIPE_LEN);
+    let initiator = snowstorm::Builder::new(PATTERN.parse()?)
+        .remote_public_key(server_pubkey)
+        .local_private_key(client_prikey)
+        .build_initiator()?;
+    let responder = snowstorm::Builder::new(PATTERN.parse()?)
+        .local_private_key(server_prikey)
+        .build_responder()?;
+    let pair = tokio::try_join!(
+        NoiseStream::handshake(client, initiator),
+        NoiseStream::handshake(server, responder)
+    )?;
+    Ok(pair)
+}
+
+/// run `handshakes` handshakes, then send `megabytes` through a noise stream, print their rates
+pub async fn run(handshakes: usize, megabytes: usize) -> Result<()> {
+    let server_key = snowstorm::Builder::new(PATTERN.parse()?).generate_keypair()?;
+    let client_key = snowstorm::Builder::new(PATTERN.parse()?).generate_keypair()?;
+    let keys = (
+        &server_key.private[..],
+        &server_key.public[..],
+        &client_key.private[..],
+    );
+    println!("Crypto backend: {}", CRYPTO_BACKEND);
+
+    let start = Instant::now();
+    for _ in 0..handshakes {
+        handshake(keys.0, keys.1, keys.2).await?;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!("Handshake: {:.0} per second", handshakes as f64 / secs);
+
+    let (mut client, mut server) = handshake(keys.0, keys.1, keys.2).await?;
+    let total = megabytes * 1024 * 1024;
+    let start = Instant::now();
+    let send = async {
+        let chunk = vec![0; CHUNK_LEN];
+        let mut sent = 0;
+        while sent < total {
+            let len = CHUNK_LEN.min(total - sent);
+            client.write_all(&chunk[..len]).await?;
+            sent += len;
+        }
+        client.flush().await
+    };
+    let receive = async {
+        let mut buf = vec![0; CHUNK_LEN];
+        let mut received = 0;
+        while received < total {
+            match server.read(&mut buf).await? {
+                0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                n => received += n,
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    tokio::try_join!(send, receive)?;
+    let secs = start.elapsed().as_secs_f64();
+    println!("Transport: {:.1} MB/s", megabytes as f64 / secs);
+    Ok(())
+}
@@ -1,19 +1,57 @@
-use std::net::SocketAddr;
-use anyhow::Result;
-
-use portguard::client::Client;
+use portguard::args::{self, Command};
+use portguard::client::{Client, ClientArgs};
+use portguard::service;
+use portguard::{exit, Result};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info")
+async fn main() {
+    let command = match args::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {e}\n\nFor more information try --help");
+            std::process::exit(exit::USAGE);
+        }
+    };
+    let result = match command {
+        Command::Help => {
+            print!("{}", args::USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("pgcli {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::InstallService(args) => {
+            portguard::logger::init(&log_level());
+            service::install(args)
+        }
+        Command::UninstallService(args) => {
+            portguard::logger::init(&log_level());
+            service::uninstall(args)
+        }
+        Command::Run(args) => {
+            portguard::logger::init(args.log_filters());
+            run(*args).await
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {e:?}");
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn log_level() -> String {
+    std::env::var("RUST_LOG").unwrap_or_else(|_| String::from("info"))
+}
+
+async fn run(args: ClientArgs) -> Result<()> {
+    if args.show_conf {
+        return Client::show_conf(args.json);
+    }
+    if let (Some(addr), true) = (args.control, args.status || args.stop) {
+        return Client::control(addr, args.stop).await;
     }
-    env_logger::init();
-    let port = std::env::args()
-        .find_map(|s| s.parse::<u16>().ok()) // first valid argument
-        .unwrap_or(8022); // default
-    let server = std::env::args().find_map(|s| s.parse::<SocketAddr>().ok());
-    Client::run_client(port, server).await.map_err(|e| {
+    Client::run_client(args.into()).await.map_err(|e| {
         log::error!("Error occured: {}", e);
         e
     })
@@ -0,0 +1,203 @@
+/// binding local listener of client, with fallback to following ports,
+/// explaining failures to bind, and accepting connections of listeners
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io;
+use tokio::net::TcpListener;
+#[cfg(feature = "server")]
+use tokio::net::TcpSocket;
+
+/// number of following ports tried when a non-strict port is in use
+const PORT_TRIES: u16 = 16;
+/// pause of accept loop after an error, e.g. out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+/// open file limit below which a warning is logged at startup
+#[cfg(all(unix, feature = "server"))]
+const FD_LIMIT_WARN: libc::rlim_t = 4096;
+
+/// bind `addr`, port 0 picks a free port
+///
+/// if port is in use and not `strict`, try following ports,
+/// otherwise fail with owner of the port if it can be found
+pub(crate) async fn bind_tcp(addr: SocketAddr, strict: bool) -> io::Result<TcpListener> {
+    let err = match TcpListener::bind(addr).await {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => e,
+        res => return res.map_err(|e| explain(addr, e)),
+    };
+    if !strict && addr.port() != 0 {
+        let ports = (1..=PORT_TRIES).filter_map(|i| addr.port().checked_add(i));
+        for port in ports {
+            let next = SocketAddr::new(addr.ip(), port);
+            if let Ok(listener) = TcpListener::bind(next).await {
+                log::warn!("Port {} is in use, use {} instead", addr.port(), port);
+                return Ok(listener);
+            }
+        }
+    }
+    let owner = port_owner(addr.port()).unwrap_or_else(|| String::from("another process"));
+    Err(io::Error::new(
+        err.kind(),
+        format!("port {} is in use by {}", addr.port(), owner),
+    ))
+}
+
+/// bind `addr` with `backlog` of pending connections
+#[cfg(feature = "server")]
+pub(crate) fn listen_tcp(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr).map_err(|e| explain(addr, e))?;
+    socket.listen(backlog)
+}
+
+/// explain a failure to bind `addr` that is not obvious from its error
+pub(crate) fn explain(addr: SocketAddr, err: io::Error) -> io::Error {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+        return err;
+    }
+    match excluded_range(addr.port()) {
+        Some((start, end)) => io::Error::new(
+            err.kind(),
+            format!(
+                "port {} is in excluded port range {}-{} reserved by system (e.g. Hyper-V or WSL), \
+                 choose another port, or see `netsh interface ipv4 show excludedportrange protocol=tcp`",
+                addr.port(),
+                start,
+                end
+            ),
+        ),
+        None => err,
+    }
+}
+
+/// excluded port range containing tcp `port`, ports in it can not be bound even if not in use
+#[cfg(windows)]
+fn excluded_range(port: u16) -> Option<(u16, u16)> {
+    let output = std::process::Command::new("netsh")
+        .args([
+            "interface",
+            "ipv4",
+            "show",
+            "excludedportrange",
+            "protocol=tcp",
+        ])
+        .output()
+        .ok()?;
+    // lines of ranges: start port, end port, and "*" for administered ones
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let start = fields.next()?.parse::<u16>().ok()?;
+            let end = fields.next()?.parse::<u16>().ok()?;
+            (start..=end).contains(&port).then_some((start, end))
+        })
+}
+
+#[cfg(not(windows))]
+fn excluded_range(_port: u16) -> Option<(u16, u16)> {
+    None
+}
+
+/// accept next connection, errors are logged and retried instead of ending accept loop
+pub(crate) async fn accept<F, Fut, T>(mut accept: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    loop {
+        match accept().await {
+            Ok(conn) => return conn,
+            // connection is gone before accepted, try next one at once
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                log::debug!("Failed to accept connection. Error: {}", e);
+            }
+            Err(e) => {
+                log::warn!("Failed to accept connection, retry later. Error: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// warn if open file limit is low, every connection takes one or two file descriptors
+#[cfg(all(unix, feature = "server"))]
+pub(crate) fn check_fd_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return;
+    }
+    if limit.rlim_cur < FD_LIMIT_WARN {
+        log::warn!(
+            "Open file limit is {} (hard limit {}), which many connections may run out of, \
+             raise it with `ulimit -n`",
+            limit.rlim_cur,
+            limit.rlim_max
+        );
+    }
+}
+
+#[cfg(all(not(unix), feature = "server"))]
+pub(crate) fn check_fd_limit() {}
+
+/// find process listening on tcp port, from procfs
+#[cfg(target_os = "linux")]
+fn port_owner(port: u16) -> Option<String> {
+    use std::fs;
+    let inodes: Vec<String> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    // fields: sl, local_address, rem_address, st, .., inode at 9
+                    let local_port = fields.get(1)?.rsplit(':').next()?;
+                    let listening = *fields.get(3)? == "0A";
+                    let matched = u16::from_str_radix(local_port, 16).ok()? == port;
+                    let inode = fields.get(9)?;
+                    (listening && matched).then(|| format!("socket:[{inode}]"))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+    // fds of processes of other users are not readable, skip them
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+        let owns = fs::read_dir(entry.path().join("fd"))
+            .ok()?
+            .flatten()
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|link| {
+                inodes
+                    .iter()
+                    .any(|inode| link.as_os_str() == inode.as_str())
+            });
+        let comm = fs::read_to_string(entry.path().join("comm")).ok();
+        owns.then(|| format!("{} (pid {})", comm.unwrap_or_default().trim(), pid))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_owner(_port: u16) -> Option<String> {
+    None
+}
@@ -1,32 +1,326 @@
-use std::net::SocketAddr;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
 use backoff::{future::retry, ExponentialBackoff};
 use bincode::Options;
 use blake2::{Blake2s256, Digest};
 use chacha20poly1305::aead::{Aead, NewAead};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce}; // Or `XChaCha20Poly1305`
+#[cfg(feature = "clap")]
+use clap::Args;
 use curve25519_dalek::EdwardsPoint;
+use fast_socks5::client::Socks5Stream;
+use fast_socks5::consts::{
+    SOCKS5_REPLY_CONNECTION_REFUSED, SOCKS5_REPLY_GENERAL_FAILURE, SOCKS5_REPLY_HOST_UNREACHABLE,
+    SOCKS5_REPLY_SUCCEEDED,
+};
+use fast_socks5::server::Socks5Socket;
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{Socks5Command, SocksError};
+use futures::TryFutureExt;
 use log;
 use serde::{Deserialize, Serialize};
 use snowstorm::NoiseStream;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::task::AbortOnDropHandle;
 
-use crate::consts::{CONF_BUF_LEN, KEYPASS_LEN, PATTERN};
-use crate::proxy;
+use crate::acl::{AllowedNet, LocalAcl};
+use crate::bind;
+use crate::console;
+use crate::consts::{Status, CONF_BUF_LEN, CONF_SCHEMA, DEFAULT_PORT, KEYPASS_LEN, PATTERN};
+use crate::control::{self, Sessions};
+use crate::early;
+use crate::error::{exit, Error, Result};
+use crate::fair::{Class, Fair, Scheduler};
+use crate::files;
+use crate::fingerprint::{self, KeyInfo};
+use crate::history::{self, History};
+use crate::http_proxy;
+use crate::i18n::Lang;
+use crate::instance::InstanceLock;
+use crate::mdns;
+use crate::measure;
+use crate::passphrase::Source;
+use crate::path::PathSet;
+use crate::pipeline::{self, SessionKeys};
+use crate::proxy::{self, Socks5Options};
+use crate::reissue;
+use crate::reload::{self, ConfWatch};
+use crate::remote::{Remote, Target};
+use crate::rules::{self, TargetRule};
+use crate::sockopt::SocketOpts;
+use crate::status;
+use crate::tasks::{Tasks, STOP_TIMEOUT};
+use crate::telemetry;
+use crate::ticket::{self, TicketCache};
+use crate::watchdog;
 
 /// client's builtin config, will be serialized to bincode
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub server_addr: SocketAddr,
-    pub target_addr: String, // TODO: should be Remote::Target, but it is untagged, cannot be decoded by bincode
-    pub reverse: bool,
+    #[serde(with = "crate::remote::tagged")]
+    pub remote: Remote,
     pub server_pubkey: Vec<u8>,
     pub client_prikey: Vec<u8>,
     pub has_keypass: bool, // client prikey passphrase
+    // new fields must be appended, a zeroed buffer decodes them as default,
+    // so that configs of older clients are still readable
+    pub reconnect: ReconnectPolicy,
+    pub profiles: Option<Vec<ClientProfile>>,
+    /// refuse to start if another instance of this client is running
+    pub single_instance: Option<bool>,
+    /// split tunneling rules of socks5 client
+    pub split: Option<SplitRules>,
+    /// resume sessions with tickets issued by server when reconnecting
+    pub resume: Option<bool>,
+    /// send first bytes of connections with handshake, if target is a socket address
+    pub early_data: Option<bool>,
+    /// options of socks5 server of reverse proxy client exposing socks5
+    pub socks5: Option<Socks5Options>,
+    /// target rules of reverse proxy client exposing socks5, enforced by client itself,
+    /// so visitors chaining through it only reach allowed targets
+    pub socks5_rules: Option<Vec<String>>,
+    /// language of messages shown to users, english if not set
+    pub lang: Option<Lang>,
+    /// send os and architecture of client with its version reported in each handshake
+    pub telemetry: Option<bool>,
+    /// report version in each handshake and read reply of server, set by `gen-cli` of
+    /// servers reading reports, clients generated by older servers never report
+    pub version_report: Option<bool>,
+    /// public key of next hop server of a relay target, client has an end-to-end
+    /// session with it through the relaying server
+    pub next_hop_pubkey: Option<Vec<u8>>,
+}
+
+/// named preset embedded in client, selected by `--profile`,
+/// each profile is a separate client of server with its own keypair and remote
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientProfile {
+    pub name: String,
+    /// local port to listen
+    pub port: u16,
+    #[serde(with = "crate::remote::tagged")]
+    pub remote: Remote,
+    pub client_prikey: Vec<u8>,
+}
+
+/// layout of `ClientConfig` before schema 2, remote is a string and its service id is lost
+#[derive(Serialize, Deserialize)]
+struct LegacyClientConfig {
+    server_addr: SocketAddr,
+    target_addr: String,
+    reverse: bool,
+    server_pubkey: Vec<u8>,
+    client_prikey: Vec<u8>,
+    has_keypass: bool,
+    reconnect: ReconnectPolicy,
+    profiles: Option<Vec<LegacyClientProfile>>,
+    single_instance: Option<bool>,
+    split: Option<SplitRules>,
+    resume: Option<bool>,
+    early_data: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyClientProfile {
+    name: String,
+    port: u16,
+    target_addr: String,
+    client_prikey: Vec<u8>,
+}
+
+/// first schema of `ClientConfig` with a tagged remote
+const TAGGED_REMOTE_SCHEMA: u32 = 2;
+
+/// legacy `target_addr` of remote, as displayed by older versions
+fn legacy_target_addr(remote: &Remote) -> String {
+    match remote {
+        Remote::Proxy(target) | Remote::RProxy(target, _) => target.to_string(),
+        // older visitors do not know chained target, server does
+        Remote::Service(id) | Remote::Chain(id, _) => format!("service (id: {id})"),
+    }
+}
+
+/// remote of legacy `target_addr`
+fn legacy_remote(target: &str, reverse: bool) -> Result<Remote> {
+    let service = target
+        .strip_prefix("service (id: ")
+        .and_then(|id| id.strip_suffix(')'));
+    match (reverse, service) {
+        // zeroed buffer of a client template
+        (false, _) if target.is_empty() => Ok(Remote::Proxy(Target::Socks5)),
+        (false, Some(id)) => id
+            .parse()
+            .map(Remote::Service)
+            .map_err(|_| Error::InvalidRemote(target.to_string())),
+        (false, None) => Remote::try_parse(Some(target), None),
+        // service id of reverse proxy client is only known by server
+        (true, _) => Remote::try_parse(Some(target), Some(0)),
+    }
+}
+
+impl TryFrom<LegacyClientConfig> for ClientConfig {
+    type Error = Error;
+    fn try_from(legacy: LegacyClientConfig) -> Result<Self> {
+        let profiles = legacy
+            .profiles
+            .map(|profiles| {
+                profiles
+                    .into_iter()
+                    .map(|p| {
+                        Ok(ClientProfile {
+                            remote: legacy_remote(&p.target_addr, false)?,
+                            name: p.name,
+                            port: p.port,
+                            client_prikey: p.client_prikey,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        Ok(ClientConfig {
+            server_addr: legacy.server_addr,
+            remote: legacy_remote(&legacy.target_addr, legacy.reverse)?,
+            server_pubkey: legacy.server_pubkey,
+            client_prikey: legacy.client_prikey,
+            has_keypass: legacy.has_keypass,
+            reconnect: legacy.reconnect,
+            profiles,
+            single_instance: legacy.single_instance,
+            split: legacy.split,
+            resume: legacy.resume,
+            early_data: legacy.early_data,
+            socks5: None,
+            socks5_rules: None,
+            lang: None,
+            telemetry: None,
+            version_report: None,
+            next_hop_pubkey: None,
+        })
+    }
+}
+
+impl From<ClientConfig> for LegacyClientConfig {
+    fn from(conf: ClientConfig) -> Self {
+        let reverse = conf.is_reverse();
+        let profiles = conf.profiles.map(|profiles| {
+            profiles
+                .into_iter()
+                .map(|p| LegacyClientProfile {
+                    name: p.name,
+                    port: p.port,
+                    target_addr: legacy_target_addr(&p.remote),
+                    client_prikey: p.client_prikey,
+                })
+                .collect()
+        });
+        LegacyClientConfig {
+            server_addr: conf.server_addr,
+            reverse,
+            target_addr: legacy_target_addr(&conf.remote),
+            server_pubkey: conf.server_pubkey,
+            client_prikey: conf.client_prikey,
+            has_keypass: conf.has_keypass,
+            reconnect: conf.reconnect,
+            profiles,
+            single_instance: conf.single_instance,
+            split: conf.split,
+            resume: conf.resume,
+            early_data: conf.early_data,
+        }
+    }
+}
+
+// reconnect policy of rclient, unset fields use default values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct ReconnectPolicy {
+    /// stop reconnecting after this many seconds, retry forever by default
+    #[cfg_attr(feature = "clap", clap(long = "reconnect-max-elapsed"))]
+    pub max_elapsed: Option<u64>,
+    /// initial reconnect interval in milliseconds [default: 500]
+    #[cfg_attr(feature = "clap", clap(long = "reconnect-initial-interval"))]
+    pub initial_interval: Option<u64>,
+    /// max reconnect interval in seconds [default: 60]
+    #[cfg_attr(feature = "clap", clap(long = "reconnect-max-interval"))]
+    pub max_interval: Option<u64>,
+    /// randomization of reconnect interval in percent [default: 50]
+    #[cfg_attr(feature = "clap", clap(long = "reconnect-jitter"))]
+    pub jitter: Option<u8>,
+}
+
+impl ReconnectPolicy {
+    /// fields set in `other` override fields of `self`
+    pub fn merge(self, other: ReconnectPolicy) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_elapsed: other.max_elapsed.or(self.max_elapsed),
+            initial_interval: other.initial_interval.or(self.initial_interval),
+            max_interval: other.max_interval.or(self.max_interval),
+            jitter: other.jitter.or(self.jitter),
+        }
+    }
+    fn backoff(&self) -> ExponentialBackoff {
+        let mut backoff = ExponentialBackoff {
+            max_elapsed_time: self.max_elapsed.map(Duration::from_secs),
+            ..Default::default()
+        };
+        if let Some(ms) = self.initial_interval {
+            backoff.initial_interval = Duration::from_millis(ms);
+            backoff.current_interval = backoff.initial_interval;
+        }
+        if let Some(secs) = self.max_interval {
+            backoff.max_interval = Duration::from_secs(secs);
+        }
+        if let Some(percent) = self.jitter {
+            backoff.randomization_factor = f64::from(percent.min(100)) / 100.0;
+        }
+        backoff
+    }
+}
+
+/// split tunneling of socks5 client, deciding locally which targets go through tunnel,
+/// rules are like "*.corp.example.com", "10.0.0.0/8" or "192.168.1.0/24:22,80"
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct SplitRules {
+    /// target routed through tunnel, others are connected directly, can be repeated,
+    /// all targets are routed through tunnel if not set
+    #[cfg_attr(feature = "clap", clap(long = "split-include"))]
+    pub include: Vec<String>,
+    /// target connected directly, overrides `--split-include`, can be repeated
+    #[cfg_attr(feature = "clap", clap(long = "split-exclude"))]
+    pub exclude: Vec<String>,
+}
+
+impl SplitRules {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+    /// rules allowing targets routed through tunnel
+    pub(crate) fn tunnel_rules(&self) -> Result<Vec<TargetRule>> {
+        let parse = |r: &String| r.parse::<TargetRule>().map_err(Error::Config);
+        let mut rules = self
+            .exclude
+            .iter()
+            .map(|r| parse(r).map(TargetRule::negate))
+            .collect::<Result<Vec<_>>>()?;
+        match self.include.is_empty() {
+            true => rules.push(parse(&String::from("*"))?),
+            false => rules.extend(self.include.iter().map(parse).collect::<Result<Vec<_>>>()?),
+        }
+        Ok(rules)
+    }
 }
 
 impl ClientConfig {
@@ -43,6 +337,39 @@ impl ClientConfig {
             .allow_trailing_bytes()
             .serialize(self)
     }
+
+    /// decode config of a binary reading `schema`, older layouts are migrated
+    pub fn from_slice_of(bytes: &[u8], schema: u32) -> Result<ClientConfig> {
+        if schema >= TAGGED_REMOTE_SCHEMA {
+            return Ok(Self::from_slice(bytes)?);
+        }
+        let legacy: LegacyClientConfig = bincode::options()
+            .with_limit(CONF_BUF_LEN as u64)
+            .allow_trailing_bytes()
+            .deserialize(bytes)?;
+        legacy.try_into()
+    }
+
+    /// encode config for a binary reading `schema`, in layout of that schema
+    pub fn to_vec_of(&self, schema: u32) -> Result<Vec<u8>> {
+        if schema >= TAGGED_REMOTE_SCHEMA {
+            return Ok(self.to_vec()?);
+        }
+        Ok(bincode::options()
+            .with_limit(CONF_BUF_LEN as u64)
+            .allow_trailing_bytes()
+            .serialize(&LegacyClientConfig::from(self.clone()))?)
+    }
+
+    /// client of reverse proxy
+    pub fn is_reverse(&self) -> bool {
+        matches!(self.remote, Remote::RProxy(_, _))
+    }
+
+    /// private key is not builtin, client is generated with `gen-cli --pubkey`
+    pub fn has_external_key(&self) -> bool {
+        self.client_prikey.is_empty()
+    }
 }
 
 #[cfg_attr(target_os = "linux", link_section = ".portguard")]
@@ -52,167 +379,1660 @@ impl ClientConfig {
 #[used]
 pub static CLIENT_CONF_BUF: [u8; CONF_BUF_LEN] = [0; CONF_BUF_LEN];
 
+/// schema of config this binary reads, checked before cloning a config into it,
+/// binaries without it are older than schema 1
+#[cfg_attr(target_os = "linux", link_section = ".pgschema")]
+#[cfg_attr(target_os = "android", link_section = ".pgschema")]
+#[cfg_attr(target_os = "windows", link_section = "pgschema")]
+#[cfg_attr(target_os = "macos", link_section = "__DATA,__pgschema")]
+#[used]
+pub static CLIENT_CONF_SCHEMA: [u8; 4] = CONF_SCHEMA.to_le_bytes();
+
+// command line arguments of client, shared by `portguard client` and `pgcli`
+#[derive(Debug)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct ClientArgs {
+    /// local port to listen, 8022 or port of selected profile by default,
+    /// 0 picks a free port, a default port in use falls back to following ports
+    #[cfg_attr(feature = "clap", clap(short, long))]
+    pub port: Option<u16>,
+    /// use a profile embedded in client, e.g. "db"
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub profile: Option<String>,
+    /// local address to listen
+    #[cfg_attr(feature = "clap", clap(short, long, default_value = "127.0.0.1"))]
+    pub listen: IpAddr,
+    /// use another server address in this run
+    #[cfg_attr(feature = "clap", clap(short, long))]
+    pub server: Option<SocketAddr>,
+    /// local address used as a path to server, can be repeated to stripe connections across paths
+    #[cfg_attr(feature = "clap", clap(long = "path"))]
+    pub paths: Vec<IpAddr>,
+    /// log level, e.g. error, warn, info, debug, trace
+    #[cfg_attr(feature = "clap", clap(long, env = "RUST_LOG", default_value = "info"))]
+    pub log_level: String,
+    /// print logs instead of status display, which generated clients show in a terminal
+    #[cfg_attr(feature = "clap", clap(short, long))]
+    pub verbose: bool,
+    /// show builtin config and exit
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub show_conf: bool,
+    /// show builtin config in json, for scripts
+    #[cfg_attr(feature = "clap", clap(long, requires = "show-conf"))]
+    pub json: bool,
+    /// loopback address of control endpoint, for `--status` and `--stop`
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub control: Option<SocketAddr>,
+    /// show active connections of the client running with `--control` and exit
+    #[cfg_attr(feature = "clap", clap(long, requires = "control"))]
+    pub status: bool,
+    /// stop the client running with `--control` and exit
+    #[cfg_attr(
+        feature = "clap",
+        clap(long, requires = "control", conflicts_with = "status")
+    )]
+    pub stop: bool,
+    /// override builtin reconnect policy
+    #[cfg_attr(feature = "clap", clap(flatten))]
+    pub reconnect: ReconnectPolicy,
+    /// only accept local connections from loopback addresses
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub loopback_only: bool,
+    /// source address or network allowed to connect, e.g. 192.168.1.0/24, can be repeated
+    #[cfg_attr(feature = "clap", clap(long = "allow"))]
+    pub allow: Vec<AllowedNet>,
+    /// share service with local network: listen on all addresses unless `--listen` is not
+    /// loopback, accept private addresses only unless `--allow` is set, log every connection
+    #[cfg_attr(feature = "clap", clap(long, conflicts_with_all = &["loopback-only", "unix-socket"]))]
+    pub bridge: bool,
+    /// accept `--allow` networks outside private ranges in bridge mode, exposing service
+    /// beyond local network
+    #[cfg_attr(feature = "clap", clap(long, requires = "bridge"))]
+    pub allow_public: bool,
+    /// listen on a unix socket instead of a tcp port (unix only)
+    #[cfg_attr(feature = "clap", clap(long, conflicts_with_all = &["port", "listen"]))]
+    pub unix_socket: Option<PathBuf>,
+    /// do not keep local history of connections
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub no_history: bool,
+    /// location of history file, ~/.portguard_history by default
+    #[cfg_attr(feature = "clap", clap(long, conflicts_with = "no-history"))]
+    pub history_file: Option<PathBuf>,
+    /// uid allowed to connect to unix socket, can be repeated
+    #[cfg_attr(feature = "clap", clap(long = "allow-uid", requires = "unix-socket"))]
+    pub allow_uids: Vec<u32>,
+    /// target reachable by visitors of reverse proxy client exposing socks5, can be repeated,
+    /// e.g. "10.0.0.0/8:5432", targets must also be allowed by rules embedded in client
+    #[cfg_attr(feature = "clap", clap(long = "allow-target"))]
+    pub allow_targets: Vec<String>,
+    /// advertise local listener via mdns with a service type, e.g. "_rdp._tcp"
+    #[cfg_attr(feature = "clap", clap(long, conflicts_with = "unix-socket"))]
+    pub mdns: Option<String>,
+    /// instance name advertised via mdns
+    #[cfg_attr(
+        feature = "clap",
+        clap(long, requires = "mdns", default_value = "portguard")
+    )]
+    pub mdns_name: String,
+    /// serve local connections as an http proxy instead of socks5, for socks5 clients,
+    /// so apps only supporting http proxies can use tunnel
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub http_proxy: bool,
+    /// options of sockets to server
+    #[cfg_attr(feature = "clap", clap(flatten))]
+    pub socket: SocketOpts,
+    /// send small writes of interactive visitors before bulk transfers, for reverse proxy clients,
+    /// the server schedules the other direction by `fair` of service limits
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub fair: bool,
+    /// times key passphrase is asked before giving up
+    #[cfg_attr(feature = "clap", clap(long, default_value = "3"))]
+    pub passphrase_attempts: u32,
+    /// seconds to wait after a wrong passphrase, doubled after each one, 0 to not wait
+    #[cfg_attr(feature = "clap", clap(long, default_value = "1"))]
+    pub passphrase_delay: u64,
+    /// pinentry program asking key passphrase when there is no terminal, e.g. "pinentry-gnome3",
+    /// env variable PORTGUARD_PASSPHRASE or systemd credential portguard-passphrase are used first
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub pinentry: Option<PathBuf>,
+    /// file with private key of client in base64, e.g. saved by `gen-keypair -o`,
+    /// for clients generated with `gen-cli --pubkey`
+    #[cfg_attr(feature = "clap", clap(long, env = "PORTGUARD_KEY_FILE"))]
+    pub key_file: Option<PathBuf>,
+    /// restart client after a panic or an unrecoverable error, writing a crash report
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub supervise: bool,
+    /// directory of crash reports of `--supervise`, temp dir by default
+    #[cfg_attr(feature = "clap", clap(long, requires = "supervise"))]
+    pub crash_dir: Option<PathBuf>,
+    /// reload builtin config when client binary is replaced, e.g. by output of `mod-cli`,
+    /// or on SIGHUP (unix), active connections are kept until they finish
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub reload: bool,
+}
+
+impl ClientArgs {
+    /// whether a status display is shown instead of logs: a generated client
+    /// runs in a terminal without `--verbose`
+    pub fn shows_status(&self) -> bool {
+        !self.verbose
+            && std::io::stdout().is_terminal()
+            && matches!(Client::builtin_conf(), Ok(Some(_)))
+    }
+    /// log filters of client, only errors are logged under status display
+    pub fn log_filters(&self) -> &str {
+        match self.shows_status() {
+            true => "error",
+            false => &self.log_level,
+        }
+    }
+}
+
+impl From<ClientArgs> for ClientOptions {
+    fn from(args: ClientArgs) -> Self {
+        let status = args.shows_status();
+        ClientOptions {
+            port: args.port,
+            profile: args.profile,
+            listen: args.listen,
+            server_addr: args.server,
+            paths: args.paths,
+            events: None,
+            reconnect: args.reconnect,
+            loopback_only: args.loopback_only,
+            allow: args.allow,
+            bridge: args.bridge,
+            allow_public: args.allow_public,
+            unix_socket: args.unix_socket,
+            allow_uids: args.allow_uids,
+            allow_targets: args.allow_targets,
+            control: args.control,
+            mdns: args.mdns.map(|t| (t, args.mdns_name)),
+            http_proxy: args.http_proxy,
+            socket: args.socket,
+            fair: args.fair,
+            history: match args.no_history {
+                true => None,
+                false => args.history_file.or_else(history::default_path),
+            },
+            passphrase_attempts: args.passphrase_attempts,
+            passphrase_delay: args.passphrase_delay,
+            pinentry: args.pinentry,
+            key_file: args.key_file,
+            supervise: args.supervise,
+            crash_dir: args.crash_dir,
+            reload: args.reload,
+            status,
+        }
+    }
+}
+
+/// client's runtime options, not embedded in binary
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// local port to listen, 8022 or port of selected profile if not set
+    pub port: Option<u16>,
+    /// name of profile embedded in client
+    pub profile: Option<String>,
+    /// local address to listen
+    pub listen: IpAddr,
+    /// use another server address in this run
+    pub server_addr: Option<SocketAddr>,
+    /// local addresses used as paths to server
+    pub paths: Vec<IpAddr>,
+    /// channel receiving client events, for embedding applications
+    pub events: Option<UnboundedSender<ClientEvent>>,
+    /// override builtin reconnect policy
+    pub reconnect: ReconnectPolicy,
+    /// only accept local connections from loopback addresses
+    pub loopback_only: bool,
+    /// allowed source addresses, all are allowed if empty
+    pub allow: Vec<AllowedNet>,
+    /// share service with local network, private source addresses only if `allow` is empty
+    pub bridge: bool,
+    /// accept allowed networks outside private ranges in bridge mode
+    pub allow_public: bool,
+    /// listen on a unix socket instead of a tcp port
+    pub unix_socket: Option<PathBuf>,
+    /// allowed peer uids of unix socket, all are allowed if empty
+    pub allow_uids: Vec<u32>,
+    /// target rules of reverse proxy client exposing socks5, in addition to embedded ones
+    pub allow_targets: Vec<String>,
+    /// loopback address of control endpoint
+    pub control: Option<SocketAddr>,
+    /// service type and instance name to advertise local listener via mdns
+    pub mdns: Option<(String, String)>,
+    /// serve local connections as an http proxy, for socks5 clients
+    pub http_proxy: bool,
+    /// options of sockets to server
+    pub socket: SocketOpts,
+    /// prioritize small writes over bulk transfers, for reverse proxy clients
+    pub fair: bool,
+    /// file to keep history of connections, no history if not set
+    pub history: Option<PathBuf>,
+    /// times key passphrase is asked before giving up
+    pub passphrase_attempts: u32,
+    /// seconds to wait after a wrong passphrase, doubled after each one
+    pub passphrase_delay: u64,
+    /// pinentry program asking key passphrase when there is no terminal
+    pub pinentry: Option<PathBuf>,
+    /// file with private key of client, for clients without builtin key
+    pub key_file: Option<PathBuf>,
+    /// restart client after a panic or an unrecoverable error
+    pub supervise: bool,
+    /// directory of crash reports when supervised, temp dir if not set
+    pub crash_dir: Option<PathBuf>,
+    /// reload builtin config when client binary is replaced
+    pub reload: bool,
+    /// show status display on stdout, for users in a terminal
+    pub status: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            port: None,
+            profile: None,
+            listen: IpAddr::from([127, 0, 0, 1]),
+            server_addr: None,
+            paths: Vec::new(),
+            events: None,
+            reconnect: ReconnectPolicy::default(),
+            loopback_only: false,
+            allow: Vec::new(),
+            bridge: false,
+            allow_public: false,
+            unix_socket: None,
+            allow_uids: Vec::new(),
+            allow_targets: Vec::new(),
+            control: None,
+            mdns: None,
+            http_proxy: false,
+            socket: SocketOpts::default(),
+            fair: false,
+            history: None,
+            passphrase_attempts: 3,
+            passphrase_delay: 1,
+            pinentry: None,
+            key_file: None,
+            supervise: false,
+            crash_dir: None,
+            reload: false,
+            status: false,
+        }
+    }
+}
+
+/// events of a running client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// client is serving, with what it serves, e.g. "127.0.0.1:8022 → 10.0.0.5:22"
+    /// of a local listener and its target
+    Ready(String),
+    /// handshake with server succeeded
+    Connected,
+    /// connection to server is lost or failed, will retry
+    Reconnecting,
+    /// rejected by server, with reason
+    Rejected(String),
+    /// server cannot be reached
+    ServerUnreachable(String),
+    /// exposed target of reverse proxy cannot be reached
+    TargetUnreachable(String),
+    /// a proxied connection is finished
+    Transferred { sent: u64, received: u64 },
+}
+
+/// runtime context shared by client tasks
+struct ClientContext {
+    conf: ClientConfig,
+    /// local address to listen
+    listen_addr: SocketAddr,
+    /// fail instead of trying following ports if listen port is in use
+    strict_port: bool,
+    /// service type and instance name to advertise local listener via mdns
+    mdns: Option<(String, String)>,
+    paths: PathSet,
+    events: Option<UnboundedSender<ClientEvent>>,
+    acl: LocalAcl,
+    /// local listener is shared with local network
+    bridge: bool,
+    /// rules of targets routed through tunnel, if socks5 client splits tunneling
+    split: Option<Vec<TargetRule>>,
+    /// socks5 target rules embedded in client and given at runtime, if it exposes socks5,
+    /// a target must be allowed by both
+    socks5_rules: [Vec<TargetRule>; 2],
+    /// serve local connections as an http proxy instead of socks5
+    http_proxy: bool,
+    /// schedule streams of reverse proxy connection fairly
+    fair: bool,
+    /// session resumption ticket, if client resumes sessions
+    tickets: Option<TicketCache>,
+    /// active connections, reported to control endpoint
+    sessions: Arc<Sessions>,
+    /// tasks of this run, stopped when it stops
+    tasks: Tasks,
+}
+
+impl ClientContext {
+    fn emit(&self, event: ClientEvent) {
+        if let Some(tx) = &self.events {
+            tx.send(event).ok();
+        }
+    }
+    fn emit_transferred(&self, bytes: Option<(u64, u64)>) {
+        if let Some((sent, received)) = bytes {
+            self.emit(ClientEvent::Transferred { sent, received });
+        }
+    }
+}
+
+/// state of builtin config reported by `info`, value is its exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[repr(i32)]
+pub enum ConfigState {
+    /// config is complete
+    Ready = exit::OK,
+    /// client key is protected by passphrase, its pubkey is unknown until unlocked
+    KeyProtected = exit::KEY_PROTECTED,
+    /// client key is not builtin, it is given by `--key-file`
+    KeyExternal = exit::KEY_EXTERNAL,
+    /// config section is zeroed, binary is not generated by `gen-cli`
+    Unprovisioned = exit::UNPROVISIONED,
+    /// config can not be decoded, e.g. it is damaged
+    Broken = exit::CONFIG,
+}
+
+/// summary of builtin config shown by `info`
+#[derive(Serialize)]
+struct Info {
+    state: ConfigState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    summary: Option<InfoSummary>,
+}
+
+#[derive(Serialize)]
+struct InfoSummary {
+    schema: u32,
+    server_addr: SocketAddr,
+    server_key: KeyInfo,
+    remote: String,
+    reverse: bool,
+    has_keypass: bool,
+    /// `None` if private key is protected by passphrase or not builtin
+    client_key: Option<KeyInfo>,
+}
+
+impl Info {
+    fn read() -> Self {
+        let broken = |e: Error| Info {
+            state: ConfigState::Broken,
+            error: Some(e.to_string()),
+            summary: None,
+        };
+        let conf = match Client::builtin_conf() {
+            Ok(Some(conf)) => conf,
+            Ok(None) => {
+                return Info {
+                    state: ConfigState::Unprovisioned,
+                    error: Some(Error::Unprovisioned.to_string()),
+                    summary: None,
+                }
+            }
+            Err(e) => return broken(e),
+        };
+        let pubkey = match Client::client_pubkey(&conf) {
+            Ok(pubkey) => pubkey,
+            Err(e) => return broken(e),
+        };
+        let state = match pubkey {
+            Some(_) => ConfigState::Ready,
+            None if conf.has_external_key() => ConfigState::KeyExternal,
+            None => ConfigState::KeyProtected,
+        };
+        let summary = InfoSummary {
+            schema: CONF_SCHEMA,
+            reverse: conf.is_reverse(),
+            server_addr: conf.server_addr,
+            server_key: KeyInfo::of(&conf.server_pubkey),
+            remote: conf.remote.to_string(),
+            has_keypass: conf.has_keypass,
+            client_key: pubkey.map(|k| KeyInfo::of(&k)),
+        };
+        Info {
+            state,
+            error: None,
+            summary: Some(summary),
+        }
+    }
+    fn print(&self) {
+        let s = match (&self.summary, &self.error) {
+            (Some(s), _) => s,
+            (None, error) => {
+                let error = error.as_deref().unwrap_or_default();
+                match self.state {
+                    ConfigState::Unprovisioned => {
+                        println!("Config: none, this binary is not generated by gen-cli")
+                    }
+                    _ => println!("Config: broken, {error}"),
+                }
+                return;
+            }
+        };
+        println!("Config: schema {}", s.schema);
+        println!("Server address: {}", s.server_addr);
+        println!("Server key: {}", s.server_key);
+        println!("Remote: {}", s.remote);
+        println!("Reverse proxy: {}", s.reverse);
+        println!("Key passphrase: {}", s.has_keypass);
+        match (&s.client_key, self.state) {
+            (Some(key), _) => println!("Client key: {key}"),
+            (None, ConfigState::KeyExternal) => {
+                println!("Client key: not builtin, given by --key-file")
+            }
+            (None, _) => println!("Client key: unknown, protected by passphrase"),
+        }
+    }
+}
+
+/// builtin config shown by `--show-conf --json`, without private keys and passwords
+#[derive(Serialize)]
+struct ConfigView<'a> {
+    lang: Lang,
+    server_addr: SocketAddr,
+    server_key: KeyInfo,
+    remote: String,
+    reverse: bool,
+    has_keypass: bool,
+    external_key: bool,
+    reconnect: ReconnectPolicy,
+    single_instance: bool,
+    resume: bool,
+    early_data: bool,
+    telemetry: bool,
+    version_report: bool,
+    socks5_request_timeout: Option<u64>,
+    socks5_no_dns: bool,
+    socks5_commands: Option<&'a [proxy::Socks5Command]>,
+    socks5_username: Option<&'a str>,
+    socks5_rules: Option<&'a [String]>,
+    split: Option<&'a SplitRules>,
+    profiles: Vec<ProfileView<'a>>,
+}
+
+#[derive(Serialize)]
+struct ProfileView<'a> {
+    name: &'a str,
+    port: u16,
+    remote: String,
+}
+
+impl<'a> ConfigView<'a> {
+    fn of(conf: &'a ClientConfig) -> Self {
+        let socks5 = conf.socks5.as_ref();
+        ConfigView {
+            lang: conf.lang.unwrap_or_default(),
+            server_addr: conf.server_addr,
+            server_key: KeyInfo::of(&conf.server_pubkey),
+            remote: conf.remote.to_string(),
+            reverse: conf.is_reverse(),
+            has_keypass: conf.has_keypass,
+            external_key: conf.has_external_key(),
+            reconnect: conf.reconnect,
+            single_instance: conf.single_instance.unwrap_or(false),
+            resume: conf.resume.unwrap_or(false),
+            early_data: conf.early_data.unwrap_or(false),
+            telemetry: conf.telemetry.unwrap_or(false),
+            version_report: conf.version_report.unwrap_or(false),
+            socks5_request_timeout: socks5.and_then(|s| s.request_timeout),
+            socks5_no_dns: socks5.is_some_and(|s| s.no_dns),
+            socks5_commands: socks5.and_then(|s| s.commands.as_deref()),
+            socks5_username: socks5.and_then(|s| Some(s.auth.as_ref()?.username.as_str())),
+            socks5_rules: conf.socks5_rules.as_deref(),
+            split: conf.split.as_ref().filter(|s| !s.is_empty()),
+            profiles: conf
+                .profiles
+                .iter()
+                .flatten()
+                .map(|p| ProfileView {
+                    name: &p.name,
+                    port: p.port,
+                    remote: p.remote.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
 pub struct Client;
 
 impl Client {
     /// entrance of client program
-    pub async fn run_client(port: u16, server_addr: Option<SocketAddr>) -> Result<()> {
-        let mut conf = ClientConfig::from_slice(&CLIENT_CONF_BUF)?;
-        if let Some(addr) = server_addr {
+    pub async fn run_client(mut opts: ClientOptions) -> Result<()> {
+        let display = match opts.status {
+            true => {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                opts.events = Some(tx);
+                let lang = Self::builtin_conf().ok().flatten().and_then(|c| c.lang);
+                let display = status::display(rx, lang.unwrap_or_default());
+                Some(AbortOnDropHandle::new(tokio::spawn(display)))
+            }
+            false => None,
+        };
+        let res = match opts.supervise {
+            true => {
+                let crash_dir = opts.crash_dir.clone().unwrap_or_else(std::env::temp_dir);
+                watchdog::supervise(opts, crash_dir).await
+            }
+            false => Self::run_once(opts).await,
+        };
+        if display.is_some() {
+            status::finish();
+        }
+        res
+    }
+    /// run client until it stops or fails
+    pub(crate) async fn run_once(mut opts: ClientOptions) -> Result<()> {
+        let unix_socket = opts.unix_socket.take();
+        let control = opts.control;
+        let mut watch = match opts.reload {
+            true => Some(ConfWatch::new()?),
+            false => None,
+        };
+        let mut ctx = Self::make_context(opts.clone()).await?;
+        log::info!(
+            "Server key fingerprint: {}",
+            fingerprint::of(&ctx.conf.server_pubkey)
+        );
+        if ctx.conf.remote == Remote::Proxy(Target::Files) {
+            Err(Error::Config(String::from(
+                "client of files target can only be used by `cp` command",
+            )))?
+        }
+        let instance = match ctx.conf.single_instance {
+            Some(true) => Self::lock_instance(&ctx, unix_socket.as_deref())?,
+            _ => None,
+        };
+        // spawn to handle control commands
+        let stop = Arc::new(Notify::new());
+        if let Some(addr) = control {
+            let sessions = ctx.sessions.clone();
+            let (stop, tasks) = (stop.clone(), ctx.tasks.clone());
+            ctx.tasks.spawn(async move {
+                if let Err(e) = control::serve(addr, sessions, stop, tasks).await {
+                    log::warn!("Control endpoint stopped. Error: {}", e);
+                }
+            });
+        }
+        let tasks = ctx.tasks.clone();
+        let res = 'run: loop {
+            let run = Self::serve(ctx.clone(), unix_socket.clone());
+            tokio::pin!(run);
+            let next = loop {
+                let conf = tokio::select! {
+                    res = &mut run => break 'run res,
+                    _ = stop.notified() => break 'run Ok(()),
+                    _ = console::interrupted() => break 'run Ok(()),
+                    conf = Self::reloaded(&mut watch) => conf,
+                };
+                match Self::context_of(opts.clone(), conf, Some(&ctx)).await {
+                    Ok(next) => break next,
+                    Err(e) => log::warn!("Failed to reload config, keep current one. Error: {}", e),
+                }
+            };
+            // a new reverse proxy connection replaces current one on server,
+            // so it is made after visitors of current one leave
+            if ctx.conf.is_reverse() && !ctx.sessions.is_idle() {
+                log::info!("Config changed, reload after active connections finish");
+                tokio::select! {
+                    res = &mut run => break 'run res,
+                    _ = stop.notified() => break 'run Ok(()),
+                    _ = console::interrupted() => break 'run Ok(()),
+                    _ = ctx.sessions.idle() => {}
+                }
+            }
+            // listeners of current config stop here, connections they accepted go on
+            log::info!("Config reloaded, target address: {}", next.conf.remote);
+            ctx = next;
+        };
+        // no connection or listener outlives this run, e.g. when supervisor restarts it
+        if !tasks.shutdown(STOP_TIMEOUT).await {
+            log::warn!("Timeout when stopping {} tasks", tasks.len());
+        }
+        drop(instance);
+        console::cleaned_up();
+        res
+    }
+    /// serve by type of client until it fails
+    async fn serve(ctx: Arc<ClientContext>, unix_socket: Option<PathBuf>) -> Result<()> {
+        match (ctx.conf.is_reverse(), unix_socket) {
+            (true, _) => Self::run_client_reverse_proxy(ctx).await,
+            (false, Some(path)) => Self::run_client_unix_proxy(path, ctx).await,
+            (false, None) => Self::run_client_proxy(ctx).await,
+        }
+    }
+    /// new config of replaced client binary, never resolves if client does not reload
+    async fn reloaded(watch: &mut Option<ConfWatch>) -> ClientConfig {
+        match watch {
+            Some(watch) => watch.changed().await,
+            None => futures::future::pending().await,
+        }
+    }
+    /// acquire single instance lock, keyed by client key
+    fn lock_instance(
+        ctx: &ClientContext,
+        unix_socket: Option<&Path>,
+    ) -> Result<Option<InstanceLock>> {
+        let id: String = Blake2s256::digest(&ctx.conf.client_prikey)[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let lock = match InstanceLock::acquire(&id) {
+            Ok(Ok(lock)) => lock,
+            Ok(Err(info)) => Err(Error::AlreadyRunning(info))?,
+            Err(e) => {
+                log::warn!("Failed to check running instances. Error: {}", e);
+                return Ok(None);
+            }
+        };
+        let listening = match (ctx.conf.is_reverse(), unix_socket) {
+            (true, _) => String::from("reverse proxy"),
+            (false, Some(path)) => format!("listening on {}", path.display()),
+            (false, None) => format!("listening on {}", ctx.listen_addr),
+        };
+        lock.record(&format!("pid {}, {}", std::process::id(), listening))?;
+        Ok(Some(lock))
+    }
+    /// advertise local listener until returned handle is dropped
+    fn spawn_mdns(
+        service_type: &str,
+        name: &str,
+        listen_addr: SocketAddr,
+        tasks: &Tasks,
+    ) -> Option<AbortOnDropHandle<Option<()>>> {
+        let ip = match listen_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => mdns::lan_ip(),
+            IpAddr::V4(ip) if !ip.is_loopback() => Ok(ip),
+            _ => {
+                log::warn!("Local listener is not reachable from lan, mdns is disabled");
+                return None;
+            }
+        };
+        let service = match ip {
+            Ok(ip) => mdns::Service::new(service_type, name, ip, listen_addr.port()),
+            Err(e) => {
+                log::warn!("Failed to get lan address for mdns. Error: {}", e);
+                return None;
+            }
+        };
+        let advertise = tasks.spawn(async move {
+            if let Err(e) = mdns::advertise(service).await {
+                log::warn!("Mdns advertisement stopped. Error: {}", e);
+            }
+        });
+        Some(AbortOnDropHandle::new(advertise))
+    }
+    /// send `--status` or `--stop` to control endpoint of a running client, print its reply
+    pub async fn control(addr: SocketAddr, stop: bool) -> Result<()> {
+        let cmd = match stop {
+            true => "stop",
+            false => "status",
+        };
+        let reply = control::request(addr, cmd).await?;
+        print!("{}", reply);
+        Ok(())
+    }
+    async fn make_context(opts: ClientOptions) -> Result<Arc<ClientContext>> {
+        Self::context_of(opts, Self::require_builtin_conf()?, None).await
+    }
+    /// context of `conf`, a context reloaded from `prev` keeps its connections and tasks
+    async fn context_of(
+        opts: ClientOptions,
+        mut conf: ClientConfig,
+        prev: Option<&ClientContext>,
+    ) -> Result<Arc<ClientContext>> {
+        if let Some(addr) = opts.server_addr {
             conf.server_addr = addr;
         }
-        // verfify client key passphrase
+        conf.reconnect = conf.reconnect.merge(opts.reconnect);
+        let mut port = opts.port;
+        if let Some(name) = &opts.profile {
+            let profile = conf
+                .profiles
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|p| &p.name == name)
+                .ok_or_else(|| Error::Config(format!("profile {name} not found")))?;
+            log::info!("Using profile: {}", profile.name);
+            conf.remote = profile.remote;
+            conf.client_prikey = profile.client_prikey;
+            port = port.or(Some(profile.port));
+        }
+        // verfify client key passphrase, prompts and waits after wrong ones block
         if conf.has_keypass {
-            conf.client_prikey = Self::decrypt_client_prikey(conf.client_prikey)?;
+            let source = Source::detect(opts.pinentry.as_deref())?;
+            let key = std::mem::take(&mut conf.client_prikey);
+            let lang = conf.lang.unwrap_or_default();
+            let (attempts, delay) = (opts.passphrase_attempts, opts.passphrase_delay);
+            conf.client_prikey = tokio::task::spawn_blocking(move || {
+                Self::decrypt_client_prikey(key, lang, &source, attempts, delay)
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+        match (conf.has_external_key(), &opts.key_file) {
+            (true, Some(path)) => conf.client_prikey = Self::read_key_file(path)?,
+            (true, None) => Err(Error::Config(String::from(
+                "client key is not builtin, give its private key by --key-file",
+            )))?,
+            (false, Some(_)) => log::warn!("Client key is builtin, --key-file is ignored"),
+            (false, None) => {}
+        }
+        let split = match (&conf.split, conf.remote == Remote::Proxy(Target::Socks5)) {
+            (Some(split), true) if !split.is_empty() => Some(split.tunnel_rules()?),
+            _ => None,
+        };
+        if opts.http_proxy && conf.remote != Remote::Proxy(Target::Socks5) {
+            Err(Error::Config(String::from(
+                "http proxy inbound is only supported by socks5 clients",
+            )))?
         }
-        let conf = Arc::new(conf);
-        match conf.reverse {
-            true => Self::run_client_reverse_proxy(conf).await,
-            false => Self::run_client_proxy(port, conf).await,
+        if opts.fair && !matches!(conf.remote, Remote::RProxy(..)) {
+            Err(Error::Config(String::from(
+                "fair scheduling is only for reverse proxy clients",
+            )))?
+        }
+        if !opts.allow_targets.is_empty()
+            && !matches!(conf.remote, Remote::RProxy(Target::Socks5, _))
+        {
+            Err(Error::Config(String::from(
+                "allowed targets are only for reverse proxy clients exposing socks5",
+            )))?
+        }
+        let parse_rules = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|r| r.parse().map_err(Error::Config))
+                .collect::<Result<Vec<TargetRule>>>()
+        };
+        let socks5_rules = [
+            parse_rules(conf.socks5_rules.as_deref().unwrap_or_default())?,
+            parse_rules(&opts.allow_targets)?,
+        ];
+        let (listen, allow) = match opts.bridge {
+            true => Self::bridge_listener(opts.listen, opts.allow, opts.allow_public)?,
+            false => (opts.listen, opts.allow),
+        };
+        let tickets = (conf.resume == Some(true)).then(TicketCache::default);
+        let sessions = match prev {
+            Some(prev) => prev.sessions.clone(),
+            None => {
+                let history =
+                    (opts.history).map(|path| History::new(path, conf.remote.to_string()));
+                Arc::new(Sessions::new(history))
+            }
+        };
+        Ok(Arc::new(ClientContext {
+            listen_addr: SocketAddr::new(listen, port.unwrap_or(DEFAULT_PORT)),
+            // a port given by user is strict, default or embedded one is not
+            strict_port: opts.port.is_some(),
+            mdns: opts.mdns,
+            conf,
+            paths: PathSet::new(opts.paths, opts.socket),
+            events: opts.events,
+            acl: LocalAcl::new(opts.loopback_only, allow, opts.allow_uids),
+            bridge: opts.bridge,
+            split,
+            socks5_rules,
+            http_proxy: opts.http_proxy,
+            fair: opts.fair,
+            tickets,
+            sessions,
+            tasks: prev.map(|prev| prev.tasks.clone()).unwrap_or_default(),
+        }))
+    }
+
+    /// listen address and allowed sources of bridge mode, a loopback `listen` becomes
+    /// all addresses, sources default to private networks, public ones need `allow_public`
+    fn bridge_listener(
+        listen: IpAddr,
+        allow: Vec<AllowedNet>,
+        allow_public: bool,
+    ) -> Result<(IpAddr, Vec<AllowedNet>)> {
+        let listen = match listen.is_loopback() {
+            true => IpAddr::from([0, 0, 0, 0]),
+            false => listen,
+        };
+        if let Some(net) = allow.iter().find(|net| !net.is_private() && !allow_public) {
+            Err(Error::Config(format!(
+                "{net} is outside private networks, add --allow-public to expose service to it"
+            )))?
         }
+        let allow = match allow.is_empty() {
+            true => AllowedNet::private_nets(),
+            false => allow,
+        };
+        Ok((listen, allow))
     }
 
     /// client type: visitor (addr, socks5, rproxy)
     /// in config: remote = "127.0.0.1:xxxx"
     ///     or     remote = "socks5"
     ///     or     remote = 66
-    async fn run_client_proxy(port: u16, conf: Arc<ClientConfig>) -> Result<()> {
-        // read client config, overwrite server address
+    async fn run_client_proxy(ctx: Arc<ClientContext>) -> Result<()> {
+        let listener = bind::bind_tcp(ctx.listen_addr, ctx.strict_port).await?;
+        let listen_addr = listener.local_addr()?;
         // log information
-        let listen_addr: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
         log::info!("Client listening on: {:?}", listen_addr);
-        log::info!("Portguard server on: {:?}", conf.server_addr);
-        log::info!("Target address: {:?}", conf.target_addr);
+        log::info!("Portguard server on: {:?}", ctx.conf.server_addr);
+        log::info!("Target address: {}", ctx.conf.remote);
+        ctx.emit(ClientEvent::Ready(format!(
+            "{listen_addr} → {}",
+            ctx.conf.remote
+        )));
+        let nets = ctx.acl.nets();
+        if ctx.bridge {
+            let nets: Vec<String> = nets.iter().map(|net| net.to_string()).collect();
+            log::warn!(
+                "Bridge mode, {} is shared with local network on {:?}, allowed sources: {}",
+                ctx.conf.remote,
+                listen_addr,
+                nets.join(", ")
+            );
+        } else if !listen_addr.ip().is_loopback() && nets.is_empty() {
+            log::warn!(
+                "Listening on {:?}, any host reaching it can connect, \
+                 use --allow to filter sources or --bridge to share with local network",
+                listen_addr
+            );
+        }
+        // spawn to advertise local listener, as long as it listens
+        let _mdns = match &ctx.mdns {
+            Some((service_type, name)) => {
+                Self::spawn_mdns(service_type, name, listen_addr, &ctx.tasks)
+            }
+            None => None,
+        };
         // start proxy
-        let listener = TcpListener::bind(listen_addr).await?;
-        while let Ok((inbound, _)) = listener.accept().await {
-            let conf = conf.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Client::handle_client_connection(inbound, &conf).await {
+        loop {
+            let (inbound, peer_addr) = bind::accept(|| listener.accept()).await;
+            if !ctx.acl.allows_addr(peer_addr.ip()) {
+                log::warn!("Refused local connection from {peer_addr}");
+                continue;
+            }
+            log::info!("New incoming peer_addr {:?}", peer_addr);
+            let ctx = ctx.clone();
+            ctx.tasks.clone().spawn(async move {
+                let (_session, inbound) = ctx.sessions.open(peer_addr.to_string(), inbound);
+                if let Err(e) = Client::handle_client_connection(inbound, &ctx).await {
                     log::warn!("{}", e);
                 }
+                if ctx.bridge {
+                    log::info!("Connection from {peer_addr:?} closed");
+                }
             });
         }
+    }
+    /// same as `run_client_proxy`, but listen on a unix socket
+    #[cfg(unix)]
+    async fn run_client_unix_proxy(path: PathBuf, ctx: Arc<ClientContext>) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+        log::info!("Client listening on: {:?}", path);
+        log::info!("Portguard server on: {:?}", ctx.conf.server_addr);
+        log::info!("Target address: {}", ctx.conf.remote);
+        // remove socket left by previous run, never remove other files
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        ctx.emit(ClientEvent::Ready(format!(
+            "{} → {}",
+            path.display(),
+            ctx.conf.remote
+        )));
+        loop {
+            let (inbound, _) = bind::accept(|| listener.accept()).await;
+            let uid = inbound.peer_cred().map(|cred| cred.uid());
+            let uid = match uid {
+                Ok(uid) if ctx.acl.allows_uid(uid) => {
+                    log::info!("New incoming peer uid {uid}");
+                    uid
+                }
+                _ => {
+                    log::warn!("Refused local connection from uid {uid:?}");
+                    continue;
+                }
+            };
+            let ctx = ctx.clone();
+            ctx.tasks.clone().spawn(async move {
+                let (_session, inbound) = ctx.sessions.open(format!("uid {uid}"), inbound);
+                if let Err(e) = Client::handle_client_connection(inbound, &ctx).await {
+                    log::warn!("{}", e);
+                }
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    async fn run_client_unix_proxy(_path: PathBuf, _ctx: Arc<ClientContext>) -> Result<()> {
+        Err(Error::Config(String::from(
+            "unix socket is not supported on this platform",
+        )))
+    }
+    async fn handle_client_connection<S>(inbound: S, ctx: &ClientContext) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if ctx.http_proxy {
+            return Self::handle_http_connection(inbound, ctx).await;
+        }
+        if let Some(rules) = &ctx.split {
+            return Self::handle_split_connection(inbound, rules, ctx).await;
+        }
+        let mut inbound = inbound;
+        let mut early = vec![];
+        if ctx.conf.early_data == Some(true)
+            && matches!(ctx.conf.remote, Remote::Proxy(Target::Addr(_)))
+        {
+            // application that speaks first, e.g. http or tls, sends its first bytes at once
+            early.resize(early::MAX_EARLY_DATA, 0);
+            let len = timeout(early::EARLY_DATA_WAIT, inbound.read(&mut early))
+                .await
+                .unwrap_or(Ok(0))?;
+            early.truncate(len);
+        }
+        let (enc_outbound, keys) = Self::connect_server(ctx, &early).await?;
+        if let Remote::Proxy(Target::Relay(_)) = ctx.conf.remote {
+            let inner = Self::handshake_next_hop(ctx, enc_outbound).await?;
+            let bytes = proxy::transfer_and_log_error(inbound, inner).await;
+            ctx.emit_transferred(bytes);
+            return Ok(());
+        }
+        // transfer data
+        let bytes = pipeline::transfer_and_log_error(inbound, enc_outbound, &keys).await;
+        ctx.emit_transferred(bytes);
+        Ok(())
+    }
+    /// serve socks5 request locally, connect target through tunnel if allowed by `rules`,
+    /// otherwise connect it directly
+    async fn handle_split_connection<S>(
+        inbound: S,
+        rules: &[TargetRule],
+        ctx: &ClientContext,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut config = fast_socks5::server::Config::default();
+        config.set_dns_resolve(false).set_execute_command(false);
+        let mut socket = Socks5Socket::new(inbound, Arc::new(config))
+            .upgrade_to_socks5()
+            .await
+            .map_err(|e| Error::Socks5(e.to_string()))?;
+        let target = socket
+            .target_addr()
+            .cloned()
+            .ok_or_else(|| Error::Socks5(String::from("no target address")))?;
+        let (domain, ip, port) = Self::lookup_target(&target);
+        let bytes = match rules::allows(rules, domain, ip, port) {
+            true => {
+                log::info!("Connecting {target} through tunnel");
+                let outbound = Self::connect_tunnel_target(ctx, target.clone()).await?;
+                let code = match &outbound {
+                    Ok(_) => SOCKS5_REPLY_SUCCEEDED,
+                    Err(SocksError::ReplyError(e)) => e.as_u8(),
+                    Err(_) => SOCKS5_REPLY_GENERAL_FAILURE,
+                };
+                proxy::socks5_reply(&mut socket, code).await?;
+                let outbound = outbound.map_err(|e| Error::Socks5(e.to_string()))?;
+                proxy::transfer_and_log_error(socket, outbound).await
+            }
+            false => {
+                log::info!("Connecting {target} directly");
+                let outbound = Self::connect_direct(domain, ip, port).await;
+                let code = match &outbound {
+                    Ok(_) => SOCKS5_REPLY_SUCCEEDED,
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        SOCKS5_REPLY_CONNECTION_REFUSED
+                    }
+                    Err(_) => SOCKS5_REPLY_HOST_UNREACHABLE,
+                };
+                proxy::socks5_reply(&mut socket, code).await?;
+                proxy::transfer_and_log_error(socket, outbound?).await
+            }
+        };
+        ctx.emit_transferred(bytes);
         Ok(())
     }
-    async fn handle_client_connection(inbound: TcpStream, conf: &ClientConfig) -> Result<()> {
-        log::info!("New incoming peer_addr {:?}", inbound.peer_addr());
-        // make noise stream
+
+    /// serve http proxy request locally, connect target through tunnel,
+    /// or directly if split tunneling rules do not allow it
+    async fn handle_http_connection<S>(mut inbound: S, ctx: &ClientContext) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = http_proxy::read_request(&mut inbound).await?;
+        let target = request.target.clone();
+        let (domain, ip, port) = Self::lookup_target(&target);
+        let bytes = match &ctx.split {
+            Some(rules) if !rules::allows(rules, domain, ip, port) => {
+                log::info!("Connecting {target} directly");
+                let outbound = Self::connect_direct(domain, ip, port).await;
+                http_proxy::serve(inbound, outbound.map_err(Error::from), request).await?
+            }
+            _ => {
+                log::info!("Connecting {target} through tunnel");
+                let outbound = match Self::connect_tunnel_target(ctx, target.clone()).await {
+                    Ok(outbound) => outbound.map_err(|e| Error::Socks5(e.to_string())),
+                    Err(e) => Err(e),
+                };
+                http_proxy::serve(inbound, outbound, request).await?
+            }
+        };
+        ctx.emit_transferred(bytes);
+        Ok(())
+    }
+    /// domain, ip and port of `target` for split tunneling rules,
+    /// domain is not resolved, so names of tunneled targets never reach local dns,
+    /// it is resolved locally only when connected directly
+    fn lookup_target(target: &TargetAddr) -> (Option<&str>, Option<IpAddr>, u16) {
+        match target {
+            TargetAddr::Ip(addr) => (None, Some(addr.ip()), addr.port()),
+            TargetAddr::Domain(host, port) => (Some(host.as_str()), None, *port),
+        }
+    }
+    /// request `target` by socks5 through tunnel, errors of socks5 request are returned inside
+    async fn connect_tunnel_target(
+        ctx: &ClientContext,
+        target: TargetAddr,
+    ) -> Result<Result<Socks5Stream<NoiseStream<TcpStream>>, SocksError>> {
+        let (enc_outbound, _) = Self::connect_server(ctx, &[]).await?;
+        Ok(
+            Socks5Stream::use_stream(enc_outbound, None, Default::default())
+                .and_then(|mut stream| async {
+                    stream.request(Socks5Command::TCPConnect, target).await?;
+                    Ok(stream)
+                })
+                .await,
+        )
+    }
+    /// connect target of split tunneling directly, by its ip or domain resolved locally
+    async fn connect_direct(
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        port: u16,
+    ) -> io::Result<TcpStream> {
+        match (ip, domain) {
+            (Some(ip), _) => TcpStream::connect((ip, port)).await,
+            (None, Some(host)) => TcpStream::connect((host, port)).await,
+            (None, None) => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    /// make noise stream to server and keys of its session,
+    /// sending `early` data with handshake if it is not empty
+    async fn connect_server(
+        ctx: &ClientContext,
+        early: &[u8],
+    ) -> Result<(NoiseStream<TcpStream>, SessionKeys)> {
+        let enc_outbound = Self::handshake(ctx, false, early)
+            .await
+            .inspect_err(|e| match e {
+                Error::Io(e) => ctx.emit(ClientEvent::ServerUnreachable(e.to_string())),
+                // server closes connection if client key is not accepted
+                e => ctx.emit(ClientEvent::Rejected(e.to_string())),
+            })?;
+        ctx.emit(ClientEvent::Connected);
+        Ok(enc_outbound)
+    }
+    /// connect to server and make noise stream with keys of its session,
+    /// resuming session with ticket if possible, enable tcp keepalive if connection is long-lived
+    async fn handshake(
+        ctx: &ClientContext,
+        keepalive: bool,
+        early: &[u8],
+    ) -> Result<(NoiseStream<TcpStream>, SessionKeys)> {
+        let conf = &ctx.conf;
+        let connect = || async {
+            let conn = ctx.paths.connect(conf.server_addr).await?;
+            // keepalive of socket options is already set
+            if keepalive && !ctx.paths.keeps_alive() {
+                proxy::set_keepalive(&conn)?;
+            }
+            Ok::<_, Error>(conn)
+        };
+        let tickets = ctx.tickets.as_ref();
+        let report = conf.version_report.unwrap_or(false);
+        let telemetry = conf.telemetry.unwrap_or(false);
+        if let Some(ticket) = tickets.and_then(TicketCache::get) {
+            let resume = async {
+                let mut conn = connect().await?;
+                ticket::present(&mut conn, &ticket).await?;
+                if !early.is_empty() {
+                    early::send(&mut conn, &ticket.secret, early).await?;
+                }
+                if report {
+                    telemetry::send(&mut conn, &ticket.secret, telemetry).await?;
+                }
+                let initiator = snowstorm::Builder::new(ticket::RESUME_PATTERN.parse()?)
+                    .psk(0, &ticket.secret)
+                    .build_initiator()?;
+                let (mut enc_conn, keys) = pipeline::handshake(conn, initiator).await?;
+                if report && telemetry::confirm(&mut enc_conn).await? {
+                    reissue::receive(&mut enc_conn).await?;
+                }
+                let next = ticket::receive(&mut enc_conn).await?;
+                if !early.is_empty() {
+                    early::confirm(&mut enc_conn, early).await?;
+                }
+                Ok::<_, Error>((enc_conn, keys, next))
+            };
+            match resume.await {
+                Ok((enc_conn, keys, next)) => {
+                    log::debug!("Session resumed with ticket");
+                    tickets.inspect(|t| t.set(next));
+                    return Ok((enc_conn, keys));
+                }
+                Err(e) => {
+                    log::info!("Failed to resume session, fall back to full handshake. Error: {e}");
+                    tickets.inspect(|t| t.set(None));
+                }
+            }
+        }
         let initiator = snowstorm::Builder::new(PATTERN.parse()?)
             .remote_public_key(&conf.server_pubkey)
             .local_private_key(&conf.client_prikey)
             .build_initiator()?;
-        let outbound = TcpStream::connect(conf.server_addr).await?;
-        let enc_outbound = NoiseStream::handshake(outbound, initiator).await?;
-        // transfer data
-        proxy::transfer_and_log_error(inbound, enc_outbound).await;
+        let mut conn = connect().await?;
+        if tickets.is_some() {
+            ticket::request(&mut conn).await?;
+        }
+        let material = early::static_secret(&conf.client_prikey, &conf.server_pubkey)
+            .ok_or_else(|| Error::Config(String::from("invalid key length")))?;
+        if !early.is_empty() {
+            early::send(&mut conn, &material, early).await?;
+        }
+        if report {
+            telemetry::send(&mut conn, &material, telemetry).await?;
+        }
+        let (mut enc_conn, keys) = pipeline::handshake(conn, initiator).await?;
+        if report && telemetry::confirm(&mut enc_conn).await? {
+            reissue::receive(&mut enc_conn).await?;
+        }
+        if let Some(tickets) = tickets {
+            tickets.set(ticket::receive(&mut enc_conn).await?);
+        }
+        if !early.is_empty() {
+            early::confirm(&mut enc_conn, early).await?;
+        }
+        Ok((enc_conn, keys))
+    }
+    /// make end-to-end session with next hop server of relay target inside session with
+    /// relaying server, which only forwards its messages
+    async fn handshake_next_hop(
+        ctx: &ClientContext,
+        outer: NoiseStream<TcpStream>,
+    ) -> Result<NoiseStream<NoiseStream<TcpStream>>> {
+        let hop = ctx.conf.next_hop_pubkey.as_deref().ok_or_else(|| {
+            Error::Config(String::from(
+                "no key of next hop server, regenerate client with a relay target",
+            ))
+        })?;
+        let initiator = snowstorm::Builder::new(PATTERN.parse()?)
+            .remote_public_key(hop)
+            .local_private_key(&ctx.conf.client_prikey)
+            .build_initiator()?;
+        let inner = NoiseStream::handshake(outer, initiator).await?;
+        log::debug!("End-to-end session with next hop {}", fingerprint::of(hop));
+        Ok(inner)
+    }
+
+    /// client type: file transfer
+    /// in config: remote = "files"
+    /// copy a file from or to server, remote path is prefixed with ':'
+    pub async fn copy_files(opts: ClientOptions, src: &str, dst: &str) -> Result<()> {
+        let ctx = Self::make_context(opts).await?;
+        if ctx.conf.remote != Remote::Proxy(Target::Files) {
+            Err(Error::Config(String::from(
+                "target of this client is not files",
+            )))?
+        }
+        let file_name = |path: &str| {
+            Path::new(path)
+                .file_name()
+                .map(PathBuf::from)
+                .ok_or_else(|| Error::Config(format!("{path} is not a file")))
+        };
+        let (remote, local) = match (src.strip_prefix(':'), dst.strip_prefix(':')) {
+            (None, Some(remote)) => (remote, src),
+            (Some(remote), None) => (remote, dst),
+            _ => Err(Error::Config(String::from(
+                "one of source and destination must be remote path prefixed with ':'",
+            )))?,
+        };
+        let (mut stream, _) = Self::connect_server(&ctx, &[]).await?;
+        let bytes = match dst.starts_with(':') {
+            true => {
+                // copy into remote directory
+                let remote = match remote.ends_with('/') {
+                    true => Path::new(remote).join(file_name(local)?),
+                    false => PathBuf::from(remote),
+                };
+                files::put(&mut stream, Path::new(local), &remote.to_string_lossy()).await?
+            }
+            false => {
+                // copy into local directory
+                let local = match Path::new(local).is_dir() {
+                    true => Path::new(local).join(file_name(remote)?),
+                    false => PathBuf::from(local),
+                };
+                files::get(&mut stream, remote, &local).await?
+            }
+        };
+        log::info!("Copied {src} to {dst}, {bytes} bytes");
+        Ok(())
+    }
+
+    /// measure round trip time, jitter and throughput of encrypted path to server,
+    /// server answers probes instead of proxying, for any type of client
+    pub async fn measure(opts: ClientOptions) -> Result<()> {
+        let ctx = Self::make_context(opts).await?;
+        let conf = &ctx.conf;
+        let start = Instant::now();
+        let mut conn = ctx.paths.connect(conf.server_addr).await?;
+        let mss = measure::path_mss(&conn);
+        measure::request(&mut conn).await?;
+        let material = early::static_secret(&conf.client_prikey, &conf.server_pubkey)
+            .ok_or_else(|| Error::Config(String::from("invalid key length")))?;
+        let report = conf.version_report.unwrap_or(false);
+        if report {
+            telemetry::send(&mut conn, &material, conf.telemetry.unwrap_or(false)).await?;
+        }
+        let initiator = snowstorm::Builder::new(PATTERN.parse()?)
+            .remote_public_key(&conf.server_pubkey)
+            .local_private_key(&conf.client_prikey)
+            .build_initiator()?;
+        let mut stream = NoiseStream::handshake(conn, initiator).await?;
+        if report && telemetry::confirm(&mut stream).await? {
+            reissue::receive(&mut stream).await?;
+        }
+        let handshake = start.elapsed();
+        log::info!("Measuring path to server {}", conf.server_addr);
+        let report = measure::run(&mut stream, handshake, mss)
+            .await
+            .map_err(|e| match e {
+                Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Error::Rejected(
+                    String::from("connection closed, server may not support measurement"),
+                ),
+                e => e,
+            })?;
+        println!("{report}");
         Ok(())
     }
 
     /// client type: rclient (rproxy client)
     /// in config: remote = ["127.0.0.1:xxxx", 66]
-    async fn run_client_reverse_proxy(conf: Arc<ClientConfig>) -> Result<()> {
-        // must be valid address: socket addr or "socks5"
-        assert!(
-            conf.target_addr.to_lowercase() == "socks5"
-                || conf.target_addr.parse::<SocketAddr>().is_ok()
-        );
+    async fn run_client_reverse_proxy(ctx: Arc<ClientContext>) -> Result<()> {
+        let conf = &ctx.conf;
+        // must be socket addr, hostname with port or socks5
+        let target = match &conf.remote {
+            Remote::RProxy(target @ (Target::Socks5 | Target::Addr(_) | Target::Host(_)), _) => {
+                target
+            }
+            remote => Err(Error::InvalidRemote(format!(
+                "{remote} cannot be exposed by reverse proxy"
+            )))?,
+        };
         // log information
-        log::info!("Client exposing service on: {}", conf.target_addr);
+        log::info!("Client exposing service on: {}", target);
         log::info!("Portguard server on: {}", conf.server_addr);
+        ctx.emit(ClientEvent::Ready(format!(
+            "{} → {target}",
+            conf.server_addr
+        )));
         // start reverse proxy
         let try_conn = || async {
-            let conf = conf.clone();
-            Self::make_reverse_proxy_conn(&conf).await.map_err(|e| {
+            log::info!("Trying to connect to server...");
+            Self::try_handshake(&ctx).await.map_err(|e| {
                 log::warn!("Failed to make reverse proxy connection. Error: {}", e);
+                match &e {
+                    Error::Rejected(reason) => ctx.emit(ClientEvent::Rejected(reason.clone())),
+                    Error::ServiceTaken | Error::HashDenied | Error::Outdated(_) => {
+                        ctx.emit(ClientEvent::Rejected(e.to_string()))
+                    }
+                    Error::Io(e) => ctx.emit(ClientEvent::ServerUnreachable(e.to_string())),
+                    _ => {}
+                }
+                // retrying does not make a denied or outdated binary accepted
+                if matches!(e, Error::HashDenied | Error::Outdated(_)) {
+                    return backoff::Error::permanent(e);
+                }
+                ctx.emit(ClientEvent::Reconnecting);
                 backoff::Error::transient(e)
             })
         };
-        retry(ExponentialBackoff::default(), try_conn).await
+        loop {
+            let enc_conn = retry(conf.reconnect.backoff(), try_conn).await?;
+            if let Err(e) = Self::make_reverse_proxy_conn(&ctx, enc_conn).await {
+                log::warn!("Reverse proxy connection lost. Error: {}", e);
+            }
+            // an established connection is lost, e.g. server is restarting,
+            // reconnect immediately with a fresh backoff
+            ctx.emit(ClientEvent::Reconnecting);
+        }
     }
-    async fn try_handshake(conf: &ClientConfig) -> Result<NoiseStream<TcpStream>> {
-        let initiator = snowstorm::Builder::new(PATTERN.parse()?)
-            .remote_public_key(&conf.server_pubkey)
-            .local_private_key(&conf.client_prikey)
-            .build_initiator()?;
-        let conn = TcpStream::connect(&conf.server_addr).await?;
-        let mut enc_conn = NoiseStream::handshake(conn, initiator).await?;
+    async fn try_handshake(ctx: &ClientContext) -> Result<NoiseStream<TcpStream>> {
+        let (mut enc_conn, _) = Self::handshake(ctx, true, &[]).await?;
         // verify hash
         let mut hasher = Blake2s256::new();
-        hasher.update(std::fs::read(std::env::current_exe()?)?);
+        hasher.update(std::fs::read(reload::exe_path()?)?);
         let res = hasher.finalize();
         enc_conn.write_all(&res).await?;
-        let ret = enc_conn.read_u8().await?;
-        match ret {
-            66 => Ok(enc_conn),
-            88 => panic!("Service is already online!"),
-            _ => Err(anyhow!("Client hash is denied by server"))?,
+        let code = enc_conn.read_u8().await?;
+        match Status::try_from(code) {
+            Ok(Status::Accepted) => Ok(enc_conn),
+            Ok(Status::ServiceTaken) => Err(Error::ServiceTaken),
+            Ok(Status::Denied) => Err(Error::HashDenied),
+            Ok(Status::Outdated | Status::Rotated) | Err(_) => Err(Error::Rejected(format!(
+                "unknown reply {code} to client hash"
+            ))),
         }
     }
-    async fn make_reverse_proxy_conn(conf: &ClientConfig) -> Result<()> {
-        // make connection with server
-        log::info!("Trying to connect to server...");
-        let enc_conn = Self::try_handshake(conf).await?;
+    async fn make_reverse_proxy_conn(
+        ctx: &Arc<ClientContext>,
+        enc_conn: NoiseStream<TcpStream>,
+    ) -> Result<()> {
         log::info!("Handshake succeeded.");
+        ctx.emit(ClientEvent::Connected);
         // make yamux outbound stream and wait for incomming stream
         let yamux_config = yamux::Config::default();
         let mut yamux_conn =
             yamux::Connection::new(enc_conn.compat(), yamux_config, yamux::Mode::Server);
+        let scheduler = ctx.fair.then(|| Arc::new(Scheduler::default()));
         while let Some(inbound) = yamux_conn.next_stream().await? {
-            let conf = conf.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Client::handle_reverse_client_connection(inbound, &conf).await {
+            let ctx = ctx.clone();
+            let inbound = Fair::new(inbound.compat(), scheduler.clone(), Class::Auto);
+            ctx.tasks.clone().spawn(async move {
+                if let Err(e) = Client::handle_reverse_client_connection(inbound, &ctx).await {
                     log::warn!("{}", e);
                 }
             });
         }
-        log::info!("Connection closed.");
-        Err(anyhow!("Connection lost"))
+        log::info!("Connection closed by server.");
+        Ok(())
     }
     /// handle yamux connection requests
     async fn handle_reverse_client_connection(
-        inbound: yamux::Stream,
-        conf: &ClientConfig,
+        inbound: Fair<Compat<yamux::Stream>>,
+        ctx: &ClientContext,
     ) -> Result<(), io::Error> {
-        log::info!("New incoming request, stream id {:?}", inbound.id());
-        if &conf.target_addr.to_lowercase() == "socks5" {
-            // target is socks5
-            proxy::transfer_to_socks5_and_log_error(inbound.compat()).await;
-        } else {
-            // target is socket addr
-            let expose_addr = &conf
-                .target_addr
-                .parse::<SocketAddr>()
-                .expect("Invalid target address");
-            let outbound = TcpStream::connect(expose_addr).await?;
-            proxy::transfer_and_log_error(inbound.compat(), outbound).await;
+        let id = inbound.get_ref().get_ref().id();
+        log::info!("New incoming request, stream id {:?}", id);
+        let peer = format!("stream {id}");
+        let (_session, inbound) = ctx.sessions.open(peer, inbound);
+        let conf = &ctx.conf;
+        let outbound = match &conf.remote {
+            Remote::RProxy(Target::Socks5, _) => {
+                let options = conf.socks5.clone().unwrap_or_default();
+                proxy::transfer_to_socks5_and_log_error(inbound, &options, &ctx.socks5_rules).await;
+                return Ok(());
+            }
+            Remote::RProxy(Target::Addr(addr), _) => TcpStream::connect(addr).await,
+            // hostname is resolved by client
+            Remote::RProxy(Target::Host(host), _) => TcpStream::connect(host.as_str()).await,
+            remote => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{remote} cannot be exposed by reverse proxy"),
+            )),
+        };
+        let outbound = outbound.inspect_err(|e| {
+            ctx.emit(ClientEvent::TargetUnreachable(e.to_string()));
+        })?;
+        let bytes = proxy::transfer_and_log_error(inbound, outbound).await;
+        ctx.emit_transferred(bytes);
+        Ok(())
+    }
+    /// verify key password, asked up to `attempts` times,
+    /// waiting longer after each wrong one to slow down guessing, blocking on prompts and waits
+    fn decrypt_client_prikey(
+        key: Vec<u8>,
+        lang: Lang,
+        source: &Source,
+        attempts: u32,
+        delay: u64,
+    ) -> Result<Vec<u8>> {
+        let attempts = match source.is_interactive() {
+            true => attempts.max(1),
+            false => 1,
+        };
+        for attempt in 1..=attempts {
+            let mut password = source.read(lang, attempt > 1)?.into_bytes();
+            password.resize(KEYPASS_LEN, 0);
+            let keypass = Key::from_slice(&password);
+            let cipher = ChaCha20Poly1305::new(keypass);
+            if let Ok(key) = cipher.decrypt(&Nonce::default(), &key[..]) {
+                return Ok(key);
+            }
+            if attempt < attempts {
+                log::warn!("Wrong key passphrase, {} attempts left", attempts - attempt);
+                let wait = delay.saturating_mul(1 << (attempt - 1).min(16));
+                std::thread::sleep(Duration::from_secs(wait));
+            }
         }
+        Err(Error::Passphrase)
+    }
+
+    /// show builtin config of current client, except private key
+    pub fn show_conf(json: bool) -> Result<()> {
+        let conf = Self::require_builtin_conf()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&ConfigView::of(&conf))?);
+            return Ok(());
+        }
+        println!("Language: {}", conf.lang.unwrap_or_default());
+        println!("Server address: {}", conf.server_addr);
+        println!("Remote: {}", conf.remote);
+        println!("Reverse proxy: {}", conf.is_reverse());
+        println!("Key passphrase: {}", conf.has_keypass);
+        println!("External key: {}", conf.has_external_key());
+        println!("Reconnect policy: {:?}", conf.reconnect);
+        println!("Single instance: {}", conf.single_instance.unwrap_or(false));
+        println!("Session resumption: {}", conf.resume.unwrap_or(false));
+        println!("Early data: {}", conf.early_data.unwrap_or(false));
+        println!("Telemetry: {}", conf.telemetry.unwrap_or(false));
+        println!("Version report: {}", conf.version_report.unwrap_or(false));
+        if let Some(hop) = &conf.next_hop_pubkey {
+            println!("Next hop key fingerprint: {}", fingerprint::of(hop));
+        }
+        if let Some(socks5) = conf.socks5.filter(|s| !s.is_default()) {
+            println!("Socks5 request timeout: {:?}", socks5.request_timeout);
+            println!("Socks5 domain targets: {}", !socks5.no_dns);
+            println!("Socks5 commands: {:?}", socks5.commands);
+            if let Some(auth) = socks5.auth {
+                println!("Socks5 username: {}", auth.username);
+            }
+        }
+        if let Some(rules) = conf.socks5_rules {
+            println!("Socks5 rules: {:?}", rules);
+        }
+        if let Some(split) = conf.split.filter(|s| !s.is_empty()) {
+            println!("Split include: {:?}", split.include);
+            println!("Split exclude: {:?}", split.exclude);
+        }
+        for p in conf.profiles.unwrap_or_default() {
+            println!("Profile {}: port {}, remote {}", p.name, p.port, p.remote);
+        }
+        println!(
+            "Server pubkey: {} (fingerprint {})",
+            base64::encode(&conf.server_pubkey),
+            fingerprint::of(&conf.server_pubkey)
+        );
         Ok(())
     }
-    /// verify key password
-    fn decrypt_client_prikey(key: Vec<u8>) -> Result<Vec<u8>> {
-        let mut password = rpassword::prompt_password("Input Key Passphrase: ")?.into_bytes();
-        password.resize(KEYPASS_LEN, 0);
-        let keypass = Key::from_slice(&password);
-        let cipher = ChaCha20Poly1305::new(keypass);
-        let key = cipher.decrypt(&Nonce::default(), &key[..])?;
+
+    /// builtin config of current client, `None` if config section is zeroed,
+    /// i.e. this binary is not generated by `gen-cli`
+    fn builtin_conf() -> Result<Option<ClientConfig>> {
+        // read through `black_box`, the zeroed initial value must not be assumed
+        let buf = std::hint::black_box(&CLIENT_CONF_BUF);
+        if buf.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        Ok(Some(ClientConfig::from_slice(buf)?))
+    }
+    /// builtin config of current client, error if there is none
+    fn require_builtin_conf() -> Result<ClientConfig> {
+        Self::builtin_conf()?.ok_or(Error::Unprovisioned)
+    }
+    /// private key of client in base64 in file `path`, e.g. saved by `gen-keypair -o`
+    fn read_key_file(path: &Path) -> Result<Vec<u8>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("failed to read key file {path:?}, {e}")))?;
+        let key = base64::decode(content.trim())?;
+        if key.len() != 32 {
+            Err(Error::Config(format!(
+                "key file {path:?} has no x25519 private key"
+            )))?
+        }
         Ok(key)
     }
+    /// public key of client, `None` if private key is protected by passphrase or not builtin
+    fn client_pubkey(conf: &ClientConfig) -> Result<Option<[u8; 32]>> {
+        if conf.has_keypass || conf.has_external_key() {
+            return Ok(None);
+        }
+        let bits = <[u8; 32]>::try_from(&conf.client_prikey[..])
+            .map_err(|_| Error::Config(String::from("invalid privkey when deriving pubkey")))?;
+        Ok(Some(
+            EdwardsPoint::mul_base_clamped(bits)
+                .to_montgomery()
+                .to_bytes(),
+        ))
+    }
 
     /// list current client public key
-    pub fn list_pubkey(server: bool) -> Result<()> {
-        let conf = ClientConfig::from_slice(&CLIENT_CONF_BUF)?;
-        let bits = conf
-            .client_prikey
-            .try_into()
-            .map_err(|_| anyhow!("Got invalid privkey when deriving pubkey"))?;
-        let point = EdwardsPoint::mul_base_clamped(bits).to_montgomery();
-        let pubkey = base64::encode(point.to_bytes());
-        println!("Client pubkey: {:?}", pubkey);
+    pub fn list_pubkey(server: bool, json: bool) -> Result<()> {
+        let conf = Self::require_builtin_conf()?;
+        let pubkey = Self::client_pubkey(&conf)?;
+        if json {
+            #[derive(Serialize)]
+            struct Keys {
+                client: Option<KeyInfo>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                server: Option<KeyInfo>,
+            }
+            let keys = Keys {
+                client: pubkey.map(|k| KeyInfo::of(&k)),
+                server: server.then(|| KeyInfo::of(&conf.server_pubkey)),
+            };
+            println!("{}", serde_json::to_string_pretty(&keys)?);
+            return Ok(());
+        }
+        match pubkey {
+            Some(pubkey) => println!(
+                "Client pubkey: {} (fingerprint {})",
+                base64::encode(pubkey),
+                fingerprint::of(&pubkey)
+            ),
+            None if conf.has_external_key() => {
+                println!("Client pubkey: unknown, private key is not builtin")
+            }
+            None => println!("Client pubkey: unknown, private key is protected by passphrase"),
+        }
         if server {
-            let key = base64::encode(conf.server_pubkey);
-            println!("Server pubkey: {:?}", key);
+            println!(
+                "Server pubkey: {} (fingerprint {})",
+                base64::encode(&conf.server_pubkey),
+                fingerprint::of(&conf.server_pubkey)
+            );
         }
         Ok(())
     }
+
+    /// show summary of builtin config and keys without unlocking private key,
+    /// state is returned for exit code of scripts
+    pub fn info(json: bool) -> Result<ConfigState> {
+        let info = Info::read();
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&info)?),
+            false => info.print(),
+        }
+        Ok(info.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(include: &[&str], exclude: &[&str]) -> Vec<TargetRule> {
+        let rules = |r: &[&str]| r.iter().map(|r| r.to_string()).collect();
+        SplitRules {
+            include: rules(include),
+            exclude: rules(exclude),
+        }
+        .tunnel_rules()
+        .unwrap()
+    }
+
+    fn tunneled(rules: &[TargetRule], target: &TargetAddr) -> bool {
+        let (domain, ip, port) = Client::lookup_target(target);
+        rules::allows(rules, domain, ip, port)
+    }
+
+    #[test]
+    fn domains_are_split_without_resolving() {
+        let target = TargetAddr::Domain(String::from("localhost"), 80);
+        assert_eq!(
+            Client::lookup_target(&target),
+            (Some("localhost"), None, 80)
+        );
+        let rules = split(&["*.corp.example.com", "10.0.0.0/8"], &[]);
+        let domain = |host: &str| TargetAddr::Domain(host.to_string(), 443);
+        assert!(tunneled(&rules, &domain("git.corp.example.com")));
+        assert!(!tunneled(&rules, &domain("example.com")));
+        // ip rules only match targets requested by address
+        assert!(!tunneled(&rules, &domain("localhost")));
+        assert!(tunneled(
+            &rules,
+            &TargetAddr::Ip("10.1.2.3:22".parse().unwrap())
+        ));
+        assert!(!tunneled(
+            &rules,
+            &TargetAddr::Ip("127.0.0.1:22".parse().unwrap())
+        ));
+    }
+
+    #[test]
+    fn excludes_override_includes() {
+        let rules = split(&[], &["192.168.0.0/16", "*.local"]);
+        assert!(tunneled(
+            &rules,
+            &TargetAddr::Ip("10.0.0.1:80".parse().unwrap())
+        ));
+        assert!(!tunneled(
+            &rules,
+            &TargetAddr::Ip("192.168.1.1:80".parse().unwrap())
+        ));
+        assert!(!tunneled(
+            &rules,
+            &TargetAddr::Domain(String::from("nas.local"), 80)
+        ));
+        assert!(tunneled(
+            &rules,
+            &TargetAddr::Domain(String::from("example.com"), 80)
+        ));
+        let rules = split(&["*.corp"], &["public.corp"]);
+        assert!(tunneled(
+            &rules,
+            &TargetAddr::Domain(String::from("git.corp"), 80)
+        ));
+        assert!(!tunneled(
+            &rules,
+            &TargetAddr::Domain(String::from("public.corp"), 80)
+        ));
+    }
 }
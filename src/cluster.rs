@@ -0,0 +1,171 @@
+/// state shared by nodes of a cluster: each node periodically pulls clients and revoked keys
+/// from its peers, so a client registered on one node is accepted by all of them
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+use crate::server::ClientEntry;
+use crate::tenant::ServiceKey;
+
+/// interval of pulling state of peers
+pub(crate) const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// limit of state message, so a peer cannot make a node allocate without bound
+const MAX_STATE_LEN: u32 = 16 << 20;
+/// service id asked for by a node pulling state, instead of a stream to service,
+/// nodes of older versions deny it as an offline service
+const SYNC_ID: usize = u32::MAX as usize;
+
+/// key asked for by a node pulling state of its peer
+pub(crate) fn sync_key() -> ServiceKey {
+    ServiceKey::new(None, SYNC_ID)
+}
+
+/// state of a node shared with its peers: clients of its own config and storage,
+/// and keys it revoked
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct NodeState {
+    pub(crate) clients: Vec<ClientEntry>,
+    pub(crate) revoked: Vec<Vec<u8>>,
+}
+
+impl NodeState {
+    pub(crate) async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<()> {
+        let msg = serde_json::to_vec(self)?;
+        stream.write_u32(msg.len() as u32).await?;
+        stream.write_all(&msg).await?;
+        Ok(())
+    }
+    pub(crate) async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self> {
+        let len = stream.read_u32().await?;
+        if len > MAX_STATE_LEN {
+            return Err(Error::Rejected(format!("cluster state of {len} bytes")));
+        }
+        let mut msg = vec![0; len as usize];
+        stream.read_exact(&mut msg).await?;
+        Ok(serde_json::from_slice(&msg)?)
+    }
+}
+
+/// state of a peer as last pulled
+#[derive(Default)]
+struct PeerState {
+    clients: HashSet<ClientEntry>,
+    revoked: HashSet<Vec<u8>>,
+}
+
+/// states last pulled from peers, a peer that cannot be reached keeps its last state
+#[derive(Default)]
+pub(crate) struct Cluster {
+    peers: RwLock<HashMap<SocketAddr, PeerState>>,
+}
+
+impl Cluster {
+    /// replace state of `node`, clients it no longer has are no longer accepted
+    pub(crate) fn update(&self, node: SocketAddr, state: NodeState) {
+        let state = PeerState {
+            clients: state.clients.into_iter().collect(),
+            revoked: state.revoked.into_iter().collect(),
+        };
+        self.peers.write().unwrap().insert(node, state);
+    }
+    /// client registered on a peer, revocations are checked by `revoked`
+    pub(crate) fn client(&self, key: &[u8]) -> Option<ClientEntry> {
+        let peers = self.peers.read().unwrap();
+        peers.values().find_map(|p| p.clients.get(key).cloned())
+    }
+    /// whether any peer revoked `key`
+    pub(crate) fn revoked(&self, key: &[u8]) -> bool {
+        let peers = self.peers.read().unwrap();
+        peers.values().any(|p| p.revoked.contains(key))
+    }
+    /// number of distinct clients registered on peers
+    pub(crate) fn clients(&self) -> usize {
+        let peers = self.peers.read().unwrap();
+        let keys: HashSet<&[u8]> = peers
+            .values()
+            .flat_map(|p| p.clients.iter().map(|c| c.pubkey()))
+            .collect();
+        keys.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str, pubkey: u8) -> ClientEntry {
+        toml::from_str(&format!(
+            "name = '{name}'\npubkey = '{}'",
+            base64::encode([pubkey; 32])
+        ))
+        .unwrap()
+    }
+
+    fn node(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn state_round_trips() {
+        let state = NodeState {
+            clients: vec![client("a", 1), client("b", 2)],
+            revoked: vec![vec![3; 32]],
+        };
+        let mut buf = Vec::new();
+        state.write(&mut buf).await.unwrap();
+        let read = NodeState::read(&mut &buf[..]).await.unwrap();
+        assert_eq!(read.clients, state.clients);
+        assert_eq!(read.revoked, state.revoked);
+    }
+
+    #[tokio::test]
+    async fn oversized_state_is_rejected() {
+        let buf = (MAX_STATE_LEN + 1).to_be_bytes();
+        assert!(NodeState::read(&mut &buf[..]).await.is_err());
+    }
+
+    #[test]
+    fn clients_and_revocations_of_peers_are_found() {
+        let cluster = Cluster::default();
+        cluster.update(
+            node(1),
+            NodeState {
+                clients: vec![client("a", 1)],
+                revoked: vec![vec![3; 32]],
+            },
+        );
+        cluster.update(
+            node(2),
+            NodeState {
+                clients: vec![client("a", 1), client("b", 2)],
+                revoked: vec![],
+            },
+        );
+        assert_eq!(
+            cluster.client(&[2; 32]).map(|c| c.pubkey().to_vec()),
+            Some(vec![2; 32])
+        );
+        assert!(cluster.client(&[3; 32]).is_none());
+        assert!(cluster.revoked(&[3; 32]) && !cluster.revoked(&[1; 32]));
+        assert_eq!(cluster.clients(), 2);
+    }
+
+    #[test]
+    fn update_replaces_state_of_peer() {
+        let cluster = Cluster::default();
+        let state = || NodeState {
+            clients: vec![client("a", 1)],
+            revoked: vec![],
+        };
+        cluster.update(node(1), state());
+        cluster.update(node(1), NodeState::default());
+        assert!(cluster.client(&[1; 32]).is_none());
+        cluster.update(node(1), state());
+        assert!(cluster.client(&[1; 32]).is_some());
+    }
+}
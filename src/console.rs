@@ -0,0 +1,105 @@
+/// console of windows: control events, so a client stopped by Ctrl+C, closing its console,
+/// logoff or shutdown closes its connections and flushes logs instead of being killed,
+/// and colors of terminal output
+#[cfg(windows)]
+use std::sync::{Condvar, Mutex, Once};
+#[cfg(windows)]
+use std::time::Duration;
+
+#[cfg(windows)]
+use tokio::sync::Notify;
+
+/// max time to clean up after close, logoff and shutdown events, windows kills process
+/// when handler returns, or 5 seconds after a close event
+#[cfg(windows)]
+const CLEANUP_LIMIT: Duration = Duration::from_secs(4);
+
+#[cfg(windows)]
+const CTRL_C_EVENT: u32 = 0;
+#[cfg(windows)]
+const CTRL_BREAK_EVENT: u32 = 1;
+#[cfg(windows)]
+const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+#[cfg(windows)]
+const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn SetConsoleCtrlHandler(
+        handler: Option<unsafe extern "system" fn(u32) -> i32>,
+        add: i32,
+    ) -> i32;
+    fn GetStdHandle(handle: u32) -> *mut std::ffi::c_void;
+    fn GetConsoleMode(console: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+    fn SetConsoleMode(console: *mut std::ffi::c_void, mode: u32) -> i32;
+}
+
+/// a control event is received
+#[cfg(windows)]
+static INTERRUPTED: Notify = Notify::const_new();
+/// client finished cleaning up
+#[cfg(windows)]
+static CLEANED_UP: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+/// called by windows on a thread of its own
+#[cfg(windows)]
+unsafe extern "system" fn on_ctrl(event: u32) -> i32 {
+    INTERRUPTED.notify_one();
+    if !matches!(event, CTRL_C_EVENT | CTRL_BREAK_EVENT) {
+        // process is killed once this returns, wait for client to clean up
+        let (done, cvar) = &CLEANED_UP;
+        let done = done.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = cvar.wait_timeout_while(done, CLEANUP_LIMIT, |done| !*done);
+    }
+    1
+}
+
+/// wait for Ctrl+C, Ctrl+Break, close of console, logoff or shutdown
+#[cfg(windows)]
+pub(crate) async fn interrupted() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            log::warn!(
+                "Failed to handle console events. Error: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    });
+    INTERRUPTED.notified().await;
+    log::info!("Interrupted by console event, shutting down");
+}
+
+#[cfg(not(windows))]
+pub(crate) async fn interrupted() {
+    futures::future::pending().await
+}
+
+/// flush logs and let a waiting console event handler return
+#[cfg(windows)]
+pub(crate) fn cleaned_up() {
+    log::logger().flush();
+    let (done, cvar) = &CLEANED_UP;
+    *done.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    cvar.notify_all();
+}
+
+#[cfg(not(windows))]
+pub(crate) fn cleaned_up() {}
+
+/// let console interpret ansi colors of stdout, false if it cannot
+#[cfg(windows)]
+pub(crate) fn enable_colors() -> bool {
+    unsafe {
+        let stdout = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        GetConsoleMode(stdout, &mut mode) != 0
+            && SetConsoleMode(stdout, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn enable_colors() -> bool {
+    true
+}
@@ -1,5 +1,83 @@
 /// Consts
 pub(crate) const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
 pub(crate) const CONF_BUF_LEN: usize = 1024;
+/// version of `ClientConfig` fields, bump it when a field is appended
+pub(crate) const CONF_SCHEMA: u32 = 9;
+#[cfg(feature = "server")]
 pub(crate) const FILEHASH_LEN: usize = 32;
 pub(crate) const KEYPASS_LEN: usize = 32;
+pub(crate) const PUBKEY_LEN: usize = 32;
+pub(crate) const DEFAULT_PORT: u16 = 8022;
+
+/// status byte replied by server, to file hash of a reverse proxy client, to heartbeat
+/// of a client and to stream request of another cluster node, values must never change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Status {
+    /// hash is denied, or service is offline on this node
+    Denied = 0,
+    Accepted = 66,
+    /// service is online with another client
+    ServiceTaken = 88,
+    /// client is older than `min_client_version`, followed by its length and text
+    Outdated = 77,
+    /// client handshakes with previous server key, heartbeat is accepted,
+    /// followed by the new server public key
+    Rotated = 55,
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> u8 {
+        status as u8
+    }
+}
+
+/// unknown code is returned as error
+impl TryFrom<u8> for Status {
+    type Error = u8;
+    fn try_from(code: u8) -> Result<Status, u8> {
+        match code {
+            0 => Ok(Status::Denied),
+            66 => Ok(Status::Accepted),
+            88 => Ok(Status::ServiceTaken),
+            77 => Ok(Status::Outdated),
+            55 => Ok(Status::Rotated),
+            code => Err(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Status;
+
+    const ALL: [Status; 5] = [
+        Status::Denied,
+        Status::Accepted,
+        Status::ServiceTaken,
+        Status::Outdated,
+        Status::Rotated,
+    ];
+
+    #[test]
+    fn status_round_trips_through_byte() {
+        for status in ALL {
+            assert_eq!(Status::try_from(u8::from(status)), Ok(status));
+        }
+    }
+
+    #[test]
+    fn status_bytes_never_change() {
+        let bytes: Vec<u8> = ALL.iter().map(|&s| s.into()).collect();
+        assert_eq!(bytes, [0, 66, 88, 77, 55]);
+    }
+
+    #[test]
+    fn unknown_byte_is_rejected() {
+        for code in 0..=u8::MAX {
+            if !ALL.iter().any(|&s| u8::from(s) == code) {
+                assert_eq!(Status::try_from(code), Err(code));
+            }
+        }
+    }
+}
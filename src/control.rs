@@ -0,0 +1,230 @@
+/// control endpoint of a running client, for `--status` and `--stop`
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::bind;
+use crate::history::History;
+use crate::tasks::Tasks;
+
+/// interval of checking whether active connections finished
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// bytes transferred so far by a connection
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter {
+    /// bytes sent by local side
+    sent: AtomicU64,
+    /// bytes received by local side
+    received: AtomicU64,
+}
+
+/// stream of local side, counting bytes read from and written to it
+pub(crate) struct Counted<S> {
+    inner: S,
+    counter: Arc<ByteCounter>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, counter: Arc<ByteCounter>) -> Self {
+        Counted { inner, counter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        self.counter.sent.fetch_add(n, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.counter.received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct Session {
+    peer: String,
+    started: Instant,
+    started_at: SystemTime,
+    counter: Arc<ByteCounter>,
+}
+
+/// active connections of client
+pub(crate) struct Sessions {
+    started: Instant,
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Session>>,
+    /// finished connections are recorded to history if set
+    history: Option<History>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new(None)
+    }
+}
+
+/// registration of an active connection, removed when dropped
+pub(crate) struct SessionGuard<'a> {
+    sessions: &'a Sessions,
+    id: u64,
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        let session = self.sessions.active.lock().unwrap().remove(&self.id);
+        if let (Some(s), Some(history)) = (session, &self.sessions.history) {
+            let (sent, received) = (
+                s.counter.sent.load(Ordering::Relaxed),
+                s.counter.received.load(Ordering::Relaxed),
+            );
+            let secs = s.started.elapsed().as_secs();
+            history.record(s.started_at, &s.peer, secs, sent, received);
+        }
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new(history: Option<History>) -> Self {
+        Sessions {
+            started: Instant::now(),
+            next_id: AtomicU64::new(0),
+            active: Mutex::new(BTreeMap::new()),
+            history,
+        }
+    }
+    /// register a connection, and wrap its local side to count bytes
+    pub(crate) fn open<S>(&self, peer: String, stream: S) -> (SessionGuard<'_>, Counted<S>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let counter = Arc::new(ByteCounter::default());
+        let session = Session {
+            peer,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            counter: counter.clone(),
+        };
+        self.active.lock().unwrap().insert(id, session);
+        let guard = SessionGuard { sessions: self, id };
+        (guard, Counted::new(stream, counter))
+    }
+    /// whether there is no active connection
+    pub(crate) fn is_idle(&self) -> bool {
+        self.active.lock().unwrap().is_empty()
+    }
+    /// wait until there is no active connection
+    pub(crate) async fn idle(&self) {
+        while !self.is_idle() {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+    }
+    fn report(&self) -> String {
+        let active = self.active.lock().unwrap();
+        let mut report = format!(
+            "uptime: {}s\nactive connections: {}\n",
+            self.started.elapsed().as_secs(),
+            active.len()
+        );
+        for (id, s) in active.iter() {
+            writeln!(
+                report,
+                "  #{} {} up {}s, sent {} bytes, received {} bytes",
+                id,
+                s.peer,
+                s.started.elapsed().as_secs(),
+                s.counter.sent.load(Ordering::Relaxed),
+                s.counter.received.load(Ordering::Relaxed),
+            )
+            .ok();
+        }
+        report
+    }
+}
+
+/// serve control commands: "status" replies a report, "stop" stops client
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    sessions: Arc<Sessions>,
+    stop: Arc<Notify>,
+    tasks: Tasks,
+) -> io::Result<()> {
+    if !addr.ip().is_loopback() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "control endpoint must be a loopback address",
+        ))?
+    }
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| bind::explain(addr, e))?;
+    log::info!("Control endpoint listening on: {:?}", addr);
+    loop {
+        let (stream, _) = bind::accept(|| listener.accept()).await;
+        let sessions = sessions.clone();
+        let stop = stop.clone();
+        tasks.spawn(async move {
+            if let Err(e) = handle_command(stream, &sessions, &stop).await {
+                log::debug!("Control command error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_command(stream: TcpStream, sessions: &Sessions, stop: &Notify) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut cmd = String::new();
+    // commands are short, a single line is enough
+    (&mut stream).take(64).read_line(&mut cmd).await?;
+    let reply = match cmd.trim() {
+        "status" => sessions.report(),
+        "stop" => {
+            log::info!("Stopped by control command");
+            stop.notify_one();
+            String::from("stopping\n")
+        }
+        _ => String::from("unknown command\n"),
+    };
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// send a command to control endpoint of a running client, return its reply
+pub(crate) async fn request(addr: SocketAddr, cmd: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(format!("{cmd}\n").as_bytes()).await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
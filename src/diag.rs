@@ -0,0 +1,126 @@
+/// diagnostics of config errors, pointing to offending key and value in config file
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+
+/// hints for errors whose messages are not clear by themselves
+const HINTS: &[(&str, &str)] = &[
+    (
+        "untagged enum Remote",
+        "remote is a target like \"127.0.0.1:22\", \"example.com:22\" or \"socks5\", \
+         a service id like 1, or [target, service id]",
+    ),
+    (
+        "invalid base64",
+        "keys and hashes are base64 strings, as generated by gen-key and gen-cli",
+    ),
+    (
+        "socks5 rule",
+        "socks5 rules are like \"*.example.com:443\", \"10.0.0.0/8:22,8000-8100\" or \"!192.168.0.0/16\"",
+    ),
+];
+
+/// split toml error message into message, key and reported line (0-based)
+fn parse_message(e: &toml::de::Error) -> (String, Option<String>, Option<usize>) {
+    let mut msg = e.to_string();
+    let line = e.line_col().map(|(line, _)| line);
+    if let Some(pos) = msg.find(" at line ") {
+        msg.truncate(pos);
+    }
+    let key = msg.find(" for key `").map(|pos| {
+        let key = msg[pos + 10..].trim_end_matches('`').to_string();
+        msg.truncate(pos);
+        key
+    });
+    (msg, key, line)
+}
+
+/// line where key is assigned, searching from `start` to next table header
+fn find_key(lines: &[&str], start: usize, key: &str) -> Option<usize> {
+    let is_key = |line: &str| {
+        line.trim_start()
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('=') || rest.starts_with('.'))
+    };
+    lines
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take_while(|(i, l)| *i == start || !l.trim_start().starts_with('['))
+        .find(|(_, l)| is_key(l))
+        .map(|(i, _)| i)
+}
+
+/// header line of first entry of array of tables `name` that fails to deserialize,
+/// because toml reports the position of another entry for such errors
+pub(crate) fn failing_entry<T: DeserializeOwned>(content: &str, name: &str) -> Option<usize> {
+    let value: toml::Value = toml::de::from_str(content).ok()?;
+    let index = value
+        .get(name)?
+        .as_array()?
+        .iter()
+        .position(|entry| entry.clone().try_into::<T>().is_err())?;
+    find_table(content, name, index)
+}
+
+/// line of header of table `name`, or of the `index`th entry if it is an array of tables
+fn find_table(content: &str, name: &str, index: usize) -> Option<usize> {
+    let array = format!("[[{name}]]");
+    let table = format!("[{name}]");
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| l.trim() == array || l.trim() == table)
+        .nth(index)
+        .map(|(i, _)| i)
+}
+
+/// error with location and source line of offending key,
+/// `table_line` finds header line of the table containing it, if known
+pub(crate) fn config_error(
+    content: &str,
+    e: toml::de::Error,
+    table_line: impl Fn(&str) -> Option<usize>,
+) -> Error {
+    let (msg, key, reported) = parse_message(&e);
+    let lines: Vec<&str> = content.lines().collect();
+    let located = match &key {
+        Some(key) => match key.rsplit_once('.') {
+            Some((table, leaf)) => table_line(table)
+                .or_else(|| find_table(content, table, 0))
+                .and_then(|start| find_key(&lines, start, leaf)),
+            // top-level key, before any table
+            None => find_key(&lines, 0, key),
+        },
+        None => None,
+    }
+    .or(reported);
+    let mut report = msg.clone();
+    if let Some(key) = &key {
+        report += &format!("\n  key: `{key}`");
+    }
+    if let Some(line) = located.filter(|&l| l < lines.len()) {
+        let src = lines[line];
+        // point to value if key is found, otherwise to reported column
+        let col = match src.find('=') {
+            Some(eq) if key.is_some() => {
+                eq + 1 + (src[eq + 1..].len() - src[eq + 1..].trim_start().len())
+            }
+            _ => e.line_col().map(|(_, c)| c).unwrap_or(0),
+        };
+        let num = (line + 1).to_string();
+        let pad = " ".repeat(num.len());
+        report += &format!(
+            "\n  --> line {}, column {}\n {pad} |\n {num} | {src}\n {pad} | {}^",
+            line + 1,
+            col + 1,
+            " ".repeat(col)
+        );
+    } else if let Some(line) = located {
+        report += &format!("\n  --> line {}, end of file", line + 1);
+    }
+    if let Some((_, hint)) = HINTS.iter().find(|(pat, _)| msg.contains(pat)) {
+        report += &format!("\n  hint: {hint}");
+    }
+    Error::Config(report)
+}
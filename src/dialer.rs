@@ -0,0 +1,70 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::net::TcpStream;
+
+/// dials tcp connections from server to targets and upstream proxies
+/// implement it to customize how server reaches them,
+/// e.g. through a jump host, with source routing, or in another network namespace
+pub trait Dialer: Send + Sync {
+    /// connect to `addr`
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<TcpStream>>;
+}
+
+/// default dialer, connects with `TcpStream::connect`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpDialer;
+
+impl Dialer for TcpDialer {
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<TcpStream>> {
+        TcpStream::connect(addr).boxed()
+    }
+}
+
+/// directory of named network namespaces, as created by `ip netns add`
+#[cfg(target_os = "linux")]
+const NETNS_DIR: &str = "/run/netns";
+/// timeout of connecting in a network namespace
+#[cfg(target_os = "linux")]
+const NETNS_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// connect to `addr` in a network namespace,
+/// `netns` is a named namespace or path of a namespace file, e.g. "/proc/1234/ns/net"
+#[cfg(target_os = "linux")]
+pub(crate) async fn dial_in_netns(netns: &str, addr: SocketAddr) -> io::Result<TcpStream> {
+    use std::os::unix::io::AsRawFd;
+
+    let path = match netns.contains('/') {
+        true => std::path::PathBuf::from(netns),
+        false => std::path::Path::new(NETNS_DIR).join(netns),
+    };
+    let ns = std::fs::File::open(path)?;
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    // entering a namespace changes the whole thread, so use a dedicated one,
+    // the socket stays in the namespace after thread exits
+    std::thread::spawn(move || {
+        let connect = || {
+            if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stream = std::net::TcpStream::connect_timeout(&addr, NETNS_CONNECT_TIMEOUT)?;
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        };
+        tx.send(connect()).ok();
+    });
+    let stream = rx
+        .await
+        .map_err(|_| io::Error::other("netns dialing thread exited"))??;
+    TcpStream::from_std(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn dial_in_netns(_netns: &str, _addr: SocketAddr) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "netns target is only supported on linux",
+    ))
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::mdns::{read_name, write_name};
+
+/// how resolved addresses are reused
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum DnsMode {
+    /// re-resolve after cache entry expires
+    #[default]
+    Ttl,
+    /// resolve once and pin the result until restart
+    Pin,
+}
+
+/// which address family is preferred
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum DnsPrefer {
+    /// use addresses in resolved order
+    #[default]
+    Any,
+    /// prefer A records
+    Ipv4,
+    /// prefer AAAA records
+    Ipv6,
+}
+
+/// dns policy for hostnames the server connects to,
+/// including domains requested by socks5 clients
+/// in config:
+/// [dns]
+/// mode = "ttl"               # or "pin"
+/// ttl = 60                   # max seconds to cache a result in ttl mode
+/// negative_ttl = 10          # seconds to cache a failed lookup
+/// prefer = "ipv4"            # or "ipv6", "any"
+/// servers = ["1.1.1.1:53"]   # upstream dns servers, system resolver if empty
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DnsConfig {
+    #[serde(default)]
+    mode: DnsMode,
+    #[serde(default = "default_ttl")]
+    ttl: u64,
+    #[serde(default = "default_negative_ttl")]
+    negative_ttl: u64,
+    #[serde(default)]
+    prefer: DnsPrefer,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    servers: Vec<SocketAddr>,
+}
+
+fn default_ttl() -> u64 {
+    60
+}
+
+fn default_negative_ttl() -> u64 {
+    10
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            mode: DnsMode::default(),
+            ttl: default_ttl(),
+            negative_ttl: default_negative_ttl(),
+            prefer: DnsPrefer::default(),
+            servers: Vec::new(),
+        }
+    }
+}
+
+impl DnsConfig {
+    pub(crate) fn is_default(&self) -> bool {
+        *self == DnsConfig::default()
+    }
+}
+
+/// caching resolver following dns policy
+#[derive(Debug, Default)]
+pub(crate) struct Resolver {
+    config: DnsConfig,
+    /// resolved addresses, with time they expire in ttl mode
+    cache: Mutex<HashMap<String, (Instant, SocketAddr)>>,
+    /// hosts failed to resolve, with time of failure
+    negative: Mutex<HashMap<String, Instant>>,
+}
+
+impl Resolver {
+    pub(crate) fn new(config: DnsConfig) -> Self {
+        Resolver {
+            config,
+            cache: Mutex::new(HashMap::new()),
+            negative: Mutex::new(HashMap::new()),
+        }
+    }
+    /// resolve "host:port" to a socket address
+    pub(crate) async fn resolve(&self, host: &str) -> io::Result<SocketAddr> {
+        if let Ok(addr) = host.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        if let Some(addr) = self.lookup_cache(host) {
+            return Ok(addr);
+        }
+        let negative_ttl = Duration::from_secs(self.config.negative_ttl);
+        if let Some(at) = self.negative.lock().unwrap().get(host) {
+            if at.elapsed() < negative_ttl {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{host} failed to resolve recently"),
+                ))?
+            }
+        }
+        let res = self.lookup(host).await;
+        let mut negative = self.negative.lock().unwrap();
+        match &res {
+            Ok(_) => negative.remove(host),
+            Err(_) => negative.insert(host.to_string(), Instant::now()),
+        };
+        res
+    }
+    async fn lookup(&self, host: &str) -> io::Result<SocketAddr> {
+        // ttl of records is only known from dns servers, not from system resolver
+        let max_ttl = Duration::from_secs(self.config.ttl);
+        let (mut addrs, ttl): (Vec<SocketAddr>, _) = match self.config.servers.is_empty() {
+            true => (tokio::net::lookup_host(host).await?.collect(), max_ttl),
+            false => {
+                let (addrs, ttl) = self.lookup_servers(host).await?;
+                (addrs, ttl.min(max_ttl))
+            }
+        };
+        // stable sort keeps resolved order within the same family
+        match self.config.prefer {
+            DnsPrefer::Any => {}
+            DnsPrefer::Ipv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+            DnsPrefer::Ipv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+        }
+        let addr = *addrs.first().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{host} has no address"))
+        })?;
+        log::debug!("Resolved {host} to {addr}, cached for {ttl:?}");
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), (Instant::now() + ttl, addr));
+        Ok(addr)
+    }
+    /// query upstream dns servers in order, with the shortest ttl of records
+    async fn lookup_servers(&self, host: &str) -> io::Result<(Vec<SocketAddr>, Duration)> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid host {host}"));
+        let (name, port) = host.rsplit_once(':').ok_or_else(invalid)?;
+        let port: u16 = port.parse().map_err(|_| invalid())?;
+        let qtypes: &[u16] = match self.config.prefer {
+            DnsPrefer::Ipv6 => &[TYPE_AAAA],
+            DnsPrefer::Any | DnsPrefer::Ipv4 => &[TYPE_A, TYPE_AAAA],
+        };
+        let mut last_err = None;
+        for server in &self.config.servers {
+            let mut addrs = Vec::new();
+            let mut ttl = u32::MAX;
+            for qtype in qtypes {
+                match query(*server, name, *qtype).await {
+                    Ok(records) => {
+                        ttl = ttl.min(records.ttl);
+                        addrs.extend(records.ips.into_iter().map(|ip| SocketAddr::new(ip, port)))
+                    }
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            if !addrs.is_empty() {
+                return Ok((addrs, Duration::from_secs(ttl.into())));
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{host} has no address"))
+        }))
+    }
+    fn lookup_cache(&self, host: &str) -> Option<SocketAddr> {
+        let cache = self.cache.lock().unwrap();
+        let (expires, addr) = cache.get(host)?;
+        let fresh = match self.config.mode {
+            DnsMode::Pin => true,
+            DnsMode::Ttl => Instant::now() < *expires,
+        };
+        fresh.then_some(*addr)
+    }
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+/// max time to wait for reply of dns server
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// addresses answered by a dns server
+#[derive(Debug, PartialEq, Eq)]
+struct Records {
+    ips: Vec<IpAddr>,
+    /// shortest ttl of answers in seconds, including cnames leading to addresses
+    ttl: u32,
+}
+
+/// query a dns server over udp for addresses of name
+async fn query(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Records> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    // id is only used to match reply of the connected socket, need not be random
+    let id = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        & 0xffff) as u16;
+    // id, flags (recursion desired), qd, an, ns, ar
+    let mut packet = id.to_be_bytes().to_vec();
+    packet.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    write_name(&mut packet, name);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    socket.send(&packet).await?;
+    let mut buf = vec![0; 4096];
+    let n = timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "dns query timeout"))??;
+    parse_reply(&buf[..n], id, qtype).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{name} is not resolved by {server}"),
+        )
+    })
+}
+
+/// addresses of answers in reply, `None` if reply is invalid or name does not exist
+fn parse_reply(packet: &[u8], id: u16, qtype: u16) -> Option<Records> {
+    let u16_at = |pos: usize| {
+        Some(u16::from_be_bytes([
+            *packet.get(pos)?,
+            *packet.get(pos + 1)?,
+        ]))
+    };
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & 0x000f != 0 {
+        return None;
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    let mut ips = Vec::new();
+    let mut min_ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = read_name(packet, pos)?.1;
+        let (rtype, len) = (u16_at(pos)?, u16_at(pos + 8)? as usize);
+        let ttl = u32::from_be_bytes(packet.get(pos + 4..pos + 8)?.try_into().ok()?);
+        min_ttl = min_ttl.min(ttl);
+        let data = packet.get(pos + 10..pos + 10 + len)?;
+        match (rtype, data.len()) {
+            (TYPE_A, 4) if rtype == qtype => {
+                ips.push(IpAddr::from(<[u8; 4]>::try_from(data).ok()?))
+            }
+            (TYPE_AAAA, 16) if rtype == qtype => {
+                ips.push(IpAddr::from(<[u8; 16]>::try_from(data).ok()?))
+            }
+            // CNAME and others, addresses of target follow in the same reply
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Some(Records { ips, ttl: min_ttl })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    /// reply to query `id` of `name`, with answers of type, ttl and data
+    fn reply(id: u16, name: &str, qtype: u16, answers: &[(u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0x81, 0x80, 0, 1, 0, answers.len() as u8, 0, 0, 0, 0]);
+        write_name(&mut packet, name);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        for (rtype, ttl, data) in answers {
+            write_name(&mut packet, name);
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&1u16.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        packet
+    }
+
+    #[test]
+    fn shortest_ttl_of_answers_is_kept() {
+        let mut cname = vec![];
+        write_name(&mut cname, "edge.example.net");
+        let answers = [
+            (TYPE_CNAME, 30, cname),
+            (TYPE_A, 300, vec![192, 0, 2, 1]),
+            (TYPE_A, 120, vec![192, 0, 2, 2]),
+        ];
+        let packet = reply(7, "example.com", TYPE_A, &answers);
+        let records = parse_reply(&packet, 7, TYPE_A).unwrap();
+        let ips = ["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+        assert_eq!(
+            records,
+            Records {
+                ips: ips.to_vec(),
+                ttl: 30
+            }
+        );
+        // other id, other type, truncated
+        assert_eq!(parse_reply(&packet, 8, TYPE_A), None);
+        assert!(parse_reply(&packet, 7, TYPE_AAAA).unwrap().ips.is_empty());
+        assert_eq!(parse_reply(&packet[..packet.len() - 1], 7, TYPE_A), None);
+    }
+
+    /// dns server answering A queries with `ttl`, counting queries
+    async fn serve(ttl: u32) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let (name, pos) = read_name(&buf[..n], 12).unwrap();
+                let qtype = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+                let id = u16::from_be_bytes([buf[0], buf[1]]);
+                let answers = match qtype {
+                    TYPE_A => vec![(TYPE_A, ttl, vec![192, 0, 2, 1])],
+                    _ => vec![],
+                };
+                if qtype == TYPE_A {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                let packet = reply(id, &name, qtype, &answers);
+                socket.send_to(&packet, peer).await.unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    fn resolver(server: SocketAddr, ttl: u64) -> Resolver {
+        Resolver::new(DnsConfig {
+            ttl,
+            servers: vec![server],
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn records_are_cached_for_their_ttl() {
+        let (server, queries) = serve(1).await;
+        let resolver = resolver(server, 60);
+        let addr = resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(addr, "192.0.2.1:443".parse().unwrap());
+        resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn ttl_of_records_is_capped_by_config() {
+        let (server, queries) = serve(3600).await;
+        let resolver = resolver(server, 1);
+        resolver.resolve("example.com:443").await.unwrap();
+        resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_is_not_cached() {
+        let (server, queries) = serve(0).await;
+        let resolver = resolver(server, 60);
+        resolver.resolve("example.com:443").await.unwrap();
+        resolver.resolve("example.com:443").await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+    }
+}
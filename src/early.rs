@@ -0,0 +1,227 @@
+/// early data of forward proxy clients, sent with first handshake message to save a round trip
+///
+/// client sends `EARLY_DATA` and sealed data before its first handshake message,
+/// sealed by a key derived from static keys of both sides, or secret of a resumed ticket.
+/// after handshake, server replies whether it accepted early data,
+/// client sends the data again in noise stream if not.
+/// early data is not forward secret, and replay is rejected by its timestamp and nonce.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::MontgomeryPoint;
+use snowstorm::NoiseStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::consts::PATTERN;
+use crate::error::{Error, Result};
+
+const EARLY_DATA: [u8; 2] = [0xfd, 0xff];
+/// max length of early data
+pub(crate) const MAX_EARLY_DATA: usize = 16 * 1024;
+/// time client waits for first bytes of application to send them as early data
+pub(crate) const EARLY_DATA_WAIT: Duration = Duration::from_millis(10);
+const NONCE_LEN: usize = 12;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// key material shared by static keys, `DH(prikey, remote pubkey)`
+pub(crate) fn static_secret(prikey: &[u8], pubkey: &[u8]) -> Option<Vec<u8>> {
+    let prikey: [u8; 32] = prikey.try_into().ok()?;
+    let pubkey: [u8; 32] = pubkey.try_into().ok()?;
+    Some(
+        MontgomeryPoint(pubkey)
+            .mul_clamped(prikey)
+            .to_bytes()
+            .to_vec(),
+    )
+}
+
+fn cipher(material: &[u8]) -> ChaCha20Poly1305 {
+    let mut hasher = Blake2s256::new();
+    hasher.update(b"portguard early data");
+    hasher.update(material);
+    ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+}
+
+/// nonce and data sealed with timestamp `ts`
+fn seal(material: &[u8], ts: u64, data: &[u8]) -> Result<Vec<u8>> {
+    // random bytes from a fresh key
+    let random = snowstorm::Builder::new(PATTERN.parse()?)
+        .generate_keypair()?
+        .private;
+    let nonce = &random[..NONCE_LEN];
+    let mut plain = ts.to_le_bytes().to_vec();
+    plain.extend_from_slice(data);
+    let sealed = cipher(material)
+        .encrypt(Nonce::from_slice(nonce), &plain[..])
+        .map_err(|_| Error::Config(String::from("failed to seal early data")))?;
+    Ok([nonce, &sealed].concat())
+}
+
+/// send early data sealed by key material, before first handshake message
+pub(crate) async fn send(conn: &mut TcpStream, material: &[u8], data: &[u8]) -> Result<()> {
+    let blob = seal(material, now(), data)?;
+    let mut msg = EARLY_DATA.to_vec();
+    msg.extend_from_slice(&(blob.len() as u16).to_le_bytes());
+    msg.extend_from_slice(&blob);
+    // do not wait for ack of this segment before sending handshake message
+    conn.set_nodelay(true)?;
+    conn.write_all(&msg).await?;
+    Ok(())
+}
+
+/// read reply of server after handshake, send early data again in noise stream if rejected
+pub(crate) async fn confirm<S>(stream: &mut NoiseStream<S>, data: &[u8]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream.read_u8().await? == 0 {
+        log::debug!("Early data rejected by server, send it in noise stream");
+        stream.write_all(data).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+pub(crate) use server::{read, EarlyDataGuard};
+
+#[cfg(feature = "server")]
+mod server {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use chacha20poly1305::aead::Aead;
+    use chacha20poly1305::Nonce;
+    use tokio::io::{self, AsyncReadExt};
+    use tokio::net::TcpStream;
+
+    use super::{cipher, now, EARLY_DATA, NONCE_LEN};
+    use crate::ticket::peek_marker;
+
+    /// early data with a timestamp out of this window is rejected
+    const EARLY_DATA_WINDOW: u64 = 30;
+
+    /// read sealed early data if client sends it
+    pub(crate) async fn read(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+        if peek_marker(stream).await? != EARLY_DATA {
+            return Ok(None);
+        }
+        stream.read_exact(&mut [0; 2]).await?;
+        let len = stream.read_u16_le().await? as usize;
+        let mut blob = vec![0; len];
+        stream.read_exact(&mut blob).await?;
+        Ok(Some(blob))
+    }
+
+    /// opens early data, rejecting replayed ones
+    #[derive(Debug, Default)]
+    pub(crate) struct EarlyDataGuard {
+        /// nonces of early data seen in window, with their timestamps
+        seen: Mutex<HashMap<Vec<u8>, u64>>,
+    }
+
+    impl EarlyDataGuard {
+        /// early data sealed by key material, `None` if it is invalid, stale or replayed
+        pub(crate) fn open(&self, material: &[u8], blob: &[u8]) -> Option<Vec<u8>> {
+            if blob.len() < NONCE_LEN {
+                return None;
+            }
+            let (nonce, sealed) = blob.split_at(NONCE_LEN);
+            let plain = cipher(material)
+                .decrypt(Nonce::from_slice(nonce), sealed)
+                .ok()?;
+            let (ts, data) = plain.split_at_checked(8)?;
+            let ts = u64::from_le_bytes(ts.try_into().ok()?);
+            let now = now();
+            if ts.abs_diff(now) > EARLY_DATA_WINDOW {
+                return None;
+            }
+            let mut seen = self.seen.lock().unwrap();
+            seen.retain(|_, t| t.abs_diff(now) <= EARLY_DATA_WINDOW);
+            seen.insert(nonce.to_vec(), ts)
+                .is_none()
+                .then(|| data.to_vec())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const MATERIAL: &[u8] = b"shared key material";
+
+    #[test]
+    fn fresh_early_data_is_opened() {
+        let guard = EarlyDataGuard::default();
+        let blob = seal(MATERIAL, now(), b"hello").unwrap();
+        assert_eq!(guard.open(MATERIAL, &blob).as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let guard = EarlyDataGuard::default();
+        let blob = seal(MATERIAL, now(), b"hello").unwrap();
+        assert!(guard.open(MATERIAL, &blob).is_some());
+        assert!(guard.open(MATERIAL, &blob).is_none());
+        // another nonce is not a replay
+        let blob = seal(MATERIAL, now(), b"hello").unwrap();
+        assert!(guard.open(MATERIAL, &blob).is_some());
+    }
+
+    #[test]
+    fn stale_timestamp_is_rejected() {
+        let guard = EarlyDataGuard::default();
+        let now = now();
+        for ts in [now - 35, now + 35, 0] {
+            let blob = seal(MATERIAL, ts, b"hello").unwrap();
+            assert!(guard.open(MATERIAL, &blob).is_none(), "{ts}");
+        }
+        for ts in [now - 25, now + 25] {
+            let blob = seal(MATERIAL, ts, b"hello").unwrap();
+            assert!(guard.open(MATERIAL, &blob).is_some(), "{ts}");
+        }
+    }
+
+    #[test]
+    fn tampered_or_foreign_early_data_is_rejected() {
+        let guard = EarlyDataGuard::default();
+        let blob = seal(MATERIAL, now(), b"hello").unwrap();
+        assert!(guard.open(b"other key material", &blob).is_none());
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(guard.open(MATERIAL, &tampered).is_none());
+        assert!(guard.open(MATERIAL, &blob[..NONCE_LEN - 1]).is_none());
+    }
+
+    #[tokio::test]
+    async fn sent_early_data_is_read_before_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            send(&mut conn, MATERIAL, b"GET / HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            conn.write_all(b"handshake").await.unwrap();
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let blob = read(&mut stream).await.unwrap().unwrap();
+        let data = EarlyDataGuard::default().open(MATERIAL, &blob).unwrap();
+        assert_eq!(data, b"GET / HTTP/1.1\r\n\r\n");
+        let mut rest = [0; 9];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"handshake");
+        client.await.unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::io;
+
+use snowstorm::SnowstormError;
+use thiserror::Error;
+
+/// error type of portguard library
+#[derive(Debug, Error)]
+pub enum Error {
+    /// network or file io error
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    /// invalid config file, builtin client config or key material
+    #[error("Config error: {0}")]
+    Config(String),
+    /// invalid remote address input
+    #[error("Invalid remote: {0}")]
+    InvalidRemote(String),
+    /// noise handshake or encryption failed
+    #[error("Noise error: {0}")]
+    Noise(#[from] SnowstormError),
+    /// handshake is not finished in time
+    #[error("Handshake timeout")]
+    Timeout,
+    /// client is rejected by server
+    #[error("Rejected by server: {0}")]
+    Rejected(String),
+    /// wrong passphrase of client key
+    #[error("Wrong key passphrase")]
+    Passphrase,
+    /// reverse proxy service is offline
+    #[error("Service {0} offline")]
+    ServiceOffline(usize),
+    /// reverse proxy service is already online
+    #[error("Service {0} already online")]
+    ServiceOnline(usize),
+    /// reverse proxy service is online with another client, seen by the client
+    #[error("Service is already online with another client, retrying until it goes offline")]
+    ServiceTaken,
+    /// hash of reverse proxy client binary is not accepted by server
+    #[error("Client binary is denied by server, it is modified or revoked, generate a new one")]
+    HashDenied,
+    /// client is older than version required by server
+    #[error("Client is older than version {0} required by server, update it")]
+    Outdated(String),
+    /// reverse proxy service has too many visitors
+    #[error("Service {0} busy")]
+    ServiceBusy(usize),
+    /// new connection is shed as server reaches a resource ceiling
+    #[error("Server overloaded: {0}")]
+    Overloaded(String),
+    /// multiplexing error of reverse proxy connection
+    #[error("Yamux error: {0}")]
+    Yamux(#[from] yamux::ConnectionError),
+    /// socks5 request of client is invalid
+    #[error("Socks5 error: {0}")]
+    Socks5(String),
+    /// another instance of a single instance client is running
+    #[error("Client is already running, {0}")]
+    AlreadyRunning(String),
+    /// failed to install or uninstall client service
+    #[error("Service error: {0}")]
+    Service(String),
+    /// failed to generate or modify client binary
+    #[error("Generation error: {0}")]
+    Gen(String),
+    /// binary has no builtin client config, it is not generated by `gen-cli`
+    #[error("No builtin config, this binary is not generated by gen-cli")]
+    Unprovisioned,
+    /// client binary does not match server config
+    #[error("Verification failed: {0}")]
+    Mismatch(String),
+}
+
+/// stable exit codes of commands, so scripts and installers can branch on failure type
+pub mod exit {
+    /// command succeeded
+    pub const OK: i32 = 0;
+    /// error not listed below
+    pub const FAILURE: i32 = 1;
+    /// invalid command line arguments, as reported by clap or the parser of `pgcli`
+    pub const USAGE: i32 = 2;
+    /// invalid config file, builtin client config, remote or key
+    pub const CONFIG: i32 = 3;
+    /// wrong key passphrase
+    pub const PASSPHRASE: i32 = 4;
+    /// rejected by server, e.g. unknown or revoked key, denied or outdated client binary
+    pub const REJECTED: i32 = 5;
+    /// server or target unreachable, connection lost or handshake timeout
+    pub const NETWORK: i32 = 6;
+    /// reverse proxy service is offline, taken or busy, or server is overloaded
+    pub const SERVICE: i32 = 7;
+    /// another instance of a single instance client is running
+    pub const ALREADY_RUNNING: i32 = 8;
+    /// failed to install or uninstall client service
+    pub const INSTALL: i32 = 9;
+    /// failed to generate or modify client binary
+    pub const GEN: i32 = 10;
+    /// client binary does not match server config, by `verify-cli`
+    pub const MISMATCH: i32 = 11;
+    /// binary has no builtin client config
+    pub const UNPROVISIONED: i32 = 12;
+    /// client key is protected by passphrase, by `info`
+    pub const KEY_PROTECTED: i32 = 13;
+    /// client key is not builtin, it is given by `--key-file`, by `info`
+    pub const KEY_EXTERNAL: i32 = 14;
+}
+
+impl Error {
+    /// exit code of a command failing with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::NetworkDown
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof => exit::NETWORK,
+                _ => exit::FAILURE,
+            },
+            Error::Config(_) | Error::InvalidRemote(_) | Error::Socks5(_) => exit::CONFIG,
+            Error::Noise(_) | Error::Rejected(_) | Error::HashDenied | Error::Outdated(_) => {
+                exit::REJECTED
+            }
+            Error::Timeout | Error::Yamux(_) => exit::NETWORK,
+            Error::Passphrase => exit::PASSPHRASE,
+            Error::ServiceOffline(_)
+            | Error::ServiceOnline(_)
+            | Error::ServiceTaken
+            | Error::ServiceBusy(_)
+            | Error::Overloaded(_) => exit::SERVICE,
+            Error::AlreadyRunning(_) => exit::ALREADY_RUNNING,
+            Error::Service(_) => exit::INSTALL,
+            Error::Gen(_) => exit::GEN,
+            Error::Unprovisioned => exit::UNPROVISIONED,
+            Error::Mismatch(_) => exit::MISMATCH,
+        }
+    }
+}
+
+/// result type of portguard library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<snowstorm::snow::Error> for Error {
+    fn from(e: snowstorm::snow::Error) -> Self {
+        Error::Noise(SnowstormError::SnowError(e))
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Config(format!("invalid builtin config, {}", e))
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Config(format!("invalid base64 key, {}", e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<std::net::AddrParseError> for Error {
+    fn from(e: std::net::AddrParseError) -> Self {
+        Error::Config(format!("invalid address, {}", e))
+    }
+}
+
+impl From<chacha20poly1305::aead::Error> for Error {
+    fn from(_: chacha20poly1305::aead::Error) -> Self {
+        Error::Passphrase
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+#[cfg(feature = "gen")]
+impl From<object::Error> for Error {
+    fn from(e: object::Error) -> Self {
+        Error::Gen(e.to_string())
+    }
+}
@@ -0,0 +1,64 @@
+use std::io;
+#[cfg(unix)]
+use std::process::{Child, Command, Stdio};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(unix)]
+use crate::proxy;
+
+/// spawn a command and bridge its stdin and stdout to inbound stream
+/// command is split by whitespace, not interpreted by a shell
+#[cfg(unix)]
+pub(crate) async fn transfer_to_exec<S>(inbound: S, cmd: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use std::os::unix::io::OwnedFd;
+
+    let mut args = cmd.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    // a socket pair serves as both stdin and stdout of child
+    let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+    let stdin = OwnedFd::from(theirs.try_clone()?);
+    let stdout = OwnedFd::from(theirs);
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::from(stdout))
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    log::debug!("Spawned command {cmd:?}, pid {}", child.id());
+    ours.set_nonblocking(true)?;
+    let outbound = tokio::net::UnixStream::from_std(ours)?;
+    proxy::transfer_and_log_error(inbound, outbound).await;
+    reap(child).await
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn transfer_to_exec<S>(_inbound: S, _cmd: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "exec target is only supported on unix",
+    ))
+}
+
+/// kill child if it is still running after tunnel is closed
+#[cfg(unix)]
+async fn reap(mut child: Child) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        if child.try_wait()?.is_none() {
+            child.kill()?;
+        }
+        child
+            .wait()
+            .map(|status| log::debug!("Command exited, {status}"))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
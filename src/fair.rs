@@ -0,0 +1,266 @@
+/// fair scheduling of streams sharing a yamux connection, so one bulk stream
+/// cannot starve interactive ones
+///
+/// a stream writing more than `BULK_BYTES` in a second is bulk until it slows down,
+/// its writes wait while writes of interactive streams are waiting for the connection,
+/// so keystrokes are not queued behind megabytes of a download. a stream may also be
+/// interactive or bulk by priority of its client.
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// bytes written in a window making a stream bulk
+const BULK_BYTES: usize = 64 * 1024;
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// writes of streams in one connection
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    /// number of interactive writes waiting for connection, and bulk writers waiting for them
+    state: Mutex<(usize, Vec<Waker>)>,
+}
+
+impl Scheduler {
+    /// an interactive write is waiting
+    fn enter(&self) {
+        self.state.lock().unwrap().0 += 1;
+    }
+    /// an interactive write is sent or dropped, bulk writers resume if no one is waiting
+    fn leave(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 -= 1;
+        if state.0 == 0 {
+            state.1.drain(..).for_each(Waker::wake);
+        }
+    }
+    /// whether a bulk write must wait, registering its waker if so
+    fn must_yield(&self, cx: &Context) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.0 == 0 {
+            return false;
+        }
+        if !state.1.iter().any(|w| w.will_wake(cx.waker())) {
+            state.1.push(cx.waker().clone());
+        }
+        true
+    }
+}
+
+/// how a stream is scheduled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Class {
+    /// never waits, other streams wait for it
+    #[cfg(feature = "server")]
+    Interactive,
+    /// interactive unless it writes more than `BULK_BYTES` in a second
+    Auto,
+    /// waits for interactive streams
+    #[cfg(feature = "server")]
+    Bulk,
+}
+
+/// stream in a connection scheduled by its `Scheduler`, or not scheduled if it has none
+pub(crate) struct Fair<S> {
+    inner: S,
+    scheduler: Option<Arc<Scheduler>>,
+    class: Class,
+    /// an interactive write of this stream is waiting
+    waiting: bool,
+    /// bytes written in current window, its start, and whether previous window was bulk
+    window: (usize, Instant, bool),
+}
+
+impl<S> Fair<S> {
+    pub(crate) fn new(inner: S, scheduler: Option<Arc<Scheduler>>, class: Class) -> Self {
+        Fair {
+            inner,
+            scheduler,
+            class,
+            waiting: false,
+            window: (0, Instant::now(), false),
+        }
+    }
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+    /// whether stream is bulk, an auto one stays bulk for a window after a busy one
+    fn is_bulk(&mut self) -> bool {
+        match self.class {
+            #[cfg(feature = "server")]
+            Class::Interactive => return false,
+            #[cfg(feature = "server")]
+            Class::Bulk => return true,
+            Class::Auto => {}
+        }
+        let (bytes, start, busy) = &mut self.window;
+        if start.elapsed() >= WINDOW {
+            *busy = *bytes > BULK_BYTES && start.elapsed() < WINDOW * 2;
+            *bytes = 0;
+            *start = Instant::now();
+        }
+        *busy || *bytes > BULK_BYTES
+    }
+    fn set_waiting(&mut self, waiting: bool) {
+        if let Some(scheduler) = &self.scheduler {
+            match (self.waiting, waiting) {
+                (false, true) => scheduler.enter(),
+                (true, false) => scheduler.leave(),
+                _ => {}
+            }
+        }
+        self.waiting = waiting;
+    }
+}
+
+impl<S> Drop for Fair<S> {
+    fn drop(&mut self) {
+        self.set_waiting(false);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Fair<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Fair<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let scheduler = match &this.scheduler {
+            Some(scheduler) => scheduler.clone(),
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        let bulk = this.is_bulk();
+        if bulk && scheduler.must_yield(cx) {
+            this.set_waiting(false);
+            return Poll::Pending;
+        }
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.set_waiting(!bulk && res.is_pending());
+        if let Poll::Ready(Ok(n)) = res {
+            this.window.0 += n;
+        }
+        res
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use tokio::io::DuplexStream;
+
+    /// waker remembering whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl Flag {
+        fn woken(&self) -> bool {
+            self.0.swap(false, Ordering::SeqCst)
+        }
+    }
+
+    fn write(stream: &mut Fair<DuplexStream>, flag: &Arc<Flag>, buf: &[u8]) -> Poll<usize> {
+        let waker = Waker::from(flag.clone());
+        let res = Pin::new(stream).poll_write(&mut Context::from_waker(&waker), buf);
+        res.map(Result::unwrap)
+    }
+
+    /// stream whose writes are pending after `capacity` bytes until its peer reads
+    fn stream(
+        scheduler: &Arc<Scheduler>,
+        class: Class,
+        capacity: usize,
+    ) -> (Fair<DuplexStream>, DuplexStream) {
+        let (inner, peer) = tokio::io::duplex(capacity);
+        (Fair::new(inner, Some(scheduler.clone()), class), peer)
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn small_writes_are_scheduled_before_bulk_ones() {
+        use tokio::io::AsyncReadExt;
+
+        let scheduler = Arc::new(Scheduler::default());
+        let (mut small, mut small_peer) = stream(&scheduler, Class::Interactive, 16);
+        let (mut bulk, _bulk_peer) = stream(&scheduler, Class::Bulk, 1024);
+        let (small_flag, bulk_flag) = (Arc::new(Flag::default()), Arc::new(Flag::default()));
+
+        // nothing waiting, bulk writes go through
+        assert_eq!(write(&mut bulk, &bulk_flag, &[0; 4]), Poll::Ready(4));
+        assert_eq!(write(&mut small, &small_flag, &[0; 16]), Poll::Ready(16));
+
+        // a small write waits for connection, bulk waits for it
+        assert_eq!(write(&mut small, &small_flag, &[1]), Poll::Pending);
+        assert_eq!(write(&mut bulk, &bulk_flag, &[0; 4]), Poll::Pending);
+        assert!(!bulk_flag.woken());
+
+        // small write is sent first, then bulk resumes
+        small_peer.read_exact(&mut [0; 16]).await.unwrap();
+        assert!(small_flag.woken());
+        assert!(!bulk_flag.woken());
+        assert_eq!(write(&mut small, &small_flag, &[1]), Poll::Ready(1));
+        assert!(bulk_flag.woken());
+        assert_eq!(write(&mut bulk, &bulk_flag, &[0; 4]), Poll::Ready(4));
+    }
+
+    #[tokio::test]
+    async fn busy_streams_become_bulk() {
+        let scheduler = Arc::new(Scheduler::default());
+        let (mut busy, _busy_peer) = stream(&scheduler, Class::Auto, 4 * BULK_BYTES);
+        let (mut quiet, _quiet_peer) = stream(&scheduler, Class::Auto, 4 * BULK_BYTES);
+        let (mut small, _small_peer) = stream(&scheduler, Class::Auto, 16);
+        let flag = Arc::new(Flag::default());
+
+        let chunk = vec![0; BULK_BYTES + 1];
+        assert_eq!(write(&mut busy, &flag, &chunk), Poll::Ready(chunk.len()));
+        assert_eq!(write(&mut quiet, &flag, &[0; 1024]), Poll::Ready(1024));
+        assert_eq!(write(&mut small, &flag, &[0; 16]), Poll::Ready(16));
+        assert_eq!(write(&mut small, &flag, &[1]), Poll::Pending);
+
+        // only the stream over `BULK_BYTES` waits
+        assert_eq!(write(&mut busy, &flag, &[0; 4]), Poll::Pending);
+        assert_eq!(write(&mut quiet, &flag, &[0; 4]), Poll::Ready(4));
+
+        // a dropped waiting write does not hold bulk streams
+        drop(small);
+        assert!(flag.woken());
+        assert_eq!(write(&mut busy, &flag, &[0; 4]), Poll::Ready(4));
+    }
+
+    #[tokio::test]
+    async fn streams_without_scheduler_never_wait() {
+        let (inner, _peer) = tokio::io::duplex(4 * BULK_BYTES);
+        let mut stream = Fair::new(inner, None, Class::Auto);
+        let flag = Arc::new(Flag::default());
+        let chunk = vec![0; BULK_BYTES + 1];
+        assert_eq!(write(&mut stream, &flag, &chunk), Poll::Ready(chunk.len()));
+        assert_eq!(write(&mut stream, &flag, &[0; 4]), Poll::Ready(4));
+    }
+}
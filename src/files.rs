@@ -0,0 +1,252 @@
+/// simple framed file transfer protocol of `portguard cp`
+///
+/// put: client sends op, path, size; server replies status;
+///      client sends data; server replies status after data is written
+/// get: client sends op, path; server replies status, size and data
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::path::PathBuf;
+
+use tokio::fs::File;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+
+const OP_PUT: u8 = 1;
+const OP_GET: u8 = 2;
+const STATUS_OK: u8 = 0;
+#[cfg(feature = "server")]
+const STATUS_ERR: u8 = 1;
+
+async fn write_str<S: AsyncWrite + Unpin>(stream: &mut S, s: &str) -> io::Result<()> {
+    let len = u16::try_from(s.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "string too long"))?;
+    stream.write_u16(len).await?;
+    stream.write_all(s.as_bytes()).await
+}
+
+async fn read_str<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn read_status<S: AsyncRead + Unpin>(stream: &mut S) -> Result<()> {
+    match stream.read_u8().await? {
+        STATUS_OK => Ok(()),
+        _ => Err(Error::Rejected(read_str(stream).await?)),
+    }
+}
+
+/// upload local file to remote path, return bytes sent
+pub(crate) async fn put<S>(stream: &mut S, local: &Path, remote: &str) -> Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut file = File::open(local).await?;
+    let size = file.metadata().await?.len();
+    stream.write_u8(OP_PUT).await?;
+    write_str(stream, remote).await?;
+    stream.write_u64(size).await?;
+    read_status(stream).await?;
+    let sent = io::copy(&mut (&mut file).take(size), stream).await?;
+    stream.flush().await?;
+    read_status(stream).await?;
+    Ok(sent)
+}
+
+/// download remote path to local file, return bytes received
+pub(crate) async fn get<S>(stream: &mut S, remote: &str, local: &Path) -> Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_u8(OP_GET).await?;
+    write_str(stream, remote).await?;
+    read_status(stream).await?;
+    let size = stream.read_u64().await?;
+    let mut file = File::create(local).await?;
+    let received = io::copy(&mut stream.take(size), &mut file).await?;
+    if received != size {
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file is truncated",
+        ))?
+    }
+    file.flush().await?;
+    Ok(received)
+}
+
+/// serve one file transfer request, paths are restricted to allowed directories
+#[cfg(feature = "server")]
+pub(crate) async fn serve<S>(mut stream: S, dirs: &[PathBuf]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let op = stream.read_u8().await?;
+    let path = read_str(&mut stream).await?;
+    let res = match op {
+        OP_PUT => serve_put(&mut stream, dirs, &path).await,
+        OP_GET => serve_get(&mut stream, dirs, &path).await,
+        _ => Err(format!("unknown operation {op}")),
+    };
+    match res {
+        Ok(bytes) => {
+            log::info!("File transfer of {path:?} finished, {bytes} bytes");
+            Ok(())
+        }
+        Err(reason) => {
+            stream.write_u8(STATUS_ERR).await?;
+            write_str(&mut stream, &reason).await?;
+            stream.flush().await?;
+            Err(Error::Rejected(format!("{path:?}, {reason}")))
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+async fn serve_put<S>(stream: &mut S, dirs: &[PathBuf], path: &str) -> Result<u64, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io_err = |e: io::Error| e.to_string();
+    let size = stream.read_u64().await.map_err(io_err)?;
+    let path = allowed_path(dirs, path)?;
+    let mut file = open_nofollow(&path, true).await.map_err(io_err)?;
+    stream.write_u8(STATUS_OK).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
+    let received = io::copy(&mut stream.take(size), &mut file)
+        .await
+        .map_err(io_err)?;
+    file.flush().await.map_err(io_err)?;
+    if received != size {
+        return Err(String::from("file is truncated"));
+    }
+    stream.write_u8(STATUS_OK).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
+    Ok(received)
+}
+
+#[cfg(feature = "server")]
+async fn serve_get<S>(stream: &mut S, dirs: &[PathBuf], path: &str) -> Result<u64, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io_err = |e: io::Error| e.to_string();
+    let path = allowed_path(dirs, path)?;
+    let mut file = open_nofollow(&path, false).await.map_err(io_err)?;
+    let size = file.metadata().await.map_err(io_err)?.len();
+    stream.write_u8(STATUS_OK).await.map_err(io_err)?;
+    stream.write_u64(size).await.map_err(io_err)?;
+    let sent = io::copy(&mut (&mut file).take(size), stream)
+        .await
+        .map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
+    Ok(sent)
+}
+
+/// resolve symlinks of parent directories of path, and check it is in one of allowed
+/// directories, path itself must not be a symlink, even a dangling one
+#[cfg(feature = "server")]
+fn allowed_path(dirs: &[PathBuf], path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(String::from("path must be absolute"));
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| String::from("invalid file name"))?;
+    let parent = path.parent().unwrap_or(path);
+    let real = parent.canonicalize().map_err(|e| e.to_string())?.join(name);
+    if std::fs::symlink_metadata(&real).is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err(String::from("path is a symlink"));
+    }
+    let allowed = dirs
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| real.starts_with(dir));
+    match allowed {
+        true => Ok(real),
+        false => Err(String::from("path is not in allowed directories")),
+    }
+}
+
+/// open file checked by `allowed_path` for reading, or create it for writing,
+/// failing if a symlink is put there meanwhile
+#[cfg(feature = "server")]
+async fn open_nofollow(path: &Path, create: bool) -> io::Result<File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    match create {
+        true => options.write(true).create(true).truncate(true),
+        false => options.read(true),
+    };
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    options.open(path).await
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_checked_against_allowed_directories() {
+        let root = std::env::temp_dir().join(format!("portguard-files-{}", std::process::id()));
+        let (share, secret) = (root.join("share"), root.join("secret"));
+        std::fs::create_dir_all(share.join("sub")).unwrap();
+        std::fs::create_dir_all(&secret).unwrap();
+        std::fs::write(share.join("report.pdf"), b"report").unwrap();
+        std::fs::write(secret.join("key"), b"key").unwrap();
+        let dirs = [share.clone()];
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        let cases = [
+            (path(&share.join("report.pdf")), true),
+            (path(&share.join("sub/new.txt")), true),
+            (path(&share.join("sub/../report.pdf")), true),
+            (path(&share.join("../secret/key")), false),
+            (path(&secret.join("key")), false),
+            (path(&share.join("missing/new.txt")), false),
+            (path(&share.join("..")), false),
+            (String::from("share/report.pdf"), false),
+        ];
+        for (path, allowed) in cases {
+            assert_eq!(allowed_path(&dirs, &path).is_ok(), allowed, "{path}");
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_are_rejected() {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("portguard-links-{}", std::process::id()));
+        let (share, secret) = (root.join("share"), root.join("secret"));
+        std::fs::create_dir_all(&share).unwrap();
+        std::fs::create_dir_all(&secret).unwrap();
+        std::fs::write(secret.join("key"), b"key").unwrap();
+        std::fs::write(share.join("report.pdf"), b"report").unwrap();
+        symlink(secret.join("key"), share.join("key")).unwrap();
+        // dangling, a put would create the file outside of allowed directories
+        symlink(secret.join("new"), share.join("new")).unwrap();
+        symlink(share.join("report.pdf"), share.join("inside")).unwrap();
+        let dirs = [share.clone()];
+        for name in ["key", "new", "inside"] {
+            let path = share.join(name).to_string_lossy().into_owned();
+            assert_eq!(
+                allowed_path(&dirs, &path),
+                Err(String::from("path is a symlink")),
+                "{name}"
+            );
+        }
+        // a symlink put after the check is not followed
+        for create in [true, false] {
+            assert!(open_nofollow(&share.join("new"), create).await.is_err());
+        }
+        assert!(!secret.join("new").exists());
+        assert!(open_nofollow(&share.join("report.pdf"), false)
+            .await
+            .is_ok());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+/// short fingerprints of public keys, to compare keys by eye or over the phone
+/// instead of reading full base64 strings
+use std::fmt;
+
+use blake2::{Blake2s256, Digest};
+use serde::Serialize;
+
+/// number of hashed bytes shown in a fingerprint
+const FINGERPRINT_LEN: usize = 8;
+
+/// fingerprint of `key`, first bytes of its blake2s hash in groups of 4 hex digits,
+/// e.g. "3f2a-9c01-b7e4-5d68"
+pub(crate) fn of(key: &[u8]) -> String {
+    Blake2s256::digest(key)[..FINGERPRINT_LEN]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// public key in base64 with its fingerprint, as shown in json output
+#[derive(Debug, Serialize)]
+pub(crate) struct KeyInfo {
+    pubkey: String,
+    fingerprint: String,
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.fingerprint, self.pubkey)
+    }
+}
+
+impl KeyInfo {
+    pub(crate) fn of(key: &[u8]) -> Self {
+        KeyInfo {
+            pubkey: base64::encode(key),
+            fingerprint: of(key),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// whether `s` is the fingerprint of `key`, case and separators are ignored
+pub(crate) fn matches(s: &str, key: &[u8]) -> bool {
+    let digits: String = s.chars().filter(char::is_ascii_hexdigit).collect();
+    digits.len() == FINGERPRINT_LEN * 2 && digits.eq_ignore_ascii_case(&of(key).replace('-', ""))
+}
@@ -1,29 +1,52 @@
 /// functions for generating keypair and client binary
+use std::ffi::OsString;
 use std::fs::{self, OpenOptions};
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use anyhow::{anyhow, Result};
 use chacha20poly1305::aead::{Aead, NewAead};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce}; // Or `XChaCha20Poly1305`
+use curve25519_dalek::EdwardsPoint;
 use memmap2::MmapOptions;
 use object::{BinaryFormat, File, Object, ObjectSection};
+use serde::Serialize;
 use snowstorm::Keypair;
 
 use crate::client::ClientConfig;
-use crate::consts::{CONF_BUF_LEN, KEYPASS_LEN, PATTERN};
+use crate::consts::{CONF_BUF_LEN, CONF_SCHEMA, KEYPASS_LEN, PATTERN};
+use crate::error::{Error, Result};
+use crate::fingerprint::{self, KeyInfo};
 
-fn serialize_conf_to_buf(conf: &ClientConfig) -> Result<[u8; CONF_BUF_LEN], bincode::Error> {
-    let v = conf.to_vec()?;
+/// config in layout of `schema`, padded to buffer length
+fn serialize_conf_to_buf(conf: &ClientConfig, schema: u32) -> Result<[u8; CONF_BUF_LEN]> {
+    let v = conf.to_vec_of(schema)?;
+    if v.len() > CONF_BUF_LEN {
+        return Err(Error::Gen(format!(
+            "client config is too large ({} bytes, max {})",
+            v.len(),
+            CONF_BUF_LEN
+        )));
+    }
     let mut bytes: [u8; CONF_BUF_LEN] = [0; CONF_BUF_LEN];
     bytes[..v.len()].clone_from_slice(&v[..]);
     Ok(bytes)
 }
 
 fn get_client_config_section(file: &File) -> Option<(u64, u64)> {
+    find_section(file, [".portguard", "pgmodify", "__portguard"])
+}
+
+fn get_config_schema_section(file: &File) -> Option<(u64, u64)> {
+    find_section(file, [".pgschema", "pgschema", "__pgschema"])
+}
+
+/// file range of section, named in elf, pe and mach-o
+fn find_section(file: &File, names: [&str; 3]) -> Option<(u64, u64)> {
     let name = match file.format() {
-        BinaryFormat::Elf => ".portguard",
-        BinaryFormat::Pe => "pgmodify",
-        BinaryFormat::MachO => "__portguard",
+        BinaryFormat::Elf => names[0],
+        BinaryFormat::Pe => names[1],
+        BinaryFormat::MachO => names[2],
         _ => todo!(),
     };
     for section in file.sections() {
@@ -41,48 +64,205 @@ pub fn gen_keypair(has_keypass: bool) -> Result<Keypair> {
     let mut keypair = snowstorm::Builder::new(PATTERN.parse()?).generate_keypair()?;
     if has_keypass {
         let mut password = rpassword::prompt_password("Input Key Passphrase: ")?.into_bytes();
+        // a mistyped passphrase would leave a client nobody can unlock
+        if rpassword::prompt_password("Repeat Key Passphrase: ")?.into_bytes() != password {
+            return Err(Error::Gen(String::from("passphrases do not match")));
+        }
         password.resize(KEYPASS_LEN, 0);
         let keypass = Key::from_slice(&password);
         let cipher = ChaCha20Poly1305::new(keypass);
-        let enc_prikey = cipher.encrypt(&Nonce::default(), &keypair.private[..])?;
+        let enc_prikey = cipher
+            .encrypt(&Nonce::default(), &keypair.private[..])
+            .map_err(|_| Error::Gen(String::from("failed to encrypt private key")))?;
         keypair.private = enc_prikey;
     }
     Ok(keypair)
 }
 
-/// generate a new client binary using a callback function that modifies config
-pub fn gen_client_binary<F>(in_path: &Path, out_path: &Path, mod_conf: F) -> Result<()>
+/// generate a keypair without any config, e.g. on an air-gapped machine, to paste into configs,
+/// private key in base64 is saved to a new file `output` readable only by its owner,
+/// e.g. for `prikey_file` of server, or printed if `output` is not set
+pub fn gen_standalone_keypair(output: Option<&Path>, json: bool) -> Result<()> {
+    let keypair = gen_keypair(false)?;
+    let prikey = base64::encode(&keypair.private);
+    if let Some(path) = output {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path).map_err(|e| {
+            Error::Gen(format!("failed to create key file {}, {e}", path.display()))
+        })?;
+        writeln!(file, "{prikey}")?;
+    }
+    if json {
+        #[derive(Serialize)]
+        struct Keys {
+            #[serde(flatten)]
+            public: KeyInfo,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            prikey: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            prikey_file: Option<PathBuf>,
+        }
+        let keys = Keys {
+            public: KeyInfo::of(&keypair.public),
+            prikey: output.is_none().then_some(prikey),
+            prikey_file: output.map(Path::to_path_buf),
+        };
+        println!("{}", serde_json::to_string_pretty(&keys)?);
+        return Ok(());
+    }
+    println!(
+        "Pubkey: {} (fingerprint {})",
+        base64::encode(&keypair.public),
+        fingerprint::of(&keypair.public)
+    );
+    match output {
+        Some(path) => println!("Prikey: saved to {}", path.display()),
+        None => println!("Prikey: {prikey}"),
+    }
+    Ok(())
+}
+
+/// find client template of a build profile, bundled in `templates/<profile>/` next to current binary
+pub fn template_path(profile: &str) -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| Error::Gen(String::from("cannot locate current binary")))?
+        .join("templates")
+        .join(profile);
+    ["pgcli", "pgcli.exe"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            Error::Gen(format!(
+                "client template of profile {} not found in {}, build it with scripts/build-templates.sh",
+                profile,
+                dir.display()
+            ))
+        })
+}
+
+/// derive public key from private key
+pub(crate) fn derive_pubkey(prikey: &[u8]) -> Result<Vec<u8>> {
+    let bits: [u8; 32] = prikey
+        .try_into()
+        .map_err(|_| Error::Config(String::from("invalid privkey when deriving pubkey")))?;
+    let point = EdwardsPoint::mul_base_clamped(bits).to_montgomery();
+    Ok(point.to_bytes().to_vec())
+}
+
+/// config buffer of a binary, error if it is not a client of the same buffer length
+fn config_range(file: &File, path: &Path) -> Result<usize> {
+    match get_client_config_section(file) {
+        Some((base, len)) if len == CONF_BUF_LEN as u64 => Ok(base as usize),
+        Some((_, len)) => Err(Error::Gen(format!(
+            "config of {} is {} bytes, {} bytes expected",
+            path.display(),
+            len,
+            CONF_BUF_LEN
+        ))),
+        None => Err(Error::Gen(format!(
+            "config not found in {}, it is not a portguard client",
+            path.display()
+        ))),
+    }
+}
+
+/// config schema read by a binary, 0 if it is older than schema 1
+fn config_schema(file: &File, buf: &[u8]) -> u32 {
+    get_config_schema_section(file)
+        .and_then(|(base, _)| buf.get(base as usize..base as usize + 4))
+        .map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// env variable of a command signing generated binaries, e.g. "signtool sign /a {}",
+/// `{}` is replaced by path of binary, which is appended if there is none
+const SIGN_COMMAND_ENV: &str = "PORTGUARD_SIGN_COMMAND";
+
+/// sign binary at `path` with command of `SIGN_COMMAND_ENV` if it is set,
+/// command is split by whitespace, not interpreted by a shell
+fn sign_binary(path: &Path) -> Result<()> {
+    let command = match std::env::var(SIGN_COMMAND_ENV) {
+        Ok(command) if !command.trim().is_empty() => command,
+        _ => return Ok(()),
+    };
+    let path = path.as_os_str();
+    let mut args: Vec<OsString> = command
+        .split_whitespace()
+        .map(|arg| match arg {
+            "{}" => path.to_owned(),
+            arg => arg.into(),
+        })
+        .collect();
+    if !command.split_whitespace().any(|arg| arg == "{}") {
+        args.push(path.to_owned());
+    }
+    log::info!("Signing client with {:?}", command);
+    let status = Command::new(&args[0])
+        .args(&args[1..])
+        .status()
+        .map_err(|e| Error::Gen(format!("failed to run signing command {command:?}: {e}")))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Gen(format!(
+            "signing command {command:?} failed, {status}"
+        ))),
+    }
+}
+
+/// generate a new client binary using a callback function that modifies config buffer,
+/// called with config schema of input binary, and sign it before it is moved to output,
+/// so that hash of a signed reverse proxy client is recorded
+fn gen_client_binary_with<F>(in_path: &Path, out_path: &Path, mod_buf: F) -> Result<()>
 where
-    F: FnOnce(ClientConfig) -> ClientConfig,
+    F: FnOnce(&mut [u8], u32) -> Result<()>,
 {
-    // 1. crate new binary
-    let new_exe = in_path.with_extension("tmp");
+    // 1. crate new binary, next to output and unique to this process, as others may generate too
+    let mut new_exe = out_path.as_os_str().to_owned();
+    new_exe.push(format!(".{}.tmp", std::process::id()));
+    let new_exe = PathBuf::from(new_exe);
     fs::copy(in_path, &new_exe)?;
     let file = OpenOptions::new().read(true).write(true).open(&new_exe)?;
     let mut buf = unsafe { MmapOptions::new().map_mut(&file) }?;
-    let file = File::parse(&*buf)?;
-
-    // 2. save config to new binary
-    if let Some(range) = get_client_config_section(&file) {
-        log::debug!("Copying config to client");
-        assert_eq!(range.1, CONF_BUF_LEN as u64);
-        let base = range.0 as usize;
-
-        let old_conf = ClientConfig::from_slice(&buf[base..(base + CONF_BUF_LEN)])?;
-        let new_conf = mod_conf(old_conf);
-
-        let conf_buf = serialize_conf_to_buf(&new_conf)?;
-        buf[base..(base + CONF_BUF_LEN)].copy_from_slice(&conf_buf);
-
-        let perms = fs::metadata(in_path)?.permissions();
-        fs::set_permissions(&new_exe, perms)?;
-        fs::rename(&new_exe, out_path)?;
-    } else {
+    let res = File::parse(&*buf)
+        .map_err(Error::from)
+        .and_then(|file| Ok((config_range(&file, in_path)?, config_schema(&file, &buf))))
+        .and_then(|(base, schema)| {
+            // 2. save config to new binary
+            log::debug!("Copying config to client");
+            mod_buf(&mut buf[base..(base + CONF_BUF_LEN)], schema)
+        });
+    // signing rewrites binary, which must not be mapped meanwhile
+    drop(buf);
+    drop(file);
+    let res = res.and_then(|_| sign_binary(&new_exe));
+    if let Err(e) = res {
         fs::remove_file(&new_exe)?;
+        return Err(e);
     }
+    let perms = fs::metadata(in_path)?.permissions();
+    fs::set_permissions(&new_exe, perms)?;
+    fs::rename(&new_exe, out_path)?;
     Ok(())
 }
 
+/// generate a new client binary using a callback function that modifies config
+pub fn gen_client_binary<F>(in_path: &Path, out_path: &Path, mod_conf: F) -> Result<()>
+where
+    F: FnOnce(ClientConfig) -> ClientConfig,
+{
+    // config is written in layout of input binary, which may be older
+    gen_client_binary_with(in_path, out_path, |buf, schema| {
+        let new_conf = mod_conf(ClientConfig::from_slice_of(buf, schema)?);
+        buf.copy_from_slice(&serialize_conf_to_buf(&new_conf, schema)?);
+        Ok(())
+    })
+}
+
 /// copy existing client with a new keypair
 pub fn modify_client_keypair<P: AsRef<Path>>(
     in_path: P,
@@ -99,24 +279,74 @@ pub fn modify_client_keypair<P: AsRef<Path>>(
     Ok(())
 }
 
-/// read config from a existing client
-fn read_client_conf<P: AsRef<Path>>(path: P) -> Result<ClientConfig> {
-    let file = OpenOptions::new().read(true).write(true).open(&path)?;
+/// embedded config buffer and its schema of a binary, schema is 0 in binaries older than schema 1
+fn read_client_conf_buf(path: &Path) -> Result<(Vec<u8>, u32)> {
+    let file = OpenOptions::new().read(true).open(path)?;
     let buf = unsafe { MmapOptions::new().map(&file) }?;
     let file = File::parse(&*buf)?;
-    if let Some(range) = get_client_config_section(&file) {
-        assert_eq!(range.1, CONF_BUF_LEN as u64);
-        let base = range.0 as usize;
-        let conf = ClientConfig::from_slice(&buf[base..(base + CONF_BUF_LEN)])?;
-        Ok(conf)
-    } else {
-        Err(anyhow!("config not found"))
-    }
+    let base = config_range(&file, path)?;
+    let schema = config_schema(&file, &buf);
+    Ok((buf[base..(base + CONF_BUF_LEN)].to_vec(), schema))
 }
 
-/// clone a client from existing one (analogy to Dolly the sheep)
-pub fn clone_client<P: AsRef<Path>>(dna_path: P, egg_path: P, out_path: P) -> Result<()> {
-    let dna = crate::gen::read_client_conf(&dna_path)?;
-    crate::gen::gen_client_binary(egg_path.as_ref(), out_path.as_ref(), |_| dna)?;
-    Ok(())
+/// read config from a existing client
+pub(crate) fn read_client_conf<P: AsRef<Path>>(path: P) -> Result<ClientConfig> {
+    let (buf, schema) = read_client_conf_buf(path.as_ref())?;
+    ClientConfig::from_slice_of(&buf, schema)
+}
+
+/// clone a client from existing one (analogy to Dolly the sheep),
+/// warn if config of dna is not of server with public key `server_pubkey`
+pub fn clone_client<P: AsRef<Path>>(
+    dna_path: P,
+    egg_path: P,
+    out_path: P,
+    server_pubkey: Option<&[u8]>,
+) -> Result<()> {
+    let (dna_path, egg_path) = (dna_path.as_ref(), egg_path.as_ref());
+    let (dna_buf, dna_schema) = read_client_conf_buf(dna_path)?;
+    let (_, egg_schema) = read_client_conf_buf(egg_path)?;
+    // fields are only appended, so an egg of older schema would ignore some of them
+    if egg_schema < dna_schema {
+        Err(Error::Gen(format!(
+            "config schema of dna is {dna_schema}, but egg only reads schema {egg_schema}, use an egg of newer version"
+        )))?
+    }
+    if dna_schema > CONF_SCHEMA {
+        log::warn!(
+            "Config schema of dna is {}, newer than {} of this binary, it is copied as is",
+            dna_schema,
+            CONF_SCHEMA
+        );
+    }
+    let dna = ClientConfig::from_slice_of(&dna_buf, dna_schema)
+        .map_err(|e| Error::Gen(format!("config of dna is broken: {e}")))?;
+    if let Some(pubkey) = server_pubkey {
+        if dna.server_pubkey != pubkey {
+            log::warn!(
+                "Server pubkey of dna {} differs from the one in config, it will not connect to this server",
+                base64::encode(&dna.server_pubkey)
+            );
+        }
+    }
+    // config of the same schema is copied as bytes, so fields unknown to this binary are kept,
+    // others are converted to layout of egg
+    let conf_buf = match dna_schema == egg_schema || dna_schema > CONF_SCHEMA {
+        true => dna_buf,
+        false => serialize_conf_to_buf(&dna, egg_schema)?.to_vec(),
+    };
+    gen_client_binary_with(egg_path, out_path.as_ref(), |buf, _| {
+        buf.copy_from_slice(&conf_buf);
+        Ok(())
+    })?;
+    // read config back, so that a broken clone is not left behind
+    match read_client_conf_buf(out_path.as_ref()) {
+        Ok((buf, _)) if buf == conf_buf => Ok(()),
+        _ => {
+            fs::remove_file(out_path.as_ref())?;
+            Err(Error::Gen(String::from(
+                "config cannot be read back from cloned client",
+            )))
+        }
+    }
 }
@@ -0,0 +1,358 @@
+/// authoritative dns responder of a gateway zone, publishing names of online reverse proxy services,
+/// so visitors reach a service by a name instead of server address and service id
+///
+/// records of a service registered by client `<name>`:
+/// `service-<name>.<zone>` A/AAAA of server, or CNAME of `host` if it is not an ip,
+/// `_portguard._tcp.service-<name>.<zone>` SRV of server port
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use crate::bind;
+use crate::mdns::{read_name, write_name, write_record};
+use crate::server::Server;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_REFUSED: u16 = 5;
+/// max length of a dns packet over udp
+const PACKET_LEN: usize = 512;
+
+/// dns responder of gateway zone
+/// in config:
+/// [gateway_dns]
+/// listen = "0.0.0.0:53"     # udp address of responder, zone is delegated to it
+/// zone = "gw.example.com"
+/// address = "203.0.113.1"   # address of records, `host` of server if not set
+/// ttl = 30                  # seconds records are cached, short as services go offline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GatewayDnsConfig {
+    pub(crate) listen: SocketAddr,
+    zone: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    address: Option<IpAddr>,
+    #[serde(default = "default_ttl", skip_serializing_if = "is_default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    30
+}
+
+fn is_default_ttl(ttl: &u32) -> bool {
+    *ttl == default_ttl()
+}
+
+/// dns label of a service registered by client `name`
+fn service_label(name: &str) -> String {
+    let name: String = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '-',
+        })
+        .collect();
+    format!("service-{}", name.trim_matches('-'))
+}
+
+/// a query of a single question
+struct Query<'a> {
+    packet: &'a [u8],
+    name: String,
+    qtype: u16,
+    /// end of question in packet
+    end: usize,
+}
+
+impl<'a> Query<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let u16_at = |pos: usize| {
+            Some(u16::from_be_bytes([
+                *packet.get(pos)?,
+                *packet.get(pos + 1)?,
+            ]))
+        };
+        // a standard query, not a response
+        if u16_at(2)? & 0xf800 != 0 || u16_at(4)? != 1 {
+            return None;
+        }
+        let (name, next) = read_name(packet, 12)?;
+        // qtype and qclass are copied into reply
+        if packet.len() < next + 4 {
+            return None;
+        }
+        Some(Query {
+            packet,
+            name: name.to_ascii_lowercase(),
+            qtype: u16_at(next)?,
+            end: next + 4,
+        })
+    }
+    /// reply with answers, written by `write_record`
+    fn reply(&self, rcode: u16, answers: Vec<Vec<u8>>) -> Vec<u8> {
+        // recursion desired is copied, recursion is not available
+        let rd = u16::from_be_bytes([self.packet[2], self.packet[3]]) & 0x0100;
+        let flags = 0x8400 | rd | rcode;
+        let mut buf = self.packet[..2].to_vec();
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(&self.packet[12..self.end]);
+        for answer in answers {
+            buf.extend(answer);
+        }
+        buf
+    }
+}
+
+struct Zone {
+    config: GatewayDnsConfig,
+    /// `host` of server, target of CNAME records if `address` is not set
+    host: String,
+    port: u16,
+}
+
+impl Zone {
+    /// answer `query`, `online` are names of clients with online services
+    fn answer(&self, query: &Query, online: &[String]) -> Vec<u8> {
+        let zone = self.config.zone.trim_matches('.').to_ascii_lowercase();
+        let name = query.name.as_str();
+        let rest = match name.strip_suffix(zone.as_str()) {
+            Some("") => return query.reply(0, Vec::new()),
+            Some(rest) if rest.ends_with('.') => rest.trim_end_matches('.'),
+            _ => return query.reply(RCODE_REFUSED, Vec::new()),
+        };
+        let (srv, label) = match rest.strip_prefix("_portguard._tcp.") {
+            Some(label) => (true, label),
+            None => (false, rest),
+        };
+        if !online.iter().any(|name| service_label(name) == label) {
+            return query.reply(RCODE_NXDOMAIN, Vec::new());
+        }
+        let host = format!("{label}.{zone}");
+        let ttl = self.config.ttl;
+        let wants = |t: u16| query.qtype == t || query.qtype == TYPE_ANY;
+        let mut answers = Vec::new();
+        let mut record = |rtype: u16, data: &[u8]| {
+            let mut buf = Vec::new();
+            write_record(&mut buf, name, rtype, CLASS_IN, ttl, data);
+            answers.push(buf);
+        };
+        if srv {
+            if wants(TYPE_SRV) {
+                // priority, weight, port, target
+                let mut data = vec![0, 0, 0, 0];
+                data.extend_from_slice(&self.port.to_be_bytes());
+                write_name(&mut data, &host);
+                record(TYPE_SRV, &data);
+            }
+            return query.reply(0, answers);
+        }
+        match self.config.address.or_else(|| self.host.parse().ok()) {
+            Some(IpAddr::V4(ip)) if wants(TYPE_A) => record(TYPE_A, &ip.octets()),
+            Some(IpAddr::V6(ip)) if wants(TYPE_AAAA) => record(TYPE_AAAA, &ip.octets()),
+            Some(_) => {}
+            None => {
+                let mut data = Vec::new();
+                write_name(&mut data, &self.host);
+                record(TYPE_CNAME, &data);
+            }
+        }
+        query.reply(0, answers)
+    }
+}
+
+/// answer queries of gateway zone, `host` and `port` are public address of server
+pub(crate) async fn serve(
+    config: GatewayDnsConfig,
+    host: String,
+    port: u16,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(config.listen)
+        .await
+        .map_err(|e| bind::explain(config.listen, e))?;
+    log::info!(
+        "Gateway dns of zone {} listening on: {:?}",
+        config.zone,
+        config.listen
+    );
+    let zone = Zone { config, host, port };
+    let mut buf = vec![0; PACKET_LEN];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                // e.g. icmp port unreachable of a previous reply on windows
+                log::debug!("Gateway dns receive error: {}", e);
+                continue;
+            }
+        };
+        let query = match Query::parse(&buf[..n]) {
+            Some(query) => query,
+            None => continue,
+        };
+        log::debug!(
+            "Gateway dns query {} type {} from {}",
+            query.name,
+            query.qtype,
+            peer
+        );
+        let online: Vec<String> = server
+            .online_services()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        let reply = zone.answer(&query, &online);
+        if let Err(e) = socket.send_to(&reply, peer).await {
+            log::debug!("Gateway dns reply error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// query of `name` and `qtype`, recursion desired
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut packet, name);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    fn zone(address: Option<&str>, host: &str) -> Zone {
+        Zone {
+            config: GatewayDnsConfig {
+                listen: "127.0.0.1:53".parse().unwrap(),
+                zone: "GW.example.com.".to_string(),
+                address: address.map(|a| a.parse().unwrap()),
+                ttl: 30,
+            },
+            host: host.to_string(),
+            port: 8022,
+        }
+    }
+
+    /// rcode and records of a reply, as (type, data)
+    fn records(reply: &[u8]) -> (u16, Vec<(u16, Vec<u8>)>) {
+        let rcode = u16::from_be_bytes([reply[2], reply[3]]) & 0x000f;
+        let count = u16::from_be_bytes([reply[6], reply[7]]);
+        let (_, mut pos) = read_name(reply, 12).unwrap();
+        pos += 4;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let (_, next) = read_name(reply, pos).unwrap();
+            let rtype = u16::from_be_bytes([reply[next], reply[next + 1]]);
+            let len = u16::from_be_bytes([reply[next + 8], reply[next + 9]]) as usize;
+            records.push((rtype, reply[next + 10..next + 10 + len].to_vec()));
+            pos = next + 10 + len;
+        }
+        assert_eq!(pos, reply.len());
+        (rcode, records)
+    }
+
+    #[test]
+    fn queries_are_parsed() {
+        let packet = query("Service-Web.gw.example.com", TYPE_AAAA);
+        let parsed = Query::parse(&packet).unwrap();
+        assert_eq!(parsed.name, "service-web.gw.example.com");
+        assert_eq!(parsed.qtype, TYPE_AAAA);
+        assert_eq!(parsed.end, packet.len());
+        // name compressed by a pointer to a name in the packet
+        let mut packet = query("gw.example.com", TYPE_A);
+        packet.truncate(12);
+        packet.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, 22, 0, 1, 0, 1]);
+        packet.extend_from_slice(&[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0]);
+        let parsed = Query::parse(&packet).unwrap();
+        assert_eq!(parsed.name, "www.example");
+        assert_eq!(parsed.end, 22);
+    }
+
+    #[test]
+    fn malformed_queries_are_ignored() {
+        let packet = query("gw.example.com", TYPE_A);
+        // truncated anywhere, including a question without qclass
+        for len in 0..packet.len() {
+            assert!(Query::parse(&packet[..len]).is_none(), "{len}");
+        }
+        // root name, qtype and no qclass
+        let mut root = packet[..12].to_vec();
+        root.extend_from_slice(&[0, 0, 1]);
+        assert!(Query::parse(&root).is_none());
+        // responses and queries of several questions
+        let mut response = packet.clone();
+        response[2] |= 0x80;
+        assert!(Query::parse(&response).is_none());
+        let mut questions = packet.clone();
+        questions[5] = 2;
+        assert!(Query::parse(&questions).is_none());
+        // loop of compression pointers
+        let mut looped = packet[..12].to_vec();
+        looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(Query::parse(&looped).is_none());
+    }
+
+    #[test]
+    fn records_of_online_services() {
+        let online = vec!["Web".to_string()];
+        let zone = zone(Some("203.0.113.1"), "gw.example.com");
+        let answer = |name: &str, qtype: u16| {
+            let packet = query(name, qtype);
+            let reply = zone.answer(&Query::parse(&packet).unwrap(), &online);
+            // id and recursion desired are kept, question is echoed
+            assert_eq!(reply[..2], packet[..2]);
+            assert_eq!(reply[2] & 0x81, 0x81);
+            assert_eq!(reply[12..packet.len()], packet[12..]);
+            records(&reply)
+        };
+        let a = (TYPE_A, vec![203, 0, 113, 1]);
+        assert_eq!(
+            answer("service-web.gw.example.com", TYPE_A),
+            (0, vec![a.clone()])
+        );
+        assert_eq!(answer("SERVICE-WEB.GW.EXAMPLE.COM", TYPE_ANY), (0, vec![a]));
+        assert_eq!(answer("service-web.gw.example.com", TYPE_AAAA), (0, vec![]));
+        let mut srv = vec![0, 0, 0, 0, 0x1f, 0x56];
+        write_name(&mut srv, "service-web.gw.example.com");
+        assert_eq!(
+            answer("_portguard._tcp.service-web.gw.example.com", TYPE_SRV),
+            (0, vec![(TYPE_SRV, srv)])
+        );
+        assert_eq!(answer("gw.example.com", TYPE_A), (0, vec![]));
+        let nxdomain = (RCODE_NXDOMAIN, vec![]);
+        assert_eq!(answer("service-db.gw.example.com", TYPE_A), nxdomain);
+        let refused = (RCODE_REFUSED, vec![]);
+        assert_eq!(answer("service-web.example.com", TYPE_A), refused);
+        assert_eq!(answer("service-web.evilgw.example.com", TYPE_A), refused);
+    }
+
+    #[test]
+    fn records_point_to_host_without_address() {
+        let online = vec!["web".to_string()];
+        let answer = |zone: &Zone, qtype: u16| {
+            let packet = query("service-web.gw.example.com", qtype);
+            records(&zone.answer(&Query::parse(&packet).unwrap(), &online))
+        };
+        let mut cname = Vec::new();
+        write_name(&mut cname, "server.example.com");
+        let by_name = zone(None, "server.example.com");
+        assert_eq!(answer(&by_name, TYPE_A), (0, vec![(TYPE_CNAME, cname)]));
+        let by_ip = zone(None, "2001:db8::1");
+        let ip: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let aaaa = (TYPE_AAAA, ip.octets().to_vec());
+        assert_eq!(answer(&by_ip, TYPE_AAAA), (0, vec![aaaa]));
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bind;
+use crate::resources::Resources;
+use crate::server::Server;
+
+/// max length of http request header
+const HTTP_HEADER_LEN: usize = 4096;
+
+/// state reported by health probes
+#[derive(Debug, Default)]
+pub(crate) struct HealthState {
+    /// main listener is accepting connections
+    pub(crate) listening: AtomicBool,
+    /// number of clients in loaded config
+    pub(crate) clients: AtomicUsize,
+    /// connections and file descriptors in use
+    pub(crate) resources: Arc<Resources>,
+}
+
+impl HealthState {
+    fn report(&self) -> String {
+        let listener = match self.listening.load(Ordering::Relaxed) {
+            true => "up",
+            false => "down",
+        };
+        let clients = self.clients.load(Ordering::Relaxed);
+        format!("listener: {listener}\nconfig: loaded ({clients} clients)\n")
+    }
+}
+
+/// serve http health probes
+/// `GET /healthz` returns 200 while server process is alive, with status report
+/// `GET /readyz` returns 200 only when main listener is accepting connections
+/// `GET /metrics` returns resource usage, usage of each client and last seen version of clients
+/// with telemetry in prometheus text format
+/// `GET /metrics?tenant=<name>` returns usage of a tenant, with its admin token as bearer token
+/// `/admin/...` is admin api, with a token of `[[admin_tokens]]` as bearer token
+pub(crate) async fn serve_health(
+    addr: SocketAddr,
+    state: Arc<HealthState>,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| bind::explain(addr, e))?;
+    log::info!("Health check listening on: {:?}", addr);
+    let tasks = server.tasks();
+    loop {
+        let (stream, _) = bind::accept(|| listener.accept()).await;
+        let state = state.clone();
+        let server = server.clone();
+        tasks.spawn(async move {
+            if let Err(e) = handle_probe(stream, &state, &server).await {
+                log::debug!("Health probe error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_probe(
+    mut stream: TcpStream,
+    state: &HealthState,
+    server: &Server,
+) -> io::Result<()> {
+    let (method, path, header) = read_request(&mut stream).await?;
+    let tenant = path.strip_prefix("/metrics?tenant=");
+    let (status, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/healthz") => ("200 OK", state.report()),
+        ("GET", "/readyz") if state.listening.load(Ordering::Relaxed) => ("200 OK", state.report()),
+        ("GET", "/readyz") => ("503 Service Unavailable", state.report()),
+        ("GET", "/metrics") => (
+            "200 OK",
+            state.resources.metrics() + &server.client_metrics(),
+        ),
+        ("GET", _) if tenant.is_some() => {
+            match server.tenant_metrics(tenant.unwrap_or_default(), bearer_token(&header)) {
+                Some(metrics) => ("200 OK", metrics),
+                None => ("403 Forbidden", String::from("forbidden\n")),
+            }
+        }
+        _ if path.starts_with("/admin/") => {
+            crate::admin::handle(
+                &method,
+                &path,
+                bearer_token(&header),
+                server,
+                state.report(),
+            )
+            .await
+        }
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    write_response(&mut stream, status, &body).await
+}
+
+/// read http request header and return method, path and whole header
+async fn read_request(stream: &mut TcpStream) -> io::Result<(String, String, String)> {
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= HTTP_HEADER_LEN {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "header too long",
+            ))?
+        }
+        buf.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&buf).into_owned();
+    let mut parts = header.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    Ok((method, path, header))
+}
+
+/// token of `Authorization: Bearer <token>` header
+fn bearer_token(header: &str) -> Option<&str> {
+    header.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("authorization")
+            .then(|| value.trim().strip_prefix("Bearer "))?
+    })
+}
+
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &str,
+) -> io::Result<()> {
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
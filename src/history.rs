@@ -0,0 +1,70 @@
+/// local rolling history of connections made through client
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// size of history file before it is rotated to `<path>.1`
+const HISTORY_MAX_LEN: u64 = 1024 * 1024;
+
+/// default location of history file, in home directory
+pub(crate) fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".portguard_history"))
+}
+
+/// history file, one line per finished connection
+#[derive(Debug)]
+pub(crate) struct History {
+    path: PathBuf,
+    /// target of client, recorded in every line
+    target: String,
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub(crate) fn new(path: PathBuf, target: String) -> Self {
+        History {
+            path,
+            target,
+            lock: Mutex::new(()),
+        }
+    }
+    /// record a finished connection
+    pub(crate) fn record(
+        &self,
+        started: SystemTime,
+        peer: &str,
+        secs: u64,
+        sent: u64,
+        received: u64,
+    ) {
+        let line = format!(
+            "{} {} -> {}, up {}s, sent {} bytes, received {} bytes\n",
+            humantime::format_rfc3339_seconds(started),
+            peer,
+            self.target,
+            secs,
+            sent,
+            received
+        );
+        if let Err(e) = self.append(&line) {
+            log::warn!("Failed to write history {:?}. Error: {}", self.path, e);
+        }
+    }
+    fn append(&self, line: &str) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() >= HISTORY_MAX_LEN) {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+}
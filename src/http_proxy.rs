@@ -0,0 +1,284 @@
+/// local http proxy inbound of socks5 client, so apps configured with an http proxy,
+/// e.g. browsers and mobile apps, use tunnel without a socks5 capable client
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use fast_socks5::util::target_addr::TargetAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Result;
+
+/// max length of http request header
+const HEADER_LEN: usize = 16 * 1024;
+/// headers meant for proxy, not forwarded to target
+const PROXY_HEADERS: [&str; 4] = [
+    "Proxy-Connection",
+    "Proxy-Authorization",
+    "Keep-Alive",
+    "Connection",
+];
+
+pub(crate) const BAD_REQUEST: &str = "400 Bad Request";
+pub(crate) const BAD_GATEWAY: &str = "502 Bad Gateway";
+
+/// request of http proxy client
+#[derive(Debug)]
+pub(crate) struct ProxyRequest {
+    pub(crate) target: TargetAddr,
+    /// rewritten request forwarded to target, empty for `CONNECT`
+    forward: Vec<u8>,
+}
+
+/// host and port of `authority`, e.g. "example.com:443" or "[::1]:80",
+/// `default_port` is used if there is none
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<TargetAddr> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port?),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Some(TargetAddr::Ip(SocketAddr::new(ip, port))),
+        Err(_) if !host.is_empty() && !host.contains(['[', ']', ':', '/']) => {
+            Some(TargetAddr::Domain(host.to_string(), port))
+        }
+        Err(_) => None,
+    }
+}
+
+impl ProxyRequest {
+    /// parse `CONNECT host:port` or a request with absolute url, e.g. `GET http://host/path`,
+    /// which is rewritten to be sent to host directly, one request per connection
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(buf).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let (method, uri, version) = (parts.next()?, parts.next()?, parts.next()?);
+        if method.eq_ignore_ascii_case("CONNECT") {
+            return Some(ProxyRequest {
+                target: parse_authority(uri, None)?,
+                forward: Vec::new(),
+            });
+        }
+        let rest = uri
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &uri[7..])?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let path = match path.starts_with('?') {
+            true => format!("/{path}"),
+            false => path.to_string(),
+        };
+        let mut forward = format!("{method} {path} {version}\r\n");
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, _) = line.split_once(':')?;
+            if !PROXY_HEADERS
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name.trim()))
+            {
+                forward += line;
+                forward += "\r\n";
+            }
+        }
+        forward += "Connection: close\r\n\r\n";
+        Some(ProxyRequest {
+            target: parse_authority(authority, Some(80))?,
+            forward: forward.into_bytes(),
+        })
+    }
+}
+
+/// read request of http proxy client, answering bad requests
+pub(crate) async fn read_request<S>(inbound: &mut S) -> Result<ProxyRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= HEADER_LEN {
+            reply(inbound, BAD_REQUEST).await?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "http proxy request header too long",
+            ))?
+        }
+        buf.push(inbound.read_u8().await?);
+    }
+    match ProxyRequest::parse(&buf) {
+        Some(request) => Ok(request),
+        None => {
+            reply(inbound, BAD_REQUEST).await?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid http proxy request, only CONNECT and http:// urls are supported",
+            ))?
+        }
+    }
+}
+
+/// answer proxy client with `status` and close
+pub(crate) async fn reply<S>(inbound: &mut S, status: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    inbound.write_all(response.as_bytes()).await?;
+    inbound.flush().await
+}
+
+/// finish `request` with connected `outbound`, answering `CONNECT` or forwarding request,
+/// then transfer data
+pub(crate) async fn serve<S, T>(
+    mut inbound: S,
+    outbound: Result<T>,
+    request: ProxyRequest,
+) -> Result<Option<(u64, u64)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut outbound = match outbound {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            reply(&mut inbound, BAD_GATEWAY).await?;
+            return Err(e);
+        }
+    };
+    match request.forward.is_empty() {
+        true => {
+            inbound
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?
+        }
+        false => outbound.write_all(&request.forward).await?,
+    }
+    Ok(crate::proxy::transfer_and_log_error(inbound, outbound).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Option<(String, String)> {
+        let request = ProxyRequest::parse(head.as_bytes())?;
+        let forward = String::from_utf8(request.forward).unwrap();
+        Some((request.target.to_string(), forward))
+    }
+
+    #[test]
+    fn authorities_are_parsed() {
+        let cases = [
+            ("example.com:443", None, Some("example.com:443")),
+            ("example.com", Some(80), Some("example.com:80")),
+            ("10.0.0.1:8080", None, Some("10.0.0.1:8080")),
+            ("[::1]:443", None, Some("[::1]:443")),
+            ("[fd00::1]", Some(80), Some("[fd00::1]:80")),
+            // no port and no default, e.g. `CONNECT`
+            ("example.com", None, None),
+            ("[::1]", None, None),
+            // ipv6 must be bracketed
+            ("::1", Some(80), None),
+            ("example.com:", Some(80), None),
+            ("example.com:http", None, None),
+            ("example.com:65536", None, None),
+            (":443", None, None),
+            ("example.com/path:80", None, None),
+        ];
+        for (authority, default_port, target) in cases {
+            let parsed = parse_authority(authority, default_port).map(|t| t.to_string());
+            assert_eq!(parsed.as_deref(), target, "{authority}");
+        }
+    }
+
+    #[test]
+    fn requests_are_rewritten_for_target() {
+        let cases = [
+            (
+                "GET http://example.com/index.html HTTP/1.1",
+                "example.com:80",
+                "GET /index.html HTTP/1.1",
+            ),
+            (
+                "GET http://example.com:8080/a?b=c HTTP/1.1",
+                "example.com:8080",
+                "GET /a?b=c HTTP/1.1",
+            ),
+            // query only and empty paths
+            (
+                "GET http://example.com?b=c HTTP/1.1",
+                "example.com:80",
+                "GET /?b=c HTTP/1.1",
+            ),
+            (
+                "HEAD HTTP://example.com HTTP/1.0",
+                "example.com:80",
+                "HEAD / HTTP/1.0",
+            ),
+            (
+                "POST http://[::1]:8080/api HTTP/1.1",
+                "[::1]:8080",
+                "POST /api HTTP/1.1",
+            ),
+        ];
+        for (line, target, forwarded) in cases {
+            let parsed = request(&format!("{line}\r\nHost: example.com\r\n\r\n"));
+            let (parsed_target, forward) = parsed.unwrap_or_else(|| panic!("{line}"));
+            assert_eq!(parsed_target, target, "{line}");
+            assert_eq!(
+                forward,
+                format!("{forwarded}\r\nHost: example.com\r\nConnection: close\r\n\r\n"),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn connect_requests_are_not_forwarded() {
+        let cases = [
+            ("CONNECT example.com:443 HTTP/1.1", Some("example.com:443")),
+            ("connect [::1]:22 HTTP/1.1", Some("[::1]:22")),
+            ("CONNECT example.com HTTP/1.1", None),
+        ];
+        for (line, target) in cases {
+            let parsed = request(&format!("{line}\r\nHost: example.com\r\n\r\n"));
+            assert_eq!(parsed.as_ref().map(|(t, _)| t.as_str()), target, "{line}");
+            assert!(
+                parsed.is_none_or(|(_, forward)| forward.is_empty()),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn proxy_headers_are_not_forwarded() {
+        let (_, forward) = request(
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+             proxy-connection: keep-alive\r\nProxy-Authorization: Basic dTpw\r\n\
+             Keep-Alive: timeout=5\r\nConnection: keep-alive\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            forward,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let invalid = [
+            // origin form, not meant for a proxy
+            "GET /index.html HTTP/1.1\r\n\r\n",
+            "GET https://example.com/ HTTP/1.1\r\n\r\n",
+            "GET http:// HTTP/1.1\r\n\r\n",
+            "GET http://example.com/\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1\r\nno colon\r\n\r\n",
+            "",
+        ];
+        for head in invalid {
+            assert!(request(head).is_none(), "{head:?}");
+        }
+    }
+}
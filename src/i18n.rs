@@ -0,0 +1,126 @@
+/// language of messages shown to users of generated clients, chosen by `gen-cli --lang`,
+/// so users who are not english speakers understand prompts and connection state,
+/// logs are always in english for whoever debugs them
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// language of client messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    /// simplified chinese
+    Zh,
+    Es,
+    Ru,
+}
+
+impl FromStr for Lang {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Lang::En),
+            "zh" => Ok(Lang::Zh),
+            "es" => Ok(Lang::Es),
+            "ru" => Ok(Lang::Ru),
+            _ => Err(format!("unknown language {s}, one of en, zh, es, ru")),
+        }
+    }
+}
+
+impl fmt::Display for Lang {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            Lang::En => "en",
+            Lang::Zh => "zh",
+            Lang::Es => "es",
+            Lang::Ru => "ru",
+        };
+        write!(f, "{code}")
+    }
+}
+
+/// message shown to users
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Msg {
+    /// asked on terminal
+    PassphrasePrompt,
+    /// description of pinentry dialog
+    PassphraseDesc,
+    /// prompt of pinentry dialog
+    PassphraseLabel,
+    WrongPassphrase,
+    Ready,
+    Connected,
+    Reconnecting,
+    Rejected,
+    ServerUnreachable,
+    TargetUnreachable,
+    Connections,
+    Errors,
+}
+
+impl Lang {
+    /// text of `msg` in this language
+    pub(crate) fn text(self, msg: Msg) -> &'static str {
+        // in order of en, zh, es, ru
+        let texts = match msg {
+            Msg::PassphrasePrompt => [
+                "Input Key Passphrase: ",
+                "请输入密钥口令: ",
+                "Introduzca la frase de contraseña de la clave: ",
+                "Введите пароль ключа: ",
+            ],
+            Msg::PassphraseDesc => [
+                "Passphrase of portguard client key",
+                "portguard 客户端密钥的口令",
+                "Frase de contraseña de la clave del cliente portguard",
+                "Пароль ключа клиента portguard",
+            ],
+            Msg::PassphraseLabel => ["Passphrase:", "口令:", "Contraseña:", "Пароль:"],
+            Msg::WrongPassphrase => [
+                "Wrong passphrase, try again",
+                "口令错误，请重试",
+                "Frase de contraseña incorrecta, inténtelo de nuevo",
+                "Неверный пароль, попробуйте ещё раз",
+            ],
+            Msg::Ready => ["Ready", "就绪", "Listo", "Готов"],
+            Msg::Connected => [
+                "Connected to server",
+                "已连接到服务器",
+                "Conectado al servidor",
+                "Подключено к серверу",
+            ],
+            Msg::Reconnecting => [
+                "Connection lost, reconnecting",
+                "连接已断开，正在重连",
+                "Conexión perdida, reconectando",
+                "Соединение потеряно, переподключение",
+            ],
+            Msg::Rejected => [
+                "Rejected by server",
+                "被服务器拒绝",
+                "Rechazado por el servidor",
+                "Отклонено сервером",
+            ],
+            Msg::ServerUnreachable => [
+                "Server unreachable",
+                "无法连接服务器",
+                "Servidor inaccesible",
+                "Сервер недоступен",
+            ],
+            Msg::TargetUnreachable => [
+                "Target unreachable",
+                "无法连接目标",
+                "Destino inaccesible",
+                "Цель недоступна",
+            ],
+            Msg::Connections => ["connections", "连接", "conexiones", "соединения"],
+            Msg::Errors => ["errors", "错误", "errores", "ошибки"],
+        };
+        texts[self as usize]
+    }
+}
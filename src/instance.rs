@@ -0,0 +1,76 @@
+/// single instance guard of client, by an exclusive lock of a file in temp dir
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+
+/// lock held by running instance, released when dropped or process exits
+pub(crate) struct InstanceLock {
+    file: File,
+}
+
+fn lock_path(id: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("portguard-{id}.lock"))
+}
+
+impl InstanceLock {
+    /// acquire lock of client `id`,
+    /// if it is held by another instance, return what the instance recorded
+    #[cfg(unix)]
+    pub(crate) fn acquire(id: &str) -> io::Result<Result<Self, String>> {
+        use std::os::unix::io::AsRawFd;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(id))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+            let mut info = String::new();
+            file.read_to_string(&mut info)?;
+            return Ok(Err(info.trim().to_string()));
+        }
+        Ok(Ok(InstanceLock { file }))
+    }
+    /// there is no guard on other platforms
+    #[cfg(not(unix))]
+    pub(crate) fn acquire(_id: &str) -> io::Result<Result<Self, String>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "single instance guard is only supported on unix",
+        ))
+    }
+    /// remove lock files of clients that are not running, return removed files
+    #[cfg(unix)]
+    pub(crate) fn remove_stale(dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        for entry in std::fs::read_dir(std::env::temp_dir())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            let id = match name
+                .strip_prefix("portguard-")
+                .and_then(|n| n.strip_suffix(".lock"))
+            {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            // a lock held by running client is kept
+            if let Ok(Ok(_lock)) = Self::acquire(&id) {
+                if !dry_run {
+                    std::fs::remove_file(lock_path(&id))?;
+                }
+                removed.push(lock_path(&id));
+            }
+        }
+        Ok(removed)
+    }
+    /// record information of this instance, shown to later ones
+    pub(crate) fn record(&self, info: &str) -> io::Result<()> {
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(info.as_bytes())
+    }
+}
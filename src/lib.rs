@@ -1,8 +1,89 @@
+mod acl;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod apply;
+pub mod args;
+mod bind;
+#[cfg(feature = "server")]
+mod cluster;
+mod console;
 mod consts;
+mod control;
+#[cfg(feature = "server")]
+mod diag;
+#[cfg(feature = "server")]
+mod dns;
+mod early;
+mod error;
+mod fair;
+#[cfg(feature = "server")]
+mod exec;
+mod files;
+mod fingerprint;
+#[cfg(feature = "server")]
+mod gwdns;
+mod history;
+mod i18n;
+mod http_proxy;
+mod instance;
+#[cfg(feature = "server")]
+mod health;
+mod mdns;
+mod measure;
+mod passphrase;
+#[cfg(feature = "server")]
+mod migrate;
+#[cfg(feature = "server")]
+mod pacing;
+mod path;
+mod pipeline;
 mod proxy;
+#[cfg(feature = "server")]
+mod qos;
+mod reissue;
+mod reload;
 mod remote;
+#[cfg(feature = "server")]
+mod resources;
+mod rules;
+#[cfg(feature = "server")]
+mod rotation;
+#[cfg(unix)]
+mod signal;
+mod sockopt;
+mod status;
+mod tasks;
+mod telemetry;
+#[cfg(feature = "server")]
+mod stats;
+#[cfg(feature = "server")]
+mod tenant;
+mod ticket;
+#[cfg(feature = "server")]
+mod upstream;
+mod watchdog;
+#[cfg(feature = "server")]
+mod web;
 
+#[cfg(feature = "gen")]
+pub mod audit;
+pub mod bench;
 pub mod client;
+#[cfg(feature = "server")]
+pub mod dialer;
+pub mod logger;
+pub mod service;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "server")]
+pub mod storage;
+#[cfg(feature = "gen")]
 pub mod gen;
-pub use remote::Remote;
+pub use acl::AllowedNet;
+pub use proxy::{Socks5Auth, Socks5Command, Socks5Options};
+pub use error::{exit, Error, Result};
+pub use i18n::Lang;
+pub use remote::{Remote, Target};
+pub use sockopt::SocketOpts;
+pub use tasks::Tasks;
@@ -0,0 +1,62 @@
+/// logger whose level can be changed at runtime
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use env_logger::filter::Filter;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// level set at runtime, overrides filters from command line if set
+static OVERRIDE: AtomicUsize = AtomicUsize::new(NO_OVERRIDE);
+const NO_OVERRIDE: usize = usize::MAX;
+
+struct DynLogger {
+    /// formatter, accepts all records
+    inner: env_logger::Logger,
+    /// filters from command line or `RUST_LOG`
+    filter: Filter,
+}
+
+impl Log for DynLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match OVERRIDE.load(Ordering::Relaxed) {
+            NO_OVERRIDE => self.filter.enabled(metadata),
+            level => metadata.level() as usize <= level,
+        }
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// init global logger with env_logger style filters, e.g. "info" or "portguard=debug"
+pub fn init(filters: &str) {
+    let filter = env_logger::filter::Builder::new().parse(filters).build();
+    let inner = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let max_level = filter.filter();
+    if log::set_boxed_logger(Box::new(DynLogger { inner, filter })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// change log level at runtime
+pub fn set_level(level: LevelFilter) {
+    OVERRIDE.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+    log::warn!("Log level changed to {}", level);
+}
+
+/// cycle log level: info -> debug -> trace -> info
+pub fn cycle_level() {
+    let next = match log::max_level() {
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    };
+    set_level(next);
+}
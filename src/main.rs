@@ -1,17 +1,22 @@
-use std::env;
 use anyhow::Result;
+use std::env;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
-use portguard::client::Client;
+use clap::{Parser, Subcommand};
+use portguard::audit;
+use portguard::client::{
+    Client, ClientArgs, ClientOptions, ConfigState, ReconnectPolicy, SplitRules,
+};
 use portguard::gen;
-use portguard::server::Server;
-use portguard::Remote;
+use portguard::server::{GenOptions, ProfilePreset, Server};
+use portguard::service::{self, InstallArgs, UninstallArgs};
+use portguard::{exit, Lang, Remote, Socks5Options};
 
 #[derive(Parser)]
 #[clap(author, version, about)]
 #[clap(args_conflicts_with_subcommands = true)]
-
 struct Cli {
     #[clap(subcommand)]
     command: Option<Commands>,
@@ -21,25 +26,45 @@ struct Cli {
     client: ClientArgs,
 }
 
-#[derive(Debug, Args)]
-struct ClientArgs {
-    /// local port to listen
-    #[clap(short, long, default_value_t = 8022)]
-    port: u16,
-    /// use another server address in this run
-    #[clap(short, long)]
-    server: Option<String>,
-}
-
 #[derive(Subcommand)]
 enum Commands {
     /// Run client
     Client(ClientArgs),
+    /// Install client as a service started at boot, with builtin config of this binary
+    InstallService(InstallArgs),
+    /// Uninstall client service, and remove local files of client
+    UninstallService(UninstallArgs),
+    /// Copy a file from or to server, remote path is prefixed with ':'
+    Cp {
+        /// source, e.g. "local.txt" or ":/srv/share/remote.txt"
+        src: String,
+        /// destination, e.g. ":/srv/share/" or "."
+        dst: String,
+        /// use another server address in this run
+        #[clap(short, long)]
+        server: Option<SocketAddr>,
+    },
+    /// Measure round trip time, jitter and throughput of tunnel to server
+    Measure {
+        /// use another server address in this run
+        #[clap(short, long)]
+        server: Option<SocketAddr>,
+    },
     /// Run server
     Server {
         /// location of config file
-        #[clap(short, long)]
-        config: PathBuf,
+        #[clap(short, long, required_unless_present = "config-from-env")]
+        config: Option<PathBuf>,
+        /// read whole config from env variable PORTGUARD_CONFIG, for containers
+        #[clap(long)]
+        config_from_env: bool,
+        /// validate config, keys and service ids, and try binding listeners, then exit
+        #[clap(long)]
+        check: bool,
+        /// serve a temporary client with a fresh key on loopback, handshake and proxy to
+        /// a local echo target through server, then exit
+        #[clap(long, conflicts_with = "check")]
+        self_test: bool,
     },
     /// Generate client binary
     GenCli {
@@ -49,33 +74,189 @@ enum Commands {
         /// location of input binary (current binary by default)
         #[clap(short, long)]
         input: Option<PathBuf>,
+        /// use bundled client template of a build profile as input, e.g. "small"
+        #[clap(long, conflicts_with = "input")]
+        profile: Option<String>,
         /// location of output binary
         #[clap(short, long)]
         output: PathBuf,
         /// name of client
         #[clap(short, long, default_value = "user")]
         name: String,
-        /// client's target address, can be socket address or "socks5"
+        /// client's target address, can be socket address, "hostname:port", "socks5" or "files"
         #[clap(short, long)]
         target: Option<String>,
         /// service id of a reverse proxy
         #[clap(short, long)]
         service: Option<usize>,
+        /// use remote of server, or of tenant, when neither target nor service is set
+        #[clap(long, conflicts_with_all = &["target", "service"])]
+        default_remote: bool,
         /// if key passphrase is needed to protect client key
         #[clap(short, long)]
         password: bool,
+        /// reconnect policy of reverse proxy client
+        #[clap(flatten)]
+        reconnect: ReconnectPolicy,
+        /// embed a profile selected by `--profile` of client, in form of "name:port:target",
+        /// can be repeated, e.g. "db:5432:10.0.0.5:5432"
+        #[clap(long = "preset", conflicts_with = "password")]
+        presets: Vec<ProfilePreset>,
+        /// generated client refuses to start if another instance of it is running
+        #[clap(long)]
+        single_instance: bool,
+        /// split tunneling rules of socks5 client
+        #[clap(flatten)]
+        split: SplitRules,
+        /// generated client resumes sessions with tickets when reconnecting,
+        /// needs a server of this version or later
+        #[clap(long)]
+        resume: bool,
+        /// generated client sends first bytes of connections with handshake,
+        /// only for clients proxying to a socket address, needs a server of this version or later
+        #[clap(long)]
+        early_data: bool,
+        /// tenant of client, defined in `[[tenants]]` of config
+        #[clap(long)]
+        tenant: Option<String>,
+        /// socks5 options of reverse proxy client exposing socks5
+        #[clap(flatten)]
+        socks5: Socks5Options,
+        /// target rule enforced by reverse proxy client exposing socks5, also for visitors
+        /// chaining through it, can be repeated, e.g. "10.0.0.0/8:5432", all targets by default
+        #[clap(long = "socks5-rule")]
+        socks5_rules: Vec<String>,
+        /// service id of a reverse proxy exposing socks5, through which `--target`
+        /// on network of its client is reached
+        #[clap(long, requires = "target", conflicts_with = "service")]
+        via: Option<usize>,
+        /// language of messages shown to users of generated client: en, zh, es or ru
+        #[clap(long)]
+        lang: Option<Lang>,
+        /// generated client sends its os and architecture with the version it reports in each
+        /// handshake, shown in `/metrics` of health check
+        #[clap(long)]
+        telemetry: bool,
+        /// register a client whose keypair is generated elsewhere, e.g. by `gen-keypair` of
+        /// its user, by its public key in base64, generated client has no private key and
+        /// is run with `--key-file`
+        #[clap(long, conflicts_with_all = &["password", "presets"])]
+        pubkey: Option<String>,
+    },
+    /// Reconcile clients in config with a desired state file, printing the plan first,
+    /// missing clients are generated, changed ones replaced and removed ones revoked
+    Apply {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// location of desired state file
+        #[clap(short = 'f', long)]
+        file: PathBuf,
+        /// location of input binary (current binary by default)
+        #[clap(short, long)]
+        input: Option<PathBuf>,
+        /// directory of generated binaries
+        #[clap(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        /// only print the plan, change nothing
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Rename a client in config, names of clients are unique
+    RenameCli {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// current name, base64 public key or key fingerprint of client
+        #[clap(short, long)]
+        from: String,
+        /// new name of client
+        #[clap(short, long)]
+        to: String,
+    },
+    /// Revoke a client and ban its key, rejected even if it is added again
+    RevokeCli {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// name, base64 public key or key fingerprint of client
+        #[clap(short, long)]
+        name: String,
+        /// reason recorded with the banned key, e.g. "laptop stolen"
+        #[clap(short, long)]
+        reason: Option<String>,
+    },
+    /// Verify a client binary against config: its hash, server key, client entry and remote,
+    /// before (re)distributing it
+    VerifyCli {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// location of client binary
+        #[clap(short, long)]
+        input: PathBuf,
+        /// expected client name, needed to find client whose key is protected by passphrase
+        #[clap(short, long)]
+        name: Option<String>,
+        /// print report in json
+        #[clap(long)]
+        json: bool,
+    },
+    /// Upgrade config file of older versions to current schema
+    MigrateConfig {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// only print changes, do not modify config file
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print statistics saved by server in `stats_file`
+    Stats {
+        /// location of config file
+        #[clap(short, long)]
+        config: PathBuf,
+        /// print statistics in json
+        #[clap(long)]
+        json: bool,
     },
     /// Generate keypairs
     GenKey {
         /// location of config file
         #[clap(short, long)]
         config: PathBuf,
+        /// keep current key accepted for this long, e.g. "30d", so clients generated with it
+        /// keep working while they are replaced by clients of the new key
+        #[clap(long, parse(try_from_str = humantime::parse_duration))]
+        grace: Option<Duration>,
+    },
+    /// Generate a keypair without config, e.g. on an air-gapped machine, printing both keys
+    /// in base64 to paste into configs
+    GenKeypair {
+        /// save private key to this new file instead of printing it, readable only by owner,
+        /// e.g. for `prikey_file` of server
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// print keys in json
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show builtin config and key fingerprints of this client without unlocking its key,
+    /// exits with 0 if ready, 13 if key is protected by passphrase, 14 if key is not builtin,
+    /// 12 if binary has no config, 3 if config is broken
+    Info {
+        /// print info in json
+        #[clap(long)]
+        json: bool,
     },
     /// List client pubkey in client config
     ListKey {
         /// if set this flag, then also list server pubkey
         #[clap(short, long)]
         server: bool,
+        /// print keys in json
+        #[clap(long)]
+        json: bool,
     },
     /// Modify a client with a new keypair
     ModCli {
@@ -88,6 +269,19 @@ enum Commands {
         /// if key passphrase is needed to protect client key
         #[clap(short, long)]
         password: bool,
+        /// location of server config, output is recorded in audit log next to it
+        /// (in current directory if not set)
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Benchmark noise handshake and transport with crypto backend of this build
+    Bench {
+        /// number of handshakes
+        #[clap(long, default_value = "1000")]
+        handshakes: usize,
+        /// megabytes sent through noise stream
+        #[clap(long, default_value = "256")]
+        megabytes: usize,
     },
     /// Clone a client from existing ones (analogy to Dolly the sheep)
     CloneCli {
@@ -100,70 +294,248 @@ enum Commands {
         /// location of output binary
         #[clap(short, long)]
         output: PathBuf,
+        /// location of server config, output is recorded in audit log next to it
+        /// (in current directory if not set), and server pubkey of dna is checked against it
+        #[clap(short, long)]
+        config: Option<PathBuf>,
     },
 }
 
-async fn run() -> Result<()> {
-    let cli = Cli::parse();
-    let client_cmd = cli.command.unwrap_or(Commands::Client(cli.client));
+async fn run(client_cmd: Commands) -> Result<()> {
     match client_cmd {
-        Commands::Client(ClientArgs { port, server }) => {
-            let server_addr = server.and_then(|s| s.parse().ok());
-            Client::run_client(port, server_addr).await?;
+        Commands::Client(args) if args.show_conf => {
+            Client::show_conf(args.json)?;
         }
-        Commands::Server { config: path } => {
-            let server = Server::build(path)?;
-            server.run_server_proxy().await?;
+        Commands::Client(args) if args.status || args.stop => {
+            // `requires` of clap makes sure control is set
+            Client::control(args.control.unwrap(), args.stop).await?;
+        }
+        Commands::Client(args) => {
+            Client::run_client(args.into()).await?;
+        }
+        Commands::InstallService(args) => {
+            service::install(args)?;
+        }
+        Commands::UninstallService(args) => {
+            service::uninstall(args)?;
+        }
+        Commands::Cp { src, dst, server } => {
+            let opts = ClientOptions {
+                server_addr: server,
+                ..Default::default()
+            };
+            Client::copy_files(opts, &src, &dst).await?;
+        }
+        Commands::Measure { server } => {
+            let opts = ClientOptions {
+                server_addr: server,
+                ..Default::default()
+            };
+            Client::measure(opts).await?;
+        }
+        Commands::Server {
+            config: path,
+            config_from_env,
+            check,
+            self_test,
+        } => {
+            let server = match path {
+                Some(path) if !config_from_env => Server::build(path)?,
+                _ => Server::build_from_env()?,
+            };
+            match (check, self_test) {
+                (true, _) => server.check()?,
+                (_, true) => server.self_test().await?,
+                _ => server.run_server_proxy().await?,
+            }
         }
         Commands::GenCli {
             config: path,
             input: in_path,
+            profile,
             output: out_path,
             name,
             target,
             service,
+            default_remote,
             password: has_password,
+            reconnect,
+            presets,
+            single_instance,
+            split,
+            resume,
+            early_data,
+            tenant,
+            socks5,
+            socks5_rules,
+            via,
+            lang,
+            telemetry,
+            pubkey,
         } => {
-            let in_path = in_path.unwrap_or(env::current_exe()?);
-            let remote = Remote::try_parse(target.as_deref(), service)
-                .map_err(|e| {
-                    log::warn!("Invalid remote input, use default. Error {}", e);
-                })
-                .ok();
+            let in_path = match (in_path, profile) {
+                (Some(path), _) => path,
+                (None, Some(profile)) => gen::template_path(&profile)?,
+                (None, None) => env::current_exe()?,
+            };
+            let remote = match (target.as_deref(), service, via) {
+                (None, None, _) if default_remote => None,
+                (None, None, _) => anyhow::bail!(
+                    "No target or service is set, use --default-remote to use remote of server"
+                ),
+                (Some(target), None, Some(id)) => Some(Remote::try_chain(target, id)?),
+                (target, service, _) => Some(Remote::try_parse(target, service)?),
+            };
+            let mut server = Server::build(path)?;
+            server.gen_client(
+                &in_path,
+                &out_path,
+                name.clone(),
+                remote,
+                GenOptions {
+                    has_keypass: has_password,
+                    reconnect,
+                    presets,
+                    single_instance,
+                    split,
+                    resume,
+                    early_data,
+                    tenant,
+                    socks5,
+                    socks5_rules,
+                    lang,
+                    telemetry,
+                    pubkey,
+                },
+            )?;
+            server.audit("gen-cli", &name, &out_path)?;
+        }
+        Commands::Apply {
+            config: path,
+            file,
+            input,
+            output_dir,
+            dry_run,
+        } => {
+            let in_path = match input {
+                Some(path) => path,
+                None => env::current_exe()?,
+            };
             let mut server = Server::build(path)?;
-            server.gen_client(in_path, out_path, name, remote, has_password)?;
+            server.apply(file, in_path, output_dir, dry_run)?;
         }
-        Commands::GenKey { config: path } => {
+        Commands::RenameCli {
+            config: path,
+            from,
+            to,
+        } => {
             let mut server = Server::build(path)?;
-            server.gen_key()?;
+            server.rename_client(&from, &to)?;
         }
-        Commands::ListKey { server } => {
-            Client::list_pubkey(server)?;
+        Commands::VerifyCli {
+            config: path,
+            input,
+            name,
+            json,
+        } => {
+            let server = Server::build(path)?;
+            if !server.verify_client(&input, name.as_deref(), json)? {
+                Err(portguard::Error::Mismatch(format!(
+                    "client binary {} does not match config",
+                    input.display()
+                )))?
+            }
+        }
+        Commands::RevokeCli {
+            config: path,
+            name,
+            reason,
+        } => {
+            let mut server = Server::build(path)?;
+            server.revoke_client(&name, reason)?;
+        }
+        Commands::MigrateConfig {
+            config: path,
+            dry_run,
+        } => {
+            Server::migrate_config(path, dry_run)?;
+        }
+        Commands::Stats { config: path, json } => {
+            Server::print_stats(path, json)?;
+        }
+        Commands::GenKey {
+            config: path,
+            grace,
+        } => {
+            let mut server = Server::build(path)?;
+            server.gen_key(grace)?;
+        }
+        Commands::GenKeypair { output, json } => {
+            gen::gen_standalone_keypair(output.as_deref(), json)?;
+        }
+        Commands::Info { json } => {
+            let state = Client::info(json)?;
+            if state != ConfigState::Ready {
+                std::process::exit(state as i32);
+            }
+        }
+        Commands::ListKey { server, json } => {
+            Client::list_pubkey(server, json)?;
         }
         Commands::ModCli {
             input: in_path,
             output: out_path,
             password: has_keypass,
+            config,
         } => {
             let in_path = in_path.unwrap_or(env::current_exe()?);
-            gen::modify_client_keypair(in_path, out_path, has_keypass)?;
+            gen::modify_client_keypair(&in_path, &out_path, has_keypass)?;
+            let log = audit::path_of(config.as_deref());
+            audit::record(&log, "mod-cli", None, &out_path)?;
         }
-        Commands::CloneCli { dna, egg, output } => {
+        Commands::Bench {
+            handshakes,
+            megabytes,
+        } => {
+            portguard::bench::run(handshakes, megabytes).await?;
+        }
+        Commands::CloneCli {
+            dna,
+            egg,
+            output,
+            config,
+        } => {
             let egg = egg.unwrap_or(env::current_exe()?);
-            gen::clone_client(dna, egg, output)?;
+            let server_pubkey = match &config {
+                Some(path) => Some(Server::build(path)?.pubkey().to_vec()),
+                None => None,
+            };
+            gen::clone_client(&dna, &egg, &output, server_pubkey.as_deref())?;
+            let log = audit::path_of(config.as_deref());
+            audit::record(&log, "clone-cli", None, &output)?;
         }
     }
     Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info")
-    }
-    env_logger::init();
-    run().await.map_err(|e| {
+async fn main() {
+    let cli = Cli::parse();
+    let client_cmd = cli.command.unwrap_or(Commands::Client(cli.client));
+    let log_level = match &client_cmd {
+        Commands::Client(args) => args.log_filters().to_string(),
+        _ => env::var("RUST_LOG").unwrap_or_else(|_| String::from("info")),
+    };
+    portguard::logger::init(&log_level);
+    if let Err(e) = run(client_cmd).await {
         log::error!("Error occured: {}", e);
-        e
-    })
+        eprintln!("Error: {e:?}");
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// exit code of failed command, see `portguard::exit`
+fn exit_code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<portguard::Error>()
+        .map_or(exit::FAILURE, portguard::Error::exit_code)
 }
@@ -0,0 +1,225 @@
+/// minimal mdns responder advertising client's local listener
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// max length of a mdns packet
+const MDNS_PACKET_LEN: usize = 9000;
+/// ttl of records naming hosts (SRV, A)
+const HOST_TTL: u32 = 120;
+/// ttl of other records (PTR, TXT)
+const OTHER_TTL: u32 = 4500;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// unique records flush caches of other responders
+const CLASS_IN_FLUSH: u16 = 0x8001;
+
+/// advertised service
+pub(crate) struct Service {
+    /// service type, e.g. "_rdp._tcp.local"
+    service_type: String,
+    /// instance name, e.g. "portguard._rdp._tcp.local"
+    instance: String,
+    /// host name, e.g. "portguard.local"
+    host: String,
+    ip: Ipv4Addr,
+    port: u16,
+}
+
+impl Service {
+    /// `service_type` is like "_rdp._tcp", `name` is a single dns label
+    pub(crate) fn new(service_type: &str, name: &str, ip: Ipv4Addr, port: u16) -> Self {
+        let service_type = format!("{}.local", service_type.trim_end_matches('.'));
+        Service {
+            instance: format!("{name}.{service_type}"),
+            host: format!("{name}.local"),
+            service_type,
+            ip,
+            port,
+        }
+    }
+    fn answers(&self, name: &str, qtype: u16) -> bool {
+        let matches =
+            |n: &str, t: u16| name.eq_ignore_ascii_case(n) && (qtype == t || qtype == TYPE_ANY);
+        matches(&self.service_type, TYPE_PTR)
+            || matches(&self.instance, TYPE_SRV)
+            || matches(&self.instance, TYPE_TXT)
+            || matches(&self.host, TYPE_A)
+    }
+    /// response packet containing all records
+    fn response(&self) -> Vec<u8> {
+        // id, flags (response, authoritative), qd, an, ns, ar
+        let mut buf = vec![0, 0, 0x84, 0, 0, 0, 0, 4, 0, 0, 0, 0];
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        write_name(&mut srv, &self.host);
+        let mut ptr = Vec::new();
+        write_name(&mut ptr, &self.instance);
+        write_record(
+            &mut buf,
+            &self.service_type,
+            TYPE_PTR,
+            CLASS_IN,
+            OTHER_TTL,
+            &ptr,
+        );
+        write_record(
+            &mut buf,
+            &self.instance,
+            TYPE_SRV,
+            CLASS_IN_FLUSH,
+            HOST_TTL,
+            &srv,
+        );
+        // a single empty string
+        write_record(
+            &mut buf,
+            &self.instance,
+            TYPE_TXT,
+            CLASS_IN_FLUSH,
+            OTHER_TTL,
+            &[0],
+        );
+        write_record(
+            &mut buf,
+            &self.host,
+            TYPE_A,
+            CLASS_IN_FLUSH,
+            HOST_TTL,
+            &self.ip.octets(),
+        );
+        buf
+    }
+}
+
+pub(crate) fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+pub(crate) fn write_record(
+    buf: &mut Vec<u8>,
+    name: &str,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    data: &[u8],
+) {
+    write_name(buf, name);
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// read a possibly compressed name at `pos`, return name and position after it
+pub(crate) fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bound jumps, avoid loops of compression pointers
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let ptr = ((l & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = ptr;
+            }
+            l => {
+                let label = packet.get(pos + 1..pos + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+/// questions of a mdns query, as (name, type)
+fn read_questions(packet: &[u8]) -> Option<Vec<(String, u16)>> {
+    let flags = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]);
+    if flags & 0x8000 != 0 {
+        // a response, not a query
+        return Some(Vec::new());
+    }
+    let count = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, next) = read_name(packet, pos)?;
+        let qtype = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        questions.push((name, qtype));
+        pos = next + 4;
+    }
+    Some(questions)
+}
+
+fn bind_multicast() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // share port 5353 with other responders on this host
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(StdUdpSocket::from(socket))
+}
+
+/// address of interface used to reach multicast group, a lan address usually
+pub(crate) fn lan_ip() -> io::Result<Ipv4Addr> {
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ipv4 address for mdns",
+        )),
+    }
+}
+
+/// announce service and answer queries of it
+pub(crate) async fn advertise(service: Service) -> io::Result<()> {
+    let socket = bind_multicast()?;
+    let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+    let response = service.response();
+    log::info!(
+        "Advertising {} on {}:{} via mdns",
+        service.instance,
+        service.ip,
+        service.port
+    );
+    // unsolicited announcements, sent twice as suggested by rfc 6762
+    for _ in 0..2 {
+        socket.send_to(&response, group).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let mut buf = vec![0; MDNS_PACKET_LEN];
+    loop {
+        let (n, _) = socket.recv_from(&mut buf).await?;
+        let questions = read_questions(&buf[..n]).unwrap_or_default();
+        if questions.iter().any(|(name, t)| service.answers(name, *t)) {
+            socket.send_to(&response, group).await?;
+        }
+    }
+}
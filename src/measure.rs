@@ -0,0 +1,257 @@
+/// measurement of latency and throughput of encrypted path between client and server
+///
+/// client sends `MEASURE` before its first handshake message, then server answers probes
+/// in noise stream instead of proxying: a ping is echoed, an upload is acknowledged
+/// when all bytes arrive, and a download is sent as many bytes as requested.
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::Result;
+
+/// sent in place of length of first handshake message, which is never that long
+const MEASURE: [u8; 2] = [0xfc, 0xff];
+const PING: u8 = b'p';
+const UPLOAD: u8 = b'u';
+const DOWNLOAD: u8 = b'd';
+const ACK: u8 = b'k';
+const PING_LEN: usize = 8;
+const CHUNK_LEN: usize = 16 * 1024;
+/// number of pings measuring round trip time
+const PINGS: u64 = 10;
+/// bytes of upload and download measuring throughput
+const TRANSFER_LEN: u64 = 4 * 1024 * 1024;
+
+/// result of measurement
+#[derive(Debug)]
+pub(crate) struct Report {
+    handshake: Duration,
+    rtts: Vec<Duration>,
+    upload: Duration,
+    download: Duration,
+    /// max segment size of tcp connection to server, path mtu minus headers
+    mss: Option<u32>,
+}
+
+impl Report {
+    /// mean difference of consecutive round trip times
+    fn jitter(&self) -> Duration {
+        let diffs: Vec<Duration> = self
+            .rtts
+            .windows(2)
+            .map(|w| w[0].max(w[1]) - w[0].min(w[1]))
+            .collect();
+        diffs.iter().sum::<Duration>() / diffs.len().max(1) as u32
+    }
+}
+
+/// throughput of `len` bytes in `elapsed`
+fn mbps(len: u64, elapsed: Duration) -> f64 {
+    len as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON) / 1e6
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let min = self.rtts.iter().min().copied().unwrap_or_default();
+        let max = self.rtts.iter().max().copied().unwrap_or_default();
+        let avg = self.rtts.iter().sum::<Duration>() / self.rtts.len().max(1) as u32;
+        writeln!(f, "Handshake: {:.2?}", self.handshake)?;
+        writeln!(
+            f,
+            "RTT: min {min:.2?}, avg {avg:.2?}, max {max:.2?} ({} pings)",
+            self.rtts.len()
+        )?;
+        writeln!(f, "Jitter: {:.2?}", self.jitter())?;
+        writeln!(
+            f,
+            "Upload: {:.2} Mbit/s ({} bytes in {:.2?})",
+            mbps(TRANSFER_LEN, self.upload),
+            TRANSFER_LEN,
+            self.upload
+        )?;
+        writeln!(
+            f,
+            "Download: {:.2} Mbit/s ({} bytes in {:.2?})",
+            mbps(TRANSFER_LEN, self.download),
+            TRANSFER_LEN,
+            self.download
+        )?;
+        match self.mss {
+            Some(mss) => write!(f, "Path MSS: {mss} bytes"),
+            None => write!(f, "Path MSS: unknown"),
+        }
+    }
+}
+
+/// max segment size of connection, which follows path mtu discovery of the os
+#[cfg(unix)]
+pub(crate) fn path_mss(conn: &TcpStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+    let mut mss: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            conn.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mut mss as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0 && mss > 0).then_some(mss as u32)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_mss(_conn: &TcpStream) -> Option<u32> {
+    None
+}
+
+/// ask server for measurement, before first handshake message
+pub(crate) async fn request(conn: &mut TcpStream) -> io::Result<()> {
+    conn.write_all(&MEASURE).await
+}
+
+async fn write_len<S: AsyncWrite + Unpin>(stream: &mut S, kind: u8, len: u64) -> io::Result<()> {
+    let mut msg = vec![kind];
+    msg.extend_from_slice(&len.to_be_bytes());
+    stream.write_all(&msg).await
+}
+
+/// write `len` zero bytes in chunks
+async fn send_bytes<S: AsyncWrite + Unpin>(stream: &mut S, len: u64) -> io::Result<()> {
+    let chunk = [0; CHUNK_LEN];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(CHUNK_LEN as u64) as usize;
+        stream.write_all(&chunk[..n]).await?;
+        left -= n as u64;
+    }
+    stream.flush().await
+}
+
+/// read and drop `len` bytes
+async fn recv_bytes<S: AsyncRead + Unpin>(stream: &mut S, len: u64) -> io::Result<()> {
+    let copied = tokio::io::copy(&mut stream.take(len), &mut tokio::io::sink()).await?;
+    match copied == len {
+        true => Ok(()),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// measure with server over noise stream made in `handshake`
+pub(crate) async fn run<S>(stream: &mut S, handshake: Duration, mss: Option<u32>) -> Result<Report>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut rtts = Vec::new();
+    for seq in 0..PINGS {
+        let mut msg = vec![PING];
+        msg.extend_from_slice(&seq.to_be_bytes());
+        let start = Instant::now();
+        stream.write_all(&msg).await?;
+        stream.flush().await?;
+        let mut echo = [0; PING_LEN + 1];
+        stream.read_exact(&mut echo).await?;
+        rtts.push(start.elapsed());
+        if echo[..] != msg[..] {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ping is not echoed",
+            ))?
+        }
+    }
+    let start = Instant::now();
+    write_len(stream, UPLOAD, TRANSFER_LEN).await?;
+    send_bytes(stream, TRANSFER_LEN).await?;
+    if stream.read_u8().await? != ACK {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "upload is not acknowledged",
+        ))?
+    }
+    let upload = start.elapsed();
+    let start = Instant::now();
+    write_len(stream, DOWNLOAD, TRANSFER_LEN).await?;
+    stream.flush().await?;
+    recv_bytes(stream, TRANSFER_LEN).await?;
+    let download = start.elapsed();
+    Ok(Report {
+        handshake,
+        rtts,
+        upload,
+        download,
+        mss,
+    })
+}
+
+#[cfg(feature = "server")]
+pub(crate) use server::{read, serve};
+
+#[cfg(feature = "server")]
+mod server {
+    use std::io;
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{recv_bytes, send_bytes, ACK, DOWNLOAD, MEASURE, PING, PING_LEN, UPLOAD};
+    use crate::error::Result;
+    use crate::ticket::peek_marker;
+
+    /// max bytes of one upload or download served
+    const MAX_TRANSFER_LEN: u64 = 64 * 1024 * 1024;
+
+    /// whether client asks for measurement, consuming its marker
+    pub(crate) async fn read(stream: &mut TcpStream) -> io::Result<bool> {
+        if peek_marker(stream).await? != MEASURE {
+            return Ok(false);
+        }
+        stream.read_exact(&mut [0; 2]).await?;
+        Ok(true)
+    }
+
+    /// answer probes of client until it closes stream
+    pub(crate) async fn serve<S>(stream: &mut S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let kind = match stream.read_u8().await {
+                Ok(kind) => kind,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => Err(e)?,
+            };
+            match kind {
+                PING => {
+                    let mut msg = [PING; PING_LEN + 1];
+                    stream.read_exact(&mut msg[1..]).await?;
+                    stream.write_all(&msg).await?;
+                }
+                UPLOAD | DOWNLOAD => {
+                    let len = stream.read_u64().await?;
+                    if len > MAX_TRANSFER_LEN {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "measurement transfer is too long",
+                        ))?
+                    }
+                    match kind {
+                        UPLOAD => {
+                            recv_bytes(stream, len).await?;
+                            stream.write_u8(ACK).await?;
+                        }
+                        _ => send_bytes(stream, len).await?,
+                    }
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown measurement probe",
+                ))?,
+            }
+            stream.flush().await?;
+        }
+    }
+}
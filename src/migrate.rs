@@ -0,0 +1,139 @@
+/// upgrade server config of older layouts to current schema
+///
+/// every step detects its own legacy layout, so steps are idempotent
+/// and a config can be migrated from any older version
+use toml::value::{Table, Value};
+
+/// a migration step, rewrites legacy layout in place and returns descriptions of changes
+type Step = fn(&mut Table) -> Vec<String>;
+
+const STEPS: &[Step] = &[untag_remotes, flatten_filehash];
+
+/// apply all steps, return descriptions of changes and warnings needing manual fix
+pub(crate) fn migrate(config: &mut Table) -> (Vec<String>, Vec<String>) {
+    let changes = STEPS.iter().flat_map(|step| step(config)).collect();
+    (changes, check_rclient_hashes(config))
+}
+
+fn clients_mut(config: &mut Table) -> impl Iterator<Item = &mut Table> {
+    config
+        .get_mut("clients")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_table_mut)
+}
+
+fn client_name(client: &Table) -> &str {
+    client.get("name").and_then(Value::as_str).unwrap_or("?")
+}
+
+/// remotes written in serde's externally tagged layout, e.g. `remote = { RProxy = ["127.0.0.1:22", 1] }`,
+/// are read as untagged ones, e.g. `remote = ["127.0.0.1:22", 1]`
+fn untag_remotes(config: &mut Table) -> Vec<String> {
+    let untag = |table: &mut Table| -> bool {
+        let tagged = match table.get("remote").and_then(Value::as_table) {
+            Some(t) if t.len() == 1 => t,
+            _ => return false,
+        };
+        let (tag, inner) = tagged.iter().next().unwrap();
+        if !["Proxy", "Service", "RProxy"].contains(&tag.as_str()) {
+            return false;
+        }
+        let inner = inner.clone();
+        table.insert(String::from("remote"), inner);
+        true
+    };
+    let mut changes = Vec::new();
+    if untag(config) {
+        changes.push(String::from("untagged default remote"));
+    }
+    for client in clients_mut(config) {
+        if untag(client) {
+            changes.push(format!("untagged remote of client {}", client_name(client)));
+        }
+    }
+    changes
+}
+
+/// file hash written as a sub table, e.g. `filehash = { hash = "..." }`,
+/// is flattened into client entry, e.g. `hash = "..."`
+fn flatten_filehash(config: &mut Table) -> Vec<String> {
+    let mut changes = Vec::new();
+    for client in clients_mut(config) {
+        let hash = match client.get("filehash") {
+            Some(Value::Table(t)) => t.get("hash").cloned(),
+            _ => continue,
+        };
+        client.remove("filehash");
+        if let Some(hash) = hash {
+            client.insert(String::from("hash"), hash);
+        }
+        changes.push(format!(
+            "flattened file hash of client {}",
+            client_name(client)
+        ));
+    }
+    changes
+}
+
+/// reverse proxy clients generated before file hash existed cannot be fixed by migration
+fn check_rclient_hashes(config: &Table) -> Vec<String> {
+    config
+        .get("clients")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_table)
+        .filter(|c| c.get("remote").is_some_and(Value::is_array) && !c.contains_key("hash"))
+        .map(|c| {
+            format!(
+                "reverse proxy client {} has no file hash, regenerate it with gen-cli",
+                client_name(c)
+            )
+        })
+        .collect()
+}
+
+/// whether two configs differ only in formatting and order of clients
+pub(crate) fn same_config(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| -> Option<Table> {
+        let mut table: Table = toml::de::from_str(s).ok()?;
+        if let Some(Value::Array(clients)) = table.get_mut("clients") {
+            clients.sort_by_key(|c| c.get("pubkey").and_then(Value::as_str).map(String::from));
+        }
+        Some(table)
+    };
+    normalize(a).is_some_and(|a| Some(a) == normalize(b))
+}
+
+/// line diff of two texts, lines prefixed with ' ', '-' or '+'
+pub(crate) fn diff(old: &str, new: &str) -> String {
+    let (a, b): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    // longest common subsequence table, configs are small
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out += &format!("  {}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out += &format!("+ {}\n", b[j]);
+            j += 1;
+        } else {
+            out += &format!("- {}\n", a[i]);
+            i += 1;
+        }
+    }
+    out
+}
@@ -0,0 +1,236 @@
+/// bandwidth caps of reverse proxy services, so one busy service cannot starve the others
+///
+/// all visitor streams of a service share a token bucket of each direction,
+/// a stream reads or writes when its bucket has tokens and is charged what it moved,
+/// so a service may exceed its rate by one chunk before it waits.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// min bytes a bucket holds when full, so small rates still move whole chunks
+const MIN_BURST: f64 = 16.0 * 1024.0;
+
+/// tokens of one direction, refilled at `rate` bytes per second
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    /// tokens, negative after a chunk larger than tokens, and time of last refill
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        let burst = (rate / 10.0).max(MIN_BURST);
+        Bucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+    fn refill(&self, state: &mut (f64, Instant)) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.burst);
+        state.1 = now;
+    }
+    /// time to wait until tokens are available, `None` if they are
+    fn delay(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        (state.0 < 0.0).then(|| Duration::from_secs_f64(-state.0 / self.rate))
+    }
+    fn charge(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.0 -= bytes as f64;
+    }
+}
+
+/// rate of a service, shared by its streams
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    /// data sent to service
+    up: Bucket,
+    /// data received from service
+    down: Bucket,
+}
+
+impl Pacer {
+    /// cap each direction to `rate` bytes per second
+    pub(crate) fn new(rate: u64) -> Self {
+        Pacer {
+            up: Bucket::new(rate),
+            down: Bucket::new(rate),
+        }
+    }
+}
+
+/// stream to a service paced by its `Pacer`, or not paced if it has none
+pub(crate) struct Paced<S> {
+    inner: S,
+    pacer: Option<Arc<Pacer>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Paced<S> {
+    pub(crate) fn new(inner: S, pacer: Option<Arc<Pacer>>) -> Self {
+        Paced {
+            inner,
+            pacer,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// wait until `bucket` has tokens
+fn poll_tokens(delay: &mut Option<Pin<Box<Sleep>>>, bucket: &Bucket, cx: &mut Context) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match bucket.delay() {
+            Some(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+            None => return Poll::Ready(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Paced<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let pacer = match &this.pacer {
+            Some(pacer) => pacer,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        ready!(poll_tokens(&mut this.read_delay, &pacer.down, cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        pacer.down.charge(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Paced<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let pacer = match &this.pacer {
+            Some(pacer) => pacer,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        ready!(poll_tokens(&mut this.write_delay, &pacer.up, cx));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        pacer.up.charge(written);
+        Poll::Ready(Ok(written))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn buckets_wait_for_what_was_charged_over_burst() {
+        let bucket = Bucket::new(1000);
+        assert_eq!(bucket.burst, MIN_BURST);
+        assert_eq!(bucket.delay(), None);
+        bucket.charge(MIN_BURST as usize);
+        assert!(bucket
+            .delay()
+            .is_none_or(|wait| wait < Duration::from_millis(10)));
+        bucket.charge(1000);
+        let wait = bucket.delay().unwrap();
+        assert!(
+            wait > Duration::from_millis(900) && wait <= Duration::from_secs(1),
+            "{wait:?}"
+        );
+    }
+
+    #[test]
+    fn large_rates_burst_a_tenth_of_a_second() {
+        assert_eq!(Bucket::new(10_000_000).burst, 1_000_000.0);
+        assert_eq!(Bucket::new(0).rate, 1.0);
+    }
+
+    /// write `bytes` through `streams` sharing `pacer` at once, return time taken
+    async fn write_through(pacer: Option<Arc<Pacer>>, streams: usize, bytes: usize) -> Duration {
+        let start = Instant::now();
+        let writers = (0..streams).map(|_| {
+            let pacer = pacer.clone();
+            tokio::spawn(async move {
+                let (inner, mut peer) = tokio::io::duplex(4096);
+                let reader = tokio::spawn(async move {
+                    let mut sink = Vec::new();
+                    peer.read_to_end(&mut sink).await.unwrap();
+                    sink.len()
+                });
+                let mut paced = Paced::new(inner, pacer);
+                paced.write_all(&vec![0; bytes]).await.unwrap();
+                paced.shutdown().await.unwrap();
+                drop(paced);
+                assert_eq!(reader.await.unwrap(), bytes);
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn streams_of_a_service_share_its_rate() {
+        // 100 KB/s, burst of 16 KB, 2 streams of 33 KB wait at least 0.5 s
+        let pacer = Arc::new(Pacer::new(100_000));
+        let elapsed = write_through(Some(pacer), 2, 33_000).await;
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn streams_without_pacer_are_not_paced() {
+        let elapsed = write_through(None, 2, 1_000_000).await;
+        assert!(elapsed < Duration::from_millis(450), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn reads_are_paced_by_down_bucket() {
+        let pacer = Arc::new(Pacer::new(100_000));
+        let (inner, mut peer) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            peer.write_all(&[0; 66_000]).await.unwrap();
+        });
+        let start = Instant::now();
+        let mut paced = Paced::new(inner, Some(pacer.clone()));
+        let mut data = Vec::new();
+        paced.read_to_end(&mut data).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(data.len(), 66_000);
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert_eq!(pacer.up.delay(), None);
+    }
+}
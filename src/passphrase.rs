@@ -0,0 +1,167 @@
+/// sources of client key passphrase, so clients started without a terminal,
+/// e.g. as a service, can still unlock their keys
+use std::env;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::i18n::{Lang, Msg};
+
+/// env variable holding passphrase
+const PASSPHRASE_ENV: &str = "PORTGUARD_PASSPHRASE";
+/// name of systemd credential holding passphrase, `LoadCredential=portguard-passphrase:<file>`
+const CREDENTIAL_NAME: &str = "portguard-passphrase";
+
+#[derive(Debug)]
+pub(crate) enum Source {
+    /// fixed passphrase from env variable or systemd credential
+    Fixed(String),
+    /// asked by a pinentry program, e.g. `pinentry-gnome3`
+    Pinentry(PathBuf),
+    /// asked on terminal
+    Tty,
+}
+
+impl Source {
+    /// env variable, then systemd credential, then pinentry, then terminal,
+    /// pinentry is only used when stdin is not a terminal
+    pub(crate) fn detect(pinentry: Option<&Path>) -> io::Result<Source> {
+        if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+            return Ok(Source::Fixed(passphrase));
+        }
+        if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+            let path = Path::new(&dir).join(CREDENTIAL_NAME);
+            if path.exists() {
+                let content = std::fs::read_to_string(path)?;
+                return Ok(Source::Fixed(content.trim_end_matches(['\r', '\n']).into()));
+            }
+        }
+        match (io::stdin().is_terminal(), pinentry) {
+            (true, _) => Ok(Source::Tty),
+            (false, Some(program)) => Ok(Source::Pinentry(program.to_path_buf())),
+            (false, None) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no terminal to ask key passphrase, set {PASSPHRASE_ENV}, \
+                     systemd credential {CREDENTIAL_NAME}, or --pinentry"
+                ),
+            )),
+        }
+    }
+    /// a fixed passphrase is not asked again after a wrong one
+    pub(crate) fn is_interactive(&self) -> bool {
+        !matches!(self, Source::Fixed(_))
+    }
+    /// get passphrase asking in `lang`, pinentry shows an error if it is asked `again`
+    pub(crate) fn read(&self, lang: Lang, again: bool) -> io::Result<String> {
+        match self {
+            Source::Fixed(passphrase) => Ok(passphrase.clone()),
+            Source::Pinentry(program) => pinentry(program, lang, again),
+            Source::Tty => {
+                if again {
+                    eprintln!("{}", lang.text(Msg::WrongPassphrase));
+                }
+                rpassword::prompt_password(lang.text(Msg::PassphrasePrompt))
+            }
+        }
+    }
+}
+
+/// ask passphrase with assuan protocol of pinentry
+fn pinentry(program: &Path, lang: Lang, again: bool) -> io::Result<String> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut input = child.stdin.take().expect("stdin is piped");
+    let mut output = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    let res = get_pin(&mut input, &mut output, lang, again);
+    writeln!(input, "BYE").ok();
+    drop(input);
+    child.wait()?;
+    res
+}
+
+fn get_pin(
+    input: &mut impl Write,
+    output: &mut impl BufRead,
+    lang: Lang,
+    again: bool,
+) -> io::Result<String> {
+    // greeting
+    response(output)?;
+    let mut request = |command: &str| -> io::Result<String> {
+        writeln!(input, "{command}")?;
+        response(output)
+    };
+    request("SETTITLE portguard")?;
+    request(&format!(
+        "SETDESC {}",
+        escape(lang.text(Msg::PassphraseDesc))
+    ))?;
+    request(&format!(
+        "SETPROMPT {}",
+        escape(lang.text(Msg::PassphraseLabel))
+    ))?;
+    if again {
+        request(&format!(
+            "SETERROR {}",
+            escape(lang.text(Msg::WrongPassphrase))
+        ))?;
+    }
+    request("GETPIN")
+}
+
+/// read assuan responses until `OK`, return data sent before it
+fn response(output: &mut impl BufRead) -> io::Result<String> {
+    let mut data = String::new();
+    loop {
+        let mut line = String::new();
+        if output.read_line(&mut line)? == 0 {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "pinentry exited",
+            ))?
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        }
+        if let Some(error) = line.strip_prefix("ERR") {
+            Err(io::Error::other(format!("pinentry: {}", error.trim())))?
+        }
+        if let Some(d) = line.strip_prefix("D ") {
+            data += &unescape(d);
+        }
+        // comments and status lines are ignored
+    }
+}
+
+/// percent escape of assuan, for `%`, CR and LF
+fn escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn unescape(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (b, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
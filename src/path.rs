@@ -0,0 +1,56 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::net::TcpStream;
+
+use crate::sockopt::SocketOpts;
+
+/// set of local paths (source addresses) used to reach server
+/// new connections are striped round-robin across paths,
+/// and fail over to next path if one is unreachable
+#[derive(Debug, Default)]
+pub(crate) struct PathSet {
+    addrs: Vec<IpAddr>,
+    next: AtomicUsize,
+    /// options of sockets to server
+    socket: SocketOpts,
+}
+
+impl PathSet {
+    pub(crate) fn new(addrs: Vec<IpAddr>, socket: SocketOpts) -> Self {
+        PathSet {
+            addrs,
+            next: AtomicUsize::new(0),
+            socket,
+        }
+    }
+    /// connect to server, using default route if no path is set
+    pub(crate) async fn connect(&self, server: SocketAddr) -> io::Result<TcpStream> {
+        if self.addrs.is_empty() {
+            return self.socket.socket(server)?.connect(server).await;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..self.addrs.len() {
+            let local = self.addrs[(start + i) % self.addrs.len()];
+            match self.connect_via(local, server).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::warn!("Path {local} to server is unavailable. Error: {e}");
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+    /// whether connections keep alive by socket options
+    pub(crate) fn keeps_alive(&self) -> bool {
+        self.socket.keepalive.is_some()
+    }
+    async fn connect_via(&self, local: IpAddr, server: SocketAddr) -> io::Result<TcpStream> {
+        let socket = self.socket.socket(server)?;
+        socket.bind(SocketAddr::new(local, 0))?;
+        socket.connect(server).await
+    }
+}
@@ -0,0 +1,376 @@
+/// pipelined transfer between a plain stream and a noise stream,
+/// each direction is split into a reading task and a crypto and writing task,
+/// with a cipher state of its own, so that a busy tunnel is not capped by a single core.
+/// frames on wire are the same as those of `NoiseStream`, peers need no change.
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use snowstorm::snow::HandshakeState;
+use snowstorm::{NoiseStream, SnowstormError, SnowstormResult};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinSet;
+
+use crate::error::{Error, Result};
+use crate::proxy::TransferError;
+
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+/// frames queued between reading task and crypto task of a direction
+const QUEUE_LEN: usize = 16;
+/// frames processed together are written with one call
+const BATCH_LEN: usize = 4 * MAX_MESSAGE_LEN;
+
+/// keys of both directions of a noise session, split at the end of its handshake,
+/// so that each direction is pipelined with its own cipher and nonce, sharing no lock
+pub(crate) struct SessionKeys {
+    /// key of messages from initiator to responder
+    initiator: [u8; KEY_LEN],
+    /// key of messages from responder to initiator
+    responder: [u8; KEY_LEN],
+}
+
+/// `NoiseStream::handshake_with_verifier`, also returning keys of the session,
+/// messages on wire are the same
+pub(crate) async fn handshake_with_verifier<T, F>(
+    mut inner: T,
+    mut state: HandshakeState,
+    verifier: F,
+) -> SnowstormResult<(NoiseStream<T>, SessionKeys)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&[u8]) -> SnowstormResult<()>,
+{
+    let mut verifier = Some(verifier);
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let mut payload = vec![0; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut message)?;
+            inner.write_u16_le(len as u16).await?;
+            inner.write_all(&message[..len]).await?;
+            inner.flush().await?;
+        } else {
+            let len = inner.read_u16_le().await? as usize;
+            inner.read_exact(&mut message[..len]).await?;
+            state.read_message(&message[..len], &mut payload)?;
+            if let Some(key) = state.get_remote_static() {
+                if let Some(verifier) = verifier.take() {
+                    verifier(key)?;
+                }
+            }
+        }
+    }
+    let (initiator, responder) = state.dangerously_get_raw_split();
+    // handshake is finished, stream is made without reading or writing
+    let stream = NoiseStream::handshake(inner, state).await?;
+    let keys = SessionKeys {
+        initiator,
+        responder,
+    };
+    Ok((stream, keys))
+}
+
+/// `NoiseStream::handshake`, also returning keys of the session
+pub(crate) async fn handshake<T>(
+    inner: T,
+    state: HandshakeState,
+) -> SnowstormResult<(NoiseStream<T>, SessionKeys)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake_with_verifier(inner, state, |_| Ok(())).await
+}
+
+/// cipher state of one direction, as that of noise with ChaChaPoly
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; KEY_LEN], nonce: u64) -> Self {
+        Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce,
+        }
+    }
+    /// little endian nonce after 4 zero bytes, max nonce is reserved by noise
+    fn next_nonce(&mut self) -> io::Result<Nonce> {
+        if self.nonce == u64::MAX {
+            return Err(invalid_data(SnowstormError::InvalidNonce(self.nonce)));
+        }
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Ok(*Nonce::from_slice(&nonce))
+    }
+    /// append length and message of `payload` to `out`
+    fn seal(&mut self, payload: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.next_nonce()?;
+        out.extend_from_slice(&((payload.len() + TAG_LEN) as u16).to_le_bytes());
+        let start = out.len();
+        out.extend_from_slice(payload);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&nonce, &[], &mut out[start..])
+            .map_err(|_| invalid_data("encryption failed"))?;
+        out.extend_from_slice(&tag);
+        Ok(())
+    }
+    /// decrypt `msg` in place, leaving its payload
+    fn open(&mut self, msg: &mut Vec<u8>) -> io::Result<()> {
+        let len = msg
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or_else(|| invalid_data("message shorter than tag"))?;
+        let nonce = self.next_nonce()?;
+        let tag = *Tag::from_slice(&msg[len..]);
+        self.aead
+            .decrypt_in_place_detached(&nonce, &[], &mut msg[..len], &tag)
+            .map_err(|_| invalid_data("decryption failed"))?;
+        msg.truncate(len);
+        Ok(())
+    }
+}
+
+/// take connection and cipher of each direction out of noise stream made with `keys`,
+/// which must have no buffered data, i.e. every message read from it is read whole
+fn into_parts(enc: NoiseStream<TcpStream>, keys: &SessionKeys) -> (TcpStream, Cipher, Cipher) {
+    let state = enc.get_state();
+    let (seal, open) = match state.is_initiator() {
+        true => (&keys.initiator, &keys.responder),
+        false => (&keys.responder, &keys.initiator),
+    };
+    let sealer = Cipher::new(seal, state.sending_nonce());
+    let opener = Cipher::new(open, state.receiving_nonce());
+    (enc.into_inner(), sealer, opener)
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+async fn read_plain<R>(mut reader: R, tx: Sender<Vec<u8>>) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut total = 0;
+    loop {
+        let mut buf = vec![0; MAX_PAYLOAD_LEN];
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        total += n as u64;
+        buf.truncate(n);
+        if tx.send(buf).await.is_err() {
+            return Ok(total);
+        }
+    }
+}
+
+async fn seal_and_write(
+    mut rx: Receiver<Vec<u8>>,
+    mut sealer: Cipher,
+    mut writer: OwnedWriteHalf,
+) -> io::Result<()> {
+    let mut out = Vec::with_capacity(BATCH_LEN + 2 + MAX_MESSAGE_LEN);
+    while let Some(mut payload) = rx.recv().await {
+        out.clear();
+        loop {
+            sealer.seal(&payload, &mut out)?;
+            // take frames queued meanwhile into this batch
+            match rx.try_recv() {
+                Ok(next) if out.len() < BATCH_LEN => payload = next,
+                Ok(next) => {
+                    writer.write_all(&out).await?;
+                    out.clear();
+                    payload = next;
+                }
+                Err(_) => break,
+            }
+        }
+        writer.write_all(&out).await?;
+    }
+    writer.shutdown().await
+}
+
+async fn read_frames(mut reader: OwnedReadHalf, tx: Sender<Vec<u8>>) -> io::Result<()> {
+    loop {
+        let len = match reader.read_u16_le().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut msg = vec![0; len];
+        reader.read_exact(&mut msg).await?;
+        if tx.send(msg).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn open_and_write<W>(
+    mut rx: Receiver<Vec<u8>>,
+    mut opener: Cipher,
+    mut writer: W,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+    let mut out = Vec::with_capacity(BATCH_LEN + MAX_MESSAGE_LEN);
+    while let Some(mut msg) = rx.recv().await {
+        out.clear();
+        loop {
+            opener.open(&mut msg)?;
+            out.extend_from_slice(&msg);
+            match rx.try_recv() {
+                Ok(next) if out.len() < BATCH_LEN => msg = next,
+                Ok(next) => {
+                    total += out.len() as u64;
+                    writer.write_all(&out).await?;
+                    out.clear();
+                    msg = next;
+                }
+                Err(_) => break,
+            }
+        }
+        total += out.len() as u64;
+        writer.write_all(&out).await?;
+    }
+    writer.shutdown().await.map(|_| total)
+}
+
+/// transfer data in both directions, return bytes from `plain` to `enc` and from `enc` to `plain`,
+/// `keys` are those of handshake of `enc`
+pub(crate) async fn transfer<S>(
+    plain: S,
+    enc: NoiseStream<TcpStream>,
+    keys: &SessionKeys,
+) -> Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (conn, sealer, opener) = into_parts(enc, keys);
+    let (conn_reader, conn_writer) = conn.into_split();
+    let (plain_reader, plain_writer) = io::split(plain);
+    let (sealed_tx, sealed_rx) = mpsc::channel(QUEUE_LEN);
+    let (opened_tx, opened_rx) = mpsc::channel(QUEUE_LEN);
+    // tasks are aborted when set is dropped, e.g. on error of one of them
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move { read_plain(plain_reader, sealed_tx).await.map(|n| (n, 0)) });
+    tasks.spawn(async move {
+        seal_and_write(sealed_rx, sealer, conn_writer)
+            .await
+            .map(|_| (0, 0))
+    });
+    tasks.spawn(async move { read_frames(conn_reader, opened_tx).await.map(|_| (0, 0)) });
+    tasks.spawn(async move {
+        open_and_write(opened_rx, opener, plain_writer)
+            .await
+            .map(|n| (0, n))
+    });
+    let (mut sent, mut received) = (0, 0);
+    while let Some(r) = tasks.join_next().await {
+        let (s, r) = r.map_err(io::Error::other)??;
+        sent += s;
+        received += r;
+    }
+    Ok((sent, received))
+}
+
+/// transfer data and log error, return bytes from `plain` to `enc` and back if succeeded
+pub(crate) async fn transfer_and_log_error<S>(
+    plain: S,
+    enc: NoiseStream<TcpStream>,
+    keys: &SessionKeys,
+) -> Option<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    transfer(plain, enc, keys)
+        .await
+        .map_err(|e| match e {
+            Error::Io(e) => TransferError::log(&e),
+            e => log::warn!("Transfer error occured. error={}", e),
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+    #[tokio::test]
+    async fn pipelined_session_talks_to_noise_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let peer = tokio::spawn(async move {
+            let conn = TcpStream::connect(addr).await.unwrap();
+            let initiator = snowstorm::Builder::new(PATTERN.parse().unwrap())
+                .build_initiator()
+                .unwrap();
+            let mut enc = NoiseStream::handshake(conn, initiator).await.unwrap();
+            // messages before pipelining advance nonces of both directions
+            enc.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            enc.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"howdy");
+            enc.write_all(&sent).await.unwrap();
+            let mut echoed = vec![0; sent.len()];
+            enc.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, sent);
+        });
+        let (conn, _) = listener.accept().await.unwrap();
+        let responder = snowstorm::Builder::new(PATTERN.parse().unwrap())
+            .build_responder()
+            .unwrap();
+        let (mut enc, keys) = handshake(conn, responder).await.unwrap();
+        let mut buf = [0; 5];
+        enc.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        enc.write_all(b"howdy").await.unwrap();
+        // plain side echoes what it receives
+        let (plain, echo) = io::duplex(MAX_MESSAGE_LEN);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = io::split(echo);
+            io::copy(&mut reader, &mut writer).await
+        });
+        let bytes = transfer(plain, enc, &keys).await.unwrap();
+        peer.await.unwrap();
+        assert_eq!(bytes, (data.len() as u64, data.len() as u64));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let key = [7; KEY_LEN];
+        let (mut sealer, mut opener) = (Cipher::new(&key, 3), Cipher::new(&key, 3));
+        let mut frame = Vec::new();
+        sealer.seal(b"payload", &mut frame).unwrap();
+        assert_eq!(frame[..2], ((7 + TAG_LEN) as u16).to_le_bytes());
+        let mut msg = frame[2..].to_vec();
+        msg[0] ^= 1;
+        assert!(opener.open(&mut msg).is_err());
+        // a message of another nonce is rejected too
+        let mut msg = frame[2..].to_vec();
+        assert!(opener.open(&mut msg).is_err());
+        assert!(opener.open(&mut vec![0; TAG_LEN - 1]).is_err());
+        let mut opener = Cipher::new(&key, 3);
+        let mut msg = frame[2..].to_vec();
+        opener.open(&mut msg).unwrap();
+        assert_eq!(msg, b"payload");
+    }
+}
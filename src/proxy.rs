@@ -1,54 +1,473 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use fast_socks5::server::Socks5Socket;
+#[cfg(feature = "clap")]
+use clap::Args;
+use fast_socks5::consts::{
+    SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED,
+    SOCKS5_REPLY_CONNECTION_NOT_ALLOWED, SOCKS5_REPLY_CONNECTION_REFUSED,
+    SOCKS5_REPLY_HOST_UNREACHABLE, SOCKS5_REPLY_SUCCEEDED, SOCKS5_REPLY_TTL_EXPIRED,
+};
+use fast_socks5::server::{Config, SimpleUserPassword, Socks5Socket};
+use fast_socks5::util::target_addr::TargetAddr;
 use futures::FutureExt;
-use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
 
-pub(crate) async fn transfer<S1, S2>(inbound: S1, outbound: S2) -> Result<(), io::Error>
+use crate::rules::{self, TargetRule};
+
+/// idle time before sending keepalive probes on long-lived connections
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+/// interval between keepalive probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// default seconds to wait for connecting to a socks5 target
+const SOCKS5_REQUEST_TIMEOUT: u64 = 10;
+
+/// options of built-in socks5 server, of server and of reverse proxy client exposing socks5
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct Socks5Options {
+    /// seconds to wait for connecting to a socks5 target [default: 10]
+    #[cfg_attr(feature = "clap", clap(long = "socks5-request-timeout"))]
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+    /// refuse socks5 targets given as domain names instead of resolving them
+    #[cfg_attr(feature = "clap", clap(long = "socks5-no-dns"))]
+    #[serde(default)]
+    pub no_dns: bool,
+    /// allowed socks5 commands, can be repeated [default: connect]
+    #[cfg_attr(feature = "clap", clap(long = "socks5-command"))]
+    #[serde(default)]
+    pub commands: Option<Vec<Socks5Command>>,
+    /// username and password required from socks5 clients, in form of "user:password"
+    #[cfg_attr(feature = "clap", clap(long = "socks5-auth"))]
+    #[serde(default)]
+    pub auth: Option<Socks5Auth>,
+}
+
+/// socks5 command, only `connect` can be served through tunnel, `bind` and `udp-associate` cannot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Socks5Command {
+    Connect,
+}
+
+impl FromStr for Socks5Command {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connect" => Ok(Socks5Command::Connect),
+            "bind" | "udp-associate" => Err(format!("socks5 command {s} is not supported")),
+            _ => Err(format!("unknown socks5 command {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Socks5Auth {
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for Socks5Auth {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Socks5Auth {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(String::from("socks5 auth should be user:password")),
+        }
+    }
+}
+
+impl Socks5Options {
+    pub fn is_default(&self) -> bool {
+        self == &Socks5Options::default()
+    }
+    /// config of fast_socks5, requests are checked and executed by caller
+    pub(crate) fn config(&self) -> Config {
+        let mut config = Config::default();
+        config.set_dns_resolve(false).set_execute_command(false);
+        if let Some(auth) = &self.auth {
+            config.set_authentication(SimpleUserPassword {
+                username: auth.username.clone(),
+                password: auth.password.clone(),
+            });
+        }
+        config
+    }
+    /// reply code refusing request to `target`, if it is not allowed by options
+    pub(crate) fn refuse(&self, target: &TargetAddr) -> Option<u8> {
+        let connect = self
+            .commands
+            .as_ref()
+            .is_none_or(|c| c.contains(&Socks5Command::Connect));
+        match target {
+            _ if !connect => Some(SOCKS5_REPLY_COMMAND_NOT_SUPPORTED),
+            TargetAddr::Domain(_, _) if self.no_dns => {
+                Some(SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED)
+            }
+            _ => None,
+        }
+    }
+    /// connect to target within request timeout
+    pub(crate) async fn connect<F>(&self, connect: F) -> io::Result<TcpStream>
+    where
+        F: Future<Output = io::Result<TcpStream>>,
+    {
+        let secs = self.request_timeout.unwrap_or(SOCKS5_REQUEST_TIMEOUT);
+        match tokio::time::timeout(Duration::from_secs(secs), connect).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "socks5 target connection timeout",
+            )),
+        }
+    }
+}
+
+/// socks5 reply code of connecting to target
+pub(crate) fn socks5_reply_code<T>(outbound: &io::Result<T>) -> u8 {
+    match outbound {
+        Ok(_) => SOCKS5_REPLY_SUCCEEDED,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => SOCKS5_REPLY_CONNECTION_REFUSED,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => SOCKS5_REPLY_TTL_EXPIRED,
+        Err(_) => SOCKS5_REPLY_HOST_UNREACHABLE,
+    }
+}
+
+/// enable tcp keepalive, so that silently dead connections are detected
+pub(crate) fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE_TIME)
+        .with_interval(KEEPALIVE_INTERVAL);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// how a transfer ended early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransferError {
+    /// peer reset or closed connection abruptly, e.g. a killed app or a lost network
+    PeerReset,
+    /// this side closed connection, e.g. a stream of a closed reverse proxy connection
+    LocalShutdown,
+    /// any other error, worth a warning
+    Failed,
+}
+
+impl TransferError {
+    pub(crate) fn of(e: &io::Error) -> Self {
+        use io::ErrorKind::*;
+        match e.kind() {
+            ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof => {
+                TransferError::PeerReset
+            }
+            NotConnected | WriteZero => TransferError::LocalShutdown,
+            _ => TransferError::Failed,
+        }
+    }
+    /// log error of transfer, only unexpected ones as warnings
+    pub(crate) fn log(e: &io::Error) {
+        match TransferError::of(e) {
+            TransferError::PeerReset => log::info!("Transfer ended by peer. error={}", e),
+            TransferError::LocalShutdown => log::debug!("Transfer ended by shutdown. error={}", e),
+            TransferError::Failed => log::warn!("Transfer error occured. error={}", e),
+        }
+    }
+}
+
+/// stream retrying reads and writes interrupted by signals (EINTR),
+/// which are not errors of transfer
+struct Restart<S>(S);
+
+impl<S: AsyncRead + Unpin> AsyncRead for Restart<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match Pin::new(&mut self.0).poll_read(cx, buf) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Restart<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match Pin::new(&mut self.0).poll_write(cx, buf) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match Pin::new(&mut self.0).poll_flush(cx) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// transfer data in both directions, return bytes sent and received by inbound,
+/// when one side fails the other is dropped, closing it
+pub(crate) async fn transfer<S1, S2>(inbound: S1, outbound: S2) -> Result<(u64, u64), io::Error>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
     S2: AsyncRead + AsyncWrite + Unpin,
 {
-    let (mut ri, mut wi) = io::split(inbound);
-    let (mut ro, mut wo) = io::split(outbound);
+    let (mut ri, mut wi) = io::split(Restart(inbound));
+    let (mut ro, mut wo) = io::split(Restart(outbound));
 
     let client_to_server = async {
-        io::copy(&mut ri, &mut wo).await?;
-        wo.shutdown().await
+        let n = io::copy(&mut ri, &mut wo).await?;
+        wo.shutdown().await.map(|_| n)
     };
     let server_to_client = async {
-        io::copy(&mut ro, &mut wi).await?;
-        wi.shutdown().await
+        let n = io::copy(&mut ro, &mut wi).await?;
+        wi.shutdown().await.map(|_| n)
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
-
-    Ok(())
+    tokio::try_join!(client_to_server, server_to_client)
 }
 
-pub(crate) async fn transfer_and_log_error<S1, S2>(inbound: S1, outbound: S2)
+/// transfer data and log error, return bytes sent and received if succeeded
+pub(crate) async fn transfer_and_log_error<S1, S2>(inbound: S1, outbound: S2) -> Option<(u64, u64)>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
     S2: AsyncRead + AsyncWrite + Unpin,
 {
-    let transfer = crate::proxy::transfer(inbound, outbound).map(|r| {
-        if let Err(e) = r {
-            log::warn!("Transfer error occured. error={}", e);
-        }
-    });
-    transfer.await;
+    let transfer = crate::proxy::transfer(inbound, outbound)
+        .map(|r| r.map_err(|e| TransferError::log(&e)).ok());
+    transfer.await
 }
 
-pub(crate) async fn transfer_to_socks5_and_log_error<S>(inbound: S)
+/// reply to socks5 request with unspecified bound address, as clients do not use it
+pub(crate) async fn socks5_reply<S>(socket: &mut S, code: u8) -> io::Result<()>
 where
+    S: AsyncWrite + Unpin,
+{
+    socket.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    socket.flush().await
+}
+
+/// serve socks5 request of inbound, connecting to targets from this host
+/// and only to those allowed by every set of `rules`
+pub(crate) async fn transfer_to_socks5_and_log_error<S>(
+    inbound: S,
+    options: &Socks5Options,
+    rules: &[Vec<TargetRule>],
+) where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let config = fast_socks5::server::Config::default();
-    let socket = Socks5Socket::new(inbound, Arc::new(config));
-    let transfer = socket.upgrade_to_socks5().map(|r| {
-        if let Err(e) = r {
-            log::warn!("Transfer error occured. error={}", e);
-        }
-    });
-    transfer.await;
+    if let Err(e) = transfer_to_socks5(inbound, options, rules).await {
+        log::warn!("Transfer error occured. error={}", e);
+    }
+}
+
+async fn transfer_to_socks5<S>(
+    inbound: S,
+    options: &Socks5Options,
+    rules: &[Vec<TargetRule>],
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut socket = Socks5Socket::new(inbound, Arc::new(options.config()))
+        .upgrade_to_socks5()
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let target = socket
+        .target_addr()
+        .cloned()
+        .ok_or(io::ErrorKind::InvalidInput)?;
+    if let Some(code) = options.refuse(&target) {
+        return socks5_reply(&mut socket, code).await;
+    }
+    let outbound = options.connect(connect_allowed(&target, rules)).await;
+    socks5_reply(&mut socket, socks5_reply_code(&outbound)).await?;
+    transfer_and_log_error(socket, outbound?).await;
+    Ok(())
+}
+
+/// connect to addresses of `target` allowed by every set of `rules` before dialing,
+/// domain rules match requested name, ip rules match its resolved addresses
+async fn connect_allowed(target: &TargetAddr, rules: &[Vec<TargetRule>]) -> io::Result<TcpStream> {
+    let (domain, addrs): (_, Vec<SocketAddr>) = match target {
+        TargetAddr::Ip(addr) => (None, vec![*addr]),
+        TargetAddr::Domain(host, port) => (
+            Some(host.as_str()),
+            tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .collect(),
+        ),
+    };
+    let allowed: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| {
+            rules
+                .iter()
+                .all(|rules| rules::allows(rules, domain, Some(addr.ip()), addr.port()))
+        })
+        .collect();
+    if allowed.is_empty() {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("socks5 target {target} is not allowed"),
+        ))?
+    }
+    TcpStream::connect(&allowed[..]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    use super::{transfer, Restart, TransferError};
+
+    /// stream failing with queued errors before reading `data` or accepting writes
+    struct Flaky {
+        errors: VecDeque<io::ErrorKind>,
+        data: &'static [u8],
+        written: Vec<u8>,
+    }
+
+    impl Flaky {
+        fn new(errors: &[io::ErrorKind], data: &'static [u8]) -> Self {
+            Flaky {
+                errors: errors.iter().copied().collect(),
+                data,
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(kind) = self.errors.pop_front() {
+                return Poll::Ready(Err(kind.into()));
+            }
+            let n = self.data.len().min(buf.remaining());
+            buf.put_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Flaky {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if let Some(kind) = self.errors.pop_front() {
+                return Poll::Ready(Err(kind.into()));
+            }
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.errors.pop_front() {
+                Some(kind) => Poll::Ready(Err(kind.into())),
+                None => Poll::Ready(Ok(())),
+            }
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn reset_and_closed_pipe_end_transfer_normally() {
+        use io::ErrorKind::*;
+        for kind in [
+            ConnectionReset,
+            ConnectionAborted,
+            BrokenPipe,
+            UnexpectedEof,
+        ] {
+            assert_eq!(TransferError::of(&kind.into()), TransferError::PeerReset);
+        }
+        for kind in [NotConnected, WriteZero] {
+            assert_eq!(
+                TransferError::of(&kind.into()),
+                TransferError::LocalShutdown
+            );
+        }
+        for kind in [Interrupted, PermissionDenied, TimedOut, Other] {
+            assert_eq!(TransferError::of(&kind.into()), TransferError::Failed);
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_reads_and_writes_are_retried() {
+        use io::ErrorKind::Interrupted;
+        let mut stream = Restart(Flaky::new(&[Interrupted, Interrupted], b"hello"));
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut stream = Restart(Flaky::new(&[Interrupted, Interrupted, Interrupted], b""));
+        stream.write_all(b"hi").await.unwrap();
+        stream.flush().await.unwrap();
+        assert_eq!(stream.0.written, b"hi");
+    }
+
+    #[tokio::test]
+    async fn reset_is_not_retried() {
+        use io::ErrorKind::{BrokenPipe, ConnectionReset};
+        let mut stream = Restart(Flaky::new(&[ConnectionReset], b"hello"));
+        let e = stream.read(&mut [0; 5]).await.unwrap_err();
+        assert_eq!(e.kind(), ConnectionReset);
+
+        let mut stream = Restart(Flaky::new(&[BrokenPipe], b""));
+        let e = stream.write(b"hi").await.unwrap_err();
+        assert_eq!(e.kind(), BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn transfer_survives_interrupts_and_fails_on_reset() {
+        use io::ErrorKind::{ConnectionReset, Interrupted};
+        let inbound = Flaky::new(&[Interrupted], b"ping");
+        let outbound = Flaky::new(&[Interrupted], b"pong");
+        assert_eq!(transfer(inbound, outbound).await.unwrap(), (4, 4));
+
+        let inbound = Flaky::new(&[ConnectionReset], b"ping");
+        let outbound = Flaky::new(&[], b"pong");
+        let e = transfer(inbound, outbound).await.unwrap_err();
+        assert_eq!(TransferError::of(&e), TransferError::PeerReset);
+    }
 }
@@ -0,0 +1,57 @@
+/// priority classes of clients, so interactive admin tunnels stay responsive when bulk syncs run
+///
+/// priority decides how visitor streams of a service are scheduled, and with `dscp` of
+/// server, how packets to the client and to its targets are marked for routers.
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+use crate::fair::Class;
+use crate::sockopt;
+
+/// dscp of expedited forwarding, for interactive traffic
+const DSCP_EF: u8 = 46;
+/// dscp of class selector 1, lower effort than best effort, for bulk traffic
+const DSCP_CS1: u8 = 8;
+
+/// priority of a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Priority {
+    /// never waits for other streams
+    High,
+    /// scheduled by its rate if service is fair
+    #[default]
+    Normal,
+    /// always waits for interactive streams
+    Low,
+}
+
+impl Priority {
+    /// schedule class of a visitor stream to a service, `fair` if service schedules fairly
+    pub(crate) fn class(self, fair: bool) -> Class {
+        match self {
+            Priority::High => Class::Interactive,
+            Priority::Normal if fair => Class::Auto,
+            Priority::Normal | Priority::Low => Class::Bulk,
+        }
+    }
+    fn dscp(self) -> Option<u8> {
+        match self {
+            Priority::High => Some(DSCP_EF),
+            Priority::Normal => None,
+            Priority::Low => Some(DSCP_CS1),
+        }
+    }
+    /// mark packets sent on `stream` with dscp of priority, normal ones are not marked
+    pub(crate) fn mark(self, stream: &TcpStream) -> io::Result<()> {
+        let tos = match self.dscp() {
+            Some(dscp) => dscp << 2,
+            None => return Ok(()),
+        };
+        let v6 = stream.local_addr()?.is_ipv6();
+        sockopt::set_tos(&SockRef::from(stream), v6, tos)
+    }
+}
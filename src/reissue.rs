@@ -0,0 +1,83 @@
+/// re-issue of client config after server key rotation, so deployed clients of the previous
+/// key are not regenerated
+///
+/// server tells a client handshaking with its previous key the new public key, in reply to
+/// heartbeat, inside the noise session of the previous key, so it is signed by that key.
+/// client writes the new key into builtin config of its binary, used after a restart, or at
+/// once with `--reload`. a reverse proxy client sends hash of its rewritten binary right after,
+/// which server records for that client.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use snowstorm::NoiseStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::client::ClientConfig;
+use crate::consts::{CONF_BUF_LEN, PUBKEY_LEN};
+use crate::error::{Error, Result};
+use crate::fingerprint;
+use crate::reload;
+
+/// read new server key after `Status::Rotated`, and rewrite client binary with it,
+/// a client that cannot rewrite itself keeps the previous key until it is regenerated
+pub(crate) async fn receive<S>(stream: &mut NoiseStream<S>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pubkey = vec![0; PUBKEY_LEN];
+    stream.read_exact(&mut pubkey).await?;
+    match rewrite(&pubkey) {
+        Ok(()) => log::info!(
+            "Server key is rotated, client binary is updated with new key {}, used after restart",
+            fingerprint::of(&pubkey)
+        ),
+        Err(e) => log::warn!(
+            "Server key is rotated, failed to update client binary, regenerate it. Error: {}",
+            e
+        ),
+    }
+    Ok(())
+}
+
+/// replace server key in builtin config of client binary
+fn rewrite(pubkey: &[u8]) -> Result<()> {
+    // code signature of macos binaries is broken by any change, and they are killed
+    if cfg!(target_os = "macos") {
+        Err(Error::Config(String::from(
+            "signed binary of macos cannot be changed",
+        )))?
+    }
+    let path = reload::exe_path()?;
+    let mut exe = std::fs::read(&path)?;
+    let offset = reload::conf_offset(&exe)?;
+    let buf = &mut exe[offset..offset + CONF_BUF_LEN];
+    let mut conf = ClientConfig::from_slice(buf)?;
+    conf.server_pubkey = pubkey.to_vec();
+    let bytes = conf.to_vec()?;
+    buf.fill(0);
+    buf[..bytes.len()].copy_from_slice(&bytes);
+    replace(&path, &exe)?;
+    Ok(())
+}
+
+/// replace binary at `path` by `bytes`, keeping its permissions
+fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut new_exe = path.as_os_str().to_owned();
+    new_exe.push(format!(".{}.tmp", std::process::id()));
+    let new_exe = PathBuf::from(new_exe);
+    let res = std::fs::write(&new_exe, bytes)
+        .and_then(|_| std::fs::set_permissions(&new_exe, std::fs::metadata(path)?.permissions()))
+        .and_then(|_| {
+            // a running binary of windows cannot be replaced, but can be renamed
+            if cfg!(windows) {
+                let old = path.with_extension("old");
+                let _ = std::fs::remove_file(&old);
+                std::fs::rename(path, old)?;
+            }
+            std::fs::rename(&new_exe, path)
+        });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&new_exe);
+    }
+    res
+}
@@ -0,0 +1,121 @@
+/// reload of builtin config of a running client, so a client binary replaced by one with
+/// a new target or key takes effect without dropping active connections
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use crate::client::{ClientConfig, CLIENT_CONF_BUF};
+use crate::consts::CONF_BUF_LEN;
+
+/// interval of checking whether client binary is replaced
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// location of client binary when it started, it stays valid after the binary is replaced,
+/// while `current_exe` of a replaced binary may point to a deleted file
+pub(crate) fn exe_path() -> io::Result<PathBuf> {
+    static EXE: OnceLock<PathBuf> = OnceLock::new();
+    if let Some(path) = EXE.get() {
+        return Ok(path.clone());
+    }
+    let path = std::env::current_exe()?;
+    Ok(EXE.get_or_init(|| path).clone())
+}
+
+/// config buffer of running binary
+fn builtin_buf() -> Vec<u8> {
+    std::hint::black_box(&CLIENT_CONF_BUF).to_vec()
+}
+
+/// offset of config buffer of running binary in content of its file
+pub(crate) fn conf_offset(exe: &[u8]) -> io::Result<usize> {
+    let current = builtin_buf();
+    exe.windows(CONF_BUF_LEN)
+        .position(|w| w == current)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "builtin config is not found in client binary",
+            )
+        })
+}
+
+/// watch of builtin config in client binary on disk
+pub(crate) struct ConfWatch {
+    path: PathBuf,
+    /// file offset of config buffer
+    offset: u64,
+    modified: Option<SystemTime>,
+    /// config buffer last read
+    current: Vec<u8>,
+    #[cfg(unix)]
+    hangup: Option<crate::signal::Signal>,
+}
+
+impl ConfWatch {
+    /// locate config buffer of running binary in its file
+    pub(crate) fn new() -> io::Result<Self> {
+        let path = exe_path()?;
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        let current = builtin_buf();
+        let offset = conf_offset(&std::fs::read(&path)?)?;
+        Ok(ConfWatch {
+            path,
+            offset: offset as u64,
+            modified,
+            current,
+            #[cfg(unix)]
+            hangup: crate::signal::Signal::new(libc::SIGHUP)
+                .map_err(|e| log::warn!("Failed to listen SIGHUP. Error: {}", e))
+                .ok(),
+        })
+    }
+    /// wait until config in binary changes, checked periodically and on SIGHUP
+    pub(crate) async fn changed(&mut self) -> ClientConfig {
+        loop {
+            let forced = self.tick().await;
+            match self.read(forced) {
+                Ok(Some(buf)) => match ClientConfig::from_slice(&buf) {
+                    Ok(conf) => return conf,
+                    Err(e) => log::warn!(
+                        "Config of replaced client binary cannot be read, restart client to use it. Error: {}",
+                        e
+                    ),
+                },
+                Ok(None) => {}
+                Err(e) => log::debug!("Failed to check client binary. Error: {}", e),
+            }
+        }
+    }
+    /// wait for next check, true if it is asked by SIGHUP
+    async fn tick(&mut self) -> bool {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup {
+            return tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => false,
+                res = hangup.recv() => res.is_ok(),
+            };
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        false
+    }
+    /// config buffer of binary on disk if it differs from last one,
+    /// binary is only read if it is modified or `forced`
+    fn read(&mut self, forced: bool) -> io::Result<Option<Vec<u8>>> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        if !forced && modified == self.modified {
+            return Ok(None);
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = vec![0; CONF_BUF_LEN];
+        file.read_exact(&mut buf)?;
+        self.modified = modified;
+        if buf == self.current {
+            return Ok(None);
+        }
+        self.current = buf.clone();
+        Ok(Some(buf))
+    }
+}
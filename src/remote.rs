@@ -1,21 +1,187 @@
 use std::{
-    error::Error,
     fmt,
-    net::{AddrParseError, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
 
-/// Type for target address
+use crate::error::Error;
+
+/// Type for target address, parsed from and displayed as strings like "127.0.0.1:22",
+/// "example.com:22", "socks5", "files", "relay:addr", "netns:name:addr" or "exec:cmd args",
+/// which are also its form in toml and json
 /// for serialize https://github.com/serde-rs/serde/issues/1560#issuecomment-1666846833
-#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Target {
     /// target address is builtin socks5
     Socks5,
+    /// target is builtin file transfer service of `portguard cp`
+    Files,
+    /// target address is next hop portguard server, in form of "relay:addr",
+    /// client has an end-to-end session with it, relaying server only forwards its messages
+    #[serde(untagged, with = "relay_serde")]
+    Relay(SocketAddr),
+    /// target address in a linux network namespace, in form of "netns:name:addr"
+    #[serde(untagged, with = "netns_serde")]
+    Netns(String, SocketAddr),
+    /// command spawned by server with stdio bridged to tunnel, in form of "exec:cmd args"
+    #[serde(untagged, with = "exec_serde")]
+    Exec(String),
     /// target address is a socket address
     #[serde(untagged)]
     Addr(SocketAddr),
+    /// target address is a hostname with port, e.g. "example.com:22", resolved when connecting
+    #[serde(untagged, with = "host_serde")]
+    Host(String),
+}
+
+mod relay_serde {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(addr: &SocketAddr, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("relay:{}", addr))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SocketAddr, D::Error> {
+        let s = String::deserialize(d)?;
+        s.strip_prefix("relay:")
+            .ok_or_else(|| serde::de::Error::custom("not a relay target"))?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+mod netns_serde {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        ns: &String,
+        addr: &SocketAddr,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("netns:{}:{}", ns, addr))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<(String, SocketAddr), D::Error> {
+        let s = String::deserialize(d)?;
+        super::parse_netns(&s).ok_or_else(|| serde::de::Error::custom("not a netns target"))
+    }
+}
+
+mod exec_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(cmd: &str, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("exec:{}", cmd))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+        let s = String::deserialize(d)?;
+        s.strip_prefix("exec:")
+            .map(String::from)
+            .ok_or_else(|| serde::de::Error::custom("not an exec target"))
+    }
+}
+
+mod host_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(host: &str, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(host)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+        let s = String::deserialize(d)?;
+        match super::parse_endpoint(&s).map_err(serde::de::Error::custom)? {
+            super::Target::Host(host) => Ok(host),
+            _ => Err(serde::de::Error::custom("not a hostname target")),
+        }
+    }
+}
+
+/// parse "ip:port", "[ipv6]:port", unambiguous unbracketed "ipv6:port" or "hostname:port",
+/// error describes what is wrong with input
+fn parse_endpoint(s: &str) -> Result<Target, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(Target::Addr(addr));
+    }
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        // unbracketed ipv6, last group may be either port or a part of address
+        let split = s
+            .rsplit_once(':')
+            .and_then(|(ip, port)| Some((ip.parse::<Ipv6Addr>().ok()?, port.parse::<u16>().ok()?)));
+        match (ip, split) {
+            (IpAddr::V6(_), Some((ip, port))) => Err(format!(
+                "{s} is ambiguous, use [{s}]:<port> for an address, or [{ip}]:{port} for port {port}"
+            ))?,
+            _ => Err(format!("{s} has no port, e.g. {}", with_port(s)))?,
+        }
+    }
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{s} has no port, e.g. {}", with_port(s)))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("invalid port {port:?} in {s}"))?;
+    if host.contains(':') {
+        // unbracketed ipv6 whose last group can only be port
+        let ip = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<Ipv6Addr>()
+            .map_err(|_| format!("invalid IPv6 address {host} in {s}"))?;
+        return Ok(Target::Addr(SocketAddr::new(ip.into(), port)));
+    }
+    check_hostname(host).map_err(|e| format!("{e} in {s}"))?;
+    Ok(Target::Host(format!("{host}:{port}")))
+}
+
+/// example of input with port
+fn with_port(s: &str) -> String {
+    match s.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{s}]:22"),
+        Err(_) => format!("{s}:22"),
+    }
+}
+
+/// labels of letters, digits, '-' and '_', a trailing dot is allowed
+fn check_hostname(host: &str) -> Result<(), String> {
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() || name.len() > 253 {
+        Err(format!("invalid hostname {host:?}"))?
+    }
+    for label in name.split('.') {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            Err(format!("invalid hostname {host:?}"))?
+        }
+    }
+    // e.g. "1.2.3.256" is a mistyped address, not a hostname
+    if name
+        .split('.')
+        .all(|l| l.chars().all(|c| c.is_ascii_digit()))
+    {
+        Err(format!("invalid IPv4 address {host}"))?
+    }
+    Ok(())
+}
+
+/// parse "netns:name:addr", name is a named netns or path of a netns file
+fn parse_netns(s: &str) -> Option<(String, SocketAddr)> {
+    let (ns, addr) = s.strip_prefix("netns:")?.split_once(':')?;
+    Some((ns.to_string(), addr.parse().ok()?))
 }
 
 impl fmt::Display for Target {
@@ -26,13 +192,22 @@ impl fmt::Display for Target {
             match self {
                 Target::Addr(a) => a.to_string(),
                 Target::Socks5 => String::from("socks5"),
+                Target::Files => String::from("files"),
+                Target::Relay(a) => format!("relay:{}", a),
+                Target::Netns(ns, a) => format!("netns:{}:{}", ns, a),
+                Target::Exec(cmd) => format!("exec:{}", cmd),
+                Target::Host(host) => host.clone(),
             }
         )
     }
 }
 
-/// Type for identifying remote
-#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+/// Type for identifying remote, displayed as and parsed from "<target>" of a proxy,
+/// "service:<id>" of a service visitor, "service:<id>:<target>" of a reverse proxy client,
+/// and "via:<id>:<target>" of a visitor reaching target through a service,
+/// in toml and json it is a target string, a service id, `[target, service id]`
+/// or `[service id, target]`
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Remote {
     /// visitor of remote address, for `ssh -L` or
@@ -42,46 +217,95 @@ pub enum Remote {
     Service(usize),
     /// client of reverse proxy, need addr and service id, for ssh -R` client
     RProxy(Target, usize),
+    /// visitor of reverse proxy exposing socks5, reaching target on network of its client
+    Chain(usize, Target),
 }
 
-impl Remote {
-    /// if input only target, client is proxy client
-    fn from_target(target: &str) -> Result<Remote, AddrParseError> {
+impl Target {
+    fn parse(target: &str) -> Result<Target, String> {
         if target.to_lowercase() == "socks5" {
-            Ok(Remote::Proxy(Target::Socks5))
+            Ok(Target::Socks5)
+        } else if target.to_lowercase() == "files" {
+            Ok(Target::Files)
+        } else if let Some(hop) = target.strip_prefix("relay:") {
+            match parse_endpoint(hop)? {
+                Target::Addr(addr) => Ok(Target::Relay(addr)),
+                _ => Err(format!("next hop {hop} must be a socket address")),
+            }
+        } else if let Some(cmd) = target.strip_prefix("exec:") {
+            Ok(Target::Exec(cmd.to_string()))
+        } else if let Some(rest) = target.strip_prefix("netns:") {
+            let (ns, addr) = rest
+                .split_once(':')
+                .ok_or_else(|| String::from("netns target should be netns:name:addr"))?;
+            match parse_endpoint(addr)? {
+                Target::Addr(addr) => Ok(Target::Netns(ns.to_string(), addr)),
+                _ => Err(format!("address {addr} in netns must be a socket address")),
+            }
         } else {
-            target
-                .parse::<SocketAddr>()
-                .map(Target::Addr)
-                .map(Remote::Proxy)
+            parse_endpoint(target)
         }
     }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::parse(s).map_err(Error::InvalidRemote)
+    }
+}
+
+impl Remote {
+    /// if input only target, client is proxy client
+    fn from_target(target: &str) -> Result<Remote, String> {
+        Target::parse(target).map(Remote::Proxy)
+    }
     /// if input only id, client is service visitor
     fn from_id(id: usize) -> Remote {
         Remote::Service(id)
     }
     /// if input both target and id, client is reverse proxy client
-    fn from_target_and_id(target: &str, id: usize) -> Result<Remote, AddrParseError> {
+    fn from_target_and_id(target: &str, id: usize) -> Result<Remote, String> {
         if target.to_lowercase() == "socks5" {
             Ok(Remote::RProxy(Target::Socks5, id))
         } else {
-            let addr = target.parse::<SocketAddr>()?;
-            Ok(Remote::RProxy(Target::Addr(addr), id))
+            parse_endpoint(target).map(|target| Remote::RProxy(target, id))
         }
     }
     /// parse optional input
-    pub fn try_parse(target: Option<&str>, id: Option<usize>) -> Result<Remote, Box<dyn Error>> {
+    pub fn try_parse(target: Option<&str>, id: Option<usize>) -> Result<Remote, Error> {
+        let invalid = Error::InvalidRemote;
         match target {
             None => match id {
                 Some(id) => Ok(Remote::from_id(id)),
-                None => Err("Invalid remote address")?,
+                None => Err(Error::InvalidRemote(String::from(
+                    "no target or service id",
+                ))),
             },
             Some(target) => Ok(match id {
-                None => Remote::from_target(target)?,
-                Some(id) => Remote::from_target_and_id(target, id)?,
+                None => Remote::from_target(target).map_err(invalid)?,
+                Some(_) if target.starts_with("relay:") => Err(Error::InvalidRemote(
+                    String::from("relay target is not supported by reverse proxy"),
+                ))?,
+                Some(_) if target.starts_with("netns:") => Err(Error::InvalidRemote(
+                    String::from("netns target is not supported by reverse proxy"),
+                ))?,
+                Some(_) if target.to_lowercase() == "files" => Err(Error::InvalidRemote(
+                    String::from("files target is not supported by reverse proxy"),
+                ))?,
+                Some(_) if target.starts_with("exec:") => Err(Error::InvalidRemote(String::from(
+                    "exec target is not supported by reverse proxy",
+                )))?,
+                Some(id) => Remote::from_target_and_id(target, id).map_err(invalid)?,
             }),
         }
     }
+    /// visitor of service `id` reaching `target`, a socket address or hostname with port
+    pub fn try_chain(target: &str, id: usize) -> Result<Remote, Error> {
+        parse_endpoint(target)
+            .map(|target| Remote::Chain(id, target))
+            .map_err(Error::InvalidRemote)
+    }
 }
 
 impl fmt::Display for Remote {
@@ -91,9 +315,247 @@ impl fmt::Display for Remote {
             "{}",
             match self {
                 Remote::Proxy(t) => t.to_string(),
-                Remote::Service(id) => format!("service (id: {})", id),
-                Remote::RProxy(t, _id) => t.to_string(),
+                Remote::Service(id) => format!("service:{}", id),
+                Remote::RProxy(t, id) => format!("service:{}:{}", id, t),
+                Remote::Chain(id, t) => format!("via:{}:{}", id, t),
             }
         )
     }
 }
+
+/// parse display form of remote, a service id may also be given alone, e.g. "7"
+impl FromStr for Remote {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse() {
+            return Ok(Remote::Service(id));
+        }
+        if let Some(rest) = s.strip_prefix("via:") {
+            let (id, target) = rest.split_once(':').ok_or_else(|| {
+                Error::InvalidRemote(format!("{s} should be via:<service id>:<target>"))
+            })?;
+            let id = id
+                .parse()
+                .map_err(|_| Error::InvalidRemote(format!("invalid service id {id:?} in {s}")))?;
+            return Remote::try_chain(target, id);
+        }
+        let (id, target) = match s.strip_prefix("service:") {
+            Some(rest) => match rest.split_once(':') {
+                Some((id, target)) => (id, Some(target)),
+                None => (rest, None),
+            },
+            None => return Remote::try_parse(Some(s), None),
+        };
+        let id = id
+            .parse()
+            .map_err(|_| Error::InvalidRemote(format!("invalid service id {id:?} in {s}")))?;
+        Remote::try_parse(target, Some(id))
+    }
+}
+
+/// tagged representation of `Remote` embedded in client config,
+/// as bincode cannot decode untagged enums, which are only for toml
+pub(crate) mod tagged {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Remote, Target};
+
+    /// variants are encoded by index, new ones must be appended
+    #[derive(Serialize, Deserialize)]
+    enum TaggedTarget {
+        Socks5,
+        Files,
+        Relay(SocketAddr),
+        Netns(String, SocketAddr),
+        Exec(String),
+        Addr(SocketAddr),
+        Host(String),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum TaggedRemote {
+        Proxy(TaggedTarget),
+        Service(usize),
+        RProxy(TaggedTarget, usize),
+        Chain(usize, TaggedTarget),
+    }
+
+    impl From<Target> for TaggedTarget {
+        fn from(target: Target) -> Self {
+            match target {
+                Target::Socks5 => TaggedTarget::Socks5,
+                Target::Files => TaggedTarget::Files,
+                Target::Relay(addr) => TaggedTarget::Relay(addr),
+                Target::Netns(ns, addr) => TaggedTarget::Netns(ns, addr),
+                Target::Exec(cmd) => TaggedTarget::Exec(cmd),
+                Target::Addr(addr) => TaggedTarget::Addr(addr),
+                Target::Host(host) => TaggedTarget::Host(host),
+            }
+        }
+    }
+
+    impl From<TaggedTarget> for Target {
+        fn from(target: TaggedTarget) -> Self {
+            match target {
+                TaggedTarget::Socks5 => Target::Socks5,
+                TaggedTarget::Files => Target::Files,
+                TaggedTarget::Relay(addr) => Target::Relay(addr),
+                TaggedTarget::Netns(ns, addr) => Target::Netns(ns, addr),
+                TaggedTarget::Exec(cmd) => Target::Exec(cmd),
+                TaggedTarget::Addr(addr) => Target::Addr(addr),
+                TaggedTarget::Host(host) => Target::Host(host),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(remote: &Remote, s: S) -> Result<S::Ok, S::Error> {
+        let tagged = match remote.clone() {
+            Remote::Proxy(target) => TaggedRemote::Proxy(target.into()),
+            Remote::Service(id) => TaggedRemote::Service(id),
+            Remote::RProxy(target, id) => TaggedRemote::RProxy(target.into(), id),
+            Remote::Chain(id, target) => TaggedRemote::Chain(id, target.into()),
+        };
+        tagged.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Remote, D::Error> {
+        Ok(match TaggedRemote::deserialize(d)? {
+            TaggedRemote::Proxy(target) => Remote::Proxy(target.into()),
+            TaggedRemote::Service(id) => Remote::Service(id),
+            TaggedRemote::RProxy(target, id) => Remote::RProxy(target.into(), id),
+            TaggedRemote::Chain(id, target) => Remote::Chain(id, target.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Remote, Target};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn remote_round_trips_through_display() {
+        let cases = [
+            (
+                "127.0.0.1:22",
+                Remote::Proxy(Target::Addr(addr("127.0.0.1:22"))),
+            ),
+            ("[::1]:22", Remote::Proxy(Target::Addr(addr("[::1]:22")))),
+            (
+                "example.com:443",
+                Remote::Proxy(Target::Host(String::from("example.com:443"))),
+            ),
+            ("socks5", Remote::Proxy(Target::Socks5)),
+            ("files", Remote::Proxy(Target::Files)),
+            (
+                "relay:10.0.0.2:8022",
+                Remote::Proxy(Target::Relay(addr("10.0.0.2:8022"))),
+            ),
+            (
+                "netns:blue:10.0.0.5:80",
+                Remote::Proxy(Target::Netns(String::from("blue"), addr("10.0.0.5:80"))),
+            ),
+            (
+                "exec:cat -u",
+                Remote::Proxy(Target::Exec(String::from("cat -u"))),
+            ),
+            ("service:7", Remote::Service(7)),
+            (
+                "service:7:127.0.0.1:22",
+                Remote::RProxy(Target::Addr(addr("127.0.0.1:22")), 7),
+            ),
+            ("service:9:socks5", Remote::RProxy(Target::Socks5, 9)),
+            (
+                "via:9:db.internal:5432",
+                Remote::Chain(9, Target::Host(String::from("db.internal:5432"))),
+            ),
+        ];
+        for (text, remote) in cases {
+            assert_eq!(text.parse::<Remote>().unwrap(), remote, "{text}");
+            assert_eq!(remote.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn service_id_alone_is_a_visitor() {
+        assert_eq!("7".parse::<Remote>().unwrap(), Remote::Service(7));
+    }
+
+    #[test]
+    fn ipv6_forms() {
+        // last group of an unbracketed address may be a port or a part of address
+        assert_eq!(
+            "2001:db8::1:22".parse::<Remote>().unwrap_err().to_string(),
+            "Invalid remote: 2001:db8::1:22 is ambiguous, use [2001:db8::1:22]:<port> for an address, or [2001:db8::1]:22 for port 22"
+        );
+        assert_eq!(
+            "[2001:db8::1]:22".parse::<Remote>().unwrap(),
+            Remote::Proxy(Target::Addr(addr("[2001:db8::1]:22")))
+        );
+    }
+
+    #[test]
+    fn invalid_remotes_are_errors() {
+        let invalid = [
+            "",
+            ":22",
+            "example.com",
+            "example.com:port",
+            "example.com:65536",
+            "-bad.example.com:22",
+            "1.2.3.256:22",
+            "::1",
+            "fe80::1:ffff:99999",
+            "service:x",
+            "service:7:files",
+            "service:7:relay:10.0.0.2:8022",
+            "service:7:exec:cat",
+            "via:9",
+            "via:x:127.0.0.1:22",
+            "relay:example.com:8022",
+            "netns:blue",
+            "netns:blue:example.com:80",
+        ];
+        for text in invalid {
+            assert!(text.parse::<Remote>().is_err(), "{text} is accepted");
+        }
+    }
+
+    #[test]
+    fn tagged_remote_round_trips_through_bincode() {
+        #[derive(Serialize, Deserialize)]
+        struct Conf {
+            #[serde(with = "super::tagged")]
+            remote: Remote,
+        }
+        let remotes = [
+            "127.0.0.1:22",
+            "example.com:443",
+            "socks5",
+            "relay:10.0.0.2:8022",
+            "netns:blue:10.0.0.5:80",
+            "exec:cat",
+            "service:7",
+            "service:7:[::1]:22",
+            "via:9:10.0.0.5:5432",
+        ];
+        for text in remotes {
+            let remote: Remote = text.parse().unwrap();
+            let bytes = bincode::serialize(&Conf {
+                remote: remote.clone(),
+            })
+            .unwrap();
+            let conf: Conf = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(conf.remote, remote);
+        }
+    }
+}
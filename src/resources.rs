@@ -0,0 +1,113 @@
+/// accounting of connections and file descriptors of server,
+/// with ceilings shedding new connections before resources run out
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+
+/// ceilings of resource usage, no ceiling if not set
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ResourceLimits {
+    /// max inbound connections handled at the same time
+    pub(crate) max_connections: Option<usize>,
+    /// max open file descriptors of server process, only counted on linux
+    pub(crate) max_open_fds: Option<usize>,
+}
+
+/// resource usage of server
+#[derive(Debug, Default)]
+pub(crate) struct Resources {
+    limits: ResourceLimits,
+    /// inbound connections being handled, one task each
+    connections: AtomicUsize,
+    /// connections shed by ceilings
+    rejected: AtomicU64,
+}
+
+/// an inbound connection counted in usage until dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard(Arc<Resources>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Resources {
+    pub(crate) fn new(limits: ResourceLimits) -> Self {
+        Resources {
+            limits,
+            ..Default::default()
+        }
+    }
+    /// count a new inbound connection, rejected if any ceiling is reached
+    pub(crate) fn admit(self: &Arc<Self>) -> Result<ConnectionGuard> {
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        let guard = ConnectionGuard(self.clone());
+        let reason = match (self.limits.max_connections, self.limits.max_open_fds) {
+            (Some(max), _) if connections >= max => Some(format!("{connections} connections")),
+            (_, Some(max)) => open_fds()
+                .filter(|fds| *fds >= max)
+                .map(|fds| format!("{fds} open files")),
+            _ => None,
+        };
+        match reason {
+            Some(reason) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(Error::Overloaded(reason))
+            }
+            None => Ok(guard),
+        }
+    }
+    /// usage in prometheus text format
+    pub(crate) fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let mut gauge = |name: &str, help: &str, value: Option<String>| {
+            if let Some(value) = value {
+                metrics += &format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+            }
+        };
+        let connections = self.connections.load(Ordering::Relaxed);
+        gauge(
+            "portguard_connections",
+            "Inbound connections being handled.",
+            Some(connections.to_string()),
+        );
+        gauge(
+            "portguard_max_connections",
+            "Ceiling of inbound connections.",
+            self.limits.max_connections.map(|n| n.to_string()),
+        );
+        gauge(
+            "portguard_open_fds",
+            "Open file descriptors of server process.",
+            open_fds().map(|n| n.to_string()),
+        );
+        gauge(
+            "portguard_max_open_fds",
+            "Ceiling of open file descriptors.",
+            self.limits.max_open_fds.map(|n| n.to_string()),
+        );
+        let rejected = self.rejected.load(Ordering::Relaxed);
+        metrics += &format!(
+            "# HELP portguard_rejected_connections_total Connections shed by ceilings.\n\
+             # TYPE portguard_rejected_connections_total counter\n\
+             portguard_rejected_connections_total {rejected}\n"
+        );
+        metrics
+    }
+}
+
+/// number of open file descriptors of this process
+#[cfg(target_os = "linux")]
+pub(crate) fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|dir| dir.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_fds() -> Option<usize> {
+    None
+}
@@ -0,0 +1,263 @@
+/// rotation of server key with a grace period, so clients generated with the previous key
+/// keep working while they are replaced by clients of the new key
+///
+/// in config, written by `gen-key --grace 30d`:
+/// [previous_key]
+/// pubkey = "..."
+/// prikey = "..."
+/// until = "2026-12-01T00:00:00Z"
+///
+/// first handshake message of client is encrypted to the server key it has,
+/// server peeks the message and reads it with the previous key if the current one cannot,
+/// and re-issues the new key to clients of the previous key, see `reissue`
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use snowstorm::NoiseStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::consts::PATTERN;
+use crate::server::HANDSHAKE_TIMEOUT;
+
+/// previous key of server, accepted until its grace period ends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PreviousKey {
+    #[serde(with = "crate::server::base64_serde")]
+    pub(crate) pubkey: Vec<u8>,
+    #[serde(with = "crate::server::base64_serde")]
+    pub(crate) prikey: Vec<u8>,
+    /// end of grace period
+    #[serde(with = "rfc3339")]
+    pub(crate) until: SystemTime,
+}
+
+impl PreviousKey {
+    pub(crate) fn new(pubkey: Vec<u8>, prikey: Vec<u8>, grace: Duration) -> Self {
+        PreviousKey {
+            pubkey,
+            prikey,
+            until: SystemTime::now() + grace,
+        }
+    }
+    /// still accepted
+    pub(crate) fn is_active(&self) -> bool {
+        SystemTime::now() < self.until
+    }
+    pub(crate) fn until(&self) -> String {
+        humantime::format_rfc3339_seconds(self.until).to_string()
+    }
+}
+
+mod rfc3339 {
+    use std::time::SystemTime;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        humantime::format_rfc3339_seconds(*t)
+            .to_string()
+            .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        let text = String::deserialize(d)?;
+        humantime::parse_rfc3339_weak(&text).map_err(|e| {
+            serde::de::Error::custom(format!(
+                "invalid time {text}, {e}, e.g. 2026-12-01T00:00:00Z"
+            ))
+        })
+    }
+}
+
+/// clients told the new server key, and hashes of their binaries rewritten with it
+#[derive(Debug, Default)]
+pub(crate) struct Reissued {
+    /// clients told the new key in their handshake, a hash they send next is of rewritten binary
+    pending: DashMap<Vec<u8>, ()>,
+    /// hashes of rewritten binaries by client key, besides hashes in config
+    hashes: DashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Reissued {
+    /// tell client the new server key after `Status::Rotated`
+    pub(crate) async fn offer<S>(
+        &self,
+        stream: &mut NoiseStream<S>,
+        client: &[u8],
+        pubkey: &[u8],
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(pubkey).await?;
+        self.pending.insert(client.to_vec(), ());
+        Ok(())
+    }
+    /// record hash of binary of client, if it is told the new key in this handshake
+    pub(crate) fn record(&self, client: &[u8], hash: &[u8]) -> bool {
+        let told = self.pending.remove(client).is_some();
+        if told {
+            self.hashes.insert(client.to_vec(), hash.to_vec());
+        }
+        told
+    }
+    /// whether hash is of binary client rewrote with the new key
+    pub(crate) fn accepts(&self, client: &[u8], hash: &[u8]) -> bool {
+        self.hashes.get(client).is_some_and(|h| *h == hash)
+    }
+}
+
+/// peek first handshake message of client, without consuming it,
+/// failing if client does not send it in handshake timeout
+pub(crate) async fn peek_message(stream: &TcpStream) -> io::Result<Vec<u8>> {
+    // length first, then the message of that length
+    let mut buf = vec![0; 2];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            if n == 0 {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+            }
+            if n == buf.len() {
+                let len = 2 + u16::from_le_bytes([buf[0], buf[1]]) as usize;
+                if buf.len() == len {
+                    return Ok(buf.split_off(2));
+                }
+                buf.resize(len, 0);
+                continue;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, peek)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// whether first handshake message is encrypted to server key `prikey`
+pub(crate) fn reads(prikey: &[u8], message: &[u8]) -> bool {
+    let responder = PATTERN.parse().ok().and_then(|params| {
+        snowstorm::Builder::new(params)
+            .local_private_key(prikey)
+            .build_responder()
+            .ok()
+    });
+    // payload is never longer than message carrying it
+    let mut payload = vec![0; message.len()];
+    responder.is_some_and(|mut r| r.read_message(message, &mut payload).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use snowstorm::Keypair;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn keypair() -> Keypair {
+        snowstorm::Builder::new(PATTERN.parse().unwrap())
+            .generate_keypair()
+            .unwrap()
+    }
+
+    /// first handshake message of a client with server key `pubkey`, with its length
+    fn first_message(pubkey: &[u8]) -> Vec<u8> {
+        let client = keypair();
+        let mut initiator = snowstorm::Builder::new(PATTERN.parse().unwrap())
+            .local_private_key(&client.private)
+            .remote_public_key(pubkey)
+            .build_initiator()
+            .unwrap();
+        let mut message = vec![0; 65535];
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        let mut framed = (len as u16).to_le_bytes().to_vec();
+        framed.extend_from_slice(&message[..len]);
+        framed
+    }
+
+    #[test]
+    fn previous_key_is_active_in_grace_period() {
+        let key = PreviousKey::new(vec![1; 32], vec![2; 32], Duration::from_secs(60));
+        assert!(key.is_active());
+        let expired = PreviousKey {
+            until: SystemTime::now() - Duration::from_secs(1),
+            ..key
+        };
+        assert!(!expired.is_active());
+    }
+
+    #[test]
+    fn message_is_read_by_key_it_is_encrypted_to() {
+        let (current, previous) = (keypair(), keypair());
+        let message = first_message(&previous.public);
+        assert!(reads(&previous.private, &message[2..]));
+        assert!(!reads(&current.private, &message[2..]));
+        assert!(!reads(&previous.private, &message[3..]));
+        assert!(!reads(&previous.private, &[]));
+    }
+
+    #[test]
+    fn until_round_trips_as_rfc3339() {
+        let key = PreviousKey {
+            pubkey: vec![1; 32],
+            prikey: vec![2; 32],
+            until: humantime::parse_rfc3339("2026-12-01T00:00:00Z").unwrap(),
+        };
+        let text = toml::to_string(&key).unwrap();
+        assert!(text.contains("until = \"2026-12-01T00:00:00Z\""), "{text}");
+        let parsed: PreviousKey = toml::from_str(&text).unwrap();
+        assert_eq!(parsed.until, key.until);
+        assert_eq!(parsed.until(), "2026-12-01T00:00:00Z");
+        assert_eq!((parsed.pubkey, parsed.prikey), (key.pubkey, key.prikey));
+        // weak form without zone is accepted, invalid times are errors
+        let weak = text.replace("2026-12-01T00:00:00Z", "2026-12-01 00:00:00");
+        assert_eq!(
+            toml::from_str::<PreviousKey>(&weak).unwrap().until,
+            key.until
+        );
+        let invalid = text.replace("2026-12-01T00:00:00Z", "next year");
+        let err = toml::from_str::<PreviousKey>(&invalid).unwrap_err();
+        assert!(err.to_string().contains("invalid time next year"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn message_is_peeked_without_consuming() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let framed = first_message(&keypair().public);
+        // sent in parts
+        let sender = tokio::spawn(async move {
+            for part in framed.chunks(40) {
+                conn.write_all(part).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            (conn, framed)
+        });
+        let message = peek_message(&stream).await.unwrap();
+        let (_conn, framed) = sender.await.unwrap();
+        assert_eq!(message, framed[2..]);
+        let mut consumed = vec![0; framed.len()];
+        stream.read_exact(&mut consumed).await.unwrap();
+        assert_eq!(consumed, framed);
+    }
+
+    #[tokio::test]
+    async fn stalled_message_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        // length of a message that never comes
+        conn.write_all(&[100, 0, 1]).await.unwrap();
+        let err = peek_message(&stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
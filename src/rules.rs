@@ -0,0 +1,280 @@
+/// rules of socks5 targets, restricting targets of built-in socks5 server,
+/// or deciding targets routed through tunnel by split tunneling client
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::acl::AllowedNet;
+
+/// hosts matched by a rule
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum RuleHost {
+    /// any host
+    Any,
+    /// ip address or network, matching resolved address of domains too
+    Net(AllowedNet),
+    /// domain and its subdomains, matching requested domain name only
+    Domain(String),
+}
+
+/// rule of socks5 targets, in form of `[!]host[:ports]`, e.g.
+/// "*.example.com:443", "10.0.0.0/8:22,8000-8100", "[fd00::/8]:22", "!192.168.0.0/16", "*"
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) struct TargetRule {
+    /// deny instead of allow matched targets
+    deny: bool,
+    host: RuleHost,
+    /// allowed port ranges, all ports if empty
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl TargetRule {
+    /// negated rule, deny if `self` allows and vice versa
+    pub(crate) fn negate(mut self) -> Self {
+        self.deny = !self.deny;
+        self
+    }
+    fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+        let host = match &self.host {
+            RuleHost::Any => true,
+            RuleHost::Net(net) => ip.is_some_and(|ip| net.contains(ip)),
+            RuleHost::Domain(suffix) => domain.is_some_and(|d| {
+                let d = d.trim_end_matches('.').to_ascii_lowercase();
+                d == *suffix || d.ends_with(&format!(".{suffix}"))
+            }),
+        };
+        host && (self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port)))
+    }
+}
+
+/// check target against rules, first matching rule decides,
+/// all targets are allowed if there is no rule, and denied if no rule matches,
+/// ip rules never match if address of domain is unknown
+pub(crate) fn allows(
+    rules: &[TargetRule],
+    domain: Option<&str>,
+    ip: Option<IpAddr>,
+    port: u16,
+) -> bool {
+    rules.is_empty()
+        || rules
+            .iter()
+            .find(|r| r.matches(domain, ip, port))
+            .is_some_and(|r| !r.deny)
+}
+
+fn parse_ports(s: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    let invalid = || format!("invalid ports: {s}");
+    s.split(',')
+        .map(|p| match p.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (lo.trim().parse(), hi.trim().parse());
+                match (lo, hi) {
+                    (Ok(lo), Ok(hi)) if lo <= hi => Ok(lo..=hi),
+                    _ => Err(invalid()),
+                }
+            }
+            None => p.trim().parse().map(|p| p..=p).map_err(|_| invalid()),
+        })
+        .collect()
+}
+
+impl FromStr for TargetRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (deny, rule) = match s.trim().strip_prefix('!') {
+            Some(rule) => (true, rule),
+            None => (false, s.trim()),
+        };
+        // ipv6 with ports must be bracketed
+        let (host, ports) = match rule.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, ports)) => match ports.strip_prefix(':') {
+                    Some(ports) => (host, Some(ports)),
+                    None => Err(format!("invalid socks5 rule: {s}"))?,
+                },
+                None => Err(format!("invalid socks5 rule: {s}"))?,
+            },
+            None if rule.matches(':').count() > 1 => (rule, None),
+            None => match rule.split_once(':') {
+                Some((host, ports)) => (host, Some(ports)),
+                None => (rule, None),
+            },
+        };
+        let host = match host {
+            "" => Err(format!("invalid socks5 rule: {s}"))?,
+            "*" => RuleHost::Any,
+            _ if host.contains('/') || host.parse::<IpAddr>().is_ok() => {
+                RuleHost::Net(host.parse()?)
+            }
+            _ => {
+                let domain = host.trim_start_matches("*.").trim_start_matches('.');
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                if domain.is_empty()
+                    || !domain
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                {
+                    Err(format!("invalid domain in socks5 rule: {s}"))?
+                }
+                RuleHost::Domain(domain)
+            }
+        };
+        let ports = ports.map(parse_ports).transpose()?.unwrap_or_default();
+        Ok(TargetRule { deny, host, ports })
+    }
+}
+
+impl fmt::Display for TargetRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.deny {
+            write!(f, "!")?;
+        }
+        match &self.host {
+            RuleHost::Any => write!(f, "*")?,
+            RuleHost::Net(net) if self.ports.is_empty() => write!(f, "{net}")?,
+            RuleHost::Net(net) if net.to_string().contains(':') => write!(f, "[{net}]")?,
+            RuleHost::Net(net) => write!(f, "{net}")?,
+            RuleHost::Domain(domain) => write!(f, "*.{domain}")?,
+        }
+        for (i, r) in self.ports.iter().enumerate() {
+            let sep = if i == 0 { ':' } else { ',' };
+            match r.start() == r.end() {
+                true => write!(f, "{sep}{}", r.start())?,
+                false => write!(f, "{sep}{}-{}", r.start(), r.end())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TargetRule {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetRule {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<TargetRule> {
+        rules.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn domain_suffix_matching() {
+        let rules = rules(&["*.example.com:443"]);
+        let cases = [
+            ("example.com", 443, true),
+            ("www.example.com", 443, true),
+            ("a.b.example.com.", 443, true),
+            ("WWW.Example.COM", 443, true),
+            ("www.example.com", 80, false),
+            ("badexample.com", 443, false),
+            ("example.com.evil.org", 443, false),
+            ("example.org", 443, false),
+        ];
+        for (domain, port, allowed) in cases {
+            assert_eq!(
+                allows(&rules, Some(domain), None, port),
+                allowed,
+                "{domain}:{port}"
+            );
+        }
+        // domain rules never match bare addresses
+        let ip = "93.184.216.34".parse().ok();
+        assert!(!allows(&rules, None, ip, 443));
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let rules = rules(&["10.0.0.0/8:22,8000-8100", "[fd00::/8]:22", "192.168.1.1"]);
+        let cases = [
+            ("10.1.2.3", 22, true),
+            ("10.255.255.255", 8050, true),
+            ("10.1.2.3", 8101, false),
+            ("11.0.0.1", 22, false),
+            ("fd12::1", 22, true),
+            ("fe80::1", 22, false),
+            ("192.168.1.1", 12345, true),
+            ("192.168.1.2", 12345, false),
+        ];
+        for (ip, port, allowed) in cases {
+            let addr = ip.parse().ok();
+            assert_eq!(allows(&rules, None, addr, port), allowed, "{ip}:{port}");
+        }
+        // ip rules match resolved address of domains, and nothing if unresolved
+        let addr = "10.0.0.1".parse().ok();
+        assert!(allows(&rules, Some("intra.corp"), addr, 22));
+        assert!(!allows(&rules, Some("intra.corp"), None, 22));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = rules(&["!10.0.0.1", "10.0.0.0/8", "!*.internal", "*"]);
+        let ip = |s: &str| s.parse().ok();
+        assert!(!allows(&rules, None, ip("10.0.0.1"), 80));
+        assert!(allows(&rules, None, ip("10.0.0.2"), 80));
+        assert!(!allows(&rules, Some("db.internal"), None, 80));
+        assert!(allows(&rules, Some("example.com"), None, 80));
+        // no rule allows everything, no matching rule denies
+        assert!(allows(&[], Some("example.com"), None, 80));
+        assert!(!allows(&rules[..1], None, ip("10.0.0.2"), 80));
+    }
+
+    #[test]
+    fn rules_round_trip_through_display() {
+        let cases = [
+            ("*", "*"),
+            ("!*", "!*"),
+            ("example.com", "*.example.com"),
+            (".Example.COM.:443", "*.example.com:443"),
+            ("10.0.0.0/8:22,8000-8100", "10.0.0.0/8:22,8000-8100"),
+            ("192.168.1.1", "192.168.1.1/32"),
+            ("fd00::/8", "fd00::/8"),
+            ("[fd00::/8]:22", "[fd00::/8]:22"),
+            ("!*.example.com:80", "!*.example.com:80"),
+        ];
+        for (rule, shown) in cases {
+            let parsed: TargetRule = rule.parse().unwrap();
+            assert_eq!(parsed.to_string(), shown, "{rule}");
+            assert_eq!(shown.parse::<TargetRule>().unwrap(), parsed, "{rule}");
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_errors() {
+        let invalid = [
+            "",
+            "!",
+            ":443",
+            "*.",
+            "exa mple.com",
+            "example.com:",
+            "example.com:http",
+            "example.com:100-10",
+            "example.com:70000",
+            "10.0.0.0/33",
+            "10.0.0.256/8",
+            "[fd00::/8",
+            "[fd00::/8]22",
+            "fd00::/129",
+        ];
+        for rule in invalid {
+            assert!(rule.parse::<TargetRule>().is_err(), "{rule:?}");
+        }
+    }
+}
@@ -1,34 +1,84 @@
 use std::borrow::Borrow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
-use anyhow::{anyhow, Result};
 use blake2::{Blake2s256, Digest};
 use dashmap::DashMap;
+use fast_socks5::client::Socks5Stream;
+use fast_socks5::server::Socks5Socket;
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::Socks5Command;
 use log;
 use serde::{Deserialize, Serialize};
-use snowstorm::{NoiseStream, SnowstormError};
-use std::time::Duration;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use snowstorm::{Keypair, NoiseStream, SnowstormError};
+use socket2::SockRef;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::time::timeout;
-use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::sync::CancellationToken;
 
 pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// max time to wait for reverse proxy connections to close on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// max time of each step of `self_test`
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// name of temporary client of `self_test`
+const SELF_TEST_CLIENT: &str = "self-test";
 
-use crate::client::ClientConfig;
-use crate::consts::{FILEHASH_LEN, PATTERN};
+use crate::admin::AdminToken;
+use crate::apply::{CurrentClient, DesiredState, Plan};
+use crate::audit;
+use crate::bench;
+use crate::bind;
+use crate::client::{ClientConfig, ClientProfile, ReconnectPolicy, SplitRules};
+use crate::cluster::{self, Cluster, NodeState};
+use crate::consts::{Status, FILEHASH_LEN, PATTERN, PUBKEY_LEN};
+use crate::diag;
+use crate::dialer::{self, Dialer, TcpDialer};
+use crate::dns::{DnsConfig, Resolver};
+use crate::early::{self, EarlyDataGuard};
+use crate::error::{Error, Result};
+use crate::exec;
+use crate::fair::{Fair, Scheduler};
+use crate::files;
+use crate::fingerprint;
 use crate::gen;
-use crate::proxy;
+use crate::gwdns::{self, GatewayDnsConfig};
+use crate::health::{self, HealthState};
+use crate::i18n::Lang;
+use crate::measure;
+use crate::migrate;
+use crate::pacing::{Paced, Pacer};
+use crate::pipeline::{self, SessionKeys};
+use crate::proxy::{self, Socks5Options};
+use crate::qos::Priority;
 use crate::remote::{Remote, Target};
+use crate::resources::{ConnectionGuard, ResourceLimits, Resources};
+use crate::rotation::{self, PreviousKey, Reissued};
+use crate::rules::{self, TargetRule};
+use crate::sockopt::SocketOpts;
+use crate::stats::{self, Stats, StatsState, STATS_SAVE_INTERVAL};
+use crate::storage::{ConfigLock, ServiceRegistration, Storage, TomlStorage};
+use crate::tasks::{Tasks, STOP_TIMEOUT};
+use crate::telemetry::{self, ClientVersion, Fleet};
+use crate::tenant::{ServiceKey, Tenant, TenantUsage};
+use crate::ticket::{self, Hello, TicketIssuer};
+use crate::upstream::Upstream;
+use crate::web::{self, HttpRoute};
 
 // type ConnMap = HashMap<usize, Mutex<yamux::Control>>;
 
 /// copy from https://users.rust-lang.org/t/serialize-a-vec-u8-to-json-as-base64/57781/2
-mod base64_serde {
+pub(crate) mod base64_serde {
     use serde::{Deserialize, Serialize};
     use serde::{Deserializer, Serializer};
 
@@ -39,7 +89,8 @@ mod base64_serde {
 
     pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
         let base64 = String::deserialize(d)?;
-        base64::decode(base64.as_bytes()).map_err(serde::de::Error::custom)
+        base64::decode(base64.as_bytes())
+            .map_err(|e| serde::de::Error::custom(format!("invalid base64, {e}")))
     }
 }
 
@@ -49,8 +100,66 @@ struct FileHash {
     hash: Vec<u8>,
 }
 
-#[derive(Eq, Debug, Serialize, Deserialize)]
-struct ClientEntry {
+/// client in `clients` named `name`, or with public key or fingerprint `name`
+fn find_client_in<'a>(
+    clients: impl IntoIterator<Item = &'a ClientEntry>,
+    name: &str,
+) -> Result<&'a ClientEntry> {
+    let matched: Vec<&ClientEntry> = clients
+        .into_iter()
+        .filter(|c| {
+            c.name == name
+                || base64::encode(&c.pubkey) == name
+                || fingerprint::matches(name, &c.pubkey)
+        })
+        .collect();
+    match matched[..] {
+        [client] => Ok(client),
+        [] => Err(Error::Config(format!("client {name} not found in config"))),
+        _ => Err(Error::Config(format!(
+            "client name {name} is used by several clients, select one by its pubkey: {}",
+            matched
+                .iter()
+                .map(|c| fingerprint::of(&c.pubkey))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// lowercase hex of hash, as printed by `b2sum`
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// noise stream accepted from client, with keys of session, key of client, early data
+/// and whether it asks for measurement
+type Accepted = (
+    NoiseStream<TcpStream>,
+    SessionKeys,
+    Vec<u8>,
+    Option<Vec<u8>>,
+    bool,
+);
+
+/// result of `verify-cli`, binary matches config if all checks are ok
+#[derive(Serialize)]
+struct VerifyReport {
+    hash: String,
+    ok: bool,
+    checks: Vec<VerifyCheck>,
+}
+
+#[derive(Serialize)]
+struct VerifyCheck {
+    item: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+/// client allowed to connect, kept in config or `Storage`
+#[derive(Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct ClientEntry {
     /// user name
     name: String,
     /// client public key for auth
@@ -61,6 +170,15 @@ struct ClientEntry {
     filehash: Option<FileHash>,
     /// client specified remote address
     remote: Option<Remote>,
+    /// client specified socks5 target rules, overrides `socks5_rules` of server
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    socks5_rules: Option<Vec<TargetRule>>,
+    /// tenant of client, services it registers and visits are in its space of ids
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    tenant: Option<String>,
+    /// priority of client, "high", "normal" or "low"
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    priority: Option<Priority>,
 }
 
 impl PartialEq for ClientEntry {
@@ -68,6 +186,15 @@ impl PartialEq for ClientEntry {
         self.pubkey == other.pubkey
     }
 }
+impl ClientEntry {
+    pub(crate) fn pubkey(&self) -> &[u8] {
+        &self.pubkey
+    }
+    /// clients are saved in this order, so that saved config does not change between runs
+    pub(crate) fn sort_key(&self) -> (&str, &[u8]) {
+        (&self.name, &self.pubkey)
+    }
+}
 impl Hash for ClientEntry {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.pubkey.hash(state);
@@ -79,6 +206,52 @@ impl Borrow<[u8]> for ClientEntry {
     }
 }
 
+/// portguard server that is allowed to be the next hop of relay,
+/// its key is embedded in clients generated with relay target to it
+#[derive(Debug, Serialize, Deserialize)]
+struct NextHop {
+    /// address of next hop server
+    addr: SocketAddr,
+    /// public key of next hop server
+    #[serde(with = "base64_serde")]
+    pubkey: Vec<u8>,
+}
+
+/// key of a revoked client, e.g. a compromised one,
+/// rejected even if a client with the same key is added again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RevokedKey {
+    #[serde(with = "base64_serde")]
+    pubkey: Vec<u8>,
+    /// name of client when it was revoked
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    reason: Option<String>,
+}
+
+/// limit of concurrent visitor streams, bandwidth and scheduling of a reverse proxy service
+#[derive(Debug, Serialize, Deserialize)]
+struct ServiceLimit {
+    /// service id
+    id: usize,
+    /// max concurrent visitor streams, unlimited if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    max_streams: Option<usize>,
+    /// max bytes per second of each direction, shared by all visitor streams
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    max_rate: Option<u64>,
+    /// max visitors waiting for a free stream, rejected as busy if exceeded
+    #[serde(default)]
+    queue: usize,
+    /// send small writes of interactive visitors before bulk transfers
+    #[serde(default)]
+    fair: bool,
+    /// tenant of service
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    tenant: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ServerConfig {
     /// server public ip or domain
@@ -96,11 +269,133 @@ struct ServerConfig {
     /// server private key
     #[serde(with = "base64_serde", default)]
     prikey: Vec<u8>,
+    /// other nodes of the cluster sharing this config,
+    /// visitors of services registered on them are forwarded
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    peers: Vec<SocketAddr>,
+    /// upstream proxy used to reach targets
+    #[serde(skip_serializing_if = "is_direct", default)]
+    upstream: Upstream,
+    /// file containing server private key in base64, e.g. a mounted secret,
+    /// `prikey` is never saved to config file if this is set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    prikey_file: Option<PathBuf>,
+    /// toml file containing extra `[[clients]]`, e.g. a mounted secret,
+    /// these clients are never saved to config file
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    clients_file: Option<PathBuf>,
+    /// address of http health check endpoint (`GET /healthz`)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    health_addr: Option<SocketAddr>,
+    /// mark packets to clients and their targets with dscp of client priority
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    dscp: bool,
+    /// directories clients of "files" target can copy files from and to
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    file_dirs: Vec<PathBuf>,
+    /// file to persist aggregate statistics, read by `stats` subcommand
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    stats_file: Option<PathBuf>,
+    /// file listing services online now with their clients, for tools watching the server
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    services_file: Option<PathBuf>,
+    /// targets built-in socks5 server can connect to, all targets if empty
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    socks5_rules: Vec<TargetRule>,
+    /// address of http reverse proxy to services, routed by `http_routes`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    http_addr: Option<SocketAddr>,
+    /// seconds a session resumption ticket is valid, no tickets are issued if 0
+    #[serde(
+        default = "default_ticket_lifetime",
+        skip_serializing_if = "is_default_ticket_lifetime"
+    )]
+    ticket_lifetime: u64,
+    /// max length of queue of pending connections of main listener
+    #[serde(
+        default = "default_listen_backlog",
+        skip_serializing_if = "is_default_listen_backlog"
+    )]
+    listen_backlog: u32,
+    /// max inbound connections handled at the same time, new ones are shed beyond it
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    max_connections: Option<usize>,
+    /// max open file descriptors of server, new connections are shed beyond it,
+    /// only counted on linux
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    max_open_fds: Option<usize>,
+    /// clients reporting a version older than this use deprecated protocol features,
+    /// they are alerted in log and counted in metrics
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    deprecated_below: Option<ClientVersion>,
+    /// clients older than this are rejected and told to update,
+    /// including clients older than version reports
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    min_client_version: Option<ClientVersion>,
+    // fields below are tables, must be placed after values for toml serialization
     /// sequence of clients
-    #[serde(skip_serializing_if = "HashSet::is_empty", default)]
+    #[serde(
+        skip_serializing_if = "HashSet::is_empty",
+        serialize_with = "serialize_sorted",
+        default
+    )]
+    clients: HashSet<ClientEntry>,
+    /// servers allowed as next hop of relay targets
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    next_hops: Vec<NextHop>,
+    /// upstream proxy for specified targets, overrides `upstream`
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    target_upstreams: HashMap<SocketAddr, Upstream>,
+    /// dns policy for hostnames server connects to
+    #[serde(skip_serializing_if = "DnsConfig::is_default", default)]
+    dns: DnsConfig,
+    /// options of built-in socks5 server
+    #[serde(skip_serializing_if = "Socks5Options::is_default", default)]
+    socks5: Socks5Options,
+    /// options of tunnel sockets: connections of clients, to cluster peers and next hops,
+    /// tos of client priority overrides it if `dscp` is set
+    #[serde(skip_serializing_if = "SocketOpts::is_default", default)]
+    socket: SocketOpts,
+    /// limits of concurrent visitor streams per service
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    service_limits: Vec<ServiceLimit>,
+    /// routes of http reverse proxy by path prefix
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    http_routes: Vec<HttpRoute>,
+    /// dns responder publishing names of online services
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    gateway_dns: Option<GatewayDnsConfig>,
+    /// tenants sharing this server
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    tenants: Vec<Tenant>,
+    /// tokens of admin api on `health_addr`, each with a role
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    admin_tokens: Vec<AdminToken>,
+    /// keys of revoked clients, never accepted
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    revoked_keys: Vec<RevokedKey>,
+    /// previous server key, still accepted in its grace period after `gen-key --grace`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    previous_key: Option<PreviousKey>,
+    /// clients loaded from `clients_file`
+    #[serde(skip)]
+    mounted_clients: HashSet<ClientEntry>,
+}
+
+/// content of `clients_file`
+#[derive(Debug, Deserialize)]
+struct ClientsFile {
+    #[serde(default)]
     clients: HashSet<ClientEntry>,
 }
 
+/// `[[revoked_keys]]` tables of config file
+#[derive(Debug, Serialize, Deserialize)]
+struct RevokedKeys {
+    #[serde(default)]
+    revoked_keys: Vec<RevokedKey>,
+}
+
 fn default_port() -> u16 {
     8022
 }
@@ -113,31 +408,627 @@ fn default_remote() -> Remote {
     Remote::Proxy(Target::Socks5)
 }
 
+/// serialize clients in a stable order, so that saved config does not change between runs
+fn serialize_sorted<S: serde::Serializer>(
+    clients: &HashSet<ClientEntry>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let mut clients: Vec<&ClientEntry> = clients.iter().collect();
+    clients.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    s.collect_seq(clients)
+}
+
+fn default_ticket_lifetime() -> u64 {
+    3600
+}
+
+fn is_default_ticket_lifetime(lifetime: &u64) -> bool {
+    *lifetime == default_ticket_lifetime()
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn is_default_listen_backlog(backlog: &u32) -> bool {
+    *backlog == default_listen_backlog()
+}
+
+fn is_direct(upstream: &Upstream) -> bool {
+    *upstream == Upstream::Direct
+}
+
+fn failing_client(content: &str) -> Option<usize> {
+    diag::failing_entry::<ClientEntry>(content, "clients")
+}
+
 impl ServerConfig {
-    fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let content = toml::ser::to_string(self)?;
+    /// parse config, errors point to offending key and value
+    fn parse(content: &str) -> Result<Self> {
+        toml::de::from_str(content).map_err(|e| {
+            diag::config_error(content, e, |table| match table {
+                "clients" => failing_client(content),
+                "next_hops" => diag::failing_entry::<NextHop>(content, table),
+                "service_limits" => diag::failing_entry::<ServiceLimit>(content, table),
+                "tenants" => diag::failing_entry::<Tenant>(content, table),
+                _ => None,
+            })
+        })
+    }
+    /// load keys and clients from secret files
+    fn load_secrets(&mut self) -> Result<()> {
+        if let Some(path) = &self.prikey_file {
+            let content = std::fs::read_to_string(path)?;
+            self.prikey = base64::decode(content.trim())?;
+            if self.pubkey.is_empty() {
+                self.pubkey = gen::derive_pubkey(&self.prikey)?;
+            }
+        }
+        if let Some(path) = &self.clients_file {
+            let content = std::fs::read_to_string(path)?;
+            let file: ClientsFile = toml::de::from_str(&content)
+                .map_err(|e| diag::config_error(&content, e, |_| failing_client(&content)))?;
+            self.mounted_clients = file.clients;
+        }
+        Ok(())
+    }
+    /// find client by public key, revoked keys are never found
+    fn client(&self, key: &[u8]) -> Option<&ClientEntry> {
+        if self.revoked(key).is_some() {
+            return None;
+        }
+        self.clients
+            .get(key)
+            .or_else(|| self.mounted_clients.get(key))
+    }
+    fn revoked(&self, key: &[u8]) -> Option<&RevokedKey> {
+        self.revoked_keys.iter().find(|r| r.pubkey == key)
+    }
+    /// previous server key if its grace period is not over
+    fn previous_key(&self) -> Option<&PreviousKey> {
+        self.previous_key.as_ref().filter(|k| k.is_active())
+    }
+    fn warn_previous_key(&self) {
+        match &self.previous_key {
+            Some(key) if key.is_active() => log::info!(
+                "Previous server key {} is accepted until {}",
+                fingerprint::of(&key.pubkey),
+                key.until()
+            ),
+            Some(key) => log::warn!(
+                "Grace period of previous server key {} ended at {}, clients with it are rejected, remove it from config",
+                fingerprint::of(&key.pubkey),
+                key.until()
+            ),
+            None => {}
+        }
+    }
+    /// warn about clients added again with revoked keys, they are rejected
+    fn warn_revoked_clients(&self) {
+        for client in self.clients.iter().chain(&self.mounted_clients) {
+            if self.revoked(&client.pubkey).is_some() {
+                log::warn!(
+                    "Client {} has a revoked key, its connections are rejected",
+                    client.name
+                );
+            }
+        }
+    }
+    /// clients named `name`, including mounted ones
+    fn clients_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a ClientEntry> {
+        self.clients
+            .iter()
+            .chain(&self.mounted_clients)
+            .filter(move |c| c.name == name)
+    }
+    /// names of clients should be unique, warn about names used by several clients,
+    /// as configs of older versions may have them
+    fn warn_duplicate_names(&self) {
+        let mut names: Vec<&str> = self
+            .clients
+            .iter()
+            .chain(&self.mounted_clients)
+            .map(|c| c.name.as_str())
+            .collect();
+        names.sort_unstable();
+        for name in names.windows(2).filter(|w| w[0] == w[1]).map(|w| w[0]) {
+            log::warn!(
+                "Client name {name} is used by several clients, rename them with `rename-cli`"
+            );
+        }
+    }
+    /// check public key of a client generated elsewhere, before it is added
+    fn check_key_importable(&self, key: &[u8]) -> Result<()> {
+        if key.len() != PUBKEY_LEN {
+            Err(Error::Config(format!(
+                "imported key has {} bytes, a x25519 public key has {PUBKEY_LEN}",
+                key.len()
+            )))?
+        }
+        if let Some(client) = self.client(key) {
+            Err(Error::Config(format!(
+                "imported key is already used by client {}",
+                client.name
+            )))?
+        }
+        if self.revoked(key).is_some() || key == self.pubkey {
+            Err(Error::Config(String::from("imported key cannot be used")))?
+        }
+        Ok(())
+    }
+    /// error if `name` is used by a client
+    fn check_name_unused(&self, name: &str) -> Result<()> {
+        match self.clients_named(name).next() {
+            Some(_) => Err(Error::Config(format!(
+                "client name {name} is already used, revoke or rename that client first"
+            ))),
+            None => Ok(()),
+        }
+    }
+    /// check that tenants of clients are defined
+    fn validate_tenants(&self) -> Result<()> {
+        for tenant in &self.tenants {
+            tenant.validate()?;
+        }
+        let clients = self.clients.iter().chain(&self.mounted_clients);
+        for client in clients {
+            if let Some(tenant) = &client.tenant {
+                self.tenant(tenant).ok_or_else(|| {
+                    Error::Config(format!(
+                        "tenant {} of client {} is not defined",
+                        tenant, client.name
+                    ))
+                })?;
+            }
+        }
+        Ok(())
+    }
+    fn tenant(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.name == name)
+    }
+    /// remote of client, default one of its tenant or server if not specified
+    fn remote_of<'a>(&'a self, client: &'a ClientEntry) -> &'a Remote {
+        client
+            .remote
+            .as_ref()
+            .or_else(|| {
+                let tenant = self.tenant(client.tenant.as_deref()?)?;
+                tenant.remote.as_ref()
+            })
+            .unwrap_or(&self.remote)
+    }
+    /// next hop server of relay target `addr`, if allowed
+    fn next_hop(&self, addr: SocketAddr) -> Result<&NextHop> {
+        self.next_hops
+            .iter()
+            .find(|h| h.addr == addr)
+            .ok_or_else(|| Error::Config(format!("next hop {addr} is not allowed")))
+    }
+    /// get upstream proxy to reach target
+    fn upstream_of(&self, target: SocketAddr) -> &Upstream {
+        self.target_upstreams.get(&target).unwrap_or(&self.upstream)
+    }
+    /// save config, clients are taken from file, as they are changed by storage
+    /// and others may have added some since config was loaded
+    fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let _lock = ConfigLock::acquire(path)?;
+        let saved = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| toml::de::from_str::<ClientsFile>(&content).ok());
+        if let Some(saved) = saved {
+            self.clients = saved.clients;
+        }
+        std::fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+    /// add `revoked` to config at `path`, appended as a `[[revoked_keys]]` table so other parts
+    /// are kept as they are, the whole config is rewritten if it cannot be appended
+    fn save_revoked(path: &Path, revoked: &RevokedKey) -> Result<()> {
+        let _lock = ConfigLock::acquire(path)?;
+        let content = std::fs::read_to_string(path)?;
+        let table = toml::ser::to_string(&RevokedKeys {
+            revoked_keys: vec![revoked.clone()],
+        })?;
+        let appended = match content.trim_end() {
+            "" => table,
+            content => format!("{content}\n\n{table}"),
+        };
+        // e.g. revoked keys in an inline array
+        let saved = toml::de::from_str::<RevokedKeys>(&appended)
+            .is_ok_and(|k| k.revoked_keys.iter().any(|r| r.pubkey == revoked.pubkey));
+        let content = match saved {
+            true => appended,
+            false => {
+                log::warn!(
+                    "Revoked key cannot be appended, rewriting config {:?}",
+                    path
+                );
+                let mut table: toml::value::Table = toml::de::from_str(&content)?;
+                let mut keys = match table.remove("revoked_keys") {
+                    Some(keys) => keys.try_into::<Vec<RevokedKey>>()?,
+                    None => Vec::new(),
+                };
+                keys.push(revoked.clone());
+                table.insert(String::from("revoked_keys"), toml::Value::try_from(keys)?);
+                toml::ser::to_string(&toml::Value::Table(table))?
+            }
+        };
         std::fs::write(path, content)?;
         Ok(())
     }
+    fn to_toml(&self) -> Result<String> {
+        let content = match self.prikey_file {
+            Some(_) => {
+                // keep private key only in secret file
+                let mut value = toml::Value::try_from(self)?;
+                if let Some(table) = value.as_table_mut() {
+                    table.remove("prikey");
+                }
+                toml::ser::to_string(&value)?
+            }
+            None => toml::ser::to_string(self)?,
+        };
+        Ok(content)
+    }
+}
+
+/// sessions of each client key, cancelled together when the key is revoked
+#[derive(Debug, Default)]
+struct KeySessions {
+    /// token shared by sessions of a key, and number of them
+    keys: DashMap<Vec<u8>, (CancellationToken, usize)>,
+}
+
+/// a session counted until dropped
+struct KeySession<'a> {
+    sessions: &'a KeySessions,
+    key: Vec<u8>,
+    cancel: CancellationToken,
 }
 
+impl Drop for KeySession<'_> {
+    fn drop(&mut self) {
+        // entry of a cancelled key is already removed
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        if let Some(mut entry) = self.sessions.keys.get_mut(&self.key) {
+            entry.1 -= 1;
+        }
+        self.sessions.keys.remove_if(&self.key, |_, (_, n)| *n == 0);
+    }
+}
+
+impl KeySessions {
+    fn open(&self, key: &[u8]) -> KeySession<'_> {
+        let mut entry = self.keys.entry(key.to_vec()).or_default();
+        entry.1 += 1;
+        KeySession {
+            sessions: self,
+            key: key.to_vec(),
+            cancel: entry.0.clone(),
+        }
+    }
+    /// cancel sessions of `key`, returns number of them
+    fn cancel(&self, key: &[u8]) -> usize {
+        match self.keys.remove(key) {
+            Some((_, (cancel, sessions))) => {
+                cancel.cancel();
+                sessions
+            }
+            None => 0,
+        }
+    }
+}
+
+/// visitor stream of a reverse proxy service
+pub(crate) type ServiceStream = Paced<Fair<Compat<yamux::Stream>>>;
+
+/// registered reverse proxy connection
+struct RproxyConn {
+    /// public key of rclient
+    pubkey: Vec<u8>,
+    /// sequence number of this registration
+    seq: u64,
+    control: yamux::Control,
+    /// writes of visitor streams
+    scheduler: Arc<Scheduler>,
+}
+
+/// concurrent visitor streams, bandwidth and scheduling of a service
+struct StreamLimit {
+    permits: Arc<Semaphore>,
+    queue: usize,
+    waiting: AtomicUsize,
+    pacer: Option<Arc<Pacer>>,
+    /// streams of normal priority are scheduled by their rate
+    fair: bool,
+}
+
+impl StreamLimit {
+    fn new(limit: &ServiceLimit) -> Self {
+        let permits = limit.max_streams.unwrap_or(Semaphore::MAX_PERMITS);
+        StreamLimit {
+            permits: Arc::new(Semaphore::new(permits)),
+            queue: limit.queue,
+            waiting: AtomicUsize::new(0),
+            pacer: limit.max_rate.map(|rate| Arc::new(Pacer::new(rate))),
+            fair: limit.fair,
+        }
+    }
+    /// wait for a free stream, `None` if the wait queue is full
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.queue {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        let permit = self.permits.clone().acquire_owned().await.ok();
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        permit
+    }
+}
+
+/// options of a generated client besides its name and remote, default ones generate
+/// a plain client with a fresh keypair
+#[derive(Debug, Default)]
+pub struct GenOptions {
+    /// if key passphrase is needed to protect client key
+    pub has_keypass: bool,
+    pub reconnect: ReconnectPolicy,
+    /// profiles embedded in client, each is a separate client of server
+    pub presets: Vec<ProfilePreset>,
+    pub single_instance: bool,
+    pub split: SplitRules,
+    pub resume: bool,
+    pub early_data: bool,
+    pub tenant: Option<String>,
+    pub socks5: Socks5Options,
+    pub socks5_rules: Vec<String>,
+    pub lang: Option<Lang>,
+    pub telemetry: bool,
+    /// base64 public key of a keypair generated elsewhere, no private key is embedded
+    pub pubkey: Option<String>,
+}
+
+/// profile to embed in a generated client, in form of "name:port:target",
+/// target is a target address or a service id
+#[derive(Debug, Clone)]
+pub struct ProfilePreset {
+    name: String,
+    port: u16,
+    remote: Remote,
+}
+
+impl FromStr for ProfilePreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (name, port, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(port), Some(target)) if !name.is_empty() => (name, port, target),
+            _ => return Err(format!("Invalid profile {s}, should be name:port:target")),
+        };
+        let port = port
+            .parse()
+            .map_err(|e| format!("Invalid port {port}: {e}"))?;
+        let remote = match target.parse::<usize>() {
+            Ok(id) => Remote::try_parse(None, Some(id)),
+            Err(_) => Remote::try_parse(Some(target), None),
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(ProfilePreset {
+            name: name.to_string(),
+            port,
+            remote,
+        })
+    }
+}
+
+/// env variable containing whole server config, for `--config-from-env`
+pub const CONFIG_ENV: &str = "PORTGUARD_CONFIG";
+
 /// Portguard server
 pub struct Server {
-    config_path: PathBuf,
+    /// location of config file, `None` if config is from env
+    config_path: Option<PathBuf>,
     config: ServerConfig,
-    conns: DashMap<usize, yamux::Control>,
+    conns: DashMap<ServiceKey, RproxyConn>,
+    conn_seq: AtomicU64,
+    limits: HashMap<ServiceKey, StreamLimit>,
+    resolver: Resolver,
+    dialer: Box<dyn Dialer>,
+    storage: Box<dyn Storage>,
+    cluster: Cluster,
+    /// keys revoked by admin api since start, also saved to config
+    revoked: RwLock<HashSet<Vec<u8>>>,
+    /// sessions of clients, closed when their keys are revoked
+    sessions: KeySessions,
+    health: Arc<HealthState>,
+    stats: Arc<StatsState>,
+    tickets: TicketIssuer,
+    early: EarlyDataGuard,
+    fleet: Fleet,
+    reissued: Reissued,
+    tasks: Tasks,
 }
 
 impl Server {
     pub fn build(path: impl AsRef<Path>) -> Result<Self> {
-        let content = std::fs::read_to_string(&path)?;
-        let config: ServerConfig = toml::de::from_str(&content)?;
+        let content = {
+            // not read while others are writing it
+            let _lock = ConfigLock::acquire(path.as_ref())?;
+            std::fs::read_to_string(&path)?
+        };
+        let config = ServerConfig::parse(&content)?;
+        Self::from_config(config, Some(path.as_ref().into()))
+    }
+    /// build server from config in env variable `PORTGUARD_CONFIG`
+    pub fn build_from_env() -> Result<Self> {
+        let content = std::env::var(CONFIG_ENV)
+            .map_err(|_| Error::Config(format!("env variable {} is not set", CONFIG_ENV)))?;
+        let config = ServerConfig::parse(&content)?;
+        Self::from_config(config, None)
+    }
+    fn from_config(mut config: ServerConfig, config_path: Option<PathBuf>) -> Result<Self> {
+        config.load_secrets()?;
+        config.validate_tenants()?;
+        config.warn_duplicate_names();
+        config.warn_revoked_clients();
+        config.warn_previous_key();
+        if !config.admin_tokens.is_empty() && config.health_addr.is_none() {
+            log::warn!("admin_tokens are set, but admin api is served only if health_addr is set");
+        }
+        let limits = ResourceLimits {
+            max_connections: config.max_connections,
+            max_open_fds: config.max_open_fds,
+        };
+        let health = Arc::new(HealthState {
+            resources: Arc::new(Resources::new(limits)),
+            ..Default::default()
+        });
+        let clients = config.clients.len() + config.mounted_clients.len();
+        health.clients.store(clients, Ordering::Relaxed);
+        let storage = TomlStorage::new(
+            config_path.clone(),
+            config.stats_file.clone(),
+            config.services_file.clone(),
+        );
+        let stats = storage.load_stats()?;
+        let limits = config
+            .service_limits
+            .iter()
+            .map(|l| {
+                (
+                    ServiceKey::new(l.tenant.as_deref(), l.id),
+                    StreamLimit::new(l),
+                )
+            })
+            .collect();
         Ok(Server {
+            limits,
+            health,
+            stats: Arc::new(StatsState::new(stats)),
+            tickets: TicketIssuer::new(&config.prikey, config.ticket_lifetime),
+            early: EarlyDataGuard::default(),
+            fleet: Fleet::new(config.deprecated_below.clone()),
+            reissued: Reissued::default(),
+            resolver: Resolver::new(config.dns.clone()),
+            dialer: Box::new(TcpDialer),
+            storage: Box::new(storage),
+            cluster: Cluster::default(),
+            revoked: RwLock::default(),
+            sessions: KeySessions::default(),
             config,
-            config_path: path.as_ref().into(),
+            config_path,
             conns: DashMap::new(),
+            conn_seq: AtomicU64::new(0),
+            tasks: Tasks::default(),
         })
     }
+    /// upgrade config file of older layouts to current schema, print a diff of changes,
+    /// original file is kept as `<path>.bak` unless `dry_run`
+    pub fn migrate_config(path: impl AsRef<Path>, dry_run: bool) -> Result<()> {
+        let path = path.as_ref();
+        let _lock = ConfigLock::acquire(path)?;
+        let content = std::fs::read_to_string(path)?;
+        let mut table: toml::value::Table =
+            toml::de::from_str(&content).map_err(|e| diag::config_error(&content, e, |_| None))?;
+        let (changes, warnings) = migrate::migrate(&mut table);
+        for change in changes {
+            log::info!("Migrate: {}", change);
+        }
+        for warning in warnings {
+            log::warn!("Manual fix needed: {}", warning);
+        }
+        // validate against current schema, then rewrite in current layout
+        let config: ServerConfig = toml::Value::Table(table).try_into()?;
+        let migrated = config.to_toml()?;
+        if migrate::same_config(&content, &migrated) {
+            log::info!("Config is up to date");
+            return Ok(());
+        }
+        print!("{}", migrate::diff(&content, &migrated));
+        if dry_run {
+            log::info!("Dry run, config is not changed");
+        } else {
+            let backup = format!("{}.bak", path.display());
+            std::fs::copy(path, &backup)?;
+            std::fs::write(path, migrated)?;
+            log::info!("Config is migrated, original one is saved to {}", backup);
+        }
+        Ok(())
+    }
+    /// print statistics saved in `stats_file` of config
+    pub fn print_stats(path: impl AsRef<Path>, json: bool) -> Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        let config = ServerConfig::parse(&content)?;
+        let path = config
+            .stats_file
+            .ok_or_else(|| Error::Config(String::from("stats_file is not set in config")))?;
+        let stats = Stats::load(&path)?;
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&stats)?),
+            false => print!("{}", stats.report()),
+        }
+        Ok(())
+    }
+    /// remove registrations left by a server not stopped cleanly, their services are offline
+    fn clear_stale_services(&self) {
+        let stale = match self.storage.load_services() {
+            Ok(stale) => stale,
+            Err(e) => {
+                log::warn!("Failed to load registrations of services. Error: {}", e);
+                return;
+            }
+        };
+        for registration in stale {
+            log::info!(
+                "Service {} of client {} is left registered since {}, it is offline now",
+                registration.service,
+                registration.client,
+                registration.since
+            );
+            if let Err(e) = self.storage.unregister_service(&registration.service) {
+                log::warn!("Failed to remove registration of service. Error: {}", e);
+            }
+        }
+    }
+    fn save_stats(&self) {
+        if let Err(e) = self.storage.save_stats(&self.stats.snapshot()) {
+            log::warn!("Failed to save statistics. Error: {}", e);
+        }
+    }
+    /// tasks of running server, shutting them down stops `run_server_proxy`
+    pub fn tasks(&self) -> Tasks {
+        self.tasks.clone()
+    }
+    /// use a custom dialer to reach targets and upstream proxies
+    pub fn with_dialer(mut self, dialer: impl Dialer + 'static) -> Self {
+        self.dialer = Box::new(dialer);
+        self
+    }
+    /// keep clients registered at runtime, statistics and registrations of services
+    /// in a custom storage, instead of config file, `stats_file` and `services_file`
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Result<Self> {
+        self.config.clients.extend(storage.load_clients()?);
+        self.config.validate_tenants()?;
+        let clients = self.config.clients.len() + self.config.mounted_clients.len();
+        self.health.clients.store(clients, Ordering::Relaxed);
+        self.stats = Arc::new(StatsState::new(storage.load_stats()?));
+        self.storage = Box::new(storage);
+        Ok(self)
+    }
+    fn save_config(&mut self) -> Result<()> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| Error::Config(String::from("config from env cannot be saved")))?;
+        self.config.save(path)
+    }
     /// code for generation
     pub fn gen_client<P: AsRef<Path>>(
         &mut self,
@@ -145,19 +1036,132 @@ impl Server {
         out_path: P,
         username: String,
         oremote: Option<Remote>,
-        has_keypass: bool,
+        opts: GenOptions,
     ) -> Result<()> {
-        // 1. set client config
-        let keypair = gen::gen_keypair(has_keypass)?;
-        let remote = oremote.unwrap_or(self.config.remote);
+        let GenOptions {
+            has_keypass,
+            reconnect,
+            presets,
+            single_instance,
+            split,
+            resume,
+            early_data,
+            tenant,
+            socks5,
+            socks5_rules,
+            lang,
+            telemetry,
+            pubkey,
+        } = opts;
+        let pubkey = pubkey.map(|key| base64::decode(key.trim())).transpose()?;
+        if let Some(key) = &pubkey {
+            self.config.check_key_importable(key)?;
+            if has_keypass || !presets.is_empty() {
+                Err(Error::Config(String::from(
+                    "imported key has no private key to protect, nor keys of profiles",
+                )))?
+            }
+        }
+        if let Some(name) = &tenant {
+            self.config
+                .tenant(name)
+                .ok_or_else(|| Error::Config(format!("tenant {name} is not defined")))?;
+        }
+        self.config.check_name_unused(&username)?;
+        for preset in &presets {
+            self.config
+                .check_name_unused(&format!("{}-{}", username, preset.name))?;
+            if let Remote::Proxy(Target::Relay(_)) = preset.remote {
+                Err(Error::Config(format!(
+                    "remote of profile {} cannot be a relay target",
+                    preset.name
+                )))?
+            }
+        }
+        // 1. set client config, a client with imported key gets its private key at run time
+        let keypair = match pubkey {
+            Some(public) => Keypair {
+                public,
+                private: Vec::new(),
+            },
+            None => gen::gen_keypair(has_keypass)?,
+        };
+        // every profile is a separate client with its own keypair
+        let mut profiles = Vec::new();
+        let mut profile_clients = Vec::new();
+        for preset in &presets {
+            let keypair = gen::gen_keypair(has_keypass)?;
+            profiles.push(ClientProfile {
+                name: preset.name.clone(),
+                port: preset.port,
+                remote: preset.remote.clone(),
+                client_prikey: keypair.private,
+            });
+            profile_clients.push(ClientEntry {
+                name: format!("{}-{}", username, preset.name),
+                pubkey: keypair.public,
+                remote: Some(preset.remote.clone()),
+                filehash: None,
+                socks5_rules: None,
+                tenant: tenant.clone(),
+                priority: None,
+            });
+        }
+        let tenant_remote = tenant
+            .as_deref()
+            .and_then(|name| self.config.tenant(name)?.remote.clone());
+        let remote = oremote
+            .clone()
+            .or(tenant_remote)
+            .unwrap_or(self.config.remote.clone());
         let reverse = matches!(remote, Remote::RProxy(_, _));
+        // client has an end-to-end session with next hop server of relay target
+        let next_hop_pubkey = match &remote {
+            Remote::Proxy(Target::Relay(addr)) => Some(self.config.next_hop(*addr)?.pubkey.clone()),
+            _ => None,
+        };
+        if !split.is_empty() {
+            if remote != Remote::Proxy(Target::Socks5) {
+                Err(Error::Config(String::from(
+                    "split tunneling is only supported by socks5 clients",
+                )))?
+            }
+            split.tunnel_rules()?;
+        }
+        let socks5_rproxy = matches!(remote, Remote::RProxy(Target::Socks5, _));
+        if (!socks5.is_default() || !socks5_rules.is_empty()) && !socks5_rproxy {
+            Err(Error::Config(String::from(
+                "socks5 options are only for reverse proxy clients exposing socks5, \
+                 socks5 of server is set in [socks5] of config",
+            )))?
+        }
+        for rule in &socks5_rules {
+            rule.parse::<TargetRule>().map_err(Error::Config)?;
+        }
+        if early_data && !matches!(remote, Remote::Proxy(Target::Addr(_))) {
+            Err(Error::Config(String::from(
+                "early data is only supported by clients proxying to a socket address",
+            )))?
+        }
         let cli_conf: ClientConfig = ClientConfig {
             server_addr: format!("{}:{}", self.config.host, self.config.port).parse()?,
-            target_addr: remote.to_string(),
-            reverse,
+            remote: remote.clone(),
             server_pubkey: self.config.pubkey.clone(),
             client_prikey: keypair.private,
             has_keypass,
+            reconnect,
+            profiles: (!profiles.is_empty()).then_some(profiles),
+            single_instance: single_instance.then_some(true),
+            split: (!split.is_empty()).then_some(split),
+            resume: resume.then_some(true),
+            early_data: early_data.then_some(true),
+            socks5: (!socks5.is_default()).then_some(socks5),
+            socks5_rules: (!socks5_rules.is_empty()).then_some(socks5_rules),
+            lang,
+            telemetry: telemetry.then_some(true),
+            // this server reads version reports
+            version_report: Some(true),
+            next_hop_pubkey,
         };
         // 2. gen client binary
         gen::gen_client_binary(in_path.as_ref(), out_path.as_ref(), |_| cli_conf)?;
@@ -175,19 +1179,280 @@ impl Server {
             pubkey: keypair.public,
             remote: oremote,
             filehash,
+            socks5_rules: None,
+            tenant,
+            priority: None,
         };
-        self.config.clients.insert(client);
-        // 4. save server config
-        self.config.save(&self.config_path)?;
+        let mut clients = vec![client];
+        clients.extend(profile_clients);
+        // 4. save clients
+        self.storage.add_clients(&clients)?;
+        for client in &clients {
+            log::info!(
+                "Client {} key fingerprint: {}",
+                client.name,
+                fingerprint::of(&client.pubkey)
+            );
+        }
+        self.config.clients.extend(clients);
         Ok(())
     }
-    pub fn gen_key(&mut self) -> Result<()> {
+    /// public key of server, embedded in its clients
+    pub fn pubkey(&self) -> &[u8] {
+        &self.config.pubkey
+    }
+    /// record a generated client in audit log next to config
+    pub fn audit(&self, command: &str, name: &str, out_path: &Path) -> Result<()> {
+        let log = audit::path_of(self.config_path.as_deref());
+        audit::record(&log, command, Some(name), out_path)
+    }
+    /// reconcile clients in config with desired state in file `desired`, printing plan first,
+    /// clients are generated from `in_path` into `out_dir`, clients of `clients_file` are kept
+    pub fn apply(
+        &mut self,
+        desired: impl AsRef<Path>,
+        in_path: impl AsRef<Path>,
+        out_dir: impl AsRef<Path>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let desired = DesiredState::load(desired.as_ref())?;
+        let current: Vec<CurrentClient> = self
+            .config
+            .clients
+            .iter()
+            .map(|c| CurrentClient {
+                name: &c.name,
+                remote: c.remote.as_ref(),
+                tenant: c.tenant.as_deref(),
+            })
+            .collect();
+        let plan = Plan::new(&desired, &current)?;
+        print!("{plan}");
+        if dry_run || plan.is_empty() {
+            return Ok(());
+        }
+        // revoke replaced clients too, they are generated again with new keys
+        let names: HashSet<&str> = plan
+            .revoke
+            .iter()
+            .map(String::as_str)
+            .chain(plan.replace.iter().map(|c| c.name.as_str()))
+            .collect();
+        let revoked: Vec<ClientEntry> = self
+            .config
+            .clients
+            .iter()
+            .filter(|c| names.contains(c.name.as_str()))
+            .cloned()
+            .collect();
+        self.storage.remove_clients(&revoked)?;
+        for client in &revoked {
+            self.config.clients.remove(client);
+            log::info!("Client {} revoked", client.name);
+        }
+        for client in plan.create.iter().chain(&plan.replace) {
+            let out_path = client.output(out_dir.as_ref());
+            if let Some(dir) = out_path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            self.gen_client(
+                in_path.as_ref(),
+                &out_path,
+                client.name.clone(),
+                client.remote()?,
+                GenOptions {
+                    tenant: client.tenant.clone(),
+                    ..Default::default()
+                },
+            )?;
+            self.audit("apply", &client.name, &out_path)?;
+            log::info!("Client {} generated to {:?}", client.name, out_path);
+        }
+        Ok(())
+    }
+    /// find client in config by a name, a base64 public key or its fingerprint
+    fn find_client(&self, name: &str) -> Result<&ClientEntry> {
+        find_client_in(&self.config.clients, name)
+    }
+    /// rename client `from`, a name or a base64 public key, to `to`
+    pub fn rename_client(&mut self, from: &str, to: &str) -> Result<()> {
+        self.config.check_name_unused(to)?;
+        let client = self.find_client(from)?;
+        let renamed = ClientEntry {
+            name: to.to_string(),
+            ..client.clone()
+        };
+        log::info!("Client {} renamed to {}", client.name, to);
+        // same public key, so entry of client is replaced
+        self.storage.add_clients(std::slice::from_ref(&renamed))?;
+        self.config.clients.replace(renamed);
+        Ok(())
+    }
+    /// revoke client `name`, a name or a base64 public key, and remember its key in `revoked_keys`,
+    /// so it is rejected even if a client with the same key is added again
+    pub fn revoke_client(&mut self, name: &str, reason: Option<String>) -> Result<()> {
+        let client = self.find_client(name)?.clone();
+        self.config.revoked_keys.push(RevokedKey {
+            pubkey: client.pubkey.clone(),
+            name: Some(client.name.clone()),
+            reason,
+        });
+        self.save_config()?;
+        self.storage.remove_clients(std::slice::from_ref(&client))?;
+        self.config.clients.remove(&client);
+        log::info!("Client {} revoked, its key is banned", client.name);
+        Ok(())
+    }
+    /// check a client binary against config: server key and address, its client entry found by
+    /// public key, by hash, or by `name` if key is protected, then name, remote and hash,
+    /// print a report and return whether everything matches
+    pub fn verify_client(
+        &self,
+        path: impl AsRef<Path>,
+        name: Option<&str>,
+        json: bool,
+    ) -> Result<bool> {
+        let report = self.verify_report(path.as_ref(), name)?;
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&report)?),
+            false => {
+                println!("{:<12} {}", "Hash", report.hash);
+                for c in &report.checks {
+                    let status = if c.ok { "ok" } else { "MISMATCH" };
+                    println!("{:<12} {status:<9} {}", c.item, c.detail);
+                }
+            }
+        }
+        Ok(report.ok)
+    }
+    fn verify_report(&self, path: &Path, name: Option<&str>) -> Result<VerifyReport> {
+        let conf = gen::read_client_conf(path)?;
+        let hash = Blake2s256::digest(std::fs::read(path)?).to_vec();
+        let mut report = VerifyReport {
+            hash: hex(&hash),
+            ok: true,
+            checks: Vec::new(),
+        };
+        let mut check = |item: &'static str, ok: bool, detail: String| {
+            report.ok &= ok;
+            report.checks.push(VerifyCheck { item, ok, detail });
+        };
+        let previous = self
+            .config
+            .previous_key()
+            .filter(|k| k.pubkey == conf.server_pubkey)
+            .map(|k| format!(" (previous key, accepted until {})", k.until()))
+            .unwrap_or_default();
+        check(
+            "Server key",
+            conf.server_pubkey == self.config.pubkey,
+            fingerprint::of(&conf.server_pubkey) + &previous,
+        );
+        let server_addr = format!("{}:{}", self.config.host, self.config.port);
+        check(
+            "Server addr",
+            conf.server_addr.to_string() == server_addr,
+            format!("{} (config: {server_addr})", conf.server_addr),
+        );
+        let clients = || {
+            self.config
+                .clients
+                .iter()
+                .chain(&self.config.mounted_clients)
+        };
+        let pubkey = match conf.has_keypass || conf.has_external_key() {
+            true => None,
+            false => Some(gen::derive_pubkey(&conf.client_prikey)?),
+        };
+        let entry = match &pubkey {
+            Some(pubkey) => clients().find(|c| &c.pubkey == pubkey),
+            // protected or external key, a reverse proxy client is known by its hash
+            None => clients()
+                .find(|c| c.filehash.as_ref().is_some_and(|f| f.hash == hash))
+                .or_else(|| clients().find(|c| Some(c.name.as_str()) == name)),
+        };
+        let key = pubkey.as_deref().or(entry.map(|c| &c.pubkey[..]));
+        let revoked = key.and_then(|k| self.config.revoked(k));
+        let entry = match (entry, revoked) {
+            (_, Some(revoked)) => {
+                let reason = revoked.reason.as_deref().unwrap_or("no reason");
+                check("Client", false, format!("key is revoked, {reason}"));
+                return Ok(report);
+            }
+            (Some(entry), None) => entry,
+            (None, None) => {
+                let detail = match pubkey {
+                    Some(pubkey) => format!("no client with key {}", fingerprint::of(&pubkey)),
+                    None => {
+                        String::from("key is protected or not builtin, select client by --name")
+                    }
+                };
+                check("Client", false, detail);
+                return Ok(report);
+            }
+        };
+        check(
+            "Client",
+            name.is_none_or(|n| n == entry.name),
+            format!("{} ({})", entry.name, fingerprint::of(&entry.pubkey)),
+        );
+        let remote = self.config.remote_of(entry);
+        // service id of reverse proxy clients older than tagged remotes is unknown
+        let same_remote = match (&conf.remote, remote) {
+            (Remote::RProxy(t, 0), Remote::RProxy(target, _)) => t == target,
+            (embedded, remote) => embedded == remote,
+        };
+        check(
+            "Remote",
+            same_remote,
+            format!("{} (config: {remote})", conf.remote),
+        );
+        match &entry.filehash {
+            Some(filehash) => check(
+                "Hash",
+                filehash.hash == hash,
+                format!("config: {}", hex(&filehash.hash)),
+            ),
+            None if conf.is_reverse() => check(
+                "Hash",
+                false,
+                String::from("no hash of reverse proxy client"),
+            ),
+            None => {}
+        }
+        Ok(report)
+    }
+    /// generate a new server key, the current one is kept as previous key accepted
+    /// for `grace`, so clients generated with it keep working while they are replaced
+    pub fn gen_key(&mut self, grace: Option<Duration>) -> Result<()> {
+        if let Some(grace) = grace {
+            if self.config.prikey_file.is_some() {
+                Err(Error::Config(String::from(
+                    "key in prikey_file cannot be rotated with a grace period",
+                )))?
+            }
+            let previous = PreviousKey::new(
+                std::mem::take(&mut self.config.pubkey),
+                std::mem::take(&mut self.config.prikey),
+                grace,
+            );
+            log::info!(
+                "Previous server key {} is accepted until {}",
+                fingerprint::of(&previous.pubkey),
+                previous.until()
+            );
+            self.config.previous_key = Some(previous);
+        }
         // gen key
         let keypair = gen::gen_keypair(false)?;
         self.config.pubkey = keypair.public;
         self.config.prikey = keypair.private;
+        log::info!(
+            "New server key fingerprint: {}",
+            fingerprint::of(&self.config.pubkey)
+        );
         // save
-        self.config.save(&self.config_path)?;
+        self.save_config()?;
         Ok(())
     }
 
@@ -199,152 +1464,1511 @@ impl Server {
         let this2 = Arc::clone(&this1);
         let listen_addr: SocketAddr = format!("0.0.0.0:{}", this1.config.port).parse().unwrap();
         log::info!("Listening on port: {:?}", listen_addr);
+        log::debug!("Noise crypto backend: {}", bench::CRYPTO_BACKEND);
+        bind::check_fd_limit();
+        this1.clear_stale_services();
 
         // TODO: spawn to handle config hot-reloading
 
-        // spwan to handle inbound connection
-        let listener = TcpListener::bind(listen_addr).await?;
-        while let Ok((inbound, _)) = listener.accept().await {
-            let this = Arc::clone(&this2);
-            tokio::spawn(async move {
-                if let Err(e) = this.handle_connection(inbound).await {
-                    log::warn!("{}", e);
+        // spawn to cycle log level on SIGUSR1
+        #[cfg(unix)]
+        this1.tasks.spawn(async {
+            match crate::signal::Signal::new(libc::SIGUSR1) {
+                Ok(mut sig) => {
+                    while sig.recv().await.is_ok() {
+                        crate::logger::cycle_level();
+                    }
+                }
+                Err(e) => log::warn!("Failed to listen SIGUSR1. Error: {}", e),
+            }
+        });
+
+        // spawn to handle health check
+        if let Some(addr) = this1.config.health_addr {
+            let state = this1.health.clone();
+            let this = Arc::clone(&this1);
+            this1.tasks.spawn(async move {
+                if let Err(e) = health::serve_health(addr, state, this).await {
+                    log::warn!("Health check stopped. Error: {}", e);
                 }
             });
         }
+
+        // spawn to serve http reverse proxy
+        if let Some(addr) = this1.config.http_addr {
+            let routes = this1.config.http_routes.clone();
+            let this = Arc::clone(&this1);
+            this1.tasks.spawn(async move {
+                if let Err(e) = web::serve(addr, routes, this).await {
+                    log::warn!("Http reverse proxy stopped. Error: {}", e);
+                }
+            });
+        }
+
+        // spawn to publish names of online services
+        if let Some(config) = this1.config.gateway_dns.clone() {
+            let (host, port) = (this1.config.host.clone(), this1.config.port);
+            let this = Arc::clone(&this1);
+            this1.tasks.spawn(async move {
+                if let Err(e) = gwdns::serve(config, host, port, this).await {
+                    log::warn!("Gateway dns stopped. Error: {}", e);
+                }
+            });
+        }
+
+        // spawn to pull clients and revoked keys of cluster peers periodically
+        if !this1.config.peers.is_empty() {
+            let this = Arc::clone(&this1);
+            this1.tasks.spawn(async move {
+                loop {
+                    this.sync_peers().await;
+                    tokio::time::sleep(cluster::SYNC_INTERVAL).await;
+                }
+            });
+        }
+
+        // spawn to save statistics periodically
+        let this = Arc::clone(&this1);
+        this1.tasks.spawn(async move {
+            loop {
+                tokio::time::sleep(STATS_SAVE_INTERVAL).await;
+                this.save_stats();
+            }
+        });
+
+        // spwan to handle inbound connection
+        let listener = this1.listen(listen_addr)?;
+        this1.health.listening.store(true, Ordering::Relaxed);
+        let serve = async {
+            loop {
+                let (inbound, peer_addr) = bind::accept(|| listener.accept()).await;
+                let guard = match this2.admit_connection() {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        log::warn!("Refused connection from {peer_addr}. {}", e);
+                        continue;
+                    }
+                };
+                let this = Arc::clone(&this2);
+                this2.tasks.spawn(async move {
+                    let _guard = guard;
+                    if let Err(e) = this.handle_connection(inbound).await {
+                        log::warn!("{}", e);
+                    }
+                });
+            }
+        };
+        tokio::select! {
+            _ = serve => {}
+            _ = Self::shutdown_signal() => this1.close_rproxy_conns().await,
+            _ = this1.tasks.cancelled() => {}
+        }
+        this1.health.listening.store(false, Ordering::Relaxed);
+        if !this1.tasks.shutdown(STOP_TIMEOUT).await {
+            log::warn!("Timeout when stopping {} tasks", this1.tasks.len());
+        }
+        this1.save_stats();
+        Ok(())
+    }
+    /// check config without serving, for CI and before deployment:
+    /// key material, service ids of reverse proxy clients, and that listeners can be bound
+    pub fn check(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        match gen::derive_pubkey(&self.config.prikey) {
+            Ok(pubkey) if pubkey == self.config.pubkey => {}
+            Ok(_) => problems.push(String::from(
+                "pubkey does not match prikey, generate keys with `gen-key`",
+            )),
+            Err(e) => problems.push(e.to_string()),
+        }
+        let mut services: HashMap<ServiceKey, &str> = HashMap::new();
+        let clients = self
+            .config
+            .clients
+            .iter()
+            .chain(&self.config.mounted_clients);
+        for client in clients.filter(|c| self.config.revoked(&c.pubkey).is_none()) {
+            if let Remote::RProxy(_, id) = self.config.remote_of(client) {
+                let key = ServiceKey::new(client.tenant.as_deref(), *id);
+                if let Some(other) = services.insert(key.clone(), &client.name) {
+                    problems.push(format!(
+                        "service {key} is served by both client {other} and {}",
+                        client.name
+                    ));
+                }
+            }
+        }
+        // all listeners are bound before any is dropped, so they do not conflict with each other
+        let listen_addr: SocketAddr = format!("0.0.0.0:{}", self.config.port).parse().unwrap();
+        let server = self.listen(listen_addr);
+        let health = self.config.health_addr.map(std::net::TcpListener::bind);
+        let http = self.config.http_addr.map(std::net::TcpListener::bind);
+        let dns = (self.config.gateway_dns.as_ref()).map(|c| std::net::UdpSocket::bind(c.listen));
+        let binds = [
+            ("server", server.err()),
+            ("health check", health.and_then(io::Result::err)),
+            ("http reverse proxy", http.and_then(io::Result::err)),
+            ("gateway dns", dns.and_then(io::Result::err)),
+        ];
+        for (name, e) in binds {
+            if let Some(e) = e {
+                problems.push(format!("cannot listen for {name}, {e}"));
+            }
+        }
+        if problems.is_empty() {
+            log::info!(
+                "Config is valid, {} clients, listening on port {} is possible",
+                self.config.clients.len() + self.config.mounted_clients.len(),
+                self.config.port
+            );
+            return Ok(());
+        }
+        for problem in &problems {
+            log::error!("Check failed: {problem}");
+        }
+        Err(Error::Config(format!(
+            "{} problem(s) found by check",
+            problems.len()
+        )))
+    }
+    /// end-to-end self test without touching config: serve a temporary client with a fresh key
+    /// on a loopback listener, handshake as that client and proxy to a local echo target
+    pub async fn self_test(mut self) -> Result<()> {
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+        self.tasks.spawn(async move {
+            if let Ok((mut conn, _)) = echo.accept().await {
+                let (mut rd, mut wr) = conn.split();
+                tokio::io::copy(&mut rd, &mut wr).await.ok();
+            }
+        });
+        let keypair = gen::gen_keypair(false)?;
+        self.config.clients.insert(ClientEntry {
+            name: String::from(SELF_TEST_CLIENT),
+            pubkey: keypair.public,
+            remote: Some(Remote::Proxy(Target::Addr(echo_addr))),
+            filehash: None,
+            socks5_rules: None,
+            tenant: None,
+            priority: None,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let listen_addr = listener.local_addr()?;
+        let this = Arc::new(self);
+        let server = Arc::clone(&this);
+        this.tasks.spawn(async move {
+            if let Ok((inbound, _)) = listener.accept().await {
+                if let Err(e) = server.handle_connection(inbound).await {
+                    log::warn!("Self test: server failed. Error: {}", e);
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let initiator = snowstorm::Builder::new(PATTERN.parse()?)
+            .remote_public_key(&this.config.pubkey)
+            .local_private_key(&keypair.private)
+            .build_initiator()?;
+        let conn = TcpStream::connect(listen_addr).await?;
+        let mut stream = timeout(SELF_TEST_TIMEOUT, NoiseStream::handshake(conn, initiator))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|e| {
+                Error::Rejected(format!("handshake failed, server keys may be broken, {e}"))
+            })?;
+        log::info!("Self test: handshake ok in {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let probe = format!(
+            "portguard self test {}",
+            fingerprint::of(&this.config.pubkey)
+        );
+        let mut echoed = vec![0; probe.len()];
+        let transfer = async {
+            stream.write_all(probe.as_bytes()).await?;
+            stream.read_exact(&mut echoed).await
+        };
+        timeout(SELF_TEST_TIMEOUT, transfer)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "proxy to target timeout"))??;
+        if echoed != probe.as_bytes() {
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data proxied to target is corrupted",
+            )))?
+        }
+        log::info!(
+            "Self test: proxy to target {} ok in {:?}",
+            echo_addr,
+            start.elapsed()
+        );
+        this.tasks.shutdown(STOP_TIMEOUT).await;
         Ok(())
     }
+    /// wait for SIGTERM or SIGINT
+    async fn shutdown_signal() {
+        #[cfg(unix)]
+        {
+            use crate::signal::Signal;
+            match (Signal::new(libc::SIGTERM), Signal::new(libc::SIGINT)) {
+                (Ok(mut term), Ok(mut int)) => {
+                    tokio::select! {
+                        _ = term.recv() => {}
+                        _ = int.recv() => {}
+                    }
+                    return;
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    log::warn!("Failed to listen shutdown signals. Error: {}", e)
+                }
+            }
+        }
+        futures::future::pending::<()>().await
+    }
+    /// close all reverse proxy connections before exiting,
+    /// so that rclients know the server is gone and reconnect immediately
+    async fn close_rproxy_conns(&self) {
+        let controls: Vec<_> = self.conns.iter().map(|c| c.control.clone()).collect();
+        log::info!(
+            "Shutting down, closing {} reverse proxy connections",
+            controls.len()
+        );
+        let close_all = futures::future::join_all(
+            controls
+                .into_iter()
+                .map(|mut control| async move { control.close().await }),
+        );
+        if timeout(SHUTDOWN_TIMEOUT, close_all).await.is_err() {
+            log::warn!("Timeout when closing reverse proxy connections");
+        }
+    }
     /// handle inbound connection
     async fn handle_connection(&self, inbound: TcpStream) -> Result<()> {
-        let enc_inbound = self.accept_noise_stream(inbound).await?;
         // at this point, client already passed verification
-        // can use `.unwrap()` here because client must have a static key
-        let token = enc_inbound.get_state().get_remote_static().unwrap();
-        let client_remote = self.config.clients.get(token).unwrap().remote;
-        let remote = client_remote.unwrap_or(self.config.remote);
-        match remote {
-            Remote::Proxy(target) => Self::start_proxy_to_target(enc_inbound, target).await?,
-            Remote::Service(id) => self.start_proxy_to_rproxy_conn(id, enc_inbound).await?,
-            Remote::RProxy(target, id) => {
-                let enc_inbound = self.try_handshake(id, enc_inbound).await?;
-                self.start_new_rproxy_conn(enc_inbound, id, target).await?;
+        let (mut enc_inbound, keys, token, early, measure) =
+            self.accept_noise_stream(inbound).await?;
+        let token = &token[..];
+        if self.is_peer_key(token) {
+            return self.handle_peer_connection(enc_inbound).await;
+        }
+        let peer_addr = enc_inbound.get_inner().peer_addr()?;
+        // opened before client is looked up, so it is cancelled if client is revoked meanwhile
+        let session = self.sessions.open(token);
+        // client may be revoked or dropped by a peer since it is verified in handshake
+        let client = self
+            .client(token)
+            .ok_or_else(|| Error::Rejected(String::from("revoked or removed client")))?;
+        let name = client.name.clone();
+        if measure {
+            log::info!("Start measurement of {name} ({peer_addr})");
+            return measure::serve(&mut enc_inbound).await;
+        }
+        let remote = self.config.remote_of(&client).clone();
+        // services are disconnected when revoked, so they remove their registrations
+        let forward = !matches!(remote, Remote::RProxy(_, _));
+        let tenant = client.tenant.as_deref();
+        let priority = client.priority.unwrap_or_default();
+        self.mark(priority, enc_inbound.get_inner());
+        let rules = client
+            .socks5_rules
+            .as_deref()
+            .unwrap_or(&self.config.socks5_rules);
+        self.stats.record_connection(&name);
+        let served = async {
+            match remote {
+                Remote::Proxy(target) => {
+                    let bytes = self
+                        .start_proxy_to_target(
+                            (enc_inbound, &keys),
+                            &name,
+                            target,
+                            rules,
+                            early,
+                            priority,
+                        )
+                        .await?;
+                    self.stats.record_bytes(&name, bytes);
+                }
+                Remote::Service(id) => {
+                    let key = ServiceKey::new(tenant, id);
+                    self.start_proxy_to_rproxy_conn(key, enc_inbound, name.clone(), None, priority)
+                        .await?
+                }
+                Remote::Chain(id, target) => {
+                    let key = ServiceKey::new(tenant, id);
+                    let target = Some(target);
+                    self.start_proxy_to_rproxy_conn(
+                        key,
+                        enc_inbound,
+                        name.clone(),
+                        target,
+                        priority,
+                    )
+                    .await?
+                }
+                Remote::RProxy(target, id) => {
+                    let key = ServiceKey::new(tenant, id);
+                    let enc_inbound = self
+                        .try_handshake(&key, enc_inbound, token, &client)
+                        .await?;
+                    // keepalive of socket options is inherited from listener
+                    if self.config.socket.keepalive.is_none() {
+                        proxy::set_keepalive(enc_inbound.get_inner())?;
+                    }
+                    self.start_new_rproxy_conn(enc_inbound, key, target, token.to_vec())
+                        .await?;
+                }
+            };
+            Ok::<(), Error>(())
+        };
+        let served = async {
+            tokio::select! {
+                served = served => served,
+                _ = session.cancel.cancelled(), if forward => {
+                    Err(Error::Rejected(String::from("client is revoked")))
+                }
             }
         };
+        // errors after handshake are logged with the client they belong to
+        if let Err(e) = served.await {
+            log::warn!("Client {name} ({peer_addr}): {e}");
+        }
         Ok(())
     }
-    /// start to handle proxy
+    /// start to handle proxy of noise stream of client and keys of its session,
+    /// return bytes sent and received by client if known
     async fn start_proxy_to_target(
-        inbound: NoiseStream<TcpStream>,
+        &self,
+        (inbound, keys): (NoiseStream<TcpStream>, &SessionKeys),
+        name: &str,
         target: Target,
-    ) -> Result<(), io::Error> {
-        let peer_addr = inbound.get_inner().peer_addr()?;
-        match target {
+        rules: &[TargetRule],
+        early: Option<Vec<u8>>,
+        priority: Priority,
+    ) -> Result<Option<(u64, u64)>> {
+        let peer = format!("{name} ({})", inbound.get_inner().peer_addr()?);
+        let bytes = match target {
             Target::Addr(addr) => {
-                log::info!("Start proxying {peer_addr} to {addr}");
-                let outbound = TcpStream::connect(addr).await?;
-                proxy::transfer_and_log_error(inbound, outbound).await;
+                log::info!("Start proxying {peer} to {addr}");
+                let mut outbound = self
+                    .config
+                    .upstream_of(addr)
+                    .connect(addr, &self.resolver, self.dialer.as_ref())
+                    .await?;
+                self.mark(priority, &outbound);
+                if let Some(data) = early {
+                    outbound.write_all(&data).await?;
+                }
+                pipeline::transfer_and_log_error(outbound, inbound, keys)
+                    .await
+                    .map(|(received, sent)| (sent, received))
+            }
+            Target::Host(host) => {
+                let addr = self.resolver.resolve(&host).await?;
+                log::info!("Start proxying {peer} to {host} ({addr})");
+                let outbound = self
+                    .config
+                    .upstream_of(addr)
+                    .connect(addr, &self.resolver, self.dialer.as_ref())
+                    .await?;
+                self.mark(priority, &outbound);
+                pipeline::transfer_and_log_error(outbound, inbound, keys)
+                    .await
+                    .map(|(received, sent)| (sent, received))
             }
             Target::Socks5 => {
-                log::info!("Start proxying {peer_addr} to built-in socks5 server");
-                proxy::transfer_to_socks5_and_log_error(inbound).await;
+                log::info!("Start proxying {peer} to built-in socks5 server");
+                self.start_socks5(inbound, rules).await?
             }
+            Target::Netns(ns, addr) => {
+                log::info!("Start proxying {peer} to {addr} in netns {ns}");
+                let outbound = dialer::dial_in_netns(&ns, addr).await?;
+                self.mark(priority, &outbound);
+                pipeline::transfer_and_log_error(outbound, inbound, keys)
+                    .await
+                    .map(|(received, sent)| (sent, received))
+            }
+            Target::Files => {
+                log::info!("Start file transfer of {peer}");
+                files::serve(inbound, &self.config.file_dirs).await?;
+                None
+            }
+            Target::Exec(cmd) => {
+                log::info!("Start proxying {peer} to command {cmd:?}");
+                exec::transfer_to_exec(inbound, &cmd).await?;
+                None
+            }
+            Target::Relay(addr) => {
+                log::info!("Start relaying {peer} to next hop {addr}");
+                self.config.next_hop(addr)?;
+                let outbound = self
+                    .config
+                    .upstream_of(addr)
+                    .connect(addr, &self.resolver, self.dialer.as_ref())
+                    .await?;
+                self.config.socket.apply_to(&outbound)?;
+                self.mark(priority, &outbound);
+                // messages of end-to-end session between client and next hop are forwarded
+                pipeline::transfer_and_log_error(outbound, inbound, keys)
+                    .await
+                    .map(|(received, sent)| (sent, received))
+            }
+        };
+        Ok(bytes)
+    }
+    /// listen for clients, accepted sockets inherit socket options of listener
+    fn listen(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let listener = bind::listen_tcp(addr, self.config.listen_backlog)?;
+        self.config
+            .socket
+            .apply(SockRef::from(&listener), addr.is_ipv6())?;
+        Ok(listener)
+    }
+    /// mark packets sent on `stream` with dscp of `priority`, if server marks them
+    fn mark(&self, priority: Priority, stream: &TcpStream) {
+        if !self.config.dscp {
+            return;
+        }
+        if let Err(e) = priority.mark(stream) {
+            log::warn!("Failed to mark packets with dscp: {e}");
         }
-        Ok(())
+    }
+    /// serve socks5 request, resolving domains with server's resolver,
+    /// and connecting only to targets allowed by `rules`
+    async fn start_socks5(
+        &self,
+        inbound: NoiseStream<TcpStream>,
+        rules: &[TargetRule],
+    ) -> Result<Option<(u64, u64)>> {
+        let options = &self.config.socks5;
+        let mut socket = Socks5Socket::new(inbound, Arc::new(options.config()))
+            .upgrade_to_socks5()
+            .await
+            .map_err(|e| Error::Socks5(e.to_string()))?;
+        if let Some(code) = socket.target_addr().and_then(|t| options.refuse(t)) {
+            proxy::socks5_reply(&mut socket, code).await?;
+            return Ok(None);
+        }
+        let (domain, target) = match socket.target_addr() {
+            Some(TargetAddr::Ip(addr)) => (None, Ok(*addr)),
+            Some(TargetAddr::Domain(host, port)) => (
+                Some(host.clone()),
+                self.resolver.resolve(&format!("{host}:{port}")).await,
+            ),
+            None => (None, Err(io::ErrorKind::InvalidInput.into())),
+        };
+        let outbound = match target {
+            Ok(addr) if !rules::allows(rules, domain.as_deref(), Some(addr.ip()), addr.port()) => {
+                let target = domain.map_or(addr.to_string(), |d| format!("{d}:{}", addr.port()));
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("socks5 target {target} is not allowed"),
+                ))
+            }
+            Ok(addr) => {
+                let upstream = self.config.upstream_of(addr);
+                options
+                    .connect(upstream.connect(addr, &self.resolver, self.dialer.as_ref()))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        proxy::socks5_reply(&mut socket, proxy::socks5_reply_code(&outbound)).await?;
+        Ok(proxy::transfer_and_log_error(socket, outbound?).await)
     }
     /// start to handle rproxy conn for visitor
     async fn start_proxy_to_rproxy_conn(
         &self,
-        id: usize,
+        key: ServiceKey,
         inbound: NoiseStream<TcpStream>,
+        client: String,
+        target: Option<Target>,
+        priority: Priority,
     ) -> Result<()> {
         let peer_addr = inbound.get_inner().peer_addr();
-        log::info!("Start proxying {peer_addr:?} to rproxy service (id: {id})");
-        let mut ctrl = self
-            .conns
-            .get_mut(&id)
-            .ok_or_else(|| anyhow!("Service offline"))?;
-        let outbound = ctrl.open_stream().await?;
-        tokio::spawn(async move {
-            proxy::transfer_and_log_error(inbound, outbound.compat()).await;
+        if !self.conns.contains_key(&key) && !self.config.peers.is_empty() {
+            let bytes = self
+                .start_proxy_to_peer_service(&key, inbound, target.as_ref())
+                .await?;
+            self.stats.record_bytes(&client, bytes);
+            return Ok(());
+        }
+        let (permit, outbound) = self.open_service_stream(&key, priority).await?;
+        match &target {
+            Some(target) => log::info!(
+                "Start proxying {client} ({peer_addr:?}) to {target} via rproxy service (id: {key})"
+            ),
+            None => {
+                log::info!("Start proxying {client} ({peer_addr:?}) to rproxy service (id: {key})")
+            }
+        }
+        let stats = self.stats.clone();
+        self.tasks.spawn(async move {
+            match Self::transfer_to_service(inbound, outbound, target.as_ref()).await {
+                Ok(bytes) => stats.record_bytes(&client, bytes),
+                Err(e) => log::warn!("Client {client} ({peer_addr:?}): {e}"),
+            }
+            drop(permit);
         });
         Ok(())
     }
+    /// transfer visitor to stream of a service, requesting chained `target` on it first,
+    /// which is served by socks5 server of reverse proxy client
+    async fn transfer_to_service<S>(
+        inbound: NoiseStream<TcpStream>,
+        outbound: S,
+        target: Option<&Target>,
+    ) -> Result<Option<(u64, u64)>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let addr = match target {
+            None => return Ok(proxy::transfer_and_log_error(inbound, outbound).await),
+            Some(Target::Addr(addr)) => TargetAddr::Ip(*addr),
+            Some(Target::Host(host)) => {
+                let (host, port) = host
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+                    .ok_or_else(|| Error::InvalidRemote(host.clone()))?;
+                TargetAddr::Domain(host, port)
+            }
+            Some(target) => Err(Error::InvalidRemote(format!(
+                "{target} cannot be reached via a service"
+            )))?,
+        };
+        let mut outbound = Socks5Stream::use_stream(outbound, None, Default::default())
+            .await
+            .map_err(|e| Error::Socks5(e.to_string()))?;
+        outbound
+            .request(Socks5Command::TCPConnect, addr)
+            .await
+            .map_err(|e| Error::Socks5(e.to_string()))?;
+        Ok(proxy::transfer_and_log_error(inbound, outbound).await)
+    }
+    /// count a new inbound connection while guard is held, shed it if a ceiling is reached
+    pub(crate) fn admit_connection(&self) -> Result<ConnectionGuard> {
+        self.health.resources.admit()
+    }
+    /// services registered on this node, with name of their clients
+    pub(crate) fn online_services(&self) -> Vec<(ServiceKey, String)> {
+        self.conns
+            .iter()
+            .filter_map(|c| Some((c.key().clone(), self.client(&c.pubkey)?.name)))
+            .collect()
+    }
+    pub(crate) fn admin_tokens(&self) -> &[AdminToken] {
+        &self.config.admin_tokens
+    }
+    /// online services of this node and names of their clients, one per line
+    pub(crate) fn admin_services(&self) -> String {
+        let mut services = self.online_services();
+        services.sort_by_key(|(key, _)| key.to_string());
+        let mut report = format!("services: {} online\n", services.len());
+        for (key, name) in services {
+            report += &format!("service {key}: {name}\n");
+        }
+        report
+    }
+    /// clients of this node, with fingerprint and tenant, one per line
+    pub(crate) fn admin_clients(&self) -> String {
+        let mut clients: Vec<&ClientEntry> = self
+            .config
+            .clients
+            .iter()
+            .chain(&self.config.mounted_clients)
+            .filter(|c| self.client(&c.pubkey).is_some())
+            .collect();
+        clients.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        clients
+            .iter()
+            .map(|c| {
+                let tenant = c.tenant.as_deref().unwrap_or("-");
+                format!("{} {} {tenant}\n", c.name, fingerprint::of(&c.pubkey))
+            })
+            .collect()
+    }
+    /// close connection of rclient of `service`, e.g. "7" or "team-a/7", `None` if it is offline
+    pub(crate) fn disconnect_service(&self, service: &str) -> Option<ServiceKey> {
+        let key = match service.rsplit_once('/') {
+            Some((tenant, id)) => ServiceKey::new(Some(tenant), id.parse().ok()?),
+            None => ServiceKey::new(None, service.parse().ok()?),
+        };
+        let (key, conn) = self.conns.remove(&key)?;
+        let mut control = conn.control;
+        self.tasks.spawn(async move { control.close().await });
+        Some(key)
+    }
+    /// revoke client found by name or fingerprint as `revoke-cli` does, saving it to config,
+    /// its key is refused from now on, its sessions are closed and its service is disconnected,
+    /// returns its name
+    pub(crate) async fn revoke_at_runtime(&self, client: &str, by: &str) -> Result<String> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| Error::Config(String::from("config from env cannot be saved")))?;
+        // clients added to storage since start are not served yet, but would be after restart
+        let stored = self.storage.load_clients()?;
+        let clients: HashSet<&ClientEntry> = self
+            .config
+            .clients
+            .iter()
+            .chain(&stored)
+            .filter(|c| self.client(&c.pubkey).is_some())
+            .collect();
+        let revoked = find_client_in(clients, client)?.clone();
+        ServerConfig::save_revoked(
+            path,
+            &RevokedKey {
+                pubkey: revoked.pubkey.clone(),
+                name: Some(revoked.name.clone()),
+                reason: Some(format!("revoked by admin {by}")),
+            },
+        )?;
+        self.storage
+            .remove_clients(std::slice::from_ref(&revoked))?;
+        self.revoked.write().unwrap().insert(revoked.pubkey.clone());
+        let services: Vec<ServiceKey> = self
+            .conns
+            .iter()
+            .filter(|c| c.pubkey == revoked.pubkey)
+            .map(|c| c.key().clone())
+            .collect();
+        for service in services {
+            self.disconnect_service(&service.to_string());
+        }
+        let sessions = self.sessions.cancel(&revoked.pubkey);
+        log::warn!(
+            "Client {} is revoked by admin {by}, {sessions} sessions are closed",
+            revoked.name
+        );
+        Ok(revoked.name)
+    }
+    /// usage of each client in prometheus text format, labeled with client name
+    pub(crate) fn client_metrics(&self) -> String {
+        self.stats.snapshot().metrics() + &self.fleet.metrics()
+    }
+    /// metrics of tenant in prometheus text format, `None` unless `token` is its admin token
+    pub(crate) fn tenant_metrics(&self, name: &str, token: Option<&str>) -> Option<String> {
+        let tenant = self.config.tenant(name)?;
+        // compare digests, so time taken does not tell how much of token matches
+        let digest = |t: &str| Blake2s256::digest(t.as_bytes());
+        if digest(tenant.admin_token.as_deref()?) != digest(token?) {
+            return None;
+        }
+        let clients: Vec<&ClientEntry> = self
+            .config
+            .clients
+            .iter()
+            .chain(&self.config.mounted_clients)
+            .filter(|c| c.tenant.as_deref() == Some(name))
+            .collect();
+        let (connections, sent, received) = self
+            .stats
+            .snapshot()
+            .usage_of(clients.iter().map(|c| c.name.as_str()));
+        let usage = TenantUsage {
+            clients: clients.len(),
+            services: self
+                .conns
+                .iter()
+                .filter(|c| c.key().tenant.as_deref() == Some(name))
+                .count(),
+            connections,
+            sent,
+            received,
+        };
+        Some(usage.metrics(name))
+    }
+    /// open a visitor stream to service registered on this node, paced by its bandwidth cap,
+    /// the permit must be held while the stream is in use
+    pub(crate) async fn open_service_stream(
+        &self,
+        key: &ServiceKey,
+        priority: Priority,
+    ) -> Result<(Option<OwnedSemaphorePermit>, ServiceStream)> {
+        let permit = self.acquire_stream(key).await?;
+        // clone control and release map entry before awaiting,
+        // so that visitors of the same service open streams concurrently
+        let (mut control, scheduler) = self
+            .service_conn(key)
+            .ok_or(Error::ServiceOffline(key.id))?;
+        let outbound = control.open_stream().await?;
+        Ok((
+            permit,
+            self.service_stream(key, outbound, scheduler, priority),
+        ))
+    }
+    /// control and scheduler of registered connection of service
+    fn service_conn(&self, key: &ServiceKey) -> Option<(yamux::Control, Arc<Scheduler>)> {
+        self.conns
+            .get(key)
+            .map(|c| (c.control.clone(), c.scheduler.clone()))
+    }
+    /// visitor stream scheduled by priority of visitor, and paced by bandwidth cap of service
+    fn service_stream(
+        &self,
+        key: &ServiceKey,
+        stream: yamux::Stream,
+        scheduler: Arc<Scheduler>,
+        priority: Priority,
+    ) -> ServiceStream {
+        let limit = self.limits.get(key);
+        let class = priority.class(limit.is_some_and(|l| l.fair));
+        let pacer = limit.and_then(|l| l.pacer.clone());
+        Paced::new(Fair::new(stream.compat(), Some(scheduler), class), pacer)
+    }
     /// start a new rproxy connection
     async fn start_new_rproxy_conn(
         &self,
         inbound: NoiseStream<TcpStream>,
-        id: usize,
+        key: ServiceKey,
         target: Target,
+        pubkey: Vec<u8>,
     ) -> Result<()> {
         // 1. make conneciton
         let peer_addr = inbound.get_inner().peer_addr()?;
         let target = target.to_string();
-        log::info!("Start reverse proxy ({peer_addr}:{target}) as service (id {id})");
+        let name = self.client(&pubkey).map_or(String::new(), |c| c.name);
+        log::info!("Start reverse proxy of {name} ({peer_addr}:{target}) as service (id {key})");
         let yamux_config = yamux::Config::default();
         let mut yamux_conn =
             yamux::Connection::new(inbound.compat(), yamux_config, yamux::Mode::Client);
         let control = yamux_conn.control();
         // 2. update connection map
-        self.conns.insert(id, control);
-        tokio::spawn(async move {
-            while let Ok(Some(_)) = yamux_conn.next_stream().await {}
-            yamux_conn.control().close().await
-        })
-        .await
-        .ok();
-        self.conns.remove(&id);
-        log::info!("Service {id} disconnect.");
+        let seq = self.conn_seq.fetch_add(1, Ordering::Relaxed);
+        let conn = RproxyConn {
+            pubkey,
+            seq,
+            control,
+            scheduler: Default::default(),
+        };
+        if let Some(old) = self.conns.insert(key.clone(), conn) {
+            // stale registration replaced by the same client
+            let mut control = old.control;
+            self.tasks.spawn(async move { control.close().await });
+        }
+        self.stats.service_online(&key, seq);
+        let registration = ServiceRegistration {
+            service: key.to_string(),
+            client: name,
+            since: stats::now(),
+        };
+        if let Err(e) = self.storage.register_service(&registration) {
+            log::warn!("Failed to save registration of service {key}. Error: {}", e);
+        }
+        self.tasks
+            .spawn(async move {
+                while let Ok(Some(_)) = yamux_conn.next_stream().await {}
+                yamux_conn.control().close().await
+            })
+            .await
+            .ok();
+        // only remove own registration, it may be replaced by a reconnected client
+        if self.conns.remove_if(&key, |_, c| c.seq == seq).is_some() {
+            if let Err(e) = self.storage.unregister_service(&registration.service) {
+                log::warn!(
+                    "Failed to remove registration of service {key}. Error: {}",
+                    e
+                );
+            }
+        }
+        self.stats.service_offline(seq);
+        log::info!("Service {key} disconnect.");
         Ok(())
     }
 
-    /// helper function
-    async fn accept_noise_stream(
+    /// start to proxy visitor to a service registered on another node
+    async fn start_proxy_to_peer_service(
         &self,
-        inbound: TcpStream,
-    ) -> Result<NoiseStream<TcpStream>, snowstorm::SnowstormError> {
-        log::info!("New incoming stream (peer_addr {:?})", inbound.peer_addr());
-        // create noise stream & client auth
-        let responder = snowstorm::Builder::new(PATTERN.parse()?)
+        key: &ServiceKey,
+        inbound: NoiseStream<TcpStream>,
+        target: Option<&Target>,
+    ) -> Result<Option<(u64, u64)>> {
+        let peer_addr = inbound.get_inner().peer_addr();
+        for node in &self.config.peers {
+            match self.try_peer_service(*node, key).await {
+                Ok(outbound) => {
+                    log::info!(
+                        "Start proxying {peer_addr:?} to rproxy service (id: {key}) on node {node}"
+                    );
+                    return Self::transfer_to_service(inbound, outbound, target).await;
+                }
+                Err(e) => log::debug!("Service {key} is not available on node {node}. Error: {e}"),
+            }
+        }
+        Err(Error::ServiceOffline(key.id))
+    }
+    /// ask a node of the cluster for a stream to service
+    async fn try_peer_service(
+        &self,
+        node: SocketAddr,
+        key: &ServiceKey,
+    ) -> Result<NoiseStream<TcpStream>> {
+        let initiator = snowstorm::Builder::new(PATTERN.parse()?)
+            .remote_public_key(&self.config.pubkey)
             .local_private_key(&self.config.prikey)
-            .build_responder()?;
-
-        let handshake = NoiseStream::handshake_with_verifier(inbound, responder, |key| {
-            if self.config.clients.contains(key) {
-                Ok(())
-            } else {
-                Err(SnowstormError::InvalidPublicKey(key.to_vec()))
+            .build_initiator()?;
+        let conn = self.config.socket.connect(node).await?;
+        let handshake = NoiseStream::handshake(conn, initiator);
+        let mut enc_conn = timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| Error::Timeout)??;
+        key.write(&mut enc_conn).await?;
+        match Status::try_from(enc_conn.read_u8().await?) {
+            Ok(Status::Accepted) => Ok(enc_conn),
+            _ => Err(Error::ServiceOffline(key.id)),
+        }
+    }
+    /// handle stream or state request from another node of the cluster
+    async fn handle_peer_connection(&self, mut inbound: NoiseStream<TcpStream>) -> Result<()> {
+        let key = ServiceKey::read(&mut inbound).await?;
+        if key == cluster::sync_key() {
+            inbound.write_u8(Status::Accepted.into()).await?;
+            return self.node_state().write(&mut inbound).await;
+        }
+        let (mut ctrl, scheduler) = match self.service_conn(&key) {
+            Some(conn) => conn,
+            None => {
+                inbound.write_u8(Status::Denied.into()).await?;
+                return Ok(());
             }
-        });
-        let enc_inbound = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
-            Ok(r) => r?,
+        };
+        let permit = match self.acquire_stream(&key).await {
+            Ok(permit) => permit,
+            Err(e) => {
+                inbound.write_u8(Status::Denied.into()).await?;
+                return Err(e);
+            }
+        };
+        let outbound =
+            self.service_stream(&key, ctrl.open_stream().await?, scheduler, Priority::Normal);
+        inbound.write_u8(Status::Accepted.into()).await?;
+        log::info!("Start proxying cluster node to rproxy service (id: {key})");
+        proxy::transfer_and_log_error(inbound, outbound).await;
+        drop(permit);
+        Ok(())
+    }
+    /// wait for a free visitor stream of service if it is limited
+    async fn acquire_stream(&self, key: &ServiceKey) -> Result<Option<OwnedSemaphorePermit>> {
+        match self.limits.get(key) {
+            Some(limit) => Ok(Some(
+                limit.acquire().await.ok_or(Error::ServiceBusy(key.id))?,
+            )),
+            None => Ok(None),
+        }
+    }
+    /// clients of config and storage of this node, shared with peers,
+    /// clients learned from peers are not shared again
+    fn node_state(&self) -> NodeState {
+        let clients = self
+            .config
+            .clients
+            .iter()
+            .chain(&self.config.mounted_clients)
+            .filter(|c| self.config.revoked(&c.pubkey).is_none());
+        NodeState {
+            clients: clients.cloned().collect(),
+            revoked: self
+                .config
+                .revoked_keys
+                .iter()
+                .map(|r| r.pubkey.clone())
+                .chain(self.revoked.read().unwrap().iter().cloned())
+                .collect(),
+        }
+    }
+    /// pull state of every peer, a peer that cannot be reached keeps its last state
+    async fn sync_peers(&self) {
+        for node in &self.config.peers {
+            let pull = async {
+                let mut conn = self.try_peer_service(*node, &cluster::sync_key()).await?;
+                NodeState::read(&mut conn).await
+            };
+            match timeout(HANDSHAKE_TIMEOUT, pull).await {
+                Ok(Ok(state)) => self.cluster.update(*node, state),
+                Ok(Err(e)) => log::debug!("Failed to pull state of node {node}. Error: {e}"),
+                Err(_) => log::debug!("Timeout when pulling state of node {node}"),
+            }
+        }
+        log::debug!("{} clients are registered on peers", self.cluster.clients());
+    }
+    /// find client by public key, in config of this node or registered on a peer,
+    /// keys revoked by this node or any peer are never found
+    fn client(&self, key: &[u8]) -> Option<ClientEntry> {
+        if self.config.revoked(key).is_some()
+            || self.cluster.revoked(key)
+            || self.revoked.read().unwrap().contains(key)
+        {
+            return None;
+        }
+        match self.config.client(key) {
+            Some(client) => Some(client.clone()),
+            None => self.cluster.client(key),
+        }
+    }
+    /// nodes of a cluster authenticate each other with the shared server key
+    fn is_peer_key(&self, key: &[u8]) -> bool {
+        !self.config.peers.is_empty() && key == &self.config.pubkey[..]
+    }
+
+    /// helper function
+    /// accept noise stream of client, with its key, early data and whether it asks for measurement
+    async fn accept_noise_stream(&self, inbound: TcpStream) -> Result<Accepted> {
+        log::info!("New incoming stream (peer_addr {:?})", inbound.peer_addr());
+        match timeout(HANDSHAKE_TIMEOUT, self.handshake(inbound)).await {
+            Ok(r) => r,
             Err(_) => Err(snowstorm::SnowstormError::HandshakeError(String::from(
                 "handshake timeout",
             )))?,
+        }
+    }
+    async fn handshake(&self, mut inbound: TcpStream) -> Result<Accepted> {
+        let hello = ticket::read_hello(&mut inbound).await?;
+        let early_blob = early::read(&mut inbound).await?;
+        let measure = measure::read(&mut inbound).await?;
+        let heartbeat_blob = telemetry::read(&mut inbound).await?;
+        if let Hello::Resume(blob) = hello {
+            // client authenticated by secret of ticket issued to it
+            let (key, secret) = self
+                .tickets
+                .open(&blob)
+                .filter(|(key, _)| {
+                    self.alert_revoked(key);
+                    self.client(key).is_some()
+                })
+                .ok_or_else(|| Error::Rejected(String::from("invalid or expired ticket")))?;
+            let responder = snowstorm::Builder::new(ticket::RESUME_PATTERN.parse()?)
+                .psk(0, &secret)
+                .build_responder()?;
+            let (mut enc_inbound, keys) = pipeline::handshake(inbound, responder).await?;
+            self.accept_heartbeat(&mut enc_inbound, &key, &secret, heartbeat_blob, false)
+                .await?;
+            self.tickets.issue(&mut enc_inbound, &key).await?;
+            log::debug!("Session resumed with ticket");
+            let early = self
+                .accept_early_data(&mut enc_inbound, &key, &secret, early_blob)
+                .await?;
+            return Ok((enc_inbound, keys, key, early, measure));
+        }
+        // create noise stream & client auth
+        let prikey = self.prikey_of(&inbound).await?;
+        let responder = snowstorm::Builder::new(PATTERN.parse()?)
+            .local_private_key(prikey)
+            .build_responder()?;
+        let (mut enc_inbound, keys) =
+            pipeline::handshake_with_verifier(inbound, responder, |key| {
+                if self.client(key).is_some() || self.is_peer_key(key) {
+                    Ok(())
+                } else {
+                    Err(SnowstormError::InvalidPublicKey(key.to_vec()))
+                }
+            })
+            .await
+            .map_err(|e| match e {
+                SnowstormError::InvalidPublicKey(key) if self.alert_revoked(&key) => {
+                    Error::Rejected(String::from("revoked key"))
+                }
+                // key in base64 as in config, instead of raw bytes
+                SnowstormError::InvalidPublicKey(key) => Error::Rejected(format!(
+                    "unknown client {} (fingerprint {})",
+                    base64::encode(&key),
+                    fingerprint::of(&key)
+                )),
+                e => e.into(),
+            })?;
+        // can use `.unwrap()` here because client must have a static key
+        let key = enc_inbound
+            .get_state()
+            .get_remote_static()
+            .unwrap()
+            .to_vec();
+        let previous = self.config.previous_key().filter(|k| k.prikey == prikey);
+        if let Some(previous) = previous {
+            log::info!(
+                "Client {} uses previous server key, replace it before {}",
+                self.client(&key).map_or(String::from("-"), |c| c.name),
+                previous.until()
+            );
+        }
+        let material = early::static_secret(prikey, &key).unwrap_or_default();
+        self.accept_heartbeat(
+            &mut enc_inbound,
+            &key,
+            &material,
+            heartbeat_blob,
+            previous.is_some(),
+        )
+        .await?;
+        if matches!(hello, Hello::FullWithTicket) {
+            self.tickets.issue(&mut enc_inbound, &key).await?;
+        }
+        let early = self
+            .accept_early_data(&mut enc_inbound, &key, &material, early_blob)
+            .await?;
+        Ok((enc_inbound, keys, key, early, measure))
+    }
+    /// check heartbeat sent with handshake by client, reject it if it is older than
+    /// `min_client_version` and tell it to update, clients older than version reports
+    /// send no heartbeat and read no reply, cluster nodes are not checked.
+    /// a client of `previous` server key is told the new key instead
+    async fn accept_heartbeat(
+        &self,
+        enc_inbound: &mut NoiseStream<TcpStream>,
+        key: &[u8],
+        material: &[u8],
+        blob: Option<Vec<u8>>,
+        previous: bool,
+    ) -> Result<()> {
+        let client = match self.client(key) {
+            Some(client) => client,
+            None => return Ok(()),
         };
-        Ok(enc_inbound)
+        let min = self.config.min_client_version.as_ref();
+        let blob = match (blob, min) {
+            (Some(blob), _) => blob,
+            (None, None) => return Ok(()),
+            (None, Some(min)) => Err(Error::Rejected(format!(
+                "client {} does not report its version, older than {min}",
+                client.name
+            )))?,
+        };
+        let heartbeat = match telemetry::open(material, &blob) {
+            Some(heartbeat) => heartbeat,
+            None => {
+                telemetry::reply(enc_inbound, Status::Denied, None).await?;
+                Err(Error::Rejected(String::from("invalid heartbeat")))?
+            }
+        };
+        if let Some(min) = min.filter(|min| heartbeat.older_than(min)) {
+            telemetry::reply(enc_inbound, Status::Outdated, Some(min)).await?;
+            Err(Error::Rejected(format!(
+                "client {} of version {} is older than {min}",
+                client.name,
+                heartbeat.version()
+            )))?
+        }
+        if previous {
+            telemetry::reply(enc_inbound, Status::Rotated, None).await?;
+            self.reissued
+                .offer(enc_inbound, key, &self.config.pubkey)
+                .await?;
+            log::info!("New server key is re-issued to client {}", client.name);
+        } else {
+            telemetry::reply(enc_inbound, Status::Accepted, None).await?;
+        }
+        self.fleet.record(&client.name, heartbeat);
+        Ok(())
+    }
+    /// private key of server the handshake of client is encrypted to,
+    /// current key unless client has the previous key in its grace period
+    async fn prikey_of(&self, inbound: &TcpStream) -> Result<&[u8]> {
+        let previous = match self.config.previous_key() {
+            Some(previous) => previous,
+            None => return Ok(&self.config.prikey),
+        };
+        let message = rotation::peek_message(inbound).await?;
+        if !rotation::reads(&self.config.prikey, &message)
+            && rotation::reads(&previous.prikey, &message)
+        {
+            return Ok(&previous.prikey);
+        }
+        Ok(&self.config.prikey)
+    }
+    /// log an alert if `key` is revoked, someone holding it may try to connect
+    fn alert_revoked(&self, key: &[u8]) -> bool {
+        let revoked = match self.config.revoked(key) {
+            Some(revoked) => revoked,
+            None => return false,
+        };
+        log::error!(
+            "Revoked key {} of client {} is used, it may be compromised{}",
+            fingerprint::of(key),
+            revoked.name.as_deref().unwrap_or("-"),
+            revoked
+                .reason
+                .as_ref()
+                .map(|r| format!(" (revoked for: {r})"))
+                .unwrap_or_default()
+        );
+        true
+    }
+    /// open early data sent by client, and tell client whether it is accepted,
+    /// only clients proxying to a socket address can send early data
+    async fn accept_early_data(
+        &self,
+        enc_inbound: &mut NoiseStream<TcpStream>,
+        key: &[u8],
+        material: &[u8],
+        blob: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let blob = match blob {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        let to_addr = self
+            .config
+            .client(key)
+            .is_some_and(|c| matches!(self.config.remote_of(c), Remote::Proxy(Target::Addr(_))));
+        let data = to_addr.then(|| self.early.open(material, &blob)).flatten();
+        match data {
+            Some(_) => log::debug!("Early data accepted"),
+            None => log::debug!("Early data rejected"),
+        }
+        enc_inbound.write_u8(data.is_some().into()).await?;
+        Ok(data)
+    }
+    /// save hash of a client binary rewritten with new server key, so it is accepted after restart
+    fn save_filehash(&self, client: &ClientEntry, hash: &[u8]) {
+        let entry = ClientEntry {
+            filehash: Some(FileHash {
+                hash: hash.to_vec(),
+            }),
+            ..client.clone()
+        };
+        match self.storage.add_clients(&[entry]) {
+            Ok(()) => log::info!(
+                "Client {} is rewritten with new server key, its hash {} is saved",
+                client.name,
+                hex(hash)
+            ),
+            Err(e) => log::warn!(
+                "Failed to save hash of client {} rewritten with new server key, it is accepted until restart. Error: {}",
+                client.name,
+                e
+            ),
+        }
     }
     async fn try_handshake(
         &self,
-        id: usize,
+        key: &ServiceKey,
         mut enc_inbound: NoiseStream<TcpStream>,
+        token: &[u8],
+        client: &ClientEntry,
     ) -> Result<NoiseStream<TcpStream>> {
-        if self.conns.contains_key(&id) {
-            enc_inbound.write_u8(88).await?;
-            Err(anyhow!("Service already online"))?
-        }
         // verify hash of client
-        let token = enc_inbound.get_state().get_remote_static().unwrap();
         let mut buf: [u8; FILEHASH_LEN] = [0; FILEHASH_LEN];
-        let real_hash = &self.config.clients.get(token).unwrap().filehash;
+        let real_hash = &client.filehash;
         enc_inbound.read_exact(&mut buf).await?;
-        if real_hash.as_ref().map_or(false, |f| f.hash == buf) {
+        // a registration of the same client is considered dead and will be replaced,
+        // because a client is reconnecting only if it lost the connection
+        let online = self.conns.get(key).map(|c| c.pubkey != token);
+        match online {
+            Some(true) => {
+                enc_inbound.write_u8(Status::ServiceTaken.into()).await?;
+                Err(Error::ServiceOnline(key.id))?
+            }
+            Some(false) => log::info!("Service {key} is re-registered, replacing stale connection"),
+            None => {}
+        }
+        let configured = real_hash.as_ref().is_some_and(|f| f.hash == buf);
+        // a binary rewritten with new server key in this handshake has a new hash
+        let rewritten = real_hash.is_some() && self.reissued.record(token, &buf) && !configured;
+        if rewritten {
+            self.save_filehash(client, &buf);
+        }
+        if configured || rewritten || self.reissued.accepts(token, &buf) {
             log::debug!("filehash verify passed, received: {:?}", &buf);
-            enc_inbound.write_u8(66).await?;
+            enc_inbound.write_u8(Status::Accepted.into()).await?;
         } else {
             log::debug!("filehash verify failed, received: {:?}", &buf);
-            enc_inbound.write_u8(0).await?;
-            Err(anyhow!("This client has an invalid hash"))?
+            enc_inbound.write_u8(Status::Denied.into()).await?;
+            Err(Error::Rejected(String::from("client has an invalid hash")))?
         }
         Ok(enc_inbound)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE: usize = 7;
+    const VISITORS: usize = 200;
+    const RCLIENT_HASH: [u8; FILEHASH_LEN] = [3; FILEHASH_LEN];
+
+    fn entry(name: &str, key: &Keypair, remote: Remote) -> ClientEntry {
+        ClientEntry {
+            name: String::from(name),
+            pubkey: key.public.clone(),
+            filehash: None,
+            remote: Some(remote),
+            socks5_rules: None,
+            tenant: None,
+            priority: None,
+        }
+    }
+
+    async fn connect(addr: SocketAddr, server: &[u8], key: &Keypair) -> NoiseStream<TcpStream> {
+        let initiator = snowstorm::Builder::new(PATTERN.parse().unwrap())
+            .remote_public_key(server)
+            .local_private_key(&key.private)
+            .build_initiator()
+            .unwrap();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        NoiseStream::handshake(conn, initiator).await.unwrap()
+    }
+
+    fn config_of(server_key: &Keypair) -> ServerConfig {
+        let mut config = ServerConfig::parse("").unwrap();
+        config.pubkey = server_key.public.clone();
+        config.prikey = server_key.private.clone();
+        config
+    }
+
+    /// accept connections to `server` on a local port
+    async fn serve(server: Arc<Server>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((inbound, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move { server.handle_connection(inbound).await });
+            }
+        });
+        addr
+    }
+
+    /// register service of rclient, echoing each visitor stream
+    async fn register_echo_service(addr: SocketAddr, server: &[u8], key: &Keypair) {
+        let mut conn = connect(addr, server, key).await;
+        conn.write_all(&RCLIENT_HASH).await.unwrap();
+        let status = Status::try_from(conn.read_u8().await.unwrap());
+        assert_eq!(status, Ok(Status::Accepted));
+        let mut yamux_conn =
+            yamux::Connection::new(conn.compat(), Default::default(), yamux::Mode::Server);
+        tokio::spawn(async move {
+            while let Ok(Some(stream)) = yamux_conn.next_stream().await {
+                tokio::spawn(async move {
+                    let (mut rd, mut wr) = tokio::io::split(stream.compat());
+                    tokio::io::copy(&mut rd, &mut wr).await.ok();
+                });
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_visitors_of_one_service_complete() {
+        let server_key = gen::gen_keypair(false).unwrap();
+        let rclient = gen::gen_keypair(false).unwrap();
+        let visitor = Arc::new(gen::gen_keypair(false).unwrap());
+        let mut config = config_of(&server_key);
+        let target = Target::Addr("127.0.0.1:1".parse().unwrap());
+        config.clients.insert(ClientEntry {
+            filehash: Some(FileHash {
+                hash: RCLIENT_HASH.to_vec(),
+            }),
+            ..entry("rclient", &rclient, Remote::RProxy(target, SERVICE))
+        });
+        config
+            .clients
+            .insert(entry("visitor", &visitor, Remote::Service(SERVICE)));
+        let server = Arc::new(Server::from_config(config, None).unwrap());
+        let addr = serve(server.clone()).await;
+        register_echo_service(addr, &server_key.public, &rclient).await;
+        while server
+            .service_conn(&ServiceKey::new(None, SERVICE))
+            .is_none()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let visits = (0..VISITORS).map(|i| {
+            let (server_pubkey, visitor) = (server_key.public.clone(), visitor.clone());
+            tokio::spawn(async move {
+                let mut stream = connect(addr, &server_pubkey, &visitor).await;
+                let probe = format!("visitor {i} of service {SERVICE}");
+                stream.write_all(probe.as_bytes()).await.unwrap();
+                let mut echoed = vec![0; probe.len()];
+                stream.read_exact(&mut echoed).await.unwrap();
+                assert_eq!(echoed, probe.as_bytes());
+            })
+        });
+        let visits = timeout(Duration::from_secs(30), futures::future::join_all(visits))
+            .await
+            .expect("visitors of service stalled");
+        for visit in visits {
+            visit.unwrap();
+        }
+    }
+
+    /// echo tcp connections on a local port
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut rd, mut wr) = conn.into_split();
+                    tokio::io::copy(&mut rd, &mut wr).await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn relayed_clients_talk_to_next_hop_end_to_end() {
+        let (edge_key, internal_key) = (
+            gen::gen_keypair(false).unwrap(),
+            gen::gen_keypair(false).unwrap(),
+        );
+        let client = gen::gen_keypair(false).unwrap();
+        let echo = echo_server().await;
+        let mut config = config_of(&internal_key);
+        config
+            .clients
+            .insert(entry("client", &client, Remote::Proxy(Target::Addr(echo))));
+        let internal = serve(Arc::new(Server::from_config(config, None).unwrap())).await;
+        let mut config = config_of(&edge_key);
+        config.next_hops.push(NextHop {
+            addr: internal,
+            pubkey: internal_key.public.clone(),
+        });
+        config.clients.insert(entry(
+            "client",
+            &client,
+            Remote::Proxy(Target::Relay(internal)),
+        ));
+        let edge = serve(Arc::new(Server::from_config(config, None).unwrap())).await;
+
+        let outer = connect(edge, &edge_key.public, &client).await;
+        let initiator = snowstorm::Builder::new(PATTERN.parse().unwrap())
+            .remote_public_key(&internal_key.public)
+            .local_private_key(&client.private)
+            .build_initiator()
+            .unwrap();
+        let mut inner = NoiseStream::handshake(outer, initiator).await.unwrap();
+        inner.write_all(b"through the edge").await.unwrap();
+        let mut echoed = [0; 16];
+        inner.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"through the edge");
+    }
+
+    #[tokio::test]
+    async fn relay_to_unlisted_hop_is_refused() {
+        let server_key = gen::gen_keypair(false).unwrap();
+        let client = gen::gen_keypair(false).unwrap();
+        let echo = echo_server().await;
+        let mut config = config_of(&server_key);
+        config
+            .clients
+            .insert(entry("client", &client, Remote::Proxy(Target::Relay(echo))));
+        let server = serve(Arc::new(Server::from_config(config, None).unwrap())).await;
+        let mut stream = connect(server, &server_key.public, &client).await;
+        stream.write_all(b"hello").await.ok();
+        let mut buf = [0; 5];
+        assert!(!matches!(stream.read(&mut buf).await, Ok(n) if n > 0));
+    }
+
+    #[tokio::test]
+    async fn clients_and_revocations_are_pulled_from_peers() {
+        let server_key = gen::gen_keypair(false).unwrap();
+        let (client, revoked) = (
+            gen::gen_keypair(false).unwrap(),
+            gen::gen_keypair(false).unwrap(),
+        );
+        let remote = Remote::Proxy(Target::Socks5);
+        let mut config = config_of(&server_key);
+        config.peers = vec!["127.0.0.1:1".parse().unwrap()];
+        config
+            .clients
+            .insert(entry("client", &client, remote.clone()));
+        config.revoked_keys.push(RevokedKey {
+            pubkey: revoked.public.clone(),
+            name: None,
+            reason: None,
+        });
+        let node_a = serve(Arc::new(Server::from_config(config, None).unwrap())).await;
+
+        let mut config = config_of(&server_key);
+        config.peers = vec![node_a];
+        config.clients.insert(entry("revoked", &revoked, remote));
+        let node_b = Server::from_config(config, None).unwrap();
+        assert!(node_b.client(&client.public).is_none());
+        assert!(node_b.client(&revoked.public).is_some());
+        node_b.sync_peers().await;
+        assert_eq!(node_b.client(&client.public).unwrap().name, "client");
+        assert!(node_b.client(&revoked.public).is_none());
+    }
+
+    #[tokio::test]
+    async fn revoking_at_runtime_closes_sessions_of_key() {
+        let dir = std::env::temp_dir().join(format!("portguard-revoke-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let (server_key, alice) = (
+            gen::gen_keypair(false).unwrap(),
+            gen::gen_keypair(false).unwrap(),
+        );
+        let echo = echo_server().await;
+        let mut config = config_of(&server_key);
+        config
+            .clients
+            .insert(entry("alice", &alice, Remote::Proxy(Target::Addr(echo))));
+        let content = format!("# kept comment\n{}", config.to_toml().unwrap());
+        std::fs::write(&path, content).unwrap();
+        let server = Arc::new(Server::build(&path).unwrap());
+        let addr = serve(server.clone()).await;
+        let mut stream = connect(addr, &server_key.public, &alice).await;
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+
+        assert_eq!(
+            server.revoke_at_runtime("alice", "root").await.unwrap(),
+            "alice"
+        );
+        let closed = timeout(Duration::from_secs(5), stream.read(&mut echoed))
+            .await
+            .expect("session of revoked key is still open");
+        assert!(!matches!(closed, Ok(n) if n > 0));
+        assert!(server.sessions.keys.is_empty());
+        assert!(server.revoke_at_runtime("alice", "root").await.is_err());
+        // appended to config, other parts are kept
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# kept comment"));
+        let server = Server::build(&path).unwrap();
+        assert!(server.client(&alice.public).is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn admin_api_checks_role_of_token() {
+        let dir = std::env::temp_dir().join(format!("portguard-admin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let alice = gen::gen_keypair(false).unwrap();
+        let mut config = config_of(&gen::gen_keypair(false).unwrap());
+        config
+            .clients
+            .insert(entry("alice", &alice, Remote::Proxy(Target::Socks5)));
+        config.admin_tokens = toml::from_str::<HashMap<String, Vec<AdminToken>>>(
+            "tokens = [{ name = 'oncall', token = 'view', role = 'read-only' },
+                       { name = 'root', token = 'revoke', role = 'admin' }]",
+        )
+        .unwrap()
+        .remove("tokens")
+        .unwrap();
+        std::fs::write(&path, config.to_toml().unwrap()).unwrap();
+        let server = Server::build(&path).unwrap();
+        let request =
+            |method, path, token| crate::admin::handle(method, path, token, &server, String::new());
+
+        let (status, body) = request("GET", "/admin/clients", Some("view")).await;
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("alice "));
+        let revoke = "/admin/revoke/alice";
+        assert_eq!(request("POST", revoke, None).await.0, "401 Unauthorized");
+        assert_eq!(
+            request("POST", revoke, Some("view")).await.0,
+            "403 Forbidden"
+        );
+        assert!(server.client(&alice.public).is_some());
+        assert_eq!(request("POST", revoke, Some("revoke")).await.0, "200 OK");
+        assert!(server.client(&alice.public).is_none());
+        // saved, refused after restart
+        let server = Server::build(&path).unwrap();
+        assert!(server.client(&alice.public).is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,398 @@
+/// installing client as a service started at boot, with builtin config of the binary,
+/// and uninstalling it with local files of client
+///
+/// linux: systemd unit, macos: launchd plist,
+/// windows: scheduled task at startup, as client is not a native windows service,
+/// with a firewall rule allowing the binary so no prompt shows at its first run
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(feature = "clap")]
+use clap::Args;
+
+use crate::error::{Error, Result};
+use crate::history;
+#[cfg(unix)]
+use crate::instance::InstanceLock;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct InstallArgs {
+    /// service name, "portguard-<binary name>" by default
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub name: Option<String>,
+    /// install for current user instead of system, started at login instead of boot
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub user: bool,
+    /// only print service definition and where it goes, change nothing
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub dry_run: bool,
+    /// arguments of client run by service, after "--", e.g. "-- --port 2222"
+    #[cfg_attr(feature = "clap", clap(last = true))]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct UninstallArgs {
+    /// service name, "portguard-<binary name>" by default
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub name: Option<String>,
+    /// uninstall service of current user instead of system
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub user: bool,
+    /// only print what would be removed, change nothing
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub dry_run: bool,
+    /// also remove this binary
+    #[cfg_attr(feature = "clap", clap(long))]
+    pub remove_binary: bool,
+}
+
+/// service name derived from name of binary
+fn default_name(exe: &Path) -> String {
+    let stem = exe
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let stem: String = stem
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '-',
+            },
+        )
+        .collect();
+    match stem.starts_with("portguard") {
+        true => stem,
+        false => format!("portguard-{stem}"),
+    }
+}
+
+fn home() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Service(String::from("home directory is unknown")))
+}
+
+/// run a command of service manager, fail if it does not succeed
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    log::info!("Running: {} {}", program, args.join(" "));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| Error::Service(format!("failed to run {program}, {e}")))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Service(format!("{program} exited with {status}"))),
+    }
+}
+
+/// location and content of service definition
+#[cfg(target_os = "linux")]
+fn definition(name: &str, exe: &Path, args: &[String], user: bool) -> Result<(PathBuf, String)> {
+    let dir = match user {
+        true => std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .map_or_else(|| home().map(|h| h.join(".config")), Ok)?
+            .join("systemd/user"),
+        false => PathBuf::from("/etc/systemd/system"),
+    };
+    // systemd splits command line like a shell, quote every word
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut exec = quote(&exe.to_string_lossy());
+    for arg in args {
+        exec += &format!(" {}", quote(arg));
+    }
+    let target = match user {
+        true => "default.target",
+        false => "multi-user.target",
+    };
+    let unit = format!(
+        "[Unit]\n\
+         Description=Portguard client {name}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={exec}\n\
+         Restart=on-failure\n\
+         RestartSec=5\n\
+         \n\
+         [Install]\n\
+         WantedBy={target}\n"
+    );
+    Ok((dir.join(format!("{name}.service")), unit))
+}
+
+#[cfg(target_os = "linux")]
+fn register(name: &str, _path: &Path, user: bool) -> Result<()> {
+    let scope: &[&str] = if user { &["--user"] } else { &[] };
+    run("systemctl", &[scope, &["daemon-reload"]].concat())?;
+    run("systemctl", &[scope, &["enable", "--now", name]].concat())
+}
+
+#[cfg(target_os = "linux")]
+fn unregister(name: &str, path: &Path, user: bool) -> Result<()> {
+    let scope: &[&str] = if user { &["--user"] } else { &[] };
+    run("systemctl", &[scope, &["disable", "--now", name]].concat())?;
+    std::fs::remove_file(path)?;
+    run("systemctl", &[scope, &["daemon-reload"]].concat())
+}
+
+#[cfg(target_os = "macos")]
+fn definition(name: &str, exe: &Path, args: &[String], user: bool) -> Result<(PathBuf, String)> {
+    let dir = match user {
+        true => home()?.join("Library/LaunchAgents"),
+        false => PathBuf::from("/Library/LaunchDaemons"),
+    };
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let mut program = format!(
+        "        <string>{}</string>\n",
+        escape(&exe.to_string_lossy())
+    );
+    for arg in args {
+        program += &format!("        <string>{}</string>\n", escape(arg));
+    }
+    let plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{name}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {program}\
+         \x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <true/>\n\
+         </dict>\n\
+         </plist>\n"
+    );
+    Ok((dir.join(format!("{name}.plist")), plist))
+}
+
+#[cfg(target_os = "macos")]
+fn register(_name: &str, path: &Path, _user: bool) -> Result<()> {
+    run("launchctl", &["load", "-w", &path.to_string_lossy()])
+}
+
+#[cfg(target_os = "macos")]
+fn unregister(_name: &str, path: &Path, _user: bool) -> Result<()> {
+    run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+    Ok(std::fs::remove_file(path)?)
+}
+
+#[cfg(windows)]
+fn definition(name: &str, exe: &Path, args: &[String], user: bool) -> Result<(PathBuf, String)> {
+    // task is created by schtasks, a copy of its command line is kept for reference
+    let dir = match user {
+        true => home()?.join("AppData\\Roaming\\portguard"),
+        false => {
+            PathBuf::from(std::env::var_os("ProgramData").unwrap_or_default()).join("portguard")
+        }
+    };
+    let quote = |s: &str| match s.contains(' ') {
+        true => format!("\"{s}\""),
+        false => s.to_string(),
+    };
+    let mut command = quote(&exe.to_string_lossy());
+    for arg in args {
+        command += &format!(" {}", quote(arg));
+    }
+    Ok((dir.join(format!("{name}.cmd")), command))
+}
+
+#[cfg(windows)]
+fn register(name: &str, path: &Path, user: bool) -> Result<()> {
+    let command = std::fs::read_to_string(path)?;
+    let schedule: &[&str] = match user {
+        true => &["/SC", "ONLOGON"],
+        false => &["/SC", "ONSTART", "/RU", "SYSTEM"],
+    };
+    let args = [&["/Create", "/F", "/TN", name, "/TR", &command], schedule].concat();
+    run("schtasks", &args).map_err(|e| match user {
+        true => e,
+        false => Error::Service(format!(
+            "{e}, a system task needs an elevated prompt (run as administrator), or use --user"
+        )),
+    })?;
+    run("schtasks", &["/Run", "/TN", name])
+}
+
+/// allow inbound connections of binary in windows firewall, replacing rule of previous install
+#[cfg(windows)]
+fn allow_firewall(name: &str, exe: &Path) -> Result<()> {
+    let rule = format!("name={name}");
+    let _ = run(
+        "netsh",
+        &["advfirewall", "firewall", "delete", "rule", &rule],
+    );
+    let program = format!("program={}", exe.display());
+    run(
+        "netsh",
+        &[
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &rule,
+            "dir=in",
+            "action=allow",
+            &program,
+            "enable=yes",
+        ],
+    )
+}
+
+#[cfg(windows)]
+fn unregister(name: &str, path: &Path, _user: bool) -> Result<()> {
+    // task may not be running, and rule may not be added
+    let _ = run("schtasks", &["/End", "/TN", name]);
+    let _ = run(
+        "netsh",
+        &[
+            "advfirewall",
+            "firewall",
+            "delete",
+            "rule",
+            &format!("name={name}"),
+        ],
+    );
+    run("schtasks", &["/Delete", "/F", "/TN", name])?;
+    Ok(std::fs::remove_file(path)?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn definition(
+    _name: &str,
+    _exe: &Path,
+    _args: &[String],
+    _user: bool,
+) -> Result<(PathBuf, String)> {
+    Err(Error::Service(String::from(
+        "service is not supported on this platform",
+    )))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn register(_name: &str, _path: &Path, _user: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn unregister(_name: &str, _path: &Path, _user: bool) -> Result<()> {
+    Ok(())
+}
+
+/// remove binary of running process
+#[cfg(not(windows))]
+fn remove_binary(exe: &Path) -> Result<()> {
+    Ok(std::fs::remove_file(exe)?)
+}
+
+/// running binary cannot be removed on windows, remove it after process exits
+#[cfg(windows)]
+fn remove_binary(exe: &Path) -> Result<()> {
+    let command = format!(
+        "timeout /T 2 /NOBREAK >NUL & del /F /Q \"{}\"",
+        exe.display()
+    );
+    Command::new("cmd").args(["/C", &command]).spawn()?;
+    Ok(())
+}
+
+/// install current binary as a service running client, and start it
+pub fn install(args: InstallArgs) -> Result<()> {
+    let exe = std::env::current_exe()?.canonicalize()?;
+    let name = args.name.unwrap_or_else(|| default_name(&exe));
+    check_name(&name)?;
+    let (path, content) = definition(&name, &exe, &args.args, args.user)?;
+    if args.dry_run {
+        println!("# {}", path.display());
+        print!("{content}");
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, content)?;
+    log::info!("Service definition written to {:?}", path);
+    // without a rule, windows asks at first run whether to allow the binary to listen,
+    // which nobody answers for a task at startup
+    #[cfg(windows)]
+    if let Err(e) = allow_firewall(&name, &exe) {
+        log::warn!("Failed to add firewall rule, error={}", e);
+    }
+    register(&name, &path, args.user)?;
+    log::info!("Service {} installed and started", name);
+    Ok(())
+}
+
+/// check service name, it is also part of file names
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        Err(Error::Service(format!("invalid service name: {name}")))?
+    }
+    Ok(())
+}
+
+/// stop and remove service of current binary, lock files of clients that are not running,
+/// history file, and optionally current binary
+pub fn uninstall(args: UninstallArgs) -> Result<()> {
+    let exe = std::env::current_exe()?.canonicalize()?;
+    let name = args.name.unwrap_or_else(|| default_name(&exe));
+    check_name(&name)?;
+    let mut removed = Vec::new();
+    let (path, _) = definition(&name, &exe, &[], args.user)?;
+    match path.exists() {
+        true if args.dry_run => removed.push(path),
+        true => {
+            unregister(&name, &path, args.user)?;
+            log::info!("Service {} stopped and removed", name);
+            removed.push(path);
+        }
+        false => log::info!("Service definition {:?} not found, skipped", path),
+    }
+    #[cfg(unix)]
+    removed.extend(InstanceLock::remove_stale(args.dry_run)?);
+    if let Some(path) = history::default_path() {
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        for path in [path, PathBuf::from(rotated)] {
+            if path.exists() {
+                if !args.dry_run {
+                    std::fs::remove_file(&path)?;
+                }
+                removed.push(path);
+            }
+        }
+    }
+    if args.remove_binary {
+        if !args.dry_run {
+            remove_binary(&exe)?;
+        }
+        removed.push(exe);
+    }
+    let verb = match args.dry_run {
+        true => "Would remove",
+        false => "Removed",
+    };
+    for path in removed {
+        println!("{verb} {}", path.display());
+    }
+    Ok(())
+}
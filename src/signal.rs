@@ -0,0 +1,107 @@
+/// unix signal handling with self-pipe
+use std::io;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use tokio::io::unix::AsyncFd;
+
+const MAX_SIGNUM: usize = 32;
+
+/// write end of pipe for each signal
+static PIPES: [AtomicI32; MAX_SIGNUM] = [const { AtomicI32::new(-1) }; MAX_SIGNUM];
+
+/// errno of current thread
+#[cfg(any(target_os = "linux", target_os = "emscripten"))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+extern "C" fn on_signal(signum: libc::c_int) {
+    // a failed write must not clobber errno of the interrupted code
+    let saved = unsafe { *errno() };
+    let fd = PIPES[signum as usize].load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = 1u8;
+        // only async-signal-safe calls here
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+    unsafe { *errno() = saved };
+}
+
+/// stream of a received signal
+pub(crate) struct Signal {
+    reader: AsyncFd<OwnedFd>,
+}
+
+impl Signal {
+    /// start listening a signal, e.g. `libc::SIGUSR1`
+    pub(crate) fn new(signum: libc::c_int) -> io::Result<Signal> {
+        if signum <= 0 || signum as usize >= MAX_SIGNUM {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid signal",
+            ))?
+        }
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+        let old = PIPES[signum as usize].swap(fds[1], Ordering::Relaxed);
+        if old >= 0 {
+            unsafe { libc::close(old) };
+        }
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signum, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        let reader = AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fds[0]) })?;
+        Ok(Signal { reader })
+    }
+    /// wait for next signal
+    pub(crate) async fn recv(&mut self) -> io::Result<()> {
+        loop {
+            let mut guard = self.reader.readable().await?;
+            let mut buf = [0u8; 64];
+            let fd = std::os::unix::io::AsRawFd::as_raw_fd(guard.get_inner());
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n > 0 {
+                return Ok(());
+            }
+            guard.clear_ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handler_keeps_errno() {
+        // write to read end of a pipe fails with EBADF
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let signum = MAX_SIGNUM - 1;
+        PIPES[signum].store(fds[0], Ordering::Relaxed);
+        unsafe { *errno() = libc::EINTR };
+        on_signal(signum as libc::c_int);
+        assert_eq!(unsafe { *errno() }, libc::EINTR);
+        PIPES[signum].store(-1, Ordering::Relaxed);
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
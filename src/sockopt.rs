@@ -0,0 +1,292 @@
+/// socket options of tunnel sockets, so they can be tuned for the network,
+/// and surrounding network can route and shape portguard traffic
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[cfg(feature = "clap")]
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpSocket;
+
+// options of tcp sockets to and from server, `[socket]` of server config or flags of client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(Args))]
+pub struct SocketOpts {
+    /// seconds of idle before tcp keepalive probes, only long-lived connections
+    /// keep alive if not set
+    #[cfg_attr(feature = "clap", clap(long, value_name = "SECS"))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub keepalive: Option<u64>,
+    /// send small writes at once, disabling nagle's algorithm
+    #[cfg_attr(feature = "clap", clap(long))]
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub nodelay: bool,
+    /// bytes of socket send buffer
+    #[cfg_attr(feature = "clap", clap(long, value_name = "BYTES"))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub send_buffer: Option<usize>,
+    /// bytes of socket receive buffer
+    #[cfg_attr(feature = "clap", clap(long, value_name = "BYTES"))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub recv_buffer: Option<usize>,
+    /// tos byte of packets, e.g. 184 for dscp 46, for shaping by network
+    #[cfg_attr(feature = "clap", clap(long))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tos: Option<u8>,
+    /// firewall mark of connections, for policy routing (linux only, needs CAP_NET_ADMIN)
+    #[cfg_attr(feature = "clap", clap(long))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub so_mark: Option<u32>,
+    /// network device connections are bound to, e.g. "wg0" (linux only, needs CAP_NET_RAW)
+    #[cfg_attr(feature = "clap", clap(long, value_name = "DEVICE"))]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bind_device: Option<String>,
+}
+
+impl SocketOpts {
+    #[cfg(feature = "server")]
+    pub(crate) fn is_default(&self) -> bool {
+        *self == SocketOpts::default()
+    }
+    /// set options on `socket`, before it connects or listens if possible,
+    /// so that the first packet is already marked and routed
+    pub(crate) fn apply(&self, socket: SockRef, v6: bool) -> io::Result<()> {
+        if let Some(secs) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+            socket
+                .set_tcp_keepalive(&keepalive)
+                .map_err(context("keepalive"))?;
+        }
+        if self.nodelay {
+            socket.set_tcp_nodelay(true).map_err(context("nodelay"))?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket
+                .set_send_buffer_size(size)
+                .map_err(context("send_buffer"))?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket
+                .set_recv_buffer_size(size)
+                .map_err(context("recv_buffer"))?;
+        }
+        if let Some(tos) = self.tos {
+            set_tos(&socket, v6, tos).map_err(context("tos"))?;
+        }
+        if let Some(mark) = self.so_mark {
+            set_mark(&socket, mark).map_err(context("so_mark"))?;
+        }
+        if let Some(device) = &self.bind_device {
+            bind_device(&socket, device).map_err(context("bind_device"))?;
+        }
+        Ok(())
+    }
+    /// new socket to `addr` with options set
+    pub(crate) fn socket(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.apply(SockRef::from(&socket), addr.is_ipv6())?;
+        Ok(socket)
+    }
+    /// connect to `addr` with options set
+    #[cfg(feature = "server")]
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> io::Result<tokio::net::TcpStream> {
+        self.socket(addr)?.connect(addr).await
+    }
+    /// set options on a connected `stream`, for sockets made by others, e.g. a dialer
+    #[cfg(feature = "server")]
+    pub(crate) fn apply_to(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        self.apply(SockRef::from(stream), stream.local_addr()?.is_ipv6())
+    }
+}
+
+/// name option failed to be set in error
+fn context(name: &'static str) -> impl Fn(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("cannot set {name}: {e}"))
+}
+
+/// set type of service byte of ipv4 socket, or traffic class of ipv6 socket
+pub(crate) fn set_tos(socket: &SockRef, v6: bool, tos: u8) -> io::Result<()> {
+    match v6 {
+        false => socket.set_tos_v4(tos as u32),
+        true => set_tclass_v6(socket, tos),
+    }
+}
+
+#[cfg(unix)]
+fn set_tclass_v6(socket: &SockRef, tclass: u8) -> io::Result<()> {
+    setsockopt(
+        socket,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_TCLASS,
+        tclass as libc::c_int,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_tclass_v6(_socket: &SockRef, _tclass: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "traffic class of ipv6 socket is only supported on unix",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &SockRef, mark: u32) -> io::Result<()> {
+    setsockopt(socket, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &SockRef, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "so_mark is only supported on linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &SockRef, device: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &SockRef, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bind_device is only supported on linux",
+    ))
+}
+
+#[cfg(unix)]
+fn setsockopt(
+    socket: &SockRef,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_parsed_from_config() {
+        let cases = [
+            ("", Some(SocketOpts::default())),
+            (
+                "keepalive = 30\nnodelay = true\ntos = 184",
+                Some(SocketOpts {
+                    keepalive: Some(30),
+                    nodelay: true,
+                    tos: Some(184),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "send_buffer = 1048576\nrecv_buffer = 65536",
+                Some(SocketOpts {
+                    send_buffer: Some(1048576),
+                    recv_buffer: Some(65536),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "so_mark = 7\nbind_device = 'wg0'",
+                Some(SocketOpts {
+                    so_mark: Some(7),
+                    bind_device: Some(String::from("wg0")),
+                    ..Default::default()
+                }),
+            ),
+            ("tos = 256", None),
+            ("keepalive = -1", None),
+            ("nodelay = 'yes'", None),
+            ("so_mark = 4294967296", None),
+            ("bind_device = 0", None),
+        ];
+        for (config, expected) in cases {
+            assert_eq!(
+                toml::from_str::<SocketOpts>(config).ok(),
+                expected,
+                "{config}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_options_are_not_written() {
+        assert_eq!(toml::to_string(&SocketOpts::default()).unwrap(), "");
+        let opts = SocketOpts {
+            nodelay: true,
+            tos: Some(184),
+            ..Default::default()
+        };
+        let config = toml::to_string(&opts).unwrap();
+        assert_eq!(config, "nodelay = true\ntos = 184\n");
+        assert_eq!(toml::from_str::<SocketOpts>(&config).unwrap(), opts);
+    }
+
+    #[tokio::test]
+    async fn options_are_set_on_sockets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = SocketOpts {
+            keepalive: Some(30),
+            nodelay: true,
+            ..Default::default()
+        };
+        let stream = opts.connect(addr).await.unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.tcp_nodelay().unwrap());
+
+        let stream = SocketOpts::default().connect(addr).await.unwrap();
+        assert!(!SockRef::from(&stream).tcp_nodelay().unwrap());
+        opts.apply_to(&stream).unwrap();
+        assert!(SockRef::from(&stream).tcp_nodelay().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn errors_name_the_option() {
+        let socket = TcpSocket::new_v4().unwrap();
+        let opts = SocketOpts {
+            bind_device: Some(String::from("no-such-device0")),
+            ..Default::default()
+        };
+        let e = opts.apply(SockRef::from(&socket), false).unwrap_err();
+        assert!(e.to_string().starts_with("cannot set bind_device: "), "{e}");
+    }
+}
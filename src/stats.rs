@@ -0,0 +1,200 @@
+/// aggregate server statistics, persisted to `stats_file` periodically
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::tenant::ServiceKey;
+
+/// interval of saving statistics to file
+pub(crate) const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// usage of a client
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ClientUsage {
+    connections: u64,
+    /// bytes sent by client
+    sent: u64,
+    /// bytes received by client
+    received: u64,
+    /// unix time of last connection
+    last_seen: u64,
+}
+
+/// uptime of a reverse proxy service
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ServiceUsage {
+    registrations: u64,
+    /// total seconds online
+    uptime: u64,
+    /// unix time of last registration
+    last_online: u64,
+}
+
+/// statistics saved in `stats_file`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    /// unix time of first record
+    since: u64,
+    total_connections: u64,
+    // tables must be placed after values for toml serialization
+    /// usage by client name
+    #[serde(default)]
+    clients: BTreeMap<String, ClientUsage>,
+    /// uptime by service id, prefixed by tenant if any, e.g. "team-a/3"
+    #[serde(default)]
+    services: BTreeMap<String, ServiceUsage>,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Stats {
+    /// load statistics of previous runs, empty if file does not exist
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(toml::de::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Stats {
+                since: now(),
+                ..Default::default()
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::ser::to_string(self)?)?;
+        Ok(())
+    }
+    /// connections, bytes sent and received of clients of `names`
+    pub(crate) fn usage_of<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> (u64, u64, u64) {
+        let names: BTreeSet<&str> = names.into_iter().collect();
+        names
+            .into_iter()
+            .filter_map(|name| self.clients.get(name))
+            .fold((0, 0, 0), |(c, s, r), u| {
+                (c + u.connections, s + u.sent, r + u.received)
+            })
+    }
+    /// usage of each client in prometheus text format, labeled with client name
+    pub(crate) fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, help: &str, value: fn(&ClientUsage) -> u64| {
+            metrics += &format!("# HELP {name} {help}\n# TYPE {name} counter\n");
+            for (client, usage) in &self.clients {
+                let client = client.replace('\\', "\\\\").replace('"', "\\\"");
+                metrics += &format!("{name}{{client=\"{client}\"}} {}\n", value(usage));
+            }
+        };
+        metric(
+            "portguard_client_connections_total",
+            "Connections of client.",
+            |u| u.connections,
+        );
+        metric(
+            "portguard_client_sent_bytes_total",
+            "Bytes sent by client.",
+            |u| u.sent,
+        );
+        metric(
+            "portguard_client_received_bytes_total",
+            "Bytes received by client.",
+            |u| u.received,
+        );
+        metrics
+    }
+    /// human readable report
+    pub(crate) fn report(&self) -> String {
+        let mut report = format!(
+            "since: {}\ntotal connections: {}\n",
+            self.since, self.total_connections
+        );
+        report += "clients:\n";
+        for (name, c) in &self.clients {
+            writeln!(
+                report,
+                "  {name}: {} connections, sent {} bytes, received {} bytes, last seen {}",
+                c.connections, c.sent, c.received, c.last_seen
+            )
+            .ok();
+        }
+        report += "services:\n";
+        for (id, s) in &self.services {
+            writeln!(
+                report,
+                "  {id}: {} registrations, up {}s, last online {}",
+                s.registrations, s.uptime, s.last_online
+            )
+            .ok();
+        }
+        report
+    }
+}
+
+/// statistics of running server
+#[derive(Debug, Default)]
+pub(crate) struct StatsState {
+    stats: Mutex<Stats>,
+    /// registrations online now, by sequence number, with service and online time
+    online: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+impl StatsState {
+    pub(crate) fn new(stats: Stats) -> Self {
+        StatsState {
+            stats: Mutex::new(stats),
+            online: Mutex::default(),
+        }
+    }
+    pub(crate) fn record_connection(&self, client: &str) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.total_connections += 1;
+        let usage = stats.clients.entry(client.to_string()).or_default();
+        usage.connections += 1;
+        usage.last_seen = now();
+    }
+    pub(crate) fn record_bytes(&self, client: &str, bytes: Option<(u64, u64)>) {
+        if let Some((sent, received)) = bytes {
+            let mut stats = self.stats.lock().unwrap();
+            let usage = stats.clients.entry(client.to_string()).or_default();
+            usage.sent += sent;
+            usage.received += received;
+        }
+    }
+    /// registration `seq` of service `key` is online
+    pub(crate) fn service_online(&self, key: &ServiceKey, seq: u64) {
+        let key = key.to_string();
+        self.online
+            .lock()
+            .unwrap()
+            .insert(seq, (key.clone(), Instant::now()));
+        let mut stats = self.stats.lock().unwrap();
+        let usage = stats.services.entry(key).or_default();
+        usage.registrations += 1;
+        usage.last_online = now();
+    }
+    /// registration `seq` is offline
+    pub(crate) fn service_offline(&self, seq: u64) {
+        if let Some((key, since)) = self.online.lock().unwrap().remove(&seq) {
+            let mut stats = self.stats.lock().unwrap();
+            let usage = stats.services.entry(key).or_default();
+            usage.uptime += since.elapsed().as_secs();
+        }
+    }
+    /// statistics including uptime of services online now
+    pub(crate) fn snapshot(&self) -> Stats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        for (key, since) in self.online.lock().unwrap().values() {
+            let usage = stats.services.entry(key.clone()).or_default();
+            usage.uptime += since.elapsed().as_secs();
+        }
+        stats
+    }
+}
@@ -0,0 +1,125 @@
+/// status display of generated clients run in a terminal, so users who are not developers
+/// see connection state and traffic in a few colored lines instead of logs
+use std::io::{self, Write};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::client::ClientEvent;
+use crate::console;
+use crate::i18n::{Lang, Msg};
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+/// move to start of line and clear it, traffic line is redrawn there
+const CLEAR: &str = "\r\x1b[2K";
+
+/// state shown by display
+#[derive(Debug, Default)]
+struct Status {
+    lang: Lang,
+    colored: bool,
+    connected: bool,
+    sent: u64,
+    received: u64,
+    connections: u64,
+    errors: u64,
+}
+
+impl Status {
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.colored {
+            true => format!("{color}{text}{RESET}"),
+            false => text.to_string(),
+        }
+    }
+    /// line printed for `event` with its color, `None` if it only updates traffic
+    fn update(&mut self, event: ClientEvent) -> Option<(&'static str, String)> {
+        let text = |msg| self.lang.text(msg);
+        match event {
+            ClientEvent::Ready(what) => Some((CYAN, format!("● {}: {what}", text(Msg::Ready)))),
+            ClientEvent::Connected if self.connected => None,
+            ClientEvent::Connected => {
+                self.connected = true;
+                Some((GREEN, format!("● {}", text(Msg::Connected))))
+            }
+            ClientEvent::Reconnecting => {
+                self.connected = false;
+                Some((YELLOW, format!("● {}", text(Msg::Reconnecting))))
+            }
+            ClientEvent::Rejected(reason) => {
+                self.errors += 1;
+                Some((RED, format!("✖ {}: {reason}", text(Msg::Rejected))))
+            }
+            ClientEvent::ServerUnreachable(e) => {
+                self.connected = false;
+                self.errors += 1;
+                Some((RED, format!("✖ {}: {e}", text(Msg::ServerUnreachable))))
+            }
+            ClientEvent::TargetUnreachable(e) => {
+                self.errors += 1;
+                Some((RED, format!("✖ {}: {e}", text(Msg::TargetUnreachable))))
+            }
+            ClientEvent::Transferred { sent, received } => {
+                self.sent += sent;
+                self.received += received;
+                self.connections += 1;
+                None
+            }
+        }
+    }
+    fn traffic(&self) -> String {
+        format!(
+            "  ↑ {}  ↓ {}  {}: {}  {}: {}",
+            bytes(self.sent),
+            bytes(self.received),
+            self.lang.text(Msg::Connections),
+            self.connections,
+            self.lang.text(Msg::Errors),
+            self.errors,
+        )
+    }
+}
+
+/// bytes in units readable by people, e.g. "1.5 MB"
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{n} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// show events of a client in `lang` until they end, each state change as a line above
+/// a traffic line, which is redrawn in place
+pub(crate) async fn display(mut events: UnboundedReceiver<ClientEvent>, lang: Lang) {
+    let mut status = Status {
+        lang,
+        colored: std::env::var_os("NO_COLOR").is_none() && console::enable_colors(),
+        ..Default::default()
+    };
+    while let Some(event) = events.recv().await {
+        let line = status.update(event);
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "{CLEAR}");
+        if let Some((color, text)) = line {
+            let _ = writeln!(stdout, "{}", status.paint(color, &text));
+        }
+        let _ = write!(stdout, "{}", status.paint(DIM, &status.traffic()));
+        let _ = stdout.flush();
+    }
+}
+
+/// end traffic line, so following output starts on a new line
+pub(crate) fn finish() {
+    println!();
+}
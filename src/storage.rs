@@ -0,0 +1,560 @@
+/// persistent state of server changing at runtime: clients registered by `gen-cli`,
+/// statistics, i.e. usage counters of clients and services, and services online now
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::server::ClientEntry;
+pub use crate::stats::Stats;
+
+use crate::error::{Error, Result};
+
+/// keeps state of server, records are opaque and serializable with serde
+/// implement it to keep state elsewhere than toml files, e.g. in a database
+pub trait Storage: Send + Sync {
+    /// clients registered at runtime, served in addition to those in config
+    fn load_clients(&self) -> Result<Vec<ClientEntry>>;
+    /// add clients, replacing those with the same public key
+    fn add_clients(&self, clients: &[ClientEntry]) -> Result<()>;
+    /// remove clients with the same public keys, revoking them
+    fn remove_clients(&self, clients: &[ClientEntry]) -> Result<()>;
+    /// statistics of previous runs, empty if there is none
+    fn load_stats(&self) -> Result<Stats>;
+    fn save_stats(&self, stats: &Stats) -> Result<()>;
+    /// services registered now, those loaded at start are left by a server not stopped cleanly
+    fn load_services(&self) -> Result<Vec<ServiceRegistration>>;
+    /// service is online, replacing registration of the same service
+    fn register_service(&self, registration: &ServiceRegistration) -> Result<()>;
+    /// service `service` is offline
+    fn unregister_service(&self, service: &str) -> Result<()>;
+}
+
+/// service registered by a reverse proxy client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceRegistration {
+    /// service id, prefixed by tenant if any, e.g. "team-a/3"
+    pub service: String,
+    /// name of client registering it
+    pub client: String,
+    /// unix time of registration
+    pub since: u64,
+}
+
+/// `[[services]]` tables of `services_file`
+#[derive(Default, Serialize, Deserialize)]
+struct ServiceTables {
+    #[serde(default)]
+    services: Vec<ServiceRegistration>,
+}
+
+/// advisory lock of config file, held while it is read or changed,
+/// so concurrent `gen-cli` runs and server do not lose each other's changes
+pub(crate) struct ConfigLock {
+    _file: File,
+}
+
+impl ConfigLock {
+    /// wait for lock of config at `path`, taken on `<path>.lock`
+    pub(crate) fn acquire(path: &Path) -> io::Result<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        Ok(ConfigLock {
+            _file: Self::lock(&options, Path::new(&lock_path))?,
+        })
+    }
+    #[cfg(unix)]
+    fn lock(options: &OpenOptions, path: &Path) -> io::Result<File> {
+        use std::os::unix::io::AsRawFd;
+        let file = options.open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(file)
+    }
+    /// lock file is opened without sharing, others retry until it is closed
+    #[cfg(windows)]
+    fn lock(options: &OpenOptions, path: &Path) -> io::Result<File> {
+        use std::os::windows::fs::OpenOptionsExt;
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        let mut options = options.clone();
+        options.share_mode(0);
+        loop {
+            match options.open(path) {
+                Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+                    std::thread::sleep(std::time::Duration::from_millis(50))
+                }
+                res => return res,
+            }
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    fn lock(options: &OpenOptions, path: &Path) -> io::Result<File> {
+        options.open(path)
+    }
+}
+
+/// `[[clients]]` tables of config file
+#[derive(Serialize, Deserialize)]
+struct ClientTables {
+    #[serde(default)]
+    clients: Vec<ClientEntry>,
+}
+
+/// name of a table header line, e.g. `[server]` or `[[clients]]`, whitespace removed
+fn table_header(line: &str) -> Option<String> {
+    let line = line.split('#').next()?.trim();
+    let header = line.starts_with('[') && line.ends_with(']') && !line.contains(['=', ',']);
+    header.then(|| line.split_whitespace().collect())
+}
+
+/// whether each line of config text starts outside of multi-line strings and arrays,
+/// so that e.g. a `[[clients]]` line in a multi-line string is not taken as a table header
+fn toplevel_lines(content: &str) -> Vec<bool> {
+    #[derive(PartialEq)]
+    enum State {
+        Value,
+        MultiLineBasic,
+        MultiLineLiteral,
+    }
+    let mut state = State::Value;
+    // nesting of arrays
+    let mut depth = 0usize;
+    let mut toplevel = Vec::new();
+    for line in content.split_inclusive('\n') {
+        let at_top = state == State::Value && depth == 0;
+        toplevel.push(at_top);
+        // brackets of table headers are not arrays
+        if at_top && line.trim_start().starts_with('[') {
+            continue;
+        }
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            i += match state {
+                State::MultiLineBasic if rest.starts_with(b"\\") => 2,
+                State::MultiLineBasic if rest.starts_with(b"\"\"\"") => {
+                    state = State::Value;
+                    3
+                }
+                State::MultiLineLiteral if rest.starts_with(b"'''") => {
+                    state = State::Value;
+                    3
+                }
+                State::MultiLineBasic | State::MultiLineLiteral => 1,
+                State::Value => match rest[0] {
+                    // rest of line is a comment
+                    b'#' => break,
+                    b'"' if rest.starts_with(b"\"\"\"") => {
+                        state = State::MultiLineBasic;
+                        3
+                    }
+                    b'\'' if rest.starts_with(b"'''") => {
+                        state = State::MultiLineLiteral;
+                        3
+                    }
+                    // single line strings end before line does
+                    b'"' => string_len(rest, b'"', true),
+                    b'\'' => string_len(rest, b'\'', false),
+                    b'[' => {
+                        depth += 1;
+                        1
+                    }
+                    b']' => {
+                        depth = depth.saturating_sub(1);
+                        1
+                    }
+                    _ => 1,
+                },
+            };
+        }
+    }
+    toplevel
+}
+
+/// length of single line string at start of `rest`, quoted by `quote`
+fn string_len(rest: &[u8], quote: u8, escapes: bool) -> usize {
+    let mut i = 1;
+    while i < rest.len() && rest[i] != quote {
+        i += match escapes && rest[i] == b'\\' {
+            true => 2,
+            false => 1,
+        };
+    }
+    i + 1
+}
+
+/// ranges of `[[clients]]` tables in config text, comments above them and
+/// before next table are excluded, so they are kept when a client is removed
+fn client_tables(content: &str) -> Vec<Range<usize>> {
+    let mut tables = Vec::new();
+    // start of current table, and end of its last line that is not a comment
+    let mut current: Option<usize> = None;
+    let mut end = 0;
+    let mut pos = 0;
+    let lines = content.split_inclusive('\n').zip(toplevel_lines(content));
+    for (line, toplevel) in lines {
+        let trimmed = line.trim();
+        let header = table_header(trimmed)
+            .filter(|_| toplevel)
+            // sub tables of a client
+            .filter(|h| !h.starts_with("[clients.") && !h.starts_with("[[clients."));
+        if let Some(header) = header {
+            tables.extend(current.take().map(|start| start..end));
+            if header == "[[clients]]" {
+                current = Some(pos);
+            }
+        }
+        pos += line.len();
+        let comment = toplevel && trimmed.starts_with('#');
+        if !trimmed.is_empty() && !comment {
+            end = pos;
+        }
+    }
+    tables.extend(current.map(|start| start..end));
+    tables
+}
+
+/// default storage, clients in `[[clients]]` of config file, statistics in `stats_file`,
+/// services online in `[[services]]` of `services_file`
+#[derive(Debug, Default, Clone)]
+pub struct TomlStorage {
+    /// `None` if config is from env, which cannot be saved
+    config_path: Option<PathBuf>,
+    /// statistics are not saved if not set
+    stats_file: Option<PathBuf>,
+    /// services online are not saved if not set
+    services_file: Option<PathBuf>,
+}
+
+impl TomlStorage {
+    pub fn new(
+        config_path: Option<PathBuf>,
+        stats_file: Option<PathBuf>,
+        services_file: Option<PathBuf>,
+    ) -> Self {
+        TomlStorage {
+            config_path,
+            stats_file,
+            services_file,
+        }
+    }
+    fn read_services(path: &Path) -> Result<Vec<ServiceRegistration>> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(toml::de::from_str::<ServiceTables>(&content)?.services),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// change services of `services_file` under its lock, written by several tasks
+    fn update_services(&self, update: impl FnOnce(&mut Vec<ServiceRegistration>)) -> Result<()> {
+        let path = match &self.services_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _lock = ConfigLock::acquire(path)?;
+        let mut services = Self::read_services(path)?;
+        update(&mut services);
+        services.sort_by(|a, b| a.service.cmp(&b.service));
+        std::fs::write(path, toml::ser::to_string(&ServiceTables { services })?)?;
+        Ok(())
+    }
+    fn read_config(&self) -> Result<Option<toml::value::Table>> {
+        match &self.config_path {
+            Some(path) => Ok(Some(toml::de::from_str(&std::fs::read_to_string(path)?)?)),
+            None => Ok(None),
+        }
+    }
+    /// remove and add `[[clients]]` by editing text of config file,
+    /// so comments and formatting of other parts are kept, `false` if config is not edited
+    fn edit_clients(
+        &self,
+        path: &Path,
+        remove: &[ClientEntry],
+        add: &[ClientEntry],
+    ) -> Result<bool> {
+        let content = std::fs::read_to_string(path)?;
+        let mut edited = String::new();
+        let mut removed = 0;
+        let mut last = 0;
+        for range in client_tables(&content) {
+            let table: ClientTables = match toml::de::from_str(&content[range.clone()]) {
+                Ok(table) => table,
+                Err(_) => return Ok(false),
+            };
+            if !table.clients.iter().any(|c| remove.contains(c)) {
+                continue;
+            }
+            removed += table.clients.len();
+            edited += &content[last..range.start];
+            // blank lines after removed table
+            let rest = &content[range.end..];
+            last = range.end + rest.len() - rest.trim_start().len();
+        }
+        edited += &content[last..];
+        let existing = self.load_clients()?;
+        if removed != existing.iter().filter(|c| remove.contains(c)).count() {
+            // e.g. clients in an inline array
+            return Ok(false);
+        }
+        if !add.is_empty() {
+            edited.truncate(edited.trim_end().len());
+            if !edited.is_empty() {
+                edited += "\n\n";
+            }
+            edited += &toml::ser::to_string(&ClientTables {
+                clients: add.to_vec(),
+            })?;
+        }
+        // edited config must still be valid
+        if toml::de::from_str::<toml::value::Table>(&edited).is_err() {
+            return Ok(false);
+        }
+        std::fs::write(path, edited)?;
+        Ok(true)
+    }
+    /// change clients by editing config, or by rewriting the whole config if it cannot be edited
+    fn change_clients(&self, remove: &[ClientEntry], add: &[ClientEntry]) -> Result<()> {
+        // clients are read again under lock, so clients added by others meanwhile are kept
+        let _lock = match &self.config_path {
+            Some(path) => Some(ConfigLock::acquire(path)?),
+            None => None,
+        };
+        if let Some(path) = &self.config_path {
+            if self.edit_clients(path, remove, add)? {
+                return Ok(());
+            }
+            log::warn!(
+                "Clients cannot be edited in place, rewriting config {:?}",
+                path
+            );
+        }
+        self.update_clients(|all| {
+            all.retain(|c| !remove.contains(c));
+            all.extend_from_slice(add);
+        })
+    }
+    /// change `[[clients]]` of config file, rewriting the whole file
+    fn update_clients(&self, update: impl FnOnce(&mut Vec<ClientEntry>)) -> Result<()> {
+        let (path, mut table) = match (&self.config_path, self.read_config()?) {
+            (Some(path), Some(table)) => (path, table),
+            _ => Err(Error::Config(String::from(
+                "config from env cannot be saved",
+            )))?,
+        };
+        let mut all: Vec<ClientEntry> = match table.remove("clients") {
+            Some(clients) => clients.try_into()?,
+            None => Vec::new(),
+        };
+        update(&mut all);
+        // stable order, so that saved config does not change between runs
+        all.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        if !all.is_empty() {
+            table.insert(String::from("clients"), toml::Value::try_from(all)?);
+        }
+        std::fs::write(path, toml::ser::to_string(&toml::Value::Table(table))?)?;
+        Ok(())
+    }
+}
+
+impl Storage for TomlStorage {
+    fn load_clients(&self) -> Result<Vec<ClientEntry>> {
+        let clients = self
+            .read_config()?
+            .and_then(|mut table| table.remove("clients"));
+        match clients {
+            Some(clients) => Ok(clients.try_into()?),
+            None => Ok(Vec::new()),
+        }
+    }
+    fn add_clients(&self, clients: &[ClientEntry]) -> Result<()> {
+        self.change_clients(clients, clients)
+    }
+    fn remove_clients(&self, clients: &[ClientEntry]) -> Result<()> {
+        self.change_clients(clients, &[])
+    }
+    fn load_stats(&self) -> Result<Stats> {
+        match &self.stats_file {
+            Some(path) => Stats::load(path),
+            None => Ok(Stats::default()),
+        }
+    }
+    fn save_stats(&self, stats: &Stats) -> Result<()> {
+        match &self.stats_file {
+            Some(path) => stats.save(path),
+            None => Ok(()),
+        }
+    }
+    fn load_services(&self) -> Result<Vec<ServiceRegistration>> {
+        match &self.services_file {
+            Some(path) => Self::read_services(path),
+            None => Ok(Vec::new()),
+        }
+    }
+    fn register_service(&self, registration: &ServiceRegistration) -> Result<()> {
+        self.update_services(|services| {
+            services.retain(|s| s.service != registration.service);
+            services.push(registration.clone());
+        })
+    }
+    fn unregister_service(&self, service: &str) -> Result<()> {
+        self.update_services(|services| services.retain(|s| s.service != service))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"# gateway of office
+port = 8022
+motd = """
+[[clients]]
+name = "not a client"
+"""
+socks5_rules = [
+  [
+    "nested",
+  ],
+]
+
+# laptop of alice
+[[clients]]
+name = "alice"
+pubkey = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+remote = "127.0.0.1:22"
+socks5_rules = [
+  "10.0.0.0/8:22", # ssh only
+]
+# comment before next table
+
+# bob, remove after june
+[[clients]]
+name = "bob"
+pubkey = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI="
+remote = "127.0.0.1:80"
+note = '''
+[server]
+'''
+
+[server]
+workers = 2
+"#;
+
+    fn client(text: &str) -> ClientEntry {
+        toml::de::from_str::<ClientTables>(text)
+            .unwrap()
+            .clients
+            .remove(0)
+    }
+
+    #[test]
+    fn client_tables_skip_strings_and_arrays() {
+        let tables: Vec<&str> = client_tables(CONFIG)
+            .into_iter()
+            .map(|range| &CONFIG[range])
+            .collect();
+        assert_eq!(tables.len(), 2, "{tables:?}");
+        assert!(tables[0].starts_with("[[clients]]\nname = \"alice\""));
+        assert!(tables[0].ends_with("# ssh only\n]\n"));
+        assert!(tables[1].starts_with("[[clients]]\nname = \"bob\""));
+        assert!(tables[1].ends_with("[server]\n'''\n"));
+        let cases = [
+            ("a = 1\n[b]\n", vec![true, true]),
+            ("a = \"\"\"\n[b]\n\"\"\"\n", vec![true, false, false]),
+            (
+                "a = [ # [\n  \"]\",\n]\n[b]\n",
+                vec![true, false, false, true],
+            ),
+            ("a = '['\n[b]\n", vec![true, true]),
+            ("a = \"\\\"[\"\n[b]\n", vec![true, true]),
+        ];
+        for (content, toplevel) in cases {
+            assert_eq!(toplevel_lines(content), toplevel, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn clients_are_edited_in_place() {
+        let path =
+            std::env::temp_dir().join(format!("portguard-clients-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let storage = TomlStorage::new(Some(path.clone()), None, None);
+        let bob = client(
+            "[[clients]]\nname = \"bob\"\npubkey = \"AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=\"",
+        );
+        let carol = client(
+            "[[clients]]\nname = \"carol\"\npubkey = \"AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=\"",
+        );
+        storage.remove_clients(&[bob]).unwrap();
+        storage.add_clients(&[carol]).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let names: Vec<String> = storage
+            .load_clients()
+            .unwrap()
+            .iter()
+            .map(|c| c.sort_key().0.to_string())
+            .collect();
+        assert_eq!(names, ["alice", "carol"]);
+        // everything but the table of bob is kept, comments above it too
+        let before = &CONFIG[..CONFIG.find("[[clients]]\nname = \"bob\"").unwrap()];
+        assert!(content.starts_with(before), "{content}");
+        assert!(content.contains("# bob, remove after june\n[server]\nworkers = 2\n"));
+        assert!(!content.contains("AgICAgIC"));
+        std::fs::remove_file(&path).unwrap();
+        let mut lock = path.into_os_string();
+        lock.push(".lock");
+        std::fs::remove_file(lock).ok();
+    }
+
+    #[test]
+    fn services_are_registered_in_file() {
+        let path =
+            std::env::temp_dir().join(format!("portguard-services-{}.toml", std::process::id()));
+        let storage = TomlStorage::new(None, None, Some(path.clone()));
+        let registration = |service: &str, client: &str, since| ServiceRegistration {
+            service: service.to_string(),
+            client: client.to_string(),
+            since,
+        };
+        assert!(storage.load_services().unwrap().is_empty());
+        storage
+            .register_service(&registration("team-a/3", "web", 1))
+            .unwrap();
+        storage
+            .register_service(&registration("1", "db", 2))
+            .unwrap();
+        // re-registration replaces the stale one
+        storage
+            .register_service(&registration("team-a/3", "web", 3))
+            .unwrap();
+        assert_eq!(
+            storage.load_services().unwrap(),
+            [
+                registration("1", "db", 2),
+                registration("team-a/3", "web", 3)
+            ]
+        );
+        storage.unregister_service("1").unwrap();
+        storage.unregister_service("2").unwrap();
+        assert_eq!(
+            storage.load_services().unwrap(),
+            [registration("team-a/3", "web", 3)]
+        );
+        std::fs::remove_file(&path).unwrap();
+        let mut lock = path.into_os_string();
+        lock.push(".lock");
+        std::fs::remove_file(lock).ok();
+        // nothing is saved without services_file
+        let storage = TomlStorage::default();
+        storage
+            .register_service(&registration("1", "db", 2))
+            .unwrap();
+        assert!(storage.load_services().unwrap().is_empty());
+    }
+}
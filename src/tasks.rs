@@ -0,0 +1,55 @@
+/// registry of spawned tasks, so shutdown stops all work of a server or client
+/// instead of leaving detached tasks running
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// max time to wait for tasks to stop
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// tasks spawned by a server or client, cloned handles share the same tasks
+#[derive(Debug, Clone, Default)]
+pub struct Tasks {
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl Tasks {
+    /// spawn a task, which is dropped at its next await once shutdown starts,
+    /// its output is `None` if so
+    pub(crate) fn spawn<F>(&self, task: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let cancel = self.cancel.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = cancel.cancelled() => None,
+                output = task => Some(output),
+            }
+        })
+    }
+    /// resolve once shutdown starts
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+    /// number of running tasks
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+    /// stop all tasks and wait for them at most `limit`, false if some did not stop in time
+    pub async fn shutdown(&self, limit: Duration) -> bool {
+        self.cancel.cancel();
+        self.tracker.close();
+        tokio::time::timeout(limit, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+}
@@ -0,0 +1,433 @@
+/// version report of clients, and opt-in telemetry of clients generated with
+/// `gen-cli --telemetry`, so operators know which client versions are still deployed
+/// before changing protocols
+///
+/// client sends `HEARTBEAT` and its sealed heartbeat before its first handshake message,
+/// sealed like early data by a key derived from static keys of both sides, or secret of a
+/// resumed ticket. heartbeat has version of client, and its os and architecture only with
+/// telemetry, nothing that identifies its user or host. after handshake, server replies
+/// whether the version is accepted by `min_client_version` of config, or that its server
+/// key is rotated, see `reissue`.
+/// server keeps time each client with telemetry is last seen in memory, shown in `/metrics`
+/// of health check, and alerts of clients older than `deprecated_below` of config.
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use snowstorm::NoiseStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::consts::{Status, PATTERN};
+use crate::error::{Error, Result};
+
+const HEARTBEAT: [u8; 2] = [0xfb, 0xff];
+const NONCE_LEN: usize = 12;
+
+/// what a client tells about itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Heartbeat {
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    arch: Option<String>,
+}
+
+impl Heartbeat {
+    fn current(telemetry: bool) -> Self {
+        Heartbeat {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: telemetry.then(|| std::env::consts::OS.to_string()),
+            arch: telemetry.then(|| std::env::consts::ARCH.to_string()),
+        }
+    }
+}
+
+fn cipher(material: &[u8]) -> ChaCha20Poly1305 {
+    let mut hasher = Blake2s256::new();
+    hasher.update(b"portguard heartbeat");
+    hasher.update(material);
+    ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+}
+
+/// send heartbeat sealed by key material, before first handshake message
+pub(crate) async fn send(conn: &mut TcpStream, material: &[u8], telemetry: bool) -> Result<()> {
+    // random bytes from a fresh key
+    let random = snowstorm::Builder::new(PATTERN.parse()?)
+        .generate_keypair()?
+        .private;
+    let nonce = &random[..NONCE_LEN];
+    let plain = serde_json::to_vec(&Heartbeat::current(telemetry))?;
+    let sealed = cipher(material)
+        .encrypt(Nonce::from_slice(nonce), &plain[..])
+        .map_err(|_| Error::Config(String::from("failed to seal heartbeat")))?;
+    let mut msg = HEARTBEAT.to_vec();
+    msg.extend_from_slice(&((NONCE_LEN + sealed.len()) as u16).to_le_bytes());
+    msg.extend_from_slice(nonce);
+    msg.extend_from_slice(&sealed);
+    conn.write_all(&msg).await?;
+    Ok(())
+}
+
+/// read reply of server to heartbeat after handshake, true if server key is rotated and
+/// the new key follows, an error telling the version server requires if client is too old
+pub(crate) async fn confirm<S>(stream: &mut NoiseStream<S>) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match Status::try_from(stream.read_u8().await?) {
+        Ok(Status::Accepted) => Ok(false),
+        Ok(Status::Rotated) => Ok(true),
+        Ok(Status::Outdated) => {
+            let mut min = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut min).await?;
+            Err(Error::Outdated(String::from_utf8_lossy(&min).into_owned()))
+        }
+        Ok(_) => Err(Error::Rejected(String::from("heartbeat is not accepted"))),
+        Err(code) => Err(Error::Rejected(format!(
+            "unknown reply {code} to heartbeat"
+        ))),
+    }
+}
+
+#[cfg(feature = "server")]
+pub(crate) use server::{open, read, reply, ClientVersion, Fleet};
+
+#[cfg(feature = "server")]
+mod server {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use chacha20poly1305::aead::Aead;
+    use chacha20poly1305::Nonce;
+    use serde::{Deserialize, Serialize};
+    use snowstorm::NoiseStream;
+    use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{cipher, Heartbeat, HEARTBEAT, NONCE_LEN};
+    use crate::consts::Status;
+    use crate::ticket::peek_marker;
+
+    /// read sealed heartbeat if client sends it, clients older than version reports do not
+    pub(crate) async fn read(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+        if peek_marker(stream).await? != HEARTBEAT {
+            return Ok(None);
+        }
+        stream.read_exact(&mut [0; 2]).await?;
+        let len = stream.read_u16_le().await? as usize;
+        let mut blob = vec![0; len];
+        stream.read_exact(&mut blob).await?;
+        Ok(Some(blob))
+    }
+
+    /// heartbeat sealed by key material, `None` if it is invalid
+    pub(crate) fn open(material: &[u8], blob: &[u8]) -> Option<Heartbeat> {
+        if blob.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = blob.split_at(NONCE_LEN);
+        let plain = cipher(material)
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .ok()?;
+        serde_json::from_slice(&plain).ok()
+    }
+
+    /// tell client whether its heartbeat is accepted, with version it needs if it is outdated
+    pub(crate) async fn reply<S>(
+        stream: &mut NoiseStream<S>,
+        status: Status,
+        min: Option<&ClientVersion>,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut msg = vec![status.into()];
+        if let Some(min) = min {
+            msg.push(min.text.len() as u8);
+            msg.extend_from_slice(min.text.as_bytes());
+        }
+        stream.write_all(&msg).await
+    }
+
+    impl Heartbeat {
+        pub(crate) fn version(&self) -> &str {
+            &self.version
+        }
+        /// whether client is older than `min`, unknown version format of a future client is not
+        pub(crate) fn older_than(&self, min: &ClientVersion) -> bool {
+            self.version
+                .parse()
+                .is_ok_and(|v: ClientVersion| v.parts < min.parts)
+        }
+    }
+
+    /// version of clients, compared by its numeric parts, e.g. "0.3.2"
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub(crate) struct ClientVersion {
+        text: String,
+        parts: Vec<u64>,
+    }
+
+    impl FromStr for ClientVersion {
+        type Err = String;
+        fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+            // pre-release or build suffix is ignored, e.g. "0.4.0-beta"
+            let core = s.split(['-', '+']).next().unwrap_or_default();
+            let mut parts: Vec<u64> = core
+                .split('.')
+                .map(|part| part.parse())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| format!("invalid client version {s}, e.g. 0.3.2"))?;
+            // "0.4" is the same version as "0.4.0"
+            while parts.last() == Some(&0) {
+                parts.pop();
+            }
+            // sent to outdated clients with a length byte
+            if s.len() > u8::MAX.into() {
+                return Err(format!("client version {s} is too long"));
+            }
+            Ok(ClientVersion {
+                text: s.to_string(),
+                parts,
+            })
+        }
+    }
+
+    impl TryFrom<String> for ClientVersion {
+        type Error = String;
+        fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+            s.parse()
+        }
+    }
+
+    impl From<ClientVersion> for String {
+        fn from(version: ClientVersion) -> Self {
+            version.text
+        }
+    }
+
+    impl fmt::Display for ClientVersion {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.text)
+        }
+    }
+
+    /// last heartbeat of a client with telemetry, and when it was received in unix seconds
+    #[derive(Debug)]
+    struct Seen {
+        version: String,
+        os: String,
+        arch: String,
+        at: u64,
+    }
+
+    /// clients with telemetry seen since server started
+    #[derive(Debug, Default)]
+    pub(crate) struct Fleet {
+        clients: Mutex<HashMap<String, Seen>>,
+        /// clients older than this use deprecated protocol features
+        deprecated_below: Option<ClientVersion>,
+        /// connections of deprecated clients by client and version
+        deprecated: Mutex<HashMap<(String, String), u64>>,
+    }
+
+    impl Fleet {
+        pub(crate) fn new(deprecated_below: Option<ClientVersion>) -> Self {
+            Fleet {
+                deprecated_below,
+                ..Default::default()
+            }
+        }
+        pub(crate) fn record(&self, client: &str, heartbeat: Heartbeat) {
+            self.check_deprecated(client, &heartbeat);
+            let (os, arch) = match (heartbeat.os, heartbeat.arch) {
+                (Some(os), Some(arch)) => (os, arch),
+                _ => return,
+            };
+            log::debug!(
+                "Client {client} is version {} on {os}/{arch}",
+                heartbeat.version
+            );
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let seen = Seen {
+                version: heartbeat.version,
+                os,
+                arch,
+                at,
+            };
+            self.clients
+                .lock()
+                .unwrap()
+                .insert(client.to_string(), seen);
+        }
+        /// count a connection of client older than `deprecated_below`,
+        /// alert once for each client and version, its connections are counted in metrics
+        fn check_deprecated(&self, client: &str, heartbeat: &Heartbeat) {
+            let min = match &self.deprecated_below {
+                Some(min) => min,
+                None => return,
+            };
+            if !heartbeat.older_than(min) {
+                return;
+            }
+            let version = &heartbeat.version;
+            let mut deprecated = self.deprecated.lock().unwrap();
+            let count = deprecated
+                .entry((client.to_string(), version.to_string()))
+                .or_default();
+            if *count == 0 {
+                log::warn!(
+                    "Client {client} of version {version} is accepted, older than {min}, it uses deprecated protocol features"
+                );
+            }
+            *count += 1;
+        }
+        /// last seen time of clients and connections of deprecated clients
+        /// in prometheus text format, labeled by what they sent
+        pub(crate) fn metrics(&self) -> String {
+            let name = "portguard_client_last_seen_seconds";
+            let mut metrics = format!(
+                "# HELP {name} Time client with telemetry last connected, with its version.\n# TYPE {name} gauge\n"
+            );
+            let escape = |s: &str| {
+                s.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            };
+            let clients = self.clients.lock().unwrap();
+            let mut clients: Vec<_> = clients.iter().collect();
+            clients.sort_by_key(|(client, _)| client.as_str());
+            for (client, seen) in clients {
+                metrics += &format!(
+                    "{name}{{client=\"{}\",version=\"{}\",os=\"{}\",arch=\"{}\"}} {}\n",
+                    escape(client),
+                    escape(&seen.version),
+                    escape(&seen.os),
+                    escape(&seen.arch),
+                    seen.at
+                );
+            }
+            let name = "portguard_deprecated_client_connections_total";
+            metrics += &format!(
+                "# HELP {name} Connections of clients older than deprecated_below.\n# TYPE {name} counter\n"
+            );
+            let deprecated = self.deprecated.lock().unwrap();
+            let mut deprecated: Vec<_> = deprecated.iter().collect();
+            deprecated.sort();
+            for ((client, version), count) in deprecated {
+                metrics += &format!(
+                    "{name}{{client=\"{}\",version=\"{}\"}} {count}\n",
+                    escape(client),
+                    escape(version)
+                );
+            }
+            metrics
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn version(s: &str) -> ClientVersion {
+        s.parse().unwrap()
+    }
+
+    fn heartbeat(version: &str) -> Heartbeat {
+        Heartbeat {
+            version: version.to_string(),
+            os: None,
+            arch: None,
+        }
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        for s in ["0.3.2", "1", "0.4.0-beta", "1.2.3+build.5", "2024.1.0"] {
+            assert_eq!(version(s).to_string(), s);
+        }
+        let long = format!("1.{}", "0".repeat(300));
+        for s in [
+            "", "v0.3.2", "0..2", "0.3.x", "0.3.", "-beta", " 0.3.2", &long,
+        ] {
+            assert!(s.parse::<ClientVersion>().is_err(), "{s:?}");
+        }
+        let err = "latest".parse::<ClientVersion>().unwrap_err();
+        assert_eq!(err, "invalid client version latest, e.g. 0.3.2");
+    }
+
+    #[test]
+    fn versions_are_ordered_by_numeric_parts() {
+        let cases = [
+            ("0.3.9", "0.4.0", true),
+            ("0.4.0", "0.4.0", false),
+            ("0.4.1", "0.4.0", false),
+            ("0.9.0", "0.10.0", true),
+            ("0.10.0", "0.9.0", false),
+            ("0.4", "0.4.0", false),
+            ("0.4.0", "0.4", false),
+            ("0.3", "0.3.1", true),
+            ("1.0.0", "0.99.99", false),
+            // pre-release or build suffix is ignored
+            ("0.4.0-beta", "0.4.0", false),
+            ("0.3.9+build", "0.4.0", true),
+            // unknown format of a future client is never older
+            ("next", "0.4.0", false),
+            ("", "0.4.0", false),
+        ];
+        for (client, min, older) in cases {
+            assert_eq!(
+                heartbeat(client).older_than(&version(min)),
+                older,
+                "{client} < {min}"
+            );
+        }
+    }
+
+    #[test]
+    fn versions_in_config_are_validated() {
+        #[derive(Debug, Deserialize)]
+        struct Config {
+            min_client_version: ClientVersion,
+        }
+        let config: Config = toml::from_str("min_client_version = '0.4.0'").unwrap();
+        assert_eq!(config.min_client_version.to_string(), "0.4.0");
+        let err = toml::from_str::<Config>("min_client_version = 'new'").unwrap_err();
+        assert!(
+            err.to_string().contains("invalid client version new"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn sent_heartbeat_is_opened_by_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            send(&mut conn, b"material", false).await.unwrap();
+            send(&mut conn, b"material", true).await.unwrap();
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let blob = read(&mut stream).await.unwrap().unwrap();
+        assert_eq!(open(b"material", &blob), Some(Heartbeat::current(false)));
+        assert_eq!(open(b"other material", &blob), None);
+        let blob = read(&mut stream).await.unwrap().unwrap();
+        let with_telemetry = open(b"material", &blob).unwrap();
+        assert_eq!(with_telemetry.version(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(with_telemetry.os.as_deref(), Some(std::env::consts::OS));
+        client.await.unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+/// tenants sharing a server, each with its own space of service ids,
+/// default remote, admin token and metrics
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+use crate::remote::Remote;
+
+/// max length of tenant name
+const MAX_NAME_LEN: usize = 64;
+/// set in service id sent to a cluster node if a tenant name follows,
+/// so nodes of older versions see no change for services without tenant
+const TENANT_FLAG: u64 = 1 << 63;
+
+/// a tenant, clients of it are labeled with its name
+/// in config:
+/// [[tenants]]
+/// name = "team-a"
+/// remote = 3              # default remote of clients of tenant, `remote` of server if not set
+/// admin_token = "secret"  # reads metrics of tenant at `GET /metrics?tenant=team-a` of health check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) remote: Option<Remote>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) admin_token: Option<String>,
+}
+
+impl Tenant {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.len() > MAX_NAME_LEN || self.name.contains('/') {
+            Err(Error::Config(format!("invalid tenant name: {}", self.name)))?
+        }
+        Ok(())
+    }
+}
+
+/// reverse proxy service, ids of each tenant are a separate space, `None` is the default one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ServiceKey {
+    pub(crate) tenant: Option<String>,
+    pub(crate) id: usize,
+}
+
+impl ServiceKey {
+    pub(crate) fn new(tenant: Option<&str>, id: usize) -> Self {
+        ServiceKey {
+            tenant: tenant.map(String::from),
+            id,
+        }
+    }
+    /// send to a cluster node asking for a stream to service
+    pub(crate) async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<()> {
+        match &self.tenant {
+            None => stream.write_u64(self.id as u64).await?,
+            Some(tenant) => {
+                let mut msg = (self.id as u64 | TENANT_FLAG).to_be_bytes().to_vec();
+                msg.push(tenant.len() as u8);
+                msg.extend_from_slice(tenant.as_bytes());
+                stream.write_all(&msg).await?;
+            }
+        }
+        Ok(())
+    }
+    pub(crate) async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self> {
+        let id = stream.read_u64().await?;
+        let tenant = match id & TENANT_FLAG {
+            0 => None,
+            _ => {
+                let mut name = vec![0; stream.read_u8().await? as usize];
+                stream.read_exact(&mut name).await?;
+                Some(String::from_utf8_lossy(&name).into_owned())
+            }
+        };
+        Ok(ServiceKey {
+            tenant,
+            id: (id & !TENANT_FLAG) as usize,
+        })
+    }
+}
+
+impl fmt::Display for ServiceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.tenant {
+            Some(tenant) => write!(f, "{}/{}", tenant, self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// usage of a tenant
+#[derive(Debug, Default)]
+pub(crate) struct TenantUsage {
+    pub(crate) clients: usize,
+    pub(crate) services: usize,
+    pub(crate) connections: u64,
+    pub(crate) sent: u64,
+    pub(crate) received: u64,
+}
+
+impl TenantUsage {
+    /// usage in prometheus text format, labeled with tenant
+    pub(crate) fn metrics(&self, tenant: &str) -> String {
+        let mut metrics = String::new();
+        let tenant = tenant.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            metrics += &format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{{tenant=\"{tenant}\"}} {value}\n"
+            );
+        };
+        metric(
+            "portguard_tenant_clients",
+            "gauge",
+            "Clients of tenant.",
+            self.clients.to_string(),
+        );
+        metric(
+            "portguard_tenant_services",
+            "gauge",
+            "Online reverse proxy services of tenant.",
+            self.services.to_string(),
+        );
+        metric(
+            "portguard_tenant_connections_total",
+            "counter",
+            "Connections of clients of tenant.",
+            self.connections.to_string(),
+        );
+        metric(
+            "portguard_tenant_sent_bytes_total",
+            "counter",
+            "Bytes sent by clients of tenant.",
+            self.sent.to_string(),
+        );
+        metric(
+            "portguard_tenant_received_bytes_total",
+            "counter",
+            "Bytes received by clients of tenant.",
+            self.received.to_string(),
+        );
+        metrics
+    }
+}
@@ -0,0 +1,375 @@
+/// session resumption tickets, letting a client reconnecting within their lifetime
+/// skip the full handshake with static keys
+///
+/// a client requesting tickets sends `REQUEST_TICKET` before its first handshake message,
+/// then server sends a ticket as the first transport message.
+/// a client resuming sends `RESUME` and the ticket, then runs `RESUME_PATTERN`
+/// authenticated by secret of the ticket, and gets a new ticket in the same way.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use snowstorm::NoiseStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::Result;
+
+/// pattern of resumed handshake, authenticated by pre-shared secret of ticket
+pub(crate) const RESUME_PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+/// sent in place of length of first handshake message, which is never that long
+const REQUEST_TICKET: [u8; 2] = [0xfe, 0xff];
+const RESUME: [u8; 2] = [0xff, 0xff];
+const SECRET_LEN: usize = 32;
+/// tickets are not used shortly before they expire, as server may see them expired
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+/// ticket issued by server, kept by client
+#[derive(Debug, Clone)]
+pub(crate) struct Ticket {
+    /// opaque to client, sealed by server
+    blob: Vec<u8>,
+    /// pre-shared key of resumed handshake
+    pub(crate) secret: Vec<u8>,
+    expires: Instant,
+}
+
+/// ticket kept by client for its next connections
+#[derive(Debug, Default)]
+pub(crate) struct TicketCache(Mutex<Option<Ticket>>);
+
+impl TicketCache {
+    /// ticket that is not about to expire
+    pub(crate) fn get(&self) -> Option<Ticket> {
+        let mut ticket = self.0.lock().unwrap();
+        if ticket
+            .as_ref()
+            .is_some_and(|t| t.expires <= Instant::now() + EXPIRY_MARGIN)
+        {
+            *ticket = None;
+        }
+        ticket.clone()
+    }
+    pub(crate) fn set(&self, ticket: Option<Ticket>) {
+        *self.0.lock().unwrap() = ticket;
+    }
+}
+
+/// tell server a ticket is wanted, before full handshake
+pub(crate) async fn request(conn: &mut TcpStream) -> Result<()> {
+    conn.write_all(&REQUEST_TICKET).await?;
+    Ok(())
+}
+
+/// present ticket to server, before resumed handshake
+pub(crate) async fn present(conn: &mut TcpStream, ticket: &Ticket) -> Result<()> {
+    let mut msg = RESUME.to_vec();
+    msg.extend_from_slice(&(ticket.blob.len() as u16).to_le_bytes());
+    msg.extend_from_slice(&ticket.blob);
+    conn.write_all(&msg).await?;
+    Ok(())
+}
+
+/// read ticket sent by server after handshake, `None` if server does not issue tickets
+pub(crate) async fn receive<S>(stream: &mut NoiseStream<S>) -> Result<Option<Ticket>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = stream.read_u16_le().await? as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut blob = vec![0; len];
+    stream.read_exact(&mut blob).await?;
+    let mut secret = vec![0; SECRET_LEN];
+    stream.read_exact(&mut secret).await?;
+    let lifetime = stream.read_u32_le().await?;
+    Ok(Some(Ticket {
+        blob,
+        secret,
+        expires: Instant::now() + Duration::from_secs(lifetime.into()),
+    }))
+}
+
+#[cfg(feature = "server")]
+pub(crate) use server::{peek_marker, read_hello, Hello, TicketIssuer};
+
+#[cfg(feature = "server")]
+mod server {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use blake2::{Blake2s256, Digest};
+    use chacha20poly1305::aead::{Aead, NewAead};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use tokio::io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{REQUEST_TICKET, RESUME, SECRET_LEN};
+    use crate::consts::PATTERN;
+    use crate::error::{Error, Result};
+    use crate::server::HANDSHAKE_TIMEOUT;
+
+    /// how client starts a connection
+    pub(crate) enum Hello {
+        /// full handshake of an older client or a cluster node
+        Full,
+        /// full handshake, then send a ticket
+        FullWithTicket,
+        /// resumed handshake with sealed ticket
+        Resume(Vec<u8>),
+    }
+
+    /// peek two bytes sent by client before handshake, a marker or length of handshake message,
+    /// failing if client does not send them in handshake timeout
+    pub(crate) async fn peek_marker(stream: &mut TcpStream) -> io::Result<[u8; 2]> {
+        let mut head = [0; 2];
+        let peek = async {
+            loop {
+                match stream.peek(&mut head).await? {
+                    0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                    1 => tokio::time::sleep(Duration::from_millis(10)).await,
+                    _ => return Ok(head),
+                }
+            }
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, peek)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    /// tell how client starts by its marker, consuming it unless it is a full handshake
+    pub(crate) async fn read_hello(stream: &mut TcpStream) -> io::Result<Hello> {
+        let mut head = peek_marker(stream).await?;
+        match head {
+            REQUEST_TICKET => {
+                stream.read_exact(&mut head).await?;
+                Ok(Hello::FullWithTicket)
+            }
+            RESUME => {
+                stream.read_exact(&mut head).await?;
+                let len = stream.read_u16_le().await? as usize;
+                let mut blob = vec![0; len];
+                stream.read_exact(&mut blob).await?;
+                Ok(Hello::Resume(blob))
+            }
+            _ => Ok(Hello::Full),
+        }
+    }
+
+    pub(super) fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// issues and opens tickets sealed by a key derived from server private key,
+    /// so tickets stay valid across restarts and nodes of a cluster
+    pub(crate) struct TicketIssuer {
+        cipher: ChaCha20Poly1305,
+        /// lifetime of tickets in seconds, no tickets are issued if 0
+        lifetime: u64,
+    }
+
+    impl TicketIssuer {
+        pub(crate) fn new(prikey: &[u8], lifetime: u64) -> Self {
+            let mut hasher = Blake2s256::new();
+            hasher.update(b"portguard ticket key");
+            hasher.update(prikey);
+            let key = hasher.finalize();
+            TicketIssuer {
+                cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+                lifetime,
+            }
+        }
+        /// send a new ticket of client, an empty one if tickets are disabled
+        pub(crate) async fn issue<S>(&self, stream: &mut S, pubkey: &[u8]) -> Result<()>
+        where
+            S: AsyncWrite + Unpin,
+        {
+            if self.lifetime == 0 {
+                stream.write_u16_le(0).await?;
+                return Ok(());
+            }
+            // random bytes from a fresh key
+            let secret = snowstorm::Builder::new(PATTERN.parse()?)
+                .generate_keypair()?
+                .private;
+            let blob = self.seal(pubkey, &secret, now() + self.lifetime)?;
+            let mut msg = (blob.len() as u16).to_le_bytes().to_vec();
+            msg.extend_from_slice(&blob);
+            msg.extend_from_slice(&secret);
+            let lifetime = self.lifetime.min(u32::MAX.into()) as u32;
+            msg.extend_from_slice(&lifetime.to_le_bytes());
+            stream.write_all(&msg).await?;
+            Ok(())
+        }
+        /// ticket of client expiring at `expires`, nonce is derived from secret
+        pub(super) fn seal(&self, pubkey: &[u8], secret: &[u8], expires: u64) -> Result<Vec<u8>> {
+            let nonce = &Blake2s256::digest(secret)[..12];
+            let mut plain = pubkey.to_vec();
+            plain.extend_from_slice(secret);
+            plain.extend_from_slice(&expires.to_le_bytes());
+            let mut blob = nonce.to_vec();
+            let sealed = self
+                .cipher
+                .encrypt(Nonce::from_slice(nonce), &plain[..])
+                .map_err(|_| Error::Config(String::from("failed to seal ticket")))?;
+            blob.extend(sealed);
+            Ok(blob)
+        }
+        /// client public key and secret of a valid ticket
+        pub(crate) fn open(&self, blob: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+            if self.lifetime == 0 || blob.len() < 12 {
+                return None;
+            }
+            let (nonce, sealed) = blob.split_at(12);
+            let plain = self.cipher.decrypt(Nonce::from_slice(nonce), sealed).ok()?;
+            let (pubkey, rest) = plain.split_at_checked(32)?;
+            let (secret, expires) = rest.split_at_checked(SECRET_LEN)?;
+            let expires = u64::from_le_bytes(expires.try_into().ok()?);
+            (expires > now()).then(|| (pubkey.to_vec(), secret.to_vec()))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::io;
+
+    use tokio::net::TcpListener;
+
+    use super::server::*;
+    use super::*;
+
+    const PRIKEY: [u8; 32] = [7; 32];
+    const PUBKEY: [u8; 32] = [9; 32];
+
+    /// ticket as received by client, parsed from message sent by issuer
+    async fn issue(issuer: &TicketIssuer) -> Option<Ticket> {
+        let mut msg = vec![];
+        issuer.issue(&mut msg, &PUBKEY).await.unwrap();
+        let mut msg = &msg[..];
+        let len = msg.read_u16_le().await.unwrap() as usize;
+        if len == 0 {
+            return None;
+        }
+        let mut blob = vec![0; len];
+        msg.read_exact(&mut blob).await.unwrap();
+        let mut secret = vec![0; SECRET_LEN];
+        msg.read_exact(&mut secret).await.unwrap();
+        let lifetime = msg.read_u32_le().await.unwrap();
+        assert!(msg.is_empty());
+        Some(Ticket {
+            blob,
+            secret,
+            expires: Instant::now() + Duration::from_secs(lifetime.into()),
+        })
+    }
+
+    #[tokio::test]
+    async fn issued_ticket_is_opened() {
+        let issuer = TicketIssuer::new(&PRIKEY, 3600);
+        let ticket = issue(&issuer).await.unwrap();
+        let (pubkey, secret) = issuer.open(&ticket.blob).unwrap();
+        assert_eq!(pubkey, PUBKEY);
+        assert_eq!(secret, ticket.secret);
+        // issuer with same key, e.g. after restart or on another node
+        assert!(TicketIssuer::new(&PRIKEY, 3600)
+            .open(&ticket.blob)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn foreign_or_tampered_ticket_is_rejected() {
+        let issuer = TicketIssuer::new(&PRIKEY, 3600);
+        let ticket = issue(&issuer).await.unwrap();
+        assert!(TicketIssuer::new(&[8; 32], 3600)
+            .open(&ticket.blob)
+            .is_none());
+        let mut blob = ticket.blob.clone();
+        *blob.last_mut().unwrap() ^= 1;
+        assert!(issuer.open(&blob).is_none());
+        assert!(issuer.open(&ticket.blob[..11]).is_none());
+    }
+
+    #[tokio::test]
+    async fn disabled_issuer_sends_no_ticket() {
+        let issuer = TicketIssuer::new(&PRIKEY, 0);
+        assert!(issue(&issuer).await.is_none());
+        let ticket = issue(&TicketIssuer::new(&PRIKEY, 3600)).await.unwrap();
+        assert!(issuer.open(&ticket.blob).is_none());
+    }
+
+    #[test]
+    fn expired_ticket_is_rejected() {
+        let issuer = TicketIssuer::new(&PRIKEY, 3600);
+        let secret = [5; SECRET_LEN];
+        let blob = issuer.seal(&PUBKEY, &secret, now() + 2).unwrap();
+        assert_eq!(issuer.open(&blob), Some((PUBKEY.to_vec(), secret.to_vec())));
+        for expires in [now(), now() - 1, 0] {
+            let blob = issuer.seal(&PUBKEY, &secret, expires).unwrap();
+            assert!(issuer.open(&blob).is_none(), "{expires}");
+        }
+    }
+
+    #[test]
+    fn cache_drops_tickets_about_to_expire() {
+        let ticket = |lifetime| Ticket {
+            blob: vec![1],
+            secret: vec![2; SECRET_LEN],
+            expires: Instant::now() + Duration::from_secs(lifetime),
+        };
+        let cache = TicketCache::default();
+        cache.set(Some(ticket(60)));
+        assert!(cache.get().is_some());
+        cache.set(Some(ticket(3)));
+        assert!(cache.get().is_none());
+        assert!(cache.get().is_none());
+    }
+
+    #[tokio::test]
+    async fn hello_is_told_by_marker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ticket = issue(&TicketIssuer::new(&PRIKEY, 3600)).await.unwrap();
+        let blob = ticket.blob.clone();
+        let client = tokio::spawn(async move {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            request(&mut conn).await.unwrap();
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            present(&mut conn, &ticket).await.unwrap();
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            conn.write_all(&[32, 0]).await.unwrap();
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            read_hello(&mut stream).await,
+            Ok(Hello::FullWithTicket)
+        ));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(read_hello(&mut stream).await, Ok(Hello::Resume(b)) if b == blob));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(read_hello(&mut stream).await, Ok(Hello::Full)));
+        // length of full handshake message is not consumed
+        assert_eq!(stream.read_u16_le().await.unwrap(), 32);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_marker_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        conn.write_all(&[0xfe]).await.unwrap();
+        let err = peek_marker(&mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // closed before sending anything
+        drop(TcpStream::connect(listener.local_addr().unwrap()).await);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let err = peek_marker(&mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        drop(conn);
+    }
+}
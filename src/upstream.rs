@@ -0,0 +1,127 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use fast_socks5::client::{Config, Socks5Stream};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::Socks5Command;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::dialer::Dialer;
+use crate::dns::Resolver;
+
+/// max length of http proxy response header
+const HTTP_HEADER_LEN: usize = 4096;
+
+/// upstream proxy used by server to reach targets
+/// in config: upstream = "direct"
+///     or     upstream = "socks5://127.0.0.1:1080"
+///     or     upstream = "http://127.0.0.1:3128"
+#[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Upstream {
+    /// connect to target directly
+    #[default]
+    Direct,
+    /// connect through a socks5 proxy
+    Socks5(String),
+    /// connect through a http proxy with CONNECT method
+    Http(String),
+}
+
+impl Upstream {
+    /// connect to target through upstream proxy
+    pub(crate) async fn connect(
+        &self,
+        target: SocketAddr,
+        resolver: &Resolver,
+        dialer: &dyn Dialer,
+    ) -> io::Result<TcpStream> {
+        match self {
+            Upstream::Direct => dialer.dial(target).await,
+            Upstream::Socks5(proxy) => {
+                let proxy = resolver.resolve(proxy).await?;
+                let stream = dialer.dial(proxy).await?;
+                let mut stream = Socks5Stream::use_stream(stream, None, Config::default())
+                    .await
+                    .map_err(io::Error::other)?;
+                stream
+                    .request(Socks5Command::TCPConnect, TargetAddr::Ip(target))
+                    .await
+                    .map_err(io::Error::other)?;
+                Ok(stream.get_socket())
+            }
+            Upstream::Http(proxy) => {
+                let proxy = resolver.resolve(proxy).await?;
+                let mut stream = dialer.dial(proxy).await?;
+                let req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n");
+                stream.write_all(req.as_bytes()).await?;
+                // read response header byte by byte, not to consume tunneled data
+                let mut header = Vec::new();
+                while !header.ends_with(b"\r\n\r\n") {
+                    if header.len() >= HTTP_HEADER_LEN {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "header too long",
+                        ))?
+                    }
+                    header.push(stream.read_u8().await?);
+                }
+                let status = String::from_utf8_lossy(&header);
+                match status.split_whitespace().nth(1) {
+                    Some("200") => Ok(stream),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!(
+                            "http proxy refused: {}",
+                            status.lines().next().unwrap_or("")
+                        ),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("direct") {
+            Ok(Upstream::Direct)
+        } else if let Some(addr) = s.strip_prefix("socks5://") {
+            Ok(Upstream::Socks5(addr.to_string()))
+        } else if let Some(addr) = s.strip_prefix("http://") {
+            Ok(Upstream::Http(addr.trim_end_matches('/').to_string()))
+        } else {
+            Err(format!("Invalid upstream proxy: {s}"))
+        }
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Upstream> for String {
+    fn from(u: Upstream) -> Self {
+        u.to_string()
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Direct => write!(f, "direct"),
+            Upstream::Socks5(a) => write!(f, "socks5://{a}"),
+            Upstream::Http(a) => write!(f, "http://{a}"),
+        }
+    }
+}
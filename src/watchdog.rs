@@ -0,0 +1,106 @@
+/// supervisor of client, restarting it after a panic or an unrecoverable error,
+/// with a crash report of each written for support
+use std::backtrace::Backtrace;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+
+/// delay before first restart, doubled after each one up to `MAX_RESTART_DELAY`
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// a run lasting this long resets restart delay
+const STABLE_RUN: Duration = Duration::from_secs(300);
+
+/// message, location and backtrace of last panic, recorded by panic hook
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = format!("{info}\n\nbacktrace:\n{}", Backtrace::force_capture());
+        *LAST_PANIC.lock().unwrap_or_else(|e| e.into_inner()) = Some(report);
+        default(info);
+    }));
+}
+
+/// errors a restart does not fix
+fn is_fatal(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Config(_)
+            | Error::InvalidRemote(_)
+            | Error::Passphrase
+            | Error::AlreadyRunning(_)
+            | Error::HashDenied
+    )
+}
+
+/// run client until it stops normally or fails with a fatal error,
+/// restarting it after other errors and panics, reports are written into `crash_dir`
+pub(crate) async fn supervise(opts: ClientOptions, crash_dir: PathBuf) -> Result<()> {
+    install_panic_hook();
+    let mut delay = RESTART_DELAY;
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let failure = match tokio::spawn(Client::run_once(opts.clone())).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) if is_fatal(&e) => return Err(e),
+            Ok(Err(e)) => format!("error: {e}"),
+            Err(e) if e.is_panic() => {
+                let panic = LAST_PANIC.lock().unwrap_or_else(|e| e.into_inner()).take();
+                format!("panic: {}", panic.unwrap_or_default())
+            }
+            Err(e) => format!("task failed: {e}"),
+        };
+        match write_report(&crash_dir, restarts, &failure) {
+            Ok(path) => log::error!(
+                "Client crashed, report is written to {}, restarting in {:?}",
+                path.display(),
+                delay
+            ),
+            Err(e) => log::error!(
+                "Client crashed, restarting in {:?}. Failed to write report: {}",
+                delay,
+                e
+            ),
+        }
+        if started.elapsed() >= STABLE_RUN {
+            delay = RESTART_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+        restarts += 1;
+    }
+}
+
+/// write a crash report, return its path
+fn write_report(dir: &Path, restarts: u64, failure: &str) -> io::Result<PathBuf> {
+    let now = SystemTime::now();
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = dir.join(format!(
+        "portguard-crash-{}-{}.txt",
+        std::process::id(),
+        secs
+    ));
+    let report = format!(
+        "portguard client crash report\ntime: {}\nversion: {}\nplatform: {}/{}\npid: {}\nrestarts: {}\n\n{}\n",
+        humantime::format_rfc3339_seconds(now),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::process::id(),
+        restarts,
+        failure
+    );
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, report)?;
+    Ok(path)
+}
@@ -0,0 +1,575 @@
+/// http reverse proxy to reverse proxy services, routed by url path prefix
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::bind;
+use crate::error::Result;
+use crate::health::write_response;
+use crate::proxy;
+use crate::qos::Priority;
+use crate::server::Server;
+use crate::tenant::ServiceKey;
+
+/// max length of http request header
+const HEADER_LEN: usize = 16 * 1024;
+/// max time to read http request header, or response header of an upgrade
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// status of response accepting an upgrade
+const SWITCHING_PROTOCOLS: &str = "101";
+/// start of http/2 connection preface with prior knowledge, read as a request header
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+/// route of http requests to a reverse proxy service
+/// in config:
+/// [[http_routes]]
+/// path = "/grafana"
+/// service = 3
+/// tenant = "team-a"    # tenant of service, if any
+/// strip_prefix = true  # forward "/grafana/login" as "/login"
+/// http2 = true         # also receive http/2 connections with prior knowledge, e.g. grpc
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct HttpRoute {
+    /// url path prefix, matched by whole segments
+    path: String,
+    /// service id of reverse proxy
+    service: usize,
+    /// remove prefix from path before forwarding
+    #[serde(default)]
+    strip_prefix: bool,
+    /// route of http/2 connections with prior knowledge (h2c), whose paths are not parsed,
+    /// first such route is used
+    #[serde(default)]
+    http2: bool,
+    /// tenant of service
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    tenant: Option<String>,
+}
+
+impl HttpRoute {
+    fn service_key(&self) -> ServiceKey {
+        ServiceKey::new(self.tenant.as_deref(), self.service)
+    }
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+    }
+    /// path forwarded to service
+    fn forward_path(&self, path: &str) -> String {
+        match self.strip_prefix {
+            true => {
+                let rest = &path[self.path.trim_end_matches('/').len()..];
+                match rest.starts_with('/') {
+                    true => rest.to_string(),
+                    false => format!("/{rest}"),
+                }
+            }
+            false => path.to_string(),
+        }
+    }
+}
+
+/// route with longest prefix matching path
+fn find_route<'a>(routes: &'a [HttpRoute], path: &str) -> Option<&'a HttpRoute> {
+    routes
+        .iter()
+        .filter(|r| r.matches(path))
+        .max_by_key(|r| r.path.trim_end_matches('/').len())
+}
+
+/// request line and headers of http request
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    path: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(buf).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let (method, path, version) = (parts.next()?, parts.next()?, parts.next()?);
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                let (name, value) = l.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(RequestHead {
+            method: method.to_string(),
+            path: path.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    fn remove_header(&mut self, name: &str) -> Option<String> {
+        let pos = self
+            .headers
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        Some(self.headers.remove(pos).1)
+    }
+    fn set_header(&mut self, name: &str, value: String) {
+        while self.remove_header(name).is_some() {}
+        self.headers.push((name.to_string(), value));
+    }
+    /// request asks to upgrade connection, e.g. to websocket or h2c
+    fn is_upgrade(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or_default();
+        self.header("Upgrade").is_some()
+            && connection
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+    }
+    /// rewrite request forwarded to service of `route`, sent by `peer`
+    fn rewrite(&mut self, route: &HttpRoute, peer: IpAddr) {
+        let forwarded_for = match self.remove_header("X-Forwarded-For") {
+            Some(list) => format!("{list}, {peer}"),
+            None => peer.to_string(),
+        };
+        self.set_header("X-Forwarded-For", forwarded_for);
+        self.set_header("X-Forwarded-Proto", String::from("http"));
+        if let Some(host) = self.header("Host").map(String::from) {
+            self.set_header("X-Forwarded-Host", host);
+        }
+        if route.strip_prefix {
+            self.set_header("X-Forwarded-Prefix", route.path.clone());
+            self.path = route.forward_path(&self.path);
+        }
+        // one request per connection, so that every request is routed,
+        // upgrades keep their connection header, e.g. "Upgrade, HTTP2-Settings",
+        // they are closed after response unless service switches protocols
+        for name in ["Keep-Alive", "Proxy-Connection"] {
+            self.remove_header(name);
+        }
+        if !self.is_upgrade() {
+            self.set_header("Connection", String::from("close"));
+        }
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.path, self.version);
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head += "\r\n";
+        head.into_bytes()
+    }
+}
+
+/// status line and headers of http response of service
+#[derive(Debug)]
+struct ResponseHead {
+    line: String,
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(buf).ok()?;
+        let mut lines = head.split("\r\n");
+        let line = lines.next()?.to_string();
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                let (name, value) = l.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(ResponseHead { line, headers })
+    }
+    fn is_switching_protocols(&self) -> bool {
+        self.line.split(' ').nth(1) == Some(SWITCHING_PROTOCOLS)
+    }
+    /// tell client that connection is closed after this response
+    fn set_close(&mut self) {
+        self.headers.retain(|(n, _)| {
+            !n.eq_ignore_ascii_case("Connection") && !n.eq_ignore_ascii_case("Keep-Alive")
+        });
+        self.headers
+            .push((String::from("Connection"), String::from("close")));
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{}\r\n", self.line);
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head += "\r\n";
+        head.into_bytes()
+    }
+}
+
+/// read http header ending with an empty line, and bytes read after it
+async fn read_head<S>(stream: &mut S) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let read = async {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            // end of header may be split between chunks
+            let from = buf.len().saturating_sub(3);
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+                let rest = buf.split_off(from + pos + 4);
+                return Ok((buf, rest));
+            }
+            if buf.len() >= HEADER_LEN {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "header too long",
+                ))?
+            }
+        }
+    };
+    timeout(HEADER_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "header timeout"))?
+}
+
+/// serve http requests, forwarding them to services by `routes`
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    routes: Vec<HttpRoute>,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| bind::explain(addr, e))?;
+    log::info!("Http reverse proxy listening on: {:?}", addr);
+    let routes = Arc::new(routes);
+    let tasks = server.tasks();
+    loop {
+        let (mut stream, peer) = bind::accept(|| listener.accept()).await;
+        let routes = routes.clone();
+        let server = server.clone();
+        tasks.spawn(async move {
+            let _guard = match server.admit_connection() {
+                Ok(guard) => guard,
+                Err(e) => {
+                    log::warn!("Refused http connection from {peer}. {}", e);
+                    let status = "503 Service Unavailable";
+                    let _ = write_response(&mut stream, status, &format!("{e}\n")).await;
+                    return;
+                }
+            };
+            if let Err(e) = handle_request(stream, peer, &routes, &server).await {
+                log::warn!("Http request of {peer} failed. Error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    routes: &[HttpRoute],
+    server: &Server,
+) -> Result<()> {
+    let (head, rest) = read_head(&mut stream).await?;
+    if head == H2_PREFACE {
+        let preface = [head, rest].concat();
+        return handle_h2_connection(stream, peer, &preface, routes, server).await;
+    }
+    let mut head = match RequestHead::parse(&head) {
+        Some(head) => head,
+        None => return Ok(write_response(&mut stream, "400 Bad Request", "bad request\n").await?),
+    };
+    let route = match find_route(routes, &head.path) {
+        Some(route) => route,
+        None => return Ok(write_response(&mut stream, "404 Not Found", "not found\n").await?),
+    };
+    log::info!(
+        "Http request {} {} of {peer} to service {}",
+        head.method,
+        head.path,
+        route.service_key()
+    );
+    let (permit, mut outbound) = match server
+        .open_service_stream(&route.service_key(), Priority::Normal)
+        .await
+    {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            log::warn!("{}", e);
+            let status = "502 Bad Gateway";
+            return Ok(write_response(&mut stream, status, &format!("{e}\n")).await?);
+        }
+    };
+    head.rewrite(route, peer.ip());
+    outbound.write_all(&head.to_bytes()).await?;
+    outbound.write_all(&rest).await?;
+    match head.is_upgrade() {
+        true => handle_upgrade(stream, outbound).await?,
+        false => {
+            proxy::transfer_and_log_error(stream, outbound).await;
+        }
+    }
+    drop(permit);
+    Ok(())
+}
+
+/// pass connection through once service switches protocols, e.g. to websocket or h2c,
+/// otherwise only its response is sent back before closing, so that next requests are routed
+async fn handle_upgrade<S>(mut stream: TcpStream, mut outbound: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, rest) = {
+        let (mut client_rd, _) = stream.split();
+        let (mut service_rd, mut service_wr) = tokio::io::split(&mut outbound);
+        // body of request, if any, until response
+        let forward = async {
+            tokio::io::copy(&mut client_rd, &mut service_wr).await.ok();
+            std::future::pending().await
+        };
+        tokio::select! {
+            head = read_head(&mut service_rd) => head?,
+            head = forward => head,
+        }
+    };
+    match ResponseHead::parse(&head) {
+        Some(response) if response.is_switching_protocols() => {
+            stream.write_all(&head).await?;
+            stream.write_all(&rest).await?;
+            proxy::transfer_and_log_error(stream, outbound).await;
+        }
+        response => {
+            let head = match response {
+                Some(mut response) => {
+                    response.set_close();
+                    response.to_bytes()
+                }
+                None => head,
+            };
+            stream.write_all(&head).await?;
+            stream.write_all(&rest).await?;
+            // requests sent after the response are dropped instead of reaching service
+            let (mut client_rd, mut client_wr) = stream.split();
+            let mut dropped = tokio::io::sink();
+            tokio::select! {
+                _ = tokio::io::copy(&mut outbound, &mut client_wr) => {}
+                _ = tokio::io::copy(&mut client_rd, &mut dropped) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// pass http/2 connection with prior knowledge through to service as is,
+/// all of its streams go to the same service
+async fn handle_h2_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    preface: &[u8],
+    routes: &[HttpRoute],
+    server: &Server,
+) -> Result<()> {
+    let route = match routes.iter().find(|r| r.http2) {
+        Some(route) => route,
+        None => {
+            log::warn!("Http/2 connection of {peer} has no route");
+            return Ok(stream.shutdown().await?);
+        }
+    };
+    log::info!("Http/2 connection of {peer} to service {}", route.service);
+    let (permit, mut outbound) = server
+        .open_service_stream(&route.service_key(), Priority::Normal)
+        .await?;
+    outbound.write_all(preface).await?;
+    proxy::transfer_and_log_error(stream, outbound).await;
+    drop(permit);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, service: usize, strip_prefix: bool) -> HttpRoute {
+        HttpRoute {
+            path: path.to_string(),
+            service,
+            strip_prefix,
+            http2: false,
+            tenant: None,
+        }
+    }
+
+    fn request(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn routes_are_matched_by_longest_prefix() {
+        let routes = [
+            route("/", 1, false),
+            route("/grafana", 2, false),
+            route("/grafana/api/", 3, false),
+        ];
+        let cases = [
+            ("/", Some(1)),
+            ("/index.html", Some(1)),
+            ("/grafana", Some(2)),
+            ("/grafana/", Some(2)),
+            ("/grafana?orgId=1", Some(2)),
+            ("/grafanax", Some(1)),
+            ("/grafana/api", Some(3)),
+            ("/grafana/api/dashboards", Some(3)),
+        ];
+        for (path, service) in cases {
+            let found = find_route(&routes, path).map(|r| r.service);
+            assert_eq!(found, service, "{path}");
+        }
+        assert!(find_route(&routes[1..], "/prometheus").is_none());
+        assert!(find_route(&routes[1..], "/grafanax").is_none());
+    }
+
+    #[test]
+    fn paths_are_forwarded_with_or_without_prefix() {
+        let cases = [
+            ("/grafana", true, "/grafana/login", "/login"),
+            ("/grafana/", true, "/grafana/login", "/login"),
+            ("/grafana", true, "/grafana", "/"),
+            ("/grafana", true, "/grafana?orgId=1", "/?orgId=1"),
+            ("/grafana", false, "/grafana/login", "/grafana/login"),
+            ("/", true, "/login", "/login"),
+        ];
+        for (prefix, strip, path, forwarded) in cases {
+            let route = route(prefix, 1, strip);
+            assert_eq!(route.forward_path(path), forwarded, "{prefix} {path}");
+        }
+    }
+
+    #[test]
+    fn requests_are_rewritten_for_service() {
+        let peer: IpAddr = "192.0.2.7".parse().unwrap();
+        let mut head = request(
+            "GET /grafana/login HTTP/1.1\r\nHost: gw.example\r\nX-Forwarded-For: 10.0.0.1\r\n\
+             Connection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\n",
+        );
+        head.rewrite(&route("/grafana", 1, true), peer);
+        assert_eq!(head.path, "/login");
+        let cases = [
+            ("X-Forwarded-For", Some("10.0.0.1, 192.0.2.7")),
+            ("X-Forwarded-Proto", Some("http")),
+            ("X-Forwarded-Host", Some("gw.example")),
+            ("X-Forwarded-Prefix", Some("/grafana")),
+            ("Connection", Some("close")),
+            ("Keep-Alive", None),
+        ];
+        for (name, value) in cases {
+            assert_eq!(head.header(name), value, "{name}");
+        }
+
+        let cases = [
+            ("Upgrade: websocket\r\nConnection: Upgrade\r\n", "Upgrade"),
+            (
+                "Upgrade: h2c\r\nConnection: Upgrade, HTTP2-Settings\r\n",
+                "Upgrade, HTTP2-Settings",
+            ),
+            // upgrade header without connection option is no upgrade
+            ("Upgrade: websocket\r\n", "close"),
+            ("Upgrade: websocket\r\nConnection: keep-alive\r\n", "close"),
+        ];
+        for (headers, connection) in cases {
+            let mut head = request(&format!("GET /ws HTTP/1.1\r\n{headers}\r\n"));
+            head.rewrite(&route("/", 1, false), peer);
+            assert_eq!(head.header("Connection"), Some(connection), "{headers}");
+            assert_eq!(head.is_upgrade(), connection != "close", "{headers}");
+        }
+    }
+
+    #[tokio::test]
+    async fn heads_are_read_with_bytes_after_them() {
+        let mut input = &b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody"[..];
+        let (head, rest) = read_head(&mut input).await.unwrap();
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(rest, b"body");
+
+        let mut truncated = &b"GET / HTTP/1.1\r\nHost: a\r\n"[..];
+        let err = read_head(&mut truncated).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let long = [b'a'; HEADER_LEN + 1];
+        let err = read_head(&mut &long[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// connected pair of tcp streams
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn upgrades_pass_through_after_switching_protocols() {
+        let (mut client, stream) = tcp_pair().await;
+        let (outbound, mut service) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(handle_upgrade(stream, outbound));
+        service
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\r\nhi")
+            .await
+            .unwrap();
+        client.write_all(b"frame").await.unwrap();
+        let mut frame = [0; 5];
+        service.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"frame");
+        drop(service);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.ends_with("\r\n\r\nhi"));
+        drop(client);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_upgrades_are_closed_after_response() {
+        let (mut client, stream) = tcp_pair().await;
+        let (outbound, mut service) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(handle_upgrade(stream, outbound));
+        service
+            .write_all(b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        let mut response = vec![0; 128];
+        let n = client.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]).into_owned();
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+        assert!(!response.contains("keep-alive"), "{response}");
+        // next request is not routed, so it never reaches service
+        client
+            .write_all(b"GET /other HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        drop(client);
+        proxy.await.unwrap().unwrap();
+        let mut received = Vec::new();
+        service.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+}